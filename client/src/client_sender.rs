@@ -28,13 +28,25 @@ pub async fn post_public_key(ip: &str, public_key: Vec<u8>) -> Result<Response,
     Ok(response)
 }
 
-pub async fn post_dna_sequence(ip: &str, id: String, dna_sequence: String, signature: Vec<u8>) -> Result<Response, String> {
+pub async fn post_dna_sequence(
+    ip: &str,
+    id: String,
+    dna_sequence: String,
+    signature: Vec<u8>,
+    encrypted: bool,
+    base: Option<String>,
+    counter: u64,
+) -> Result<Response, String> {
     let address = ip.to_string() + "/insert_dna_sequence";
     let client = Client::new();
-    let mut data = HashMap::new();
-    data.insert("id", id); 
-    data.insert("dna_sequence", dna_sequence);
-    data.insert("signature", encode(signature));
+    let data = serde_json::json!({
+        "id": id,
+        "dna_sequence": dna_sequence,
+        "signature": encode(signature),
+        "encrypted": encrypted,
+        "base": base,
+        "counter": counter,
+    });
 
     let response = match client.post(address)
         .json(&data)
@@ -46,6 +58,88 @@ pub async fn post_dna_sequence(ip: &str, id: String, dna_sequence: String, signa
     Ok(response)
 }
 
+/// Posts a patch against `base`, either `{"format": "dmp", "patch_txt": ...}` or
+/// `{"format": "edits", "edits": [...]}`.
+pub async fn post_patch(
+    ip: &str,
+    id: String,
+    base: String,
+    patch: serde_json::Value,
+    counter: u64,
+    signature: Vec<u8>,
+) -> Result<Response, String> {
+    let address = ip.to_string() + "/patch_dna_sequence";
+    let client = Client::new();
+    let data = serde_json::json!({
+        "id": id,
+        "base": base,
+        "patch": patch,
+        "counter": counter,
+        "signature": encode(signature),
+    });
+
+    let response = match client.post(address)
+        .json(&data)
+        .send()
+        .await {
+            Ok(r) => r,
+            Err(e) => panic!("Patch post request has failed with: {:?}", e),
+        };
+    Ok(response)
+}
+
+/// Uploads a sequence in parts of `part_size` bytes, then finalizes the upload with the
+/// signature of its envelope.
+pub async fn upload_dna_sequence(
+    ip: &str,
+    id: String,
+    dna_sequence: &str,
+    part_size: usize,
+    counter: u64,
+    signature: Vec<u8>,
+) -> Result<Response, String> {
+    let client = Client::new();
+    let started: serde_json::Value = client.post(ip.to_string() + "/uploads")
+        .json(&serde_json::json!({ "id": id }))
+        .send()
+        .await
+        .map_err(|e| e.to_string())?
+        .json()
+        .await
+        .map_err(|e| e.to_string())?;
+    let upload = started["upload"].as_str().ok_or("No upload handle in the response")?.to_string();
+
+    let bytes = dna_sequence.as_bytes();
+    for (index, part) in bytes.chunks(part_size).enumerate() {
+        let response = client.put(format!("{}/uploads/{}/{}", ip, upload, index))
+            .body(part.to_vec())
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("Part {} was refused: {}", index, response.status()));
+        }
+    }
+
+    let data = serde_json::json!({
+        "signature": encode(signature),
+        "counter": counter,
+    });
+    client.post(format!("{}/uploads/{}/finalize", ip, upload))
+        .json(&data)
+        .send()
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Reads a sequence as streamed plain text.
+pub async fn stream_dna_sequence(ip: &str, id: String) -> Result<Response, String> {
+    Client::new().get(format!("{}/dna/{}/stream", ip, id))
+        .send()
+        .await
+        .map_err(|e| e.to_string())
+}
+
 pub async fn get_dna_sequence(ip: &str, id: String) -> Result<Response, String> {
     let address = ip.to_string() + "/dna";
     let client = Client::new();
@@ -1,11 +1,15 @@
 pub mod dna_client {
 
-    use ring::rand::SystemRandom;
+    use base64::{Engine as _, engine::general_purpose};
+    use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+    use ring::rand::{SecureRandom, SystemRandom};
     use ring::signature::{KeyPair, Ed25519KeyPair};
+    use envelope::SequenceEnvelope;
 
     pub struct DnaClient {
         pub key_pair: Ed25519KeyPair,
         pub dna_sequence: String,
+        encryption_key: Option<[u8; 32]>,
     }
 
 
@@ -23,24 +27,85 @@ pub mod dna_client {
             DnaClient {
                 dna_sequence: dna_sequence.into(),
                 key_pair,
+                encryption_key: None,
             }
         }
 
+        /// Creates a client that encrypts its sequence before upload, so nodes
+        /// only ever store ciphertext. The AES-256-GCM key never leaves the client.
+        pub fn new_encrypted(dna_sequence: impl Into<String>) -> Self {
+            let (key_pair, rng) = generate_key_pair();
+            let mut encryption_key = [0u8; 32];
+            rng.fill(&mut encryption_key).unwrap();
+            DnaClient {
+                dna_sequence: dna_sequence.into(),
+                key_pair,
+                encryption_key: Some(encryption_key),
+            }
+        }
+
+        pub fn is_encrypted(&self) -> bool {
+            self.encryption_key.is_some()
+        }
+
         pub fn get_pub_key(&self) -> Vec<u8> {
             self.key_pair.public_key().as_ref().to_vec().clone() 
         }
 
         pub fn sign(&self) -> Vec<u8> {
-            let dna_bytes = self.dna_sequence.as_bytes();
+            self.sign_bytes(self.dna_sequence.as_bytes())
+        }
+
+        /// Signs the envelope of `dna_sequence` instead of its text, so the signature covers
+        /// its SHA-256, `id` and the write `counter`, which must increase with every write.
+        pub fn sign_envelope(&self, id: &str, counter: u64) -> Vec<u8> {
+            let envelope = SequenceEnvelope::new(id, self.dna_sequence.as_bytes(), counter);
+            self.sign_bytes(envelope.canonical().as_bytes())
+        }
+
+        fn sign_bytes(&self, bytes: &[u8]) -> Vec<u8> {
             let signature = self
                 .key_pair
-                .sign(dna_bytes)
+                .sign(bytes)
                 .as_ref()
                 .to_vec();
             println!("signature: {:?}", &signature);
             signature
         }
 
+        /// Returns the value to upload and its signature: the plain sequence, or
+        /// base64(nonce || ciphertext) for encrypted clients.
+        pub fn payload(&self) -> (String, Vec<u8>) {
+            let Some(key) = self.encryption_key else {
+                return (self.dna_sequence.clone(), self.sign());
+            };
+            let key = LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &key).unwrap());
+            let mut nonce = [0u8; NONCE_LEN];
+            SystemRandom::new().fill(&mut nonce).unwrap();
+            let mut sealed = self.dna_sequence.as_bytes().to_vec();
+            key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut sealed)
+                .unwrap();
+            let mut blob = nonce.to_vec();
+            blob.extend(sealed);
+            let payload = general_purpose::STANDARD.encode(blob);
+            let signature = self.sign_bytes(payload.as_bytes());
+            (payload, signature)
+        }
+
+        /// Decrypts a payload returned by a node for an encrypted client.
+        pub fn decrypt(&self, payload: &str) -> Option<String> {
+            let key = LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &self.encryption_key?).unwrap());
+            let blob = general_purpose::STANDARD.decode(payload).ok()?;
+            if blob.len() < NONCE_LEN {
+                return None;
+            }
+            let (nonce, sealed) = blob.split_at(NONCE_LEN);
+            let mut sealed = sealed.to_vec();
+            let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+            let plain = key.open_in_place(nonce, Aad::empty(), &mut sealed).ok()?;
+            String::from_utf8(plain.to_vec()).ok()
+        }
+
         pub fn set_dna_sequence(&mut self, dna_sequence: impl Into<String>) {
             self.dna_sequence = dna_sequence.into();
         }
@@ -2,7 +2,7 @@ mod dna_client;
 mod client_sender;
 
 use crate::dna_client::dna_client::DnaClient;
-use tracing::{debug, info};
+use tracing::info;
 
 const IP: &str = "http://127.0.0.1:8082";
 const ANOTHER_IP: &str = "http://127.0.0.1:8083";
@@ -22,31 +22,96 @@ async fn main() {
         .trim_matches('\"')
         .to_string();
     info!("id: {}", id);
-    let signature = dna_client.sign();
+    // Every write signs the envelope of the sequence with a counter one above the last write.
+    let signature = dna_client.sign_envelope(&id, 1);
 
     let dna_response = client_sender::post_dna_sequence(
         IP, 
         id.clone(), 
         dna_client.dna_sequence.clone(), 
-        signature.clone()
+        signature.clone(),
+        false,
+        None,
+        1,
     ).await.unwrap();
     info!("Dna sequence post response: {:?}", dna_response);
 
     let dna_get_response = client_sender::get_dna_sequence(IP, id.clone()).await.unwrap(); 
     info!("Dna sequence get response: {:?}", dna_get_response);
+    let stored: serde_json::Value = dna_get_response.json().await.unwrap();
+    let base = stored["version"].as_str().map(str::to_string);
 
     dna_client.set_dna_sequence("TCCG");
-    let signature = dna_client.sign();
+    let signature = dna_client.sign_envelope(&id, 2);
 
-    let patch_response = client_sender::post_dna_sequence(IP, id.clone(), dna_client.dna_sequence.clone(), signature).await.unwrap();
+    // Any node accepts writes and routes them through the primary, so the update may go elsewhere.
+    // The update states the version it was made against, so a concurrent update is not overwritten.
+    let patch_response = client_sender::post_dna_sequence(ANOTHER_IP, id.clone(), dna_client.dna_sequence.clone(), signature, false, base.clone(), 2).await.unwrap();
     info!("Dna patch post response: {:?}", patch_response);
 
-    let dna_get_response = client_sender::get_dna_sequence(IP, id.clone()).await.unwrap(); 
+    // The same base is now stale, so a second update against it is refused with 409.
+    dna_client.set_dna_sequence("TCCA");
+    let signature = dna_client.sign_envelope(&id, 3);
+    let stale_response = client_sender::post_dna_sequence(IP, id.clone(), dna_client.dna_sequence.clone(), signature, false, base, 3).await.unwrap();
+    info!("Stale dna patch post response: {} {}", stale_response.status(), stale_response.text().await.unwrap());
+
+    let dna_get_response = client_sender::get_dna_sequence(ANOTHER_IP, id.clone()).await.unwrap(); 
     info!("Dna patch get response: {:?}", dna_get_response);
     
-    let dna_get_response = client_sender::get_dna_sequence(ANOTHER_IP, id).await.unwrap(); 
+    let dna_get_response = client_sender::get_dna_sequence(IP, id.clone()).await.unwrap(); 
     info!("Dna patch get response: {:?}", &dna_get_response);
-    info!("Dna patch response: {}", dna_get_response.text().await.unwrap().trim_matches('\"').to_string());
+    let stored: serde_json::Value = dna_get_response.json().await.unwrap();
+    info!("Dna patch response: {}", stored["dna_sequence"]);
+
+    // Only the edits are uploaded; the signature covers the envelope of the patched sequence.
+    let base = stored["version"].as_str().unwrap_or_default().to_string();
+    dna_client.set_dna_sequence("TGCCGA");
+    let edits = serde_json::json!({
+        "format": "edits",
+        "edits": [
+            {"op": "insert", "position": 1, "bases": "G"},
+            {"op": "insert", "position": 4, "bases": "A"},
+        ],
+    });
+    let signature = dna_client.sign_envelope(&id, 3);
+    let patch_response = client_sender::post_patch(IP, id.clone(), base, edits, 3, signature).await.unwrap();
+    info!("Dna edits post response: {} {}", patch_response.status(), patch_response.text().await.unwrap());
+
+    let dna_get_response = client_sender::get_dna_sequence(ANOTHER_IP, id).await.unwrap();
+    info!("Dna edits get response: {}", dna_get_response.text().await.unwrap());
+
+    // Client-side encrypted sequence: nodes only see ciphertext.
+    let encrypted_client = DnaClient::new_encrypted("GATTACA");
+    let pk_response = client_sender::post_public_key(IP, encrypted_client.get_pub_key()).await.unwrap();
+    let id = pk_response.text().await.unwrap().trim_matches('\"').to_string();
+    let (payload, signature) = encrypted_client.payload();
+    let dna_response = client_sender::post_dna_sequence(
+        IP,
+        id.clone(),
+        payload,
+        signature,
+        encrypted_client.is_encrypted(),
+        None,
+        0,
+    ).await.unwrap();
+    info!("Encrypted dna sequence post response: {:?}", dna_response);
+
+    let dna_get_response = client_sender::get_dna_sequence(ANOTHER_IP, id).await.unwrap();
+    let stored: serde_json::Value = dna_get_response.json().await.unwrap();
+    let ciphertext = stored["dna_sequence"].as_str().unwrap_or_default();
+    info!("Encrypted dna sequence stored as: {}", ciphertext);
+    info!("Decrypted dna sequence: {:?}", encrypted_client.decrypt(ciphertext));
+
+    // Large sequences are uploaded in parts; the signature covers the envelope of the whole.
+    let large_client = DnaClient::new("ACGT".repeat(1 << 19));
+    let pk_response = client_sender::post_public_key(IP, large_client.get_pub_key()).await.unwrap();
+    let id = pk_response.text().await.unwrap().trim_matches('\"').to_string();
+    let signature = large_client.sign_envelope(&id, 1);
+    let upload_response = client_sender::upload_dna_sequence(IP, id.clone(), &large_client.dna_sequence, 512 * 1024, 1, signature).await.unwrap();
+    info!("Chunked upload response: {} {}", upload_response.status(), upload_response.text().await.unwrap());
+
+    let stream_response = client_sender::stream_dna_sequence(ANOTHER_IP, id).await.unwrap();
+    info!("Streamed {} bases", stream_response.text().await.unwrap().len());
 
     pub fn init_tracing() {
         use tracing::level_filters::LevelFilter;
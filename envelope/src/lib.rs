@@ -0,0 +1,79 @@
+//! Canonical signed envelope of a DNA sequence, shared by the node and the client so both hash
+//! and serialise a sequence exactly the same way.
+
+use sha2::{Digest, Sha256};
+
+/// Format version of the envelope, part of its canonical form.
+pub const VERSION: u32 = 1;
+
+/// What the owner of a sequence signs instead of its text: the SHA-256 of the sequence, bound to
+/// its id and to a counter the owner increases with every write, so an older envelope cannot be
+/// replayed over a newer one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SequenceEnvelope {
+    pub id: String,
+    pub sha256: String, // Lowercase hex SHA-256 of the sequence as uploaded.
+    pub counter: u64,
+}
+
+impl SequenceEnvelope {
+    /// Creates the envelope of `sequence`.
+    pub fn new(id: impl Into<String>, sequence: &[u8], counter: u64) -> Self {
+        Self::from_digest(id, sha256_hex(sequence), counter)
+    }
+
+    /// Creates an envelope from an already computed digest, as after applying a patch.
+    pub fn from_digest(id: impl Into<String>, sha256: impl Into<String>, counter: u64) -> Self {
+        SequenceEnvelope { id: id.into(), sha256: sha256.into(), counter }
+    }
+
+    /// Returns the canonical form that is signed: `dna-envelope:{VERSION}:{id}:{sha256}:{counter}`.
+    pub fn canonical(&self) -> String {
+        format!("dna-envelope:{}:{}:{}:{}", VERSION, self.id, self.sha256, self.counter)
+    }
+}
+
+/// Returns the lowercase hex SHA-256 of `bytes`.
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    Sha256::digest(bytes).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sha256_is_lowercase_hex() {
+        assert_eq!(sha256_hex(b""), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(sha256_hex(b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
+
+    #[test]
+    fn new_hashes_the_sequence() {
+        let envelope = SequenceEnvelope::new("id", b"ACGT", 3);
+        assert_eq!(envelope, SequenceEnvelope::from_digest("id", sha256_hex(b"ACGT"), 3));
+    }
+
+    #[test]
+    fn canonical_form_round_trips() {
+        let envelope = SequenceEnvelope::new("4a33efc0-77da-4e04-8190-cd5f0029c1b2", b"ACGT", 7);
+        let canonical = envelope.canonical();
+        let fields: Vec<&str> = canonical.split(':').collect();
+        assert_eq!(fields[..2], ["dna-envelope", "1"]);
+        let parsed = SequenceEnvelope::from_digest(fields[2], fields[3], fields[4].parse().unwrap());
+        assert_eq!(parsed, envelope);
+        assert_eq!(parsed.canonical(), canonical);
+    }
+
+    #[test]
+    fn canonical_form_binds_every_field() {
+        let envelope = SequenceEnvelope::new("id", b"ACGT", 1);
+        for other in [
+            SequenceEnvelope::new("id2", b"ACGT", 1),
+            SequenceEnvelope::new("id", b"ACGA", 1),
+            SequenceEnvelope::new("id", b"ACGT", 2),
+        ] {
+            assert_ne!(other.canonical(), envelope.canonical());
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use crate::{
+    config::Config,
+    identity::NodeIdentity,
+    repository::{
+        db::{DbHandle, QuerryError},
+        snapshot::{self, Cursor, SnapshotError, SnapshotManifest, PAGE_RECORDS},
+    },
+};
+
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use actix_web::web::Bytes;
+use futures::StreamExt;
+use serde::Serialize;
+use tracing::info;
+use thiserror::Error;
+use actix_web::{
+    http::{header::ContentType, StatusCode},
+    error::ResponseError,
+    web::Json,
+    HttpRequest,
+    HttpResponse,
+    web,
+};
+
+/// Errors for administrative requests.
+#[derive(Debug, Error, derive_more::Display)]
+pub enum AdminError {
+    #[display(fmt = "Administrative requests are only accepted from the local host")]
+    Forbidden,
+    SnapshotFailed(SnapshotError),
+    QueryFailed(QuerryError),
+}
+
+impl ResponseError for AdminError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AdminError::Forbidden => StatusCode::FORBIDDEN,
+            AdminError::SnapshotFailed(_) | AdminError::QueryFailed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .insert_header(ContentType::json())
+            .body(self.to_string())
+    }
+}
+
+/// Rejects requests that do not come from the node's own host.
+fn require_local(request: &HttpRequest) -> Result<(), AdminError> {
+    match request.peer_addr() {
+        Some(address) if address.ip().is_loopback() => Ok(()),
+        _ => Err(AdminError::Forbidden),
+    }
+}
+
+/// Response for snapshot requests.
+#[derive(Serialize)]
+struct SnapshotResponse {
+    path: String,
+    manifest: SnapshotManifest,
+}
+
+/// Handler for taking an online snapshot of the node's database with a signed manifest.
+#[actix_web::post("/admin/snapshot")]
+async fn create_snapshot(
+    request: HttpRequest,
+    db: web::Data<Arc<Mutex<DbHandle>>>,
+    config: web::Data<Config>,
+    identity: web::Data<Arc<NodeIdentity>>,
+) -> Result<Json<SnapshotResponse>, AdminError> {
+    require_local(&request)?;
+    let (path, manifest) = {
+        let db = db.lock().unwrap();
+        snapshot::create(&db, &config.node_id, &identity, Path::new(&config.snapshot_dir))
+            .map_err(AdminError::SnapshotFailed)?
+    };
+    info!("Wrote snapshot {} with {} sequences", path.display(), manifest.dna_sequences);
+    Ok(Json(SnapshotResponse {
+        path: path.to_string_lossy().into_owned(),
+        manifest,
+    }))
+}
+
+/// Handler returning a freshly signed manifest of the node's current state, so peers can
+/// compare it with their own before trusting a restored node.
+#[actix_web::get("/manifest")]
+async fn get_manifest(
+    db: web::Data<Arc<Mutex<DbHandle>>>,
+    config: web::Data<Config>,
+    identity: web::Data<Arc<NodeIdentity>>,
+) -> Result<Json<SnapshotManifest>, AdminError> {
+    let db = db.lock().unwrap();
+    SnapshotManifest::describe(&db, &config.node_id, &identity)
+        .map(Json)
+        .map_err(AdminError::QueryFailed)
+}
+
+/// Handler streaming the node's state to a bootstrapping peer as JSON lines: the signed
+/// manifest first, then every record it describes. Records are read a page at a time, with the
+/// database unlocked between pages, so writes made meanwhile may differ from the manifest. The
+/// stream holds plaintext sequences, so without mutual TLS only the local host may request it.
+#[actix_web::get("/snapshot")]
+async fn stream_snapshot(
+    request: HttpRequest,
+    db: web::Data<Arc<Mutex<DbHandle>>>,
+    config: web::Data<Config>,
+    identity: web::Data<Arc<NodeIdentity>>,
+) -> Result<HttpResponse, AdminError> {
+    if config.tls.ca.is_none() {
+        require_local(&request)?;
+    }
+    let manifest = SnapshotManifest::describe_paged(&db, &config.node_id, &identity).map_err(AdminError::QueryFailed)?;
+    info!("Streaming {} records to a bootstrapping peer", manifest.public_keys + manifest.dna_sequences + manifest.tombstones);
+    let manifest = serde_json::to_string(&manifest).map(|line| Bytes::from(line + "\n"))
+        .map_err(actix_web::error::ErrorInternalServerError);
+    let db = db.get_ref().clone();
+    let records = futures::stream::try_unfold(Cursor::default(), move |mut cursor| {
+        let db = db.clone();
+        async move {
+            let page = cursor.next_page(&db.lock().unwrap(), PAGE_RECORDS).map_err(actix_web::error::ErrorInternalServerError)?;
+            if page.is_empty() {
+                return Ok(None);
+            }
+            let mut lines = String::new();
+            for record in &page {
+                lines += &serde_json::to_string(record).map_err(actix_web::error::ErrorInternalServerError)?;
+                lines.push('\n');
+            }
+            Ok(Some((Bytes::from(lines), cursor)))
+        }
+    });
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(futures::stream::once(async { manifest }).chain(records)))
+}
@@ -0,0 +1,183 @@
+use crate::{
+    api::dna_sequence::{self, DbDnaSequenceError},
+    model::{
+        dna_sequence::DnaSequence,
+        manifest::{ChunkData, ChunkRequest, SequenceManifest},
+    },
+    repository::{
+        chunk::ChunkRef,
+        db::{DbHandle, QuerryError},
+    },
+    sender::Cluster,
+};
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use tracing::{debug, info};
+use thiserror::Error;
+use actix_web::{
+    http::{header::ContentType, StatusCode},
+    error::ResponseError,
+    web::Json,
+    HttpResponse,
+    web,
+};
+
+/// Bases requested from a peer in one batch, give or take the last chunk.
+const FETCH_BATCH_BYTES: usize = 4 * 1024 * 1024;
+
+/// Errors for serving and fetching sequence chunks.
+#[derive(Debug, Error, derive_more::Display)]
+pub enum ChunkError {
+    ChunkNotFound(QuerryError),
+    #[display(fmt = "{} is not a member of the cluster", _0)]
+    UnknownSource(String),
+    #[display(fmt = "Manifest of {} does not cover the sequence in order", _0)]
+    InvalidManifest(Arc<str>),
+    #[display(fmt = "Chunk batch starting at {} is no longer held by {}: {}", _0, _1, _2)]
+    Gone(String, String, String), // First hash of the batch, source and its response.
+    #[display(fmt = "Could not fetch the chunk batch starting at {} from {}: {}", _0, _1, _2)]
+    FetchFailed(String, String, String),
+    #[display(fmt = "Chunk {} from {} does not match its hash", _0, _1)]
+    Mismatch(String, String),
+    Rejected(DbDnaSequenceError),
+}
+
+impl ResponseError for ChunkError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ChunkError::ChunkNotFound(QuerryError::EmptyTableErrorW(_)) => StatusCode::NOT_FOUND,
+            ChunkError::ChunkNotFound(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ChunkError::UnknownSource(_) => StatusCode::FORBIDDEN,
+            ChunkError::InvalidManifest(_) | ChunkError::Mismatch(..) => StatusCode::BAD_REQUEST,
+            // The source replaced the sequence since, and will replicate the newer version.
+            ChunkError::Gone(..) => StatusCode::CONFLICT,
+            ChunkError::FetchFailed(..) => StatusCode::BAD_GATEWAY,
+            ChunkError::Rejected(e) => e.status_code(),
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .insert_header(ContentType::json())
+            .body(self.to_string())
+    }
+}
+
+/// Handler serving a stored chunk to a peer replicating a sequence.
+#[actix_web::get("/chunks/{hash}")]
+async fn get_chunk(
+    db: web::Data<Arc<Mutex<DbHandle>>>,
+    hash: web::Path<String>,
+) -> Result<HttpResponse, ChunkError> {
+    let data = db.lock().unwrap().get_chunk(&hash).map_err(ChunkError::ChunkNotFound)?;
+    Ok(HttpResponse::Ok().content_type("text/plain").body(data.to_string()))
+}
+
+/// Handler serving a batch of chunks to a peer, failing unless every chunk is held.
+#[actix_web::post("/chunks/batch")]
+async fn get_chunks(
+    db: web::Data<Arc<Mutex<DbHandle>>>,
+    request: Json<ChunkRequest>,
+) -> Result<Json<Vec<ChunkData>>, ChunkError> {
+    let db = db.lock().unwrap();
+    let chunks = request.hashes.iter()
+        .map(|hash| Ok(ChunkData { hash: hash.clone(), data: db.get_chunk(hash)?.to_string() }))
+        .collect::<Result<_, QuerryError>>()
+        .map_err(ChunkError::ChunkNotFound)?;
+    Ok(Json(chunks))
+}
+
+/// Handler for sequences shared as a manifest. Only the chunks this node lacks are fetched
+/// from the sending node, in batches, and checked against their hashes; the assembled
+/// sequence is then verified like a shared sequence.
+#[actix_web::post("/share_manifest")]
+async fn share_manifest(
+    db: web::Data<Arc<Mutex<DbHandle>>>,
+    cluster: web::Data<Cluster>,
+    request: Json<SequenceManifest>,
+) -> Result<Json<String>, ChunkError> {
+    let manifest = request.into_inner();
+    if !cluster.is_member(&manifest.source) {
+        return Err(ChunkError::UnknownSource(manifest.source));
+    }
+    if !manifest.is_contiguous() {
+        return Err(ChunkError::InvalidManifest(manifest.id));
+    }
+    let mut missing: Vec<&ChunkRef> = vec![];
+    {
+        let db = db.lock().unwrap();
+        for chunk in &manifest.chunks {
+            let held = db.has_chunk(&chunk.hash).map_err(|e| ChunkError::ChunkNotFound(e.into()))?;
+            if !held && !missing.iter().any(|missing| missing.hash == chunk.hash) {
+                missing.push(chunk);
+            }
+        }
+    }
+
+    let mut fetched: HashMap<String, String> = HashMap::new();
+    let mut batch: Vec<String> = vec![];
+    let mut batch_bytes = 0;
+    for (index, chunk) in missing.iter().enumerate() {
+        batch.push(chunk.hash.clone());
+        batch_bytes += chunk.length;
+        if batch_bytes >= FETCH_BATCH_BYTES || index + 1 == missing.len() {
+            fetched.extend(fetch_chunks(&cluster, &manifest.source, std::mem::take(&mut batch)).await?);
+            batch_bytes = 0;
+        }
+    }
+    debug!("Fetched {} of {} chunks of {} from {}", fetched.len(), manifest.chunks.len(), &manifest.id, &manifest.source);
+
+    let mut sequence = String::with_capacity(manifest.length);
+    {
+        let db = db.lock().unwrap();
+        for chunk in &manifest.chunks {
+            let held;
+            let data = match fetched.get(&chunk.hash) {
+                Some(data) => data.as_str(),
+                None => {
+                    held = db.get_chunk(&chunk.hash).map_err(ChunkError::ChunkNotFound)?;
+                    &held
+                },
+            };
+            if data.len() != chunk.length {
+                return Err(ChunkError::Mismatch(chunk.hash.clone(), manifest.source));
+            }
+            sequence.push_str(data);
+        }
+    }
+    let dna_sequence = DnaSequence::signed(manifest.id, sequence.into(), manifest.signature, manifest.encrypted, manifest.counter);
+    let id = dna_sequence::accept_shared(&db.lock().unwrap(), &dna_sequence).map_err(ChunkError::Rejected)?;
+    info!("Stored sequence {} from a manifest", &id);
+    Ok(Json(id.to_string()))
+}
+
+/// Fetches chunks from the peer at `source`, checking each hashes to what was asked for.
+async fn fetch_chunks(cluster: &Cluster, source: &str, hashes: Vec<String>) -> Result<HashMap<String, String>, ChunkError> {
+    let first = hashes.first().cloned().unwrap_or_default();
+    let failed = |e: String| ChunkError::FetchFailed(first.clone(), source.to_string(), e);
+    let response = cluster.client.post(cluster.url(source, "/chunks/batch"))
+        .json(&ChunkRequest { hashes: hashes.clone() })
+        .send()
+        .await
+        .map_err(|e| failed(e.to_string()))?;
+    if response.status().is_client_error() {
+        return Err(ChunkError::Gone(first.clone(), source.to_string(), response.status().to_string()));
+    }
+    if !response.status().is_success() {
+        return Err(failed(response.status().to_string()));
+    }
+    let chunks: Vec<ChunkData> = response.json().await.map_err(|e| failed(e.to_string()))?;
+    if chunks.len() != hashes.len() {
+        return Err(failed(format!("{} chunks sent for {} requested", chunks.len(), hashes.len())));
+    }
+    let mut fetched = HashMap::new();
+    for (hash, chunk) in hashes.into_iter().zip(chunks) {
+        if chunk.hash != hash || envelope::sha256_hex(chunk.data.as_bytes()) != hash {
+            return Err(ChunkError::Mismatch(hash, source.to_string()));
+        }
+        fetched.insert(hash, chunk.data);
+    }
+    Ok(fetched)
+}
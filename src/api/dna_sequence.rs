@@ -1,21 +1,26 @@
 use crate::{
     model::{
         public_key::{PublicKey, WrongSignatureError},
-        dna_sequence::DnaSequence,
-        patch::Patch,
+        dna_sequence::{self, DnaSequence, InvalidSequenceError, Strand},
+        patch::{ClientPatch, Patch, PatchError, PatchKind},
+        variant::{self, SequenceChange},
+        record::Record,
+        tombstone::{Tombstone, TombstoneError},
     },
-    repository::db::{DbHandle, QuerryError},
-    sender,
+    config::Config,
+    ordering::{Ordering, OrderingError},
+    repository::{cipher::CipherError, db::{DbHandle, EmptyTableError, QuerryError, RangeError}},
+    sender::{self, Cluster},
 };
 
 use std::sync::{Arc, Mutex};
 
+use actix_web::web::Bytes;
 use serde::{Serialize, Deserialize};
 use tracing::{debug, info};
-use diff_match_patch_rs::{DiffMatchPatch, Efficient, PatchInput};
 use thiserror::Error;
 use actix_web::{
-    http::header::ContentType,
+    http::{header::ContentType, StatusCode},
     error::ResponseError,
     web::Json,
     HttpResponse,
@@ -28,10 +33,56 @@ pub enum DbDnaSequenceError {
     DnaSequenceNotFound(QuerryError),
     PushFailed(QuerryError),
     SignatureVerificationFailed(WrongSignatureError),
-    PatchFailed,
+    InvalidPatch(PatchError),
+    #[display(fmt = "Invalid range [{}, {}) for a sequence of length {}", _0, _1, _2)]
+    InvalidRange(usize, usize, usize),
+    InvalidSequence(InvalidSequenceError),
+    #[display(fmt = "Dna Sequence {} is encrypted by its owner", _0)]
+    EncryptedSequence(Arc<str>),
+    #[display(fmt = "Dna Sequence {} is at version {}, not {}", _0, _1, _2)]
+    Conflict(Arc<str>, String, String), // Id, current version and the base version of the update.
+    #[display(fmt = "Dna Sequence {} does not exist, so it is not at version {}", _0, _1)]
+    MissingBase(Arc<str>, String),
+    #[display(fmt = "No change of Dna Sequence {} recorded from {}", _0, _1)]
+    NoChange(Arc<str>, String),
+    InvalidTombstone(TombstoneError),
+    #[display(fmt = "Dna Sequence is encrypted at rest and this node has no key to open it")]
+    Encrypted,
+    Ordering(OrderingError),
+}
+
+impl DbDnaSequenceError {
+    /// Maps a failed read, telling sequences sealed under a key the node was not given and
+    /// ranges outside the sequence apart from missing sequences.
+    fn read(e: QuerryError) -> Self {
+        match e {
+            QuerryError::CipherErrorW(CipherError::Sealed) => DbDnaSequenceError::Encrypted,
+            QuerryError::RangeErrorW(RangeError(start, end, length)) => DbDnaSequenceError::InvalidRange(start, end, length),
+            e => DbDnaSequenceError::DnaSequenceNotFound(e),
+        }
+    }
 }
 
 impl ResponseError for DbDnaSequenceError { 
+    fn status_code(&self) -> StatusCode {
+        match self {
+            DbDnaSequenceError::InvalidRange(..)
+            | DbDnaSequenceError::InvalidSequence(_)
+            | DbDnaSequenceError::InvalidPatch(_)
+            | DbDnaSequenceError::EncryptedSequence(_) => StatusCode::BAD_REQUEST,
+            DbDnaSequenceError::SignatureVerificationFailed(_) => StatusCode::UNAUTHORIZED,
+            DbDnaSequenceError::Conflict(..) | DbDnaSequenceError::MissingBase(..) => StatusCode::CONFLICT,
+            DbDnaSequenceError::InvalidTombstone(_) => StatusCode::BAD_REQUEST,
+            DbDnaSequenceError::NoChange(..)
+            | DbDnaSequenceError::DnaSequenceNotFound(QuerryError::EmptyTableErrorW(EmptyTableError::NoDnaSequences)) => StatusCode::NOT_FOUND,
+            DbDnaSequenceError::Encrypted => StatusCode::SERVICE_UNAVAILABLE,
+            DbDnaSequenceError::PushFailed(QuerryError::DeletedErrorW(_)) => StatusCode::GONE,
+            DbDnaSequenceError::PushFailed(QuerryError::StaleErrorW(_)) => StatusCode::CONFLICT,
+            DbDnaSequenceError::Ordering(e) => e.status_code(),
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
     fn error_response(&self) -> HttpResponse { 
         HttpResponse::build(self.status_code())
             .insert_header(ContentType::json())
@@ -43,6 +94,34 @@ impl ResponseError for DbDnaSequenceError {
 #[derive(Serialize)]
 struct GetDnaSequencesResponse { 
     dna_sequence: String,
+    encrypted: bool,
+    version: String, // Base version for updates to this sequence.
+}
+
+/// Response for retrieving a subsequence.
+#[derive(Serialize)]
+struct GetDnaRangeResponse {
+    id: Arc<str>,
+    start: usize,
+    end: usize,
+    strand: Strand,
+    dna_sequence: String,
+}
+
+/// Query parameters for subsequence reads. Coordinates are 0-based and half-open.
+#[derive(Deserialize)]
+pub struct RangeQuery {
+    start: usize,
+    end: usize,
+    #[serde(default)]
+    strand: Strand,
+}
+
+/// Query parameters for VCF exports: the version the change starts from, the latest change
+/// when unset.
+#[derive(Deserialize)]
+pub struct VcfQuery {
+    base: Option<String>,
 }
 
 /// Request structure for submitting a new DNA sequence.
@@ -51,12 +130,43 @@ pub struct SubmitDnaSequence {
     id: Arc<str>,
     dna_sequence: Arc<str>,
     signature: Arc<str>,
+    #[serde(default)]
+    encrypted: bool, // Set by clients uploading client-side encrypted sequences.
+    #[serde(default)]
+    base: Option<String>, // Version the update was made against; refused with 409 if the sequence moved on.
+    #[serde(default)]
+    counter: u64, // Counter of the signed envelope; 0 when the signature covers the sequence itself.
 }
 
 #[derive(Deserialize)] 
 pub struct SubmitPatch {
     id: Arc<str>,
-    patch_txt: Arc<str>,
+    #[serde(flatten)]
+    kind: PatchKind, // Diff-match-patch text or variants.
+    signature: Arc<str>,
+    #[serde(default)]
+    base: Option<Arc<str>>, // Version the patch was made against.
+    #[serde(default)]
+    counter: u64,
+}
+
+/// Request structure for a client patch. The owner signs the envelope of the patched sequence,
+/// so the full text never has to be uploaded.
+#[derive(Deserialize)]
+pub struct SubmitClientPatch {
+    id: Arc<str>,
+    base: String, // Version the patch was made against.
+    patch: ClientPatch,
+    counter: u64, // Counter of the signed envelope, above the stored sequence's.
+    signature: Arc<str>,
+}
+
+/// Request structure for deleting a DNA sequence. The owner signs
+/// `delete:{id}:{counter}:{deleted_at}`.
+#[derive(Deserialize)]
+pub struct SubmitDeletion {
+    counter: u64, // Envelope counter of the version being deleted.
+    deleted_at: i64,
     signature: Arc<str>,
 }
 
@@ -75,11 +185,125 @@ async fn dna(
     let id = request.id.clone();
     let db = db.lock().unwrap();
     match db.get_dna_sequence(id) { 
-        Ok(read_seq) => Ok(Json(GetDnaSequencesResponse { dna_sequence: read_seq.dna_sequence.to_string() })),
-        Err(e) => Err(DbDnaSequenceError::DnaSequenceNotFound(e)),
+        Ok(read_seq) => Ok(Json(GetDnaSequencesResponse {
+            version: read_seq.version(),
+            dna_sequence: read_seq.dna_sequence.to_string(),
+            encrypted: read_seq.encrypted,
+        })),
+        Err(e) => Err(DbDnaSequenceError::read(e)),
     } 
 }
 
+/// Handler streaming a DNA sequence as plain text one stored chunk at a time, so a large
+/// sequence is never held in memory whole.
+#[actix_web::get("/dna/{id}/stream")]
+async fn dna_stream(
+    db: web::Data<Arc<Mutex<DbHandle>>>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, DbDnaSequenceError> {
+    let id: Arc<str> = id.into_inner().into();
+    let (length, chunks) = {
+        let db = db.lock().unwrap();
+        let length = db.get_dna_sequence_length(id.clone())
+            .map_err(DbDnaSequenceError::read)?;
+        let chunks = db.get_chunk_refs(id.clone())
+            .map_err(|e| DbDnaSequenceError::DnaSequenceNotFound(e.into()))?;
+        // Chunks are sealed alike, so an unreadable sequence is refused before the headers go out.
+        if let Some(first) = chunks.first() {
+            db.get_chunk(&first.hash).map_err(DbDnaSequenceError::read)?;
+        }
+        (length, chunks)
+    };
+    let mut response = HttpResponse::Ok();
+    response.content_type("text/plain");
+    if chunks.is_empty() && length > 0 {
+        // Stored before chunking and not readable at startup, so it is sent whole.
+        let dna_sequence = db.lock().unwrap().get_dna_sequence(id)
+            .map_err(DbDnaSequenceError::read)?;
+        return Ok(response.body(dna_sequence.dna_sequence.to_string()));
+    }
+    let db = db.into_inner();
+    let body = chunks.into_iter().map(move |chunk| {
+        db.lock().unwrap().get_chunk(&chunk.hash)
+            .map(|data| Bytes::from(data.to_string()))
+            .map_err(actix_web::error::ErrorInternalServerError)
+    });
+    Ok(response.no_chunking(length as u64).streaming(futures::stream::iter(body)))
+}
+
+/// Handler for retrieving the `[start, end)` region of a DNA sequence.
+/// The minus strand returns the reverse complement of the region.
+#[actix_web::get("/dna/{id}/range")]
+async fn dna_range(
+    db: web::Data<Arc<Mutex<DbHandle>>>,
+    id: web::Path<String>,
+    range: web::Query<RangeQuery>,
+) -> Result<Json<GetDnaRangeResponse>, DbDnaSequenceError> {
+    let id: Arc<str> = id.into_inner().into();
+    let db = db.lock().unwrap();
+    if db.is_encrypted(id.clone()).map_err(DbDnaSequenceError::read)? {
+        return Err(DbDnaSequenceError::EncryptedSequence(id));
+    }
+    let length = db.get_dna_sequence_length(id.clone())
+        .map_err(DbDnaSequenceError::read)?;
+    if range.start >= range.end || range.end > length {
+        return Err(DbDnaSequenceError::InvalidRange(range.start, range.end, length));
+    }
+    let slice = db.get_dna_sequence_range(id.clone(), range.start, range.end)
+        .map_err(DbDnaSequenceError::read)?;
+    let dna_sequence = match range.strand {
+        Strand::Plus => slice.to_string(),
+        Strand::Minus => dna_sequence::reverse_complement(&slice),
+    };
+    Ok(Json(GetDnaRangeResponse {
+        id,
+        start: range.start,
+        end: range.end,
+        strand: range.strand,
+        dna_sequence,
+    }))
+}
+
+/// Handler listing the recorded changes of a sequence, oldest first.
+#[actix_web::get("/dna/{id}/changes")]
+async fn dna_changes(
+    db: web::Data<Arc<Mutex<DbHandle>>>,
+    id: web::Path<String>,
+) -> Result<Json<Vec<SequenceChange>>, DbDnaSequenceError> {
+    let id: Arc<str> = id.into_inner().into();
+    let db = db.lock().unwrap();
+    if db.is_encrypted(id.clone()).map_err(DbDnaSequenceError::read)? {
+        return Err(DbDnaSequenceError::EncryptedSequence(id));
+    }
+    db.get_changes(id).map(Json).map_err(DbDnaSequenceError::read)
+}
+
+/// Handler exporting the change of a sequence from version `base` to the next as VCF.
+#[actix_web::get("/dna/{id}/vcf")]
+async fn dna_vcf(
+    db: web::Data<Arc<Mutex<DbHandle>>>,
+    id: web::Path<String>,
+    query: web::Query<VcfQuery>,
+) -> Result<HttpResponse, DbDnaSequenceError> {
+    let id: Arc<str> = id.into_inner().into();
+    let db = db.lock().unwrap();
+    if db.is_encrypted(id.clone()).map_err(DbDnaSequenceError::read)? {
+        return Err(DbDnaSequenceError::EncryptedSequence(id));
+    }
+    let changes = db.get_changes(id.clone()).map_err(DbDnaSequenceError::read)?;
+    let change = match &query.base {
+        Some(base) => changes.iter().rev().find(|change| &change.base == base),
+        None => changes.last(),
+    };
+    let change = change.ok_or_else(|| {
+        let from = query.base.as_ref().map_or("any version".to_string(), |base| format!("version {}", base));
+        DbDnaSequenceError::NoChange(id.clone(), from)
+    })?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/x-vcf")
+        .body(variant::to_vcf(&id, change)))
+}
+
 /// Handler for shared patches.
 #[actix_web::post("/share_patch")]
 async fn share_patch(
@@ -87,33 +311,27 @@ async fn share_patch(
     request: Json<SubmitPatch>,
 ) -> Result<Json<String>, DbDnaSequenceError> { 
 
-    let patch = request.patch_txt.clone();
     let id = request.id.clone();
     let signature = request.signature.clone();
     let db = db.lock().unwrap();
-    let dna_sequence = db.get_dna_sequence(id.clone()).expect("Error -- No dna sequence with given id");
-    let dmp = DiffMatchPatch::new();
-    let patches = dmp.patch_from_text::<Efficient>(patch.as_ref()).unwrap();
-    let (patched_sequence_str, ops) = dmp.patch_apply(&patches, dna_sequence.dna_sequence.as_ref()).unwrap();
-    let patched_sequence: Arc<str> = patched_sequence_str.into();
-    let mut success = true;
-    ops.iter().for_each(|&o| success = success && o);
-    if !success { 
-        return Err(DbDnaSequenceError::PatchFailed);
-    } 
-
-    //retrieving that id's public key
-    let public_key = db.get_public_key(id.clone()).unwrap();
+    let dna_sequence = db.get_dna_sequence(id.clone())
+        .map_err(DbDnaSequenceError::read)?;
+    if dna_sequence.encrypted {
+        return Err(DbDnaSequenceError::EncryptedSequence(id));
+    }
+    check_base(&dna_sequence, request.base.as_deref())?;
+    let patched_sequence: Arc<str> = request.kind.apply(&dna_sequence.dna_sequence)
+        .map_err(DbDnaSequenceError::InvalidPatch)?
+        .into();
 
     //checking the signature with that id's public key - we check the patched value.
-    PublicKey::check_signature(signature.clone(), public_key, patched_sequence.clone())
-        .map_err(DbDnaSequenceError::SignatureVerificationFailed)?;
-
+    let new_sequence = DnaSequence::signed(id.clone(), patched_sequence, signature, false, request.counter);
+    new_sequence.validate().map_err(DbDnaSequenceError::InvalidSequence)?;
+    check_sequence_signature(&db, &new_sequence)?;
 
-    let new_sequence = DnaSequence::new(id.clone(), patched_sequence.clone());
     match db.push_dna_sequence(&new_sequence) { 
         Ok(id) => Ok(Json(id.clone().to_string())),
-        Err(e) => Err(DbDnaSequenceError::PushFailed(QuerryError::RusqliteError(e))),
+        Err(e) => Err(DbDnaSequenceError::PushFailed(e)),
     } 
 }
 
@@ -126,85 +344,246 @@ async fn share_dna_sequence(
     let dna_sequence_raw = request.dna_sequence.clone();
     let id = request.id.clone();
     let signature = request.signature.clone(); 
-    let dna_sequence = DnaSequence::new(id.clone(), dna_sequence_raw.clone());
-
-    //retrieving that id's public key
-    let db = db.lock().unwrap();
-    let public_key = db.get_public_key(id.clone()).unwrap();
-
-    //checking the signature with that id's public key - nodes check signatures of shared dna
-    PublicKey::check_signature(signature.clone(), public_key, dna_sequence_raw.clone())
-        .map_err(DbDnaSequenceError::SignatureVerificationFailed)?;
+    let dna_sequence = DnaSequence::signed(id.clone(), dna_sequence_raw.clone(), signature.clone(), request.encrypted, request.counter);
+    let id = accept_shared(&db.lock().unwrap(), &dna_sequence)?;
+    Ok(Json(id.to_string()))
+}
 
-    match db.push_dna_sequence(&dna_sequence) { 
-        Ok(id) => Ok(Json(id.to_string())),
-        Err(e) => Err(DbDnaSequenceError::PushFailed(QuerryError::RusqliteError(e))),
-    } 
+/// Validates a sequence shared by a peer and stores it if its owner signed it - nodes check
+/// signatures of shared dna.
+pub(crate) fn accept_shared(db: &DbHandle, dna_sequence: &DnaSequence) -> Result<Arc<str>, DbDnaSequenceError> {
+    dna_sequence.validate().map_err(DbDnaSequenceError::InvalidSequence)?;
+    check_sequence_signature(db, dna_sequence)?;
+    db.push_dna_sequence(dna_sequence).map_err(DbDnaSequenceError::PushFailed)
 }
 
-/// Handler for inserting a new DNA sequence and applying patches.
+/// Handler for inserting a new DNA sequence and applying patches. With the ordering layer
+/// enabled the whole signed sequence is ordered across the cluster instead.
 #[actix_web::post("/insert_dna_sequence")]
 async fn insert_dna_sequence(
     db: web::Data<Arc<Mutex<DbHandle>>>,
-    addresses: web::Data<Vec<String>>,
+    cluster: web::Data<Cluster>,
+    ordering: web::Data<Ordering>,
     request: Json<SubmitDnaSequence>,
 ) -> Result<Json<String>, DbDnaSequenceError> { 
     let dna_sequence_raw = request.dna_sequence.clone();
     let id = request.id.clone();
     debug!("id: {}", &id);
     let signature = request.signature.clone();
-    let mut dna_sequence = DnaSequence::new(id.clone(), dna_sequence_raw.clone());
+    let dna_sequence = DnaSequence::signed(id.clone(), dna_sequence_raw.clone(), signature.clone(), request.encrypted, request.counter);
+    let id = store_dna_sequence(&db, &cluster, &ordering, dna_sequence, request.base.clone()).await?;
+    Ok(Json(id.to_string()))
+}
 
-    //retrieving that id's public key
-    let db = db.lock().unwrap();
-    let public_key = db.get_public_key(id.clone()).unwrap();
-
-    //checking the signature with that id's public key.
-    PublicKey::check_signature(signature.clone(), public_key, dna_sequence_raw.clone())
-        .map_err(DbDnaSequenceError::SignatureVerificationFailed)?;
-
-    let _ = match db.get_dna_sequence(request.id.clone()) { 
-        Ok(old_sequence) => { 
-            debug!("Existing sequence found");
-            dna_sequence.id = old_sequence.id.clone();
-
-            // Sending patches
-            let dmp = DiffMatchPatch::new();
-            let diffs = dmp.diff_main::<Efficient>(
-                old_sequence.dna_sequence.as_ref(), 
-                request.dna_sequence.as_ref()
-            ).unwrap();
-            let patches = dmp.patch_make(PatchInput::new_diffs(&diffs)).unwrap();
-            let patch_txt: Arc<str> = dmp.patch_to_text(&patches).into();
-
-            let res = db.push_dna_sequence(&dna_sequence); 
-            drop(db);
-            match res {
-                Ok(id) => { 
-                    let patch = Patch::new(id, patch_txt);
-                    let _ = tokio::spawn(async move { 
-                        sender::broadcast_patch(addresses.as_ref().to_owned(), signature, patch).await; 
-                    }).await;
-                },
-                Err(e) => return Err(DbDnaSequenceError::PushFailed(QuerryError::RusqliteError(e))),
+/// Validates and stores a signed sequence submitted by a client, then replicates it: ordered
+/// across the cluster when the ordering layer is enabled, broadcast by this node otherwise.
+pub(crate) async fn store_dna_sequence(
+    db: &Arc<Mutex<DbHandle>>,
+    cluster: &Cluster,
+    ordering: &Ordering,
+    mut dna_sequence: DnaSequence,
+    base: Option<String>,
+) -> Result<Arc<str>, DbDnaSequenceError> {
+    let id = dna_sequence.id.clone();
+    dna_sequence.validate().map_err(DbDnaSequenceError::InvalidSequence)?;
+    let signature = dna_sequence.signature.clone()
+        .ok_or(DbDnaSequenceError::SignatureVerificationFailed(WrongSignatureError::VerificationFailed))?;
+
+    if ordering.is_enabled() {
+        check_sequence_signature(&db.lock().unwrap(), &dna_sequence)?;
+        ordering.order_against(Record::DnaSequence(dna_sequence), base).await
+            .map_err(DbDnaSequenceError::Ordering)?;
+        return Ok(id);
+    }
+
+    let patch = {
+        //checking the signature with that id's public key.
+        let db = db.lock().unwrap();
+        check_sequence_signature(&db, &dna_sequence)?;
+
+        let existing = db.get_dna_sequence(id.clone());
+        if let Some(base) = &base {
+            match &existing {
+                Ok(old_sequence) => check_base(old_sequence, Some(base))?,
+                Err(_) => return Err(DbDnaSequenceError::MissingBase(id, base.clone())),
+            }
+        }
+        let patch = match existing { 
+            // Encrypted records are opaque, so they are replicated whole rather than diffed.
+            Ok(old_sequence) if old_sequence.encrypted || dna_sequence.encrypted => {
+                debug!("Existing encrypted sequence found");
+                None
+            },
+            Ok(old_sequence) => { 
+                debug!("Existing sequence found");
+                dna_sequence.id = old_sequence.id.clone();
+
+                // Computing the variants to send to peers
+                let variants = variant::variants(&old_sequence.dna_sequence, &dna_sequence.dna_sequence);
+                Some(Patch::new(old_sequence.id.clone(), PatchKind::Variants { variants }, old_sequence.version().into(), dna_sequence.counter))
+            },
+            Err(_) => { 
+                info!("Pushing new sequence");
+                None
             }
-            Some(old_sequence)
+        };
+        db.push_dna_sequence(&dna_sequence)
+            .map_err(DbDnaSequenceError::PushFailed)?;
+        patch
+    };
+
+    let cluster = cluster.clone();
+    match patch {
+        Some(patch) => {
+            let _ = tokio::spawn(async move { 
+                sender::broadcast_patch(cluster, signature, patch).await; 
+            }).await;
+        },
+        None => {
+            debug!("inserting new sequence");
+            let _ = tokio::spawn(async move { 
+                sender::broadcast_dna_sequence(cluster, dna_sequence, signature).await; 
+            }).await;
         },
-        Err(_) => { 
-            info!("Pushing new sequence");
-            match db.push_dna_sequence(&dna_sequence) { 
-                Ok(_) => { 
-                    debug!("inserting new sequence");
-                    let _ = tokio::spawn(async move { 
-                        sender::broadcast_dna_sequence(addresses.as_ref().to_owned(), dna_sequence, signature).await; 
-                    }).await;
+    }
+    Ok(id)
+}
+
+/// Handler for patches computed by the client against a stated base version. Only the patch
+/// is uploaded, and the owner signs the version of the patched sequence rather than its text.
+#[actix_web::post("/patch_dna_sequence")]
+async fn patch_dna_sequence(
+    db: web::Data<Arc<Mutex<DbHandle>>>,
+    cluster: web::Data<Cluster>,
+    ordering: web::Data<Ordering>,
+    request: Json<SubmitClientPatch>,
+) -> Result<Json<String>, DbDnaSequenceError> {
+    let id = request.id.clone();
+    let signature = request.signature.clone();
+    let (dna_sequence, patch) = {
+        let db = db.lock().unwrap();
+        let current = db.get_dna_sequence(id.clone())
+            .map_err(|_| DbDnaSequenceError::MissingBase(id.clone(), request.base.clone()))?;
+        if current.encrypted {
+            return Err(DbDnaSequenceError::EncryptedSequence(id));
+        }
+        check_base(&current, Some(&request.base))?;
+        let patched: Arc<str> = request.patch.apply(&current.dna_sequence)
+            .map_err(DbDnaSequenceError::InvalidPatch)?
+            .into();
+        let dna_sequence = DnaSequence::signed(id.clone(), patched, signature.clone(), false, request.counter);
+        dna_sequence.validate().map_err(DbDnaSequenceError::InvalidSequence)?;
+        check_owner_signature(&db, &id, signature.clone(), dna_sequence.envelope().canonical().into())?;
+        if ordering.is_enabled() {
+            (dna_sequence, None)
+        } else {
+            let kind = match &request.patch {
+                ClientPatch::Dmp { patch_txt } => PatchKind::Text { patch_txt: patch_txt.as_str().into() },
+                ClientPatch::Variants { variants } => PatchKind::Variants { variants: variants.clone() },
+                ClientPatch::Edits { .. } => PatchKind::Variants {
+                    variants: variant::variants(&current.dna_sequence, &dna_sequence.dna_sequence),
                 },
-                Err(e) => return Err(DbDnaSequenceError::PushFailed(QuerryError::RusqliteError(e))),
-            }
-            None
+            };
+            db.push_dna_sequence(&dna_sequence).map_err(DbDnaSequenceError::PushFailed)?;
+            (dna_sequence, Some(Patch::new(id.clone(), kind, request.base.as_str().into(), request.counter)))
         }
     };
-    Ok(Json(id.clone().to_string()))
 
+    match patch {
+        None => {
+            ordering.order_against(Record::DnaSequence(dna_sequence), Some(request.base.clone())).await
+                .map_err(DbDnaSequenceError::Ordering)?;
+        },
+        Some(patch) => {
+            let _ = tokio::spawn(async move {
+                sender::broadcast_patch(cluster.as_ref().clone(), signature, patch).await;
+            }).await;
+        },
+    }
+    info!("Patched sequence {}", &id);
+    Ok(Json(id.to_string()))
+}
+
+/// Checks the stored sequence is at the version an update was made against, if it states one.
+fn check_base(current: &DnaSequence, base: Option<&str>) -> Result<(), DbDnaSequenceError> {
+    match base {
+        Some(base) if current.version() != base => {
+            Err(DbDnaSequenceError::Conflict(current.id.clone(), current.version(), base.to_string()))
+        },
+        _ => Ok(()),
+    }
+}
+
+/// Verifies the owner's signature of a sequence.
+fn check_sequence_signature(db: &DbHandle, dna_sequence: &DnaSequence) -> Result<(), DbDnaSequenceError> {
+    let public_key = db.get_public_key(dna_sequence.id.clone())
+        .map_err(|_| DbDnaSequenceError::SignatureVerificationFailed(WrongSignatureError::NoPublicKey))?;
+    let signature = dna_sequence.signature.clone()
+        .ok_or(DbDnaSequenceError::SignatureVerificationFailed(WrongSignatureError::VerificationFailed))?;
+    dna_sequence.check_signature(signature, public_key)
+        .map_err(DbDnaSequenceError::SignatureVerificationFailed)
 }
 
+/// Verifies an owner-signed message against the public key of `id`.
+fn check_owner_signature(db: &DbHandle, id: &Arc<str>, signature: Arc<str>, message: Arc<str>) -> Result<(), DbDnaSequenceError> {
+    let public_key = db.get_public_key(id.clone())
+        .map_err(|_| DbDnaSequenceError::SignatureVerificationFailed(WrongSignatureError::NoPublicKey))?;
+    PublicKey::check_signature(signature, public_key, message)
+        .map_err(DbDnaSequenceError::SignatureVerificationFailed)
+}
+
+/// Verifies a tombstone against the owner's public key, the node's clock and the stored
+/// sequence. `grace_secs` is the tombstone grace period.
+fn check_tombstone(db: &DbHandle, tombstone: &Tombstone, grace_secs: i64) -> Result<(), DbDnaSequenceError> {
+    check_owner_signature(db, &tombstone.id, tombstone.signature.clone(), tombstone.message())?;
+    let stored_at = db.get_sequence_stamp(tombstone.id.clone())
+        .map_err(|e| DbDnaSequenceError::PushFailed(e.into()))?
+        .map(|(_, stored_at)| stored_at);
+    tombstone.check_time(chrono::Utc::now().timestamp(), grace_secs, stored_at)
+        .map_err(DbDnaSequenceError::InvalidTombstone)
+}
+
+/// Verifies a tombstone and records it.
+fn record_tombstone(db: &DbHandle, tombstone: &Tombstone, grace_secs: i64) -> Result<Arc<str>, DbDnaSequenceError> {
+    check_tombstone(db, tombstone, grace_secs)?;
+    db.push_tombstone(tombstone, chrono::Utc::now().timestamp())
+        .map_err(DbDnaSequenceError::PushFailed)
+}
+
+/// Handler for deleting a DNA sequence and broadcasting its tombstone, or ordering it across
+/// the cluster when the ordering layer is enabled.
+#[actix_web::delete("/dna/{id}")]
+async fn delete_dna_sequence(
+    db: web::Data<Arc<Mutex<DbHandle>>>,
+    cluster: web::Data<Cluster>,
+    ordering: web::Data<Ordering>,
+    config: web::Data<Config>,
+    id: web::Path<String>,
+    request: Json<SubmitDeletion>,
+) -> Result<Json<String>, DbDnaSequenceError> {
+    let tombstone = Tombstone::new(id.into_inner().into(), request.counter, request.deleted_at, request.signature.clone());
+    if ordering.is_enabled() {
+        let id = tombstone.id.clone();
+        check_tombstone(&db.lock().unwrap(), &tombstone, config.tombstone_grace_secs)?;
+        ordering.order(Record::Tombstone(tombstone)).await.map_err(DbDnaSequenceError::Ordering)?;
+        info!("Deleted sequence {}", &id);
+        return Ok(Json(id.to_string()));
+    }
+    let id = record_tombstone(&db.lock().unwrap(), &tombstone, config.tombstone_grace_secs)?;
+    info!("Deleted sequence {}", &id);
+    let _ = tokio::spawn(async move {
+        sender::broadcast_tombstone(cluster.as_ref().clone(), tombstone).await;
+    }).await;
+    Ok(Json(id.to_string()))
+}
+
+/// Handler for tombstones shared by another peer.
+#[actix_web::post("/share_tombstone")]
+async fn share_tombstone(
+    db: web::Data<Arc<Mutex<DbHandle>>>,
+    config: web::Data<Config>,
+    request: Json<Tombstone>,
+) -> Result<Json<String>, DbDnaSequenceError> {
+    let id = record_tombstone(&db.lock().unwrap(), &request, config.tombstone_grace_secs)?;
+    Ok(Json(id.to_string()))
+}
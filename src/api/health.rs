@@ -0,0 +1,78 @@
+use crate::{
+    evidence::EvidenceReport,
+    health::{PeerHealth, RequestStats},
+    repository::db::DbHandle,
+    sender::Cluster,
+};
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+use actix_web::{
+    web::Json,
+    HttpResponse,
+    web,
+};
+
+/// Handler answering peer heartbeats.
+#[actix_web::get("/health")]
+async fn heartbeat() -> HttpResponse {
+    HttpResponse::Ok().finish()
+}
+
+/// Liveness of a single peer.
+#[derive(Serialize)]
+struct PeerStatus {
+    id: Arc<str>,
+    address: String,
+    queue_depth: usize, // Replication messages waiting in the outbox for this peer.
+    quarantined: bool, // Caught misbehaving often enough that its acks no longer count.
+    replication: RequestStats,
+    #[serde(flatten)]
+    health: PeerHealth,
+}
+
+/// Response for peer liveness requests.
+#[derive(Serialize)]
+struct PeersResponse {
+    peers: Vec<PeerStatus>,
+}
+
+/// Handler reporting the failure detector's view of every peer in the membership, with the
+/// messages queued for it and the latency of replication requests.
+#[actix_web::get("/cluster/peers")]
+async fn peers(
+    db: web::Data<Arc<Mutex<DbHandle>>>,
+    cluster: web::Data<Cluster>,
+) -> Json<PeersResponse> {
+    let depths: HashMap<String, usize> = db.lock().unwrap().get_queue_depths()
+        .unwrap_or_default()
+        .into_iter()
+        .collect();
+    let membership = cluster.membership.read().unwrap().clone();
+    let members = membership.current.members.iter()
+        .chain(membership.pending.iter().flat_map(|pending| pending.member_set.members.iter()));
+    let mut peers: Vec<PeerStatus> = vec![];
+    for member in members.filter(|member| member.id != cluster.node_id) {
+        if peers.iter().any(|peer| peer.address == member.address) {
+            continue;
+        }
+        peers.push(PeerStatus {
+            id: member.id.as_str().into(),
+            address: member.address.clone(),
+            queue_depth: depths.get(&member.address).copied().unwrap_or(0),
+            quarantined: cluster.evidence.is_quarantined(&member.id),
+            replication: cluster.metrics.stats(&member.address),
+            health: cluster.detector.health(&member.address),
+        });
+    }
+    Json(PeersResponse { peers })
+}
+
+/// Handler listing the evidence of misbehaviour collected against peers and which of them are
+/// quarantined. Each piece carries the signed messages proving it.
+#[actix_web::get("/cluster/evidence")]
+async fn cluster_evidence(cluster: web::Data<Cluster>) -> Result<Json<EvidenceReport>, actix_web::Error> {
+    cluster.evidence.report().map(Json).map_err(actix_web::error::ErrorInternalServerError)
+}
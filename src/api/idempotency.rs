@@ -0,0 +1,42 @@
+use crate::{
+    repository::db::DbHandle,
+    sender::IDEMPOTENCY_KEY,
+};
+
+use std::sync::{Arc, Mutex};
+
+use tracing::{debug, error};
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    middleware::Next,
+    HttpResponse,
+    web,
+    Error,
+};
+
+/// Middleware acknowledging replication messages already applied without applying them again,
+/// so a sender retrying after a lost acknowledgement has no effect.
+pub async fn deduplicate(
+    request: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let key = request.headers().get(IDEMPOTENCY_KEY)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let db = request.app_data::<web::Data<Arc<Mutex<DbHandle>>>>().cloned();
+    let (Some(key), Some(db)) = (key, db) else {
+        return Ok(next.call(request).await?.map_into_left_body());
+    };
+    if db.lock().unwrap().is_received(&key).unwrap_or(false) {
+        debug!("Acknowledging duplicate message {}", &key);
+        return Ok(request.into_response(HttpResponse::Ok().finish()).map_into_right_body());
+    }
+    let response = next.call(request).await?;
+    if response.status().is_success() {
+        if let Err(e) = db.lock().unwrap().mark_received(&key, chrono::Utc::now().timestamp()) {
+            error!("Could not record message {}: {}", &key, e);
+        }
+    }
+    Ok(response.map_into_left_body())
+}
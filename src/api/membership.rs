@@ -0,0 +1,153 @@
+use crate::{
+    config::Config,
+    identity::NodeIdentity,
+    membership::{JointAck, Membership, MembershipError, Reconfiguration, ReconfigurationCommit},
+    repository::db::{DbHandle, QuerryError},
+    sender::{self, Cluster},
+};
+
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+use tracing::{info, warn};
+use thiserror::Error;
+use actix_web::{
+    http::{header::ContentType, StatusCode},
+    error::ResponseError,
+    web::Json,
+    HttpResponse,
+    web,
+};
+
+/// Errors for membership requests.
+#[derive(Debug, Error, derive_more::Display)]
+pub enum ReconfigurationError {
+    Rejected(MembershipError),
+    PersistFailed(QuerryError),
+    #[display(fmt = "Joint configuration reached {} of {} required acks; retry to resume", _0, _1)]
+    NoQuorum(usize, u32),
+}
+
+impl ResponseError for ReconfigurationError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ReconfigurationError::Rejected(MembershipError::Unauthorized | MembershipError::Unproven(..)) => StatusCode::UNAUTHORIZED,
+            ReconfigurationError::Rejected(MembershipError::Invalid(_)) => StatusCode::BAD_REQUEST,
+            ReconfigurationError::Rejected(_) => StatusCode::CONFLICT,
+            ReconfigurationError::PersistFailed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ReconfigurationError::NoQuorum(..) => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .insert_header(ContentType::json())
+            .body(self.to_string())
+    }
+}
+
+/// Applies a membership step under the cluster lock and persists the result when it changed.
+fn apply(
+    cluster: &Cluster,
+    db: &Mutex<DbHandle>,
+    step: impl FnOnce(&mut Membership) -> Result<bool, MembershipError>,
+) -> Result<bool, ReconfigurationError> {
+    let mut membership = cluster.membership.write().unwrap();
+    let changed = step(&mut membership).map_err(ReconfigurationError::Rejected)?;
+    if changed {
+        membership.persist(&db.lock().unwrap()).map_err(ReconfigurationError::PersistFailed)?;
+    }
+    Ok(changed)
+}
+
+/// Response for completed reconfigurations.
+#[derive(Serialize)]
+struct ReconfigurationResponse {
+    epoch: u64,
+    acks: usize,
+}
+
+/// Handler coordinating an admin-signed membership change. The node enters the joint
+/// configuration and collects the signed acks of the old and new members. Once they hold a
+/// quorum of both, it tells every node to switch to the new membership, with the acks as proof.
+#[actix_web::post("/admin/reconfigure")]
+async fn reconfigure(
+    db: web::Data<Arc<Mutex<DbHandle>>>,
+    cluster: web::Data<Cluster>,
+    config: web::Data<Config>,
+    identity: web::Data<Arc<NodeIdentity>>,
+    request: Json<Reconfiguration>,
+) -> Result<Json<ReconfigurationResponse>, ReconfigurationError> {
+    let reconfiguration = request.into_inner();
+    reconfiguration.verify(&config.admin_keys).map_err(ReconfigurationError::Rejected)?;
+    apply(&cluster, &db, |membership| membership.begin(&reconfiguration))?;
+    let view = cluster.view();
+    let previous = cluster.membership.read().unwrap().current.clone();
+    info!("Entering joint configuration for epoch {}, waiting for {} of {} peers", reconfiguration.epoch, view.quorum, view.addresses.len());
+    let acks: Vec<JointAck> = sender::broadcast_reconfiguration(&cluster, view.addresses.clone(), "/cluster/reconfigure", &reconfiguration).await
+        .into_iter()
+        .filter_map(|body| serde_json::from_str(&body).ok())
+        .collect();
+    let count = acks.len();
+    let commit = ReconfigurationCommit::new(reconfiguration, previous, cluster.node_id.clone(), acks, &identity);
+    if let Err(e) = commit.verify(&config.admin_keys, &commit.previous, &cluster.node_id, &identity) {
+        warn!("Joint configuration for epoch {} reached {} of {} acks: {}", commit.reconfiguration.epoch, count, view.quorum, e);
+        return Err(ReconfigurationError::NoQuorum(count, view.quorum));
+    }
+    apply(&cluster, &db, |membership| membership.commit(&commit.reconfiguration))?;
+    let committed = sender::broadcast_reconfiguration(&cluster, view.addresses, "/cluster/reconfigure/commit", &commit).await.len();
+    info!("Committed membership epoch {} on {} peers", commit.reconfiguration.epoch, committed);
+    Ok(Json(ReconfigurationResponse { epoch: commit.reconfiguration.epoch, acks: count }))
+}
+
+/// Handler entering the joint configuration announced by a coordinating peer, answering with
+/// this node's signed ack.
+#[actix_web::post("/cluster/reconfigure")]
+async fn share_reconfiguration(
+    db: web::Data<Arc<Mutex<DbHandle>>>,
+    cluster: web::Data<Cluster>,
+    config: web::Data<Config>,
+    identity: web::Data<Arc<NodeIdentity>>,
+    request: Json<Reconfiguration>,
+) -> Result<Json<JointAck>, ReconfigurationError> {
+    request.verify(&config.admin_keys).map_err(ReconfigurationError::Rejected)?;
+    if apply(&cluster, &db, |membership| membership.begin(&request))? {
+        info!("Entered joint configuration for epoch {}", request.epoch);
+    }
+    Ok(Json(JointAck::new(cluster.node_id.clone(), &request, &identity)))
+}
+
+/// Handler switching to the new membership once the coordinator proves the joint quorum acked.
+/// Nodes the commit lists as old members only accept it if it started from their membership.
+#[actix_web::post("/cluster/reconfigure/commit")]
+async fn commit_reconfiguration(
+    db: web::Data<Arc<Mutex<DbHandle>>>,
+    cluster: web::Data<Cluster>,
+    config: web::Data<Config>,
+    identity: web::Data<Arc<NodeIdentity>>,
+    request: Json<ReconfigurationCommit>,
+) -> Result<HttpResponse, ReconfigurationError> {
+    let known = cluster.membership.read().unwrap().current.clone();
+    request.verify(&config.admin_keys, &known, &cluster.node_id, &identity).map_err(ReconfigurationError::Rejected)?;
+    let epoch = request.reconfiguration.epoch;
+    let changed = apply(&cluster, &db, |membership| {
+        let was_member = request.previous.members.iter().any(|member| member.id == cluster.node_id);
+        if membership.epoch < epoch && was_member && !membership.current.same_members(&request.previous) {
+            return Err(MembershipError::Unproven(epoch, "joint configuration started from another membership".to_string()));
+        }
+        membership.commit(&request.reconfiguration)
+    })?;
+    if changed {
+        info!("Switched to membership epoch {}", epoch);
+        if !cluster.membership.read().unwrap().is_member(&cluster.node_id) {
+            warn!("This node is not a member of epoch {}", epoch);
+        }
+    }
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Handler returning the membership this node follows.
+#[actix_web::get("/cluster/membership")]
+async fn get_membership(cluster: web::Data<Cluster>) -> Json<Membership> {
+    Json(cluster.membership.read().unwrap().clone())
+}
@@ -1,2 +1,11 @@
+pub mod admin;
+pub mod chunk;
 pub mod dna_sequence;
+pub mod health;
+pub mod idempotency;
+pub mod membership;
+pub mod ordering;
 pub mod public_key;
+pub mod search;
+pub mod status;
+pub mod upload;
@@ -0,0 +1,105 @@
+use crate::ordering::{Divergence, Envelope, Ordering, OrderingError, OrderingStatus, LOG_PAGE};
+use crate::model::ordered::{Operation, OrderedOperation};
+use crate::sender::Cluster;
+
+use serde::Deserialize;
+use actix_web::{
+    http::{header::ContentType, StatusCode},
+    error::ResponseError,
+    web::Json,
+    HttpResponse,
+    web,
+};
+
+impl ResponseError for OrderingError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            OrderingError::NotPrimary { .. } => StatusCode::MISDIRECTED_REQUEST,
+            OrderingError::ViewChange(_) | OrderingError::Busy | OrderingError::Disabled => StatusCode::SERVICE_UNAVAILABLE,
+            OrderingError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            OrderingError::Rejected(_) => StatusCode::CONFLICT,
+            OrderingError::InvalidMessage(_) => StatusCode::BAD_REQUEST,
+            OrderingError::Unreachable(..) => StatusCode::SERVICE_UNAVAILABLE,
+            OrderingError::Forwarded(status, _) => StatusCode::from_u16(*status).unwrap_or(StatusCode::BAD_GATEWAY),
+            OrderingError::QueryFailed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .insert_header(ContentType::json())
+            .body(self.to_string())
+    }
+}
+
+/// Query parameters for reading the operation log. Both bounds are sequence numbers.
+#[derive(Deserialize)]
+pub struct LogQuery {
+    #[serde(default)]
+    after: u64,
+    until: Option<u64>,
+}
+
+/// Query parameters for comparing operation logs.
+#[derive(Deserialize)]
+pub struct CompareQuery {
+    peer: String, // Id or API address of the peer.
+}
+
+/// Handler for ordering messages from other replicas.
+#[actix_web::post("/ordering/message")]
+async fn ordering_message(
+    ordering: web::Data<Ordering>,
+    request: Json<Envelope>,
+) -> Result<HttpResponse, OrderingError> {
+    ordering.receive(request.into_inner())?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Handler for client writes forwarded by other replicas to the primary. Answers with the
+/// sequence number the write executed at.
+#[actix_web::post("/ordering/request")]
+async fn ordering_request(
+    ordering: web::Data<Ordering>,
+    request: Json<Operation>,
+) -> Result<Json<u64>, OrderingError> {
+    ordering.order_forwarded(request.into_inner()).await.map(Json)
+}
+
+/// Handler serving the operation log to replicas catching up or comparing logs, one page at a time.
+#[actix_web::get("/ordering/log")]
+async fn ordering_log(
+    ordering: web::Data<Ordering>,
+    query: web::Query<LogQuery>,
+) -> Result<Json<Vec<OrderedOperation>>, OrderingError> {
+    ordering.log(query.after, query.until.unwrap_or(u64::MAX), LOG_PAGE).map(Json)
+}
+
+/// Handler returning the new view message that started the replica's current view, so replicas
+/// that missed it can verify and enter the view.
+#[actix_web::get("/ordering/view")]
+async fn ordering_view(ordering: web::Data<Ordering>) -> Json<Option<Envelope>> {
+    Json(ordering.new_view())
+}
+
+/// Handler reporting the view, primary and progress of the local replica.
+#[actix_web::get("/ordering/status")]
+async fn ordering_status(ordering: web::Data<Ordering>) -> Json<OrderingStatus> {
+    Json(ordering.status())
+}
+
+/// Handler comparing the local operation log with a peer's, reporting the first entry they differ at.
+#[actix_web::get("/ordering/compare")]
+async fn ordering_compare(
+    ordering: web::Data<Ordering>,
+    cluster: web::Data<Cluster>,
+    query: web::Query<CompareQuery>,
+) -> Result<Json<Divergence>, OrderingError> {
+    if !ordering.is_enabled() {
+        return Err(OrderingError::Disabled);
+    }
+    let address = cluster.membership.read().unwrap().current.members.iter()
+        .find(|member| member.id == query.peer)
+        .map_or_else(|| query.peer.clone(), |member| member.address.clone());
+    ordering.compare(&address).await.map(Json)
+}
@@ -1,4 +1,5 @@
 use actix_web::{
+    http::StatusCode,
     error::ResponseError,
     web::Json,
     HttpResponse,
@@ -10,17 +11,26 @@ use actix_web::http::header::ContentType;
 use serde::Deserialize;
 use crate::repository::db::DbHandle;
 use crate::repository::db::QuerryError;
-use crate::model::public_key::PublicKey;
-use crate::sender;
-use tracing::{debug, info};
+use crate::model::{public_key::PublicKey, record::Record};
+use crate::ordering::{Ordering, OrderingError};
+use crate::sender::{self, Cluster};
+use tracing::debug;
 
 /// Errors for public key operations.
 #[derive(Debug, Error, derive_more::Display)]
 pub enum DbPublicKeyError {
     PushFailed(QuerryError),
+    Ordering(OrderingError),
 }
 
 impl ResponseError for DbPublicKeyError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            DbPublicKeyError::Ordering(e) => e.status_code(),
+            DbPublicKeyError::PushFailed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
     fn error_response(&self) -> HttpResponse {
         HttpResponse::build(self.status_code())
             .insert_header(ContentType::json())
@@ -51,24 +61,31 @@ async fn share_public_key(
     }
 }
 
-/// Handler for inserting a new public key and broadcasting it.
+/// Handler for inserting a new public key and broadcasting it, or ordering it across the
+/// cluster when the ordering layer is enabled.
 #[actix_web::post("/insert_public_key")]
 async fn insert_public_key(
     db: web::Data<Arc<Mutex<DbHandle>>>,
-    addresses: web::Data<Vec<String>>,
+    cluster: web::Data<Cluster>,
+    ordering: web::Data<Ordering>,
     request: Json<SubmitPublicKey>,
 ) -> Result<Json<String>, DbPublicKeyError> {
     debug!("Creating public key");
     let public_key_encoded = request.public_key.clone();
     let public_key = PublicKey::try_from(public_key_encoded).unwrap();
     let id = public_key.id.clone();
+    if ordering.is_enabled() {
+        let record = Record::PublicKey { id: id.clone(), public_key: request.public_key.clone() };
+        ordering.order(record).await.map_err(DbPublicKeyError::Ordering)?;
+        return Ok(Json(id.to_string()));
+    }
     debug!("locking db");
-    let db = db.lock().unwrap();
-    match db.push_public_key(&public_key) {
+    let res = db.lock().unwrap().push_public_key(&public_key);
+    match res {
         Ok(_) => {
             debug!("inserting new pk");
             let _ = tokio::spawn(async move {
-                sender::broadcast_public_key(addresses.as_ref().to_owned(), public_key).await;
+                sender::broadcast_public_key(cluster.as_ref().clone(), public_key).await;
             }).await;
         },
         Err(e) => return Err(DbPublicKeyError::PushFailed(QuerryError::RusqliteError(e))),
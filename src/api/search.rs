@@ -0,0 +1,157 @@
+use crate::{
+    model::{
+        dna_sequence::Strand,
+        motif::{Motif, MotifError, MotifKind},
+    },
+    repository::{cipher::CipherError, db::{DbHandle, QuerryError, KMER_LENGTH}},
+};
+
+use std::sync::{Arc, Mutex};
+
+use serde::{Serialize, Deserialize};
+use tracing::debug;
+use thiserror::Error;
+use actix_web::{
+    http::{header::ContentType, StatusCode},
+    error::ResponseError,
+    web::Json,
+    HttpResponse,
+    web,
+};
+
+/// Errors for motif searches.
+#[derive(Debug, Error, derive_more::Display)]
+pub enum SearchError {
+    InvalidPattern(MotifError),
+    QueryFailed(QuerryError),
+}
+
+impl ResponseError for SearchError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            SearchError::InvalidPattern(_) => StatusCode::BAD_REQUEST,
+            SearchError::QueryFailed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .insert_header(ContentType::json())
+            .body(self.to_string())
+    }
+}
+
+/// Request structure for motif searches.
+#[derive(Deserialize)]
+pub struct SearchRequest {
+    pattern: Arc<str>,
+    #[serde(default = "default_kind")]
+    kind: MotifKind,
+}
+
+fn default_kind() -> MotifKind {
+    MotifKind::Exact
+}
+
+/// A single motif occurrence within a stored sequence.
+#[derive(Serialize)]
+struct SearchMatch {
+    id: Arc<str>,
+    position: usize,
+    length: usize,
+    strand: Strand,
+}
+
+/// Response for motif searches.
+#[derive(Serialize)]
+struct SearchResponse {
+    matches: Vec<SearchMatch>,
+}
+
+/// Lists the sequences that may contain the motif, through the k-mer index when it can narrow
+/// the search and all sequences otherwise.
+fn candidates(db: &DbHandle, motif: &Motif) -> Result<Vec<Arc<str>>, SearchError> {
+    match motif.index_kmers(KMER_LENGTH) {
+        Some(kmers) => db.get_dna_sequence_ids_by_kmers(&kmers),
+        None => db.get_dna_sequence_ids(),
+    }.map_err(|e| SearchError::QueryFailed(QuerryError::RusqliteError(e)))
+}
+
+/// Handler for finding stored sequences containing a motif on either strand.
+/// Exact and IUPAC patterns are narrowed down through the k-mer index first.
+#[actix_web::post("/search")]
+async fn search(
+    db: web::Data<Arc<Mutex<DbHandle>>>,
+    request: Json<SearchRequest>,
+) -> Result<Json<SearchResponse>, SearchError> {
+    let motif = Motif::new(request.kind, request.pattern.clone())
+        .map_err(SearchError::InvalidPattern)?;
+    let candidates = candidates(&db.lock().unwrap(), &motif)?;
+    debug!("Scanning {} candidate sequences for {}", candidates.len(), &motif.pattern);
+
+    let mut matches = vec![];
+    for id in candidates {
+        // The lock is held per sequence so writes are not stalled by long scans.
+        let dna_sequence = match db.lock().unwrap().get_dna_sequence(id.clone()) {
+            Ok(dna_sequence) => dna_sequence,
+            Err(QuerryError::EmptyTableErrorW(_)) => continue, // Deleted since the candidates were listed.
+            Err(QuerryError::CipherErrorW(CipherError::Sealed)) => continue, // Sealed at rest and this node has no key.
+            Err(e) => return Err(SearchError::QueryFailed(e)),
+        };
+        if dna_sequence.encrypted {
+            continue;
+        }
+        matches.extend(motif.find(&dna_sequence.dna_sequence).into_iter().map(|m| SearchMatch {
+            id: id.clone(),
+            position: m.position,
+            length: m.length,
+            strand: m.strand,
+        }));
+    }
+    Ok(Json(SearchResponse { matches }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::dna_sequence::{reverse_complement, DnaSequence};
+
+    #[test]
+    fn index_candidates_equal_a_full_scan() {
+        let db = DbHandle::new(":memory:".to_string(), None).unwrap();
+        let sequences = [
+            ("dna", "ACGTTGCAGAATTCCGATCGGATCCAAGCTTGCATGC"),
+            ("rna", "UUGACCGAAUUCUAGGCAUGCAAGGUACCUUGA"),
+            ("lower", "ttgaccgaattctaggcatgcaaggtaccttga"),
+            ("minus", &reverse_complement("CCCCGTCTAGACGGTACCGCGAATTCAAAA")),
+            ("short", "GAATTC"),
+            ("none", "CCCCCCCCCCCCCCCCCCCCCCCCCCCCCCC"),
+        ];
+        for (id, sequence) in sequences {
+            db.push_dna_sequence(&DnaSequence::new(id.into(), sequence.into())).unwrap();
+        }
+        let patterns = [
+            (MotifKind::Exact, "GAATTCTA"),
+            (MotifKind::Exact, "GAAUUCUAGG"),
+            (MotifKind::Exact, "cgtctagacgg"),
+            (MotifKind::Exact, "GAATTC"),
+            (MotifKind::Iupac, "GGTACCNNNR"),
+            (MotifKind::Iupac, "RGAATTCY"),
+            (MotifKind::Iupac, "NNNNNNNNNN"),
+            (MotifKind::Regex, "G[AU]+TC"),
+        ];
+        for (kind, pattern) in patterns {
+            let motif = Motif::new(kind, pattern.into()).unwrap();
+            let matching = |ids: Vec<Arc<str>>| -> Vec<Arc<str>> {
+                ids.into_iter()
+                    .filter(|id| !motif.find(&db.get_dna_sequence(id.clone()).unwrap().dna_sequence).is_empty())
+                    .collect()
+            };
+            let found = matching(candidates(&db, &motif).unwrap());
+            assert!(!found.is_empty(), "{} matches nothing", pattern);
+            assert_eq!(found, matching(db.get_dna_sequence_ids().unwrap()), "{}", pattern);
+        }
+        let narrowed = candidates(&db, &Motif::new(MotifKind::Exact, "GAATTCTA".into()).unwrap()).unwrap();
+        assert!(!narrowed.contains(&Arc::from("none")));
+    }
+}
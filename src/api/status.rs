@@ -0,0 +1,36 @@
+use crate::{
+    bootstrap::BootstrapProgress,
+    config::Config,
+    repository::{chunk::StorageStats, db::DbHandle},
+};
+
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+use actix_web::{
+    web::Json,
+    web,
+};
+
+/// Response for status requests.
+#[derive(Serialize)]
+struct StatusResponse {
+    node_id: String,
+    bootstrap: BootstrapProgress,
+    storage: Option<StorageStats>, // Unset if the database could not be read.
+}
+
+/// Handler reporting the node's identity, the progress of any bootstrap from a peer and the
+/// storage sequences use once their shared chunks are deduplicated.
+#[actix_web::get("/status")]
+async fn status(
+    config: web::Data<Config>,
+    progress: web::Data<Arc<Mutex<BootstrapProgress>>>,
+    db: web::Data<Arc<Mutex<DbHandle>>>,
+) -> Json<StatusResponse> {
+    Json(StatusResponse {
+        node_id: config.node_id.clone(),
+        bootstrap: progress.lock().unwrap().clone(),
+        storage: db.lock().unwrap().get_storage_stats().ok(),
+    })
+}
@@ -0,0 +1,165 @@
+use crate::{
+    api::dna_sequence::{self, DbDnaSequenceError},
+    config::Config,
+    model::{
+        dna_sequence::DnaSequence,
+        public_key::WrongSignatureError,
+    },
+    ordering::Ordering,
+    repository::db::{DbHandle, QuerryError},
+    sender::Cluster,
+};
+
+use std::sync::{Arc, Mutex};
+
+use actix_web::web::Bytes;
+use serde::{Serialize, Deserialize};
+use tracing::info;
+use thiserror::Error;
+use actix_web::{
+    http::{header::ContentType, StatusCode},
+    error::ResponseError,
+    web::Json,
+    HttpResponse,
+    web,
+};
+use uuid::Uuid;
+
+/// Errors for chunked uploads.
+#[derive(Debug, Error, derive_more::Display)]
+pub enum UploadError {
+    #[display(fmt = "Upload {} does not exist or expired", _0)]
+    NotFound(String),
+    #[display(fmt = "Part {} of the upload is missing", _0)]
+    MissingPart(usize),
+    #[display(fmt = "Upload parts must be UTF-8 text")]
+    InvalidPart,
+    #[display(fmt = "Sequence exceeds the limit of {} bytes", _0)]
+    TooLarge(usize),
+    QueryFailed(QuerryError),
+    Rejected(DbDnaSequenceError),
+}
+
+impl ResponseError for UploadError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            UploadError::NotFound(_) => StatusCode::NOT_FOUND,
+            UploadError::MissingPart(_) | UploadError::InvalidPart => StatusCode::BAD_REQUEST,
+            UploadError::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            UploadError::QueryFailed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            UploadError::Rejected(e) => e.status_code(),
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .insert_header(ContentType::json())
+            .body(self.to_string())
+    }
+}
+
+impl From<rusqlite::Error> for UploadError {
+    fn from(e: rusqlite::Error) -> Self {
+        UploadError::QueryFailed(e.into())
+    }
+}
+
+/// Request opening an upload of sequence `id`.
+#[derive(Deserialize)]
+pub struct StartUpload {
+    id: Arc<str>,
+}
+
+/// Response for an opened upload: the handle its parts are sent to.
+#[derive(Serialize)]
+struct UploadStarted {
+    upload: String,
+}
+
+/// Response for a stored part.
+#[derive(Serialize)]
+struct PartReceived {
+    index: usize,
+    length: usize,
+}
+
+/// Request finalizing an upload. The owner signs the envelope of the whole sequence, so the
+/// signature covers the SHA-256 of every part joined in index order.
+#[derive(Deserialize)]
+pub struct FinalizeUpload {
+    signature: Arc<str>,
+    counter: u64, // Counter of the signed envelope.
+    #[serde(default)]
+    encrypted: bool,
+    #[serde(default)]
+    base: Option<String>, // Version the upload replaces; refused with 409 if the sequence moved on.
+}
+
+/// Handler opening a chunked upload for a sequence whose owner registered a public key.
+#[actix_web::post("/uploads")]
+async fn start_upload(
+    db: web::Data<Arc<Mutex<DbHandle>>>,
+    request: Json<StartUpload>,
+) -> Result<Json<UploadStarted>, UploadError> {
+    let db = db.lock().unwrap();
+    if db.get_public_key(request.id.clone()).is_err() {
+        return Err(UploadError::Rejected(DbDnaSequenceError::SignatureVerificationFailed(WrongSignatureError::NoPublicKey)));
+    }
+    let upload = Uuid::new_v4().to_string();
+    db.push_upload(&upload, request.id.clone(), chrono::Utc::now().timestamp())?;
+    info!("Opened upload {} of sequence {}", &upload, &request.id);
+    Ok(Json(UploadStarted { upload }))
+}
+
+/// Handler storing part `index` of an upload from the raw request body. Parts may arrive in
+/// any order, and resending a part replaces it.
+#[actix_web::put("/uploads/{upload}/{index}")]
+async fn upload_part(
+    db: web::Data<Arc<Mutex<DbHandle>>>,
+    path: web::Path<(String, usize)>,
+    body: Bytes,
+) -> Result<Json<PartReceived>, UploadError> {
+    let (upload, index) = path.into_inner();
+    let data = std::str::from_utf8(&body).map_err(|_| UploadError::InvalidPart)?;
+    let db = db.lock().unwrap();
+    if db.get_upload(&upload)?.is_none() {
+        return Err(UploadError::NotFound(upload));
+    }
+    db.push_upload_part(&upload, index, data)?;
+    Ok(Json(PartReceived { index, length: data.len() }))
+}
+
+/// Handler joining the parts of an upload and storing the sequence as a regular signed write.
+/// A refused upload keeps its parts until it expires, so it can be finalized again.
+#[actix_web::post("/uploads/{upload}/finalize")]
+async fn finalize_upload(
+    db: web::Data<Arc<Mutex<DbHandle>>>,
+    cluster: web::Data<Cluster>,
+    ordering: web::Data<Ordering>,
+    config: web::Data<Config>,
+    upload: web::Path<String>,
+    request: Json<FinalizeUpload>,
+) -> Result<Json<String>, UploadError> {
+    let upload = upload.into_inner();
+    let (id, sequence) = {
+        let db = db.lock().unwrap();
+        let id = db.get_upload(&upload)?.ok_or_else(|| UploadError::NotFound(upload.clone()))?;
+        let mut sequence = String::new();
+        for (expected, (index, part)) in db.get_upload_parts(&upload).map_err(UploadError::QueryFailed)?.into_iter().enumerate() {
+            if index != expected {
+                return Err(UploadError::MissingPart(expected));
+            }
+            sequence.push_str(&part);
+            if sequence.len() > config.upload.sequence_limit_bytes {
+                return Err(UploadError::TooLarge(config.upload.sequence_limit_bytes));
+            }
+        }
+        (id, sequence)
+    };
+    let dna_sequence = DnaSequence::signed(id, sequence.into(), request.signature.clone(), request.encrypted, request.counter);
+    let id = dna_sequence::store_dna_sequence(&db, &cluster, &ordering, dna_sequence, request.base.clone()).await
+        .map_err(UploadError::Rejected)?;
+    db.lock().unwrap().remove_upload(&upload)?;
+    info!("Finalized upload {} of sequence {}", &upload, &id);
+    Ok(Json(id.to_string()))
+}
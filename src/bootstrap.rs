@@ -0,0 +1,284 @@
+use crate::{
+    config::{Config, PeerConfig},
+    model::{public_key::PublicKey, record::Record},
+    repository::{
+        db::{DbHandle, QuerryError},
+        snapshot::{SnapshotError, SnapshotManifest},
+    },
+};
+use std::sync::{Arc, Mutex};
+use reqwest::Client;
+use serde::Serialize;
+use thiserror::Error;
+use tracing::{error, info, warn};
+
+/// Records between progress log lines.
+const LOG_EVERY: usize = 1_000;
+
+/// Phase of a bootstrap from a peer snapshot.
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BootstrapState {
+    #[default]
+    Idle,
+    Running,
+    Done,
+    Failed,
+}
+
+/// Progress of a bootstrap, reported by `/status`.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct BootstrapProgress {
+    pub state: BootstrapState,
+    pub peer: Option<String>,
+    pub expected: usize, // Records announced by the peer's manifest.
+    pub received: usize,
+    pub applied: usize,
+    pub skipped: usize, // Records already replicated live, or deleted.
+    pub rejected: usize, // Records failing signature or key verification.
+    pub merkle_root: Option<String>, // Root announced by the peer's manifest.
+    pub matches_manifest: Option<bool>, // Whether the local state equals the manifest once done.
+    pub error: Option<String>,
+}
+
+/// Errors that abort a bootstrap.
+#[derive(Error, Debug, derive_more::From, derive_more::Display)]
+pub enum BootstrapError {
+    #[display(fmt = "Snapshot request failed: {}", _0)]
+    Request(reqwest::Error),
+    #[display(fmt = "Peer answered {}", _0)]
+    #[from(ignore)]
+    Status(reqwest::StatusCode),
+    #[display(fmt = "Malformed snapshot stream: {}", _0)]
+    #[from(ignore)]
+    Malformed(String),
+    #[display(fmt = "Manifest is signed by {} but the peer is configured with {}", _0, _1)]
+    #[from(ignore)]
+    UnexpectedSigner(String, String),
+    #[display(fmt = "Manifest is signed by {}, which is neither a pinned peer key nor an admin key; use --insecure-bootstrap to trust it", _0)]
+    #[from(ignore)]
+    UntrustedSigner(String),
+    Manifest(SnapshotError),
+    QueryFailed(QuerryError),
+}
+
+impl From<rusqlite::Error> for BootstrapError {
+    fn from(e: rusqlite::Error) -> Self {
+        BootstrapError::QueryFailed(e.into())
+    }
+}
+
+/// What happened to a single streamed record.
+pub(crate) enum Outcome {
+    Applied,
+    Skipped,
+    Rejected(String),
+}
+
+/// Keys a bootstrap trusts the peer's manifest with besides the peer's configured public key.
+pub struct Trust {
+    pub admin_keys: Vec<String>,
+    pub insecure: bool, // Trust a manifest signed by any key, as `--insecure-bootstrap` asks.
+}
+
+/// Finds the peer to bootstrap from by id or address. Unknown addresses are used without a
+/// pinned public key.
+pub fn resolve_peer(config: &Config, target: &str) -> PeerConfig {
+    config.peers.iter()
+        .find(|peer| peer.id == target || peer.address == target)
+        .cloned()
+        .unwrap_or_else(|| PeerConfig { id: target.to_string(), address: target.to_string(), public_key: None })
+}
+
+/// Streams a snapshot from `peer`, verifying every record against its owner's key before storing
+/// it. The manifest must be signed by the peer's configured key, or by an admin key if it has
+/// none, unless `trust` is insecure. The node keeps serving and accepting live replication
+/// meanwhile; records it already holds are left alone since live writes are newer than the
+/// snapshot. `client` must not limit the total request time, as the stream lasts as long as the
+/// peer's state takes to send.
+pub async fn bootstrap(
+    db: Arc<Mutex<DbHandle>>,
+    progress: Arc<Mutex<BootstrapProgress>>,
+    peer: PeerConfig,
+    trust: Trust,
+    client: Client,
+    scheme: &'static str,
+) {
+    *progress.lock().unwrap() = BootstrapProgress {
+        state: BootstrapState::Running,
+        peer: Some(peer.id.clone()),
+        ..BootstrapProgress::default()
+    };
+    info!("Bootstrapping from {} at {}", &peer.id, &peer.address);
+    let mut session = Session { db, progress: progress.clone(), peer, trust, manifest: None };
+    let result = session.run(&client, scheme).await;
+    let mut progress = progress.lock().unwrap();
+    match result {
+        Ok(()) => {
+            progress.state = BootstrapState::Done;
+            info!(
+                "Bootstrap done: {} applied, {} skipped, {} rejected",
+                progress.applied, progress.skipped, progress.rejected,
+            );
+        },
+        Err(e) => {
+            error!("Bootstrap failed: {}", e);
+            progress.state = BootstrapState::Failed;
+            progress.error = Some(e.to_string());
+        },
+    }
+}
+
+/// State of one snapshot download.
+struct Session {
+    db: Arc<Mutex<DbHandle>>,
+    progress: Arc<Mutex<BootstrapProgress>>,
+    peer: PeerConfig,
+    trust: Trust,
+    manifest: Option<SnapshotManifest>,
+}
+
+impl Session {
+    async fn run(&mut self, client: &Client, scheme: &str) -> Result<(), BootstrapError> {
+        let mut response = client.get(format!("{}{}/snapshot", scheme, &self.peer.address)).send().await?;
+        if !response.status().is_success() {
+            return Err(BootstrapError::Status(response.status()));
+        }
+        let mut buffer = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            buffer.extend_from_slice(&chunk);
+            while let Some(end) = buffer.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=end).collect();
+                self.line(&line)?;
+            }
+        }
+        self.line(&buffer)?;
+        self.finish()
+    }
+
+    /// Handles one line of the stream: the signed manifest first, then one record per line.
+    fn line(&mut self, line: &[u8]) -> Result<(), BootstrapError> {
+        if line.iter().all(u8::is_ascii_whitespace) {
+            return Ok(());
+        }
+        let Some(manifest) = &self.manifest else {
+            let manifest: SnapshotManifest = serde_json::from_slice(line)
+                .map_err(|e| BootstrapError::Malformed(format!("manifest: {}", e)))?;
+            manifest.check_signature()?;
+            match &self.peer.public_key {
+                Some(pinned) if *pinned != manifest.public_key => {
+                    return Err(BootstrapError::UnexpectedSigner(manifest.public_key, pinned.clone()));
+                },
+                Some(_) => (),
+                None if self.trust.admin_keys.contains(&manifest.public_key) => (),
+                None if self.trust.insecure => {
+                    warn!("Peer {} has no configured public key, trusting its manifest signature as asked", &self.peer.id);
+                },
+                None => return Err(BootstrapError::UntrustedSigner(manifest.public_key)),
+            }
+            info!(
+                "Peer {} announces {} public keys, {} sequences and {} tombstones with Merkle root {}",
+                &manifest.node_id, manifest.public_keys, manifest.dna_sequences, manifest.tombstones, &manifest.merkle_root,
+            );
+            let mut progress = self.progress.lock().unwrap();
+            progress.expected = manifest.public_keys + manifest.dna_sequences + manifest.tombstones;
+            progress.merkle_root = Some(manifest.merkle_root.clone());
+            self.manifest = Some(manifest);
+            return Ok(());
+        };
+        let record: Record = serde_json::from_slice(line)
+            .map_err(|e| BootstrapError::Malformed(e.to_string()))?;
+        let outcome = apply(&self.db.lock().unwrap(), record)?;
+        let mut progress = self.progress.lock().unwrap();
+        progress.received += 1;
+        match outcome {
+            Outcome::Applied => progress.applied += 1,
+            Outcome::Skipped => progress.skipped += 1,
+            Outcome::Rejected(reason) => {
+                warn!("Rejected record {} from {}: {}", progress.received, &manifest.node_id, reason);
+                progress.rejected += 1;
+            },
+        }
+        if progress.received.is_multiple_of(LOG_EVERY) {
+            info!("Bootstrap progress: {} of {} records", progress.received, progress.expected);
+        }
+        Ok(())
+    }
+
+    /// Compares the local state with the manifest once the stream ended. A stream cut short
+    /// fails the request itself; the peer reads its records in pages after signing the manifest,
+    /// so writes it received meanwhile can change the record count.
+    fn finish(&self) -> Result<(), BootstrapError> {
+        let manifest = self.manifest.as_ref()
+            .ok_or_else(|| BootstrapError::Malformed("stream ended before the manifest".to_string()))?;
+        let mut progress = self.progress.lock().unwrap();
+        if progress.received != progress.expected {
+            warn!("Peer streamed {} records for the {} its manifest announced", progress.received, progress.expected);
+        }
+        let matches = manifest.check_contents(&self.db.lock().unwrap());
+        if let Err(e) = &matches {
+            warn!("Local state differs from the peer's manifest, as expected after live writes or rejections: {}", e);
+        }
+        progress.matches_manifest = Some(matches.is_ok());
+        Ok(())
+    }
+}
+
+/// Verifies a streamed record and stores it unless the node already holds it.
+pub(crate) fn apply(db: &DbHandle, record: Record) -> Result<Outcome, BootstrapError> {
+    match record {
+        Record::PublicKey { id, public_key } => {
+            let public_key = match PublicKey::from_raw(id.to_string(), public_key) {
+                Ok(key) if key.public_key.as_ref().is_some_and(|raw| raw.len() == 32) => key,
+                _ => return Ok(Outcome::Rejected(format!("malformed public key {}", id))),
+            };
+            match db.get_public_key(id.clone()) {
+                Ok(existing) if existing.public_key != public_key.public_key => {
+                    Ok(Outcome::Rejected(format!("public key {} conflicts with the local one", id)))
+                },
+                Ok(_) => Ok(Outcome::Skipped),
+                Err(_) => {
+                    db.push_public_key(&public_key)?;
+                    Ok(Outcome::Applied)
+                },
+            }
+        },
+        Record::DnaSequence(dna_sequence) => {
+            let id = dna_sequence.id.clone();
+            let Ok(public_key) = db.get_public_key(id.clone()) else {
+                return Ok(Outcome::Rejected(format!("sequence {} has no public key", id)));
+            };
+            let Some(signature) = dna_sequence.signature.clone() else {
+                return Ok(Outcome::Rejected(format!("sequence {} is unsigned", id)));
+            };
+            if dna_sequence.check_signature(signature, public_key).is_err() {
+                return Ok(Outcome::Rejected(format!("sequence {} has a bad signature", id)));
+            }
+            if let Err(e) = dna_sequence.validate() {
+                return Ok(Outcome::Rejected(format!("sequence {}: {}", id, e)));
+            }
+            if db.has_dna_sequence(id.clone())? || db.is_deleted(id)? {
+                return Ok(Outcome::Skipped);
+            }
+            db.push_dna_sequence(&dna_sequence)?;
+            Ok(Outcome::Applied)
+        },
+        Record::Tombstone(tombstone) => {
+            let id = tombstone.id.clone();
+            let Ok(public_key) = db.get_public_key(id.clone()) else {
+                return Ok(Outcome::Rejected(format!("tombstone {} has no public key", id)));
+            };
+            if PublicKey::check_signature(tombstone.signature.clone(), public_key, tombstone.message()).is_err() {
+                return Ok(Outcome::Rejected(format!("tombstone {} has a bad signature", id)));
+            }
+            if db.is_deleted(id.clone())? {
+                return Ok(Outcome::Skipped);
+            }
+            match db.push_tombstone(&tombstone, chrono::Utc::now().timestamp()) {
+                Ok(_) => Ok(Outcome::Applied),
+                Err(QuerryError::StaleErrorW(_)) => Ok(Outcome::Rejected(format!("tombstone {} deletes an older version", id))),
+                Err(e) => Err(e.into()),
+            }
+        },
+    }
+}
@@ -0,0 +1,293 @@
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use clap::{Args, Parser, Subcommand};
+use tracing::{info, warn};
+
+use crate::bootstrap::{self, Outcome};
+use crate::config::{Config, ConfigError, ConfigOverrides};
+use crate::model::record::Record;
+use crate::identity::NodeIdentity;
+use crate::membership::MEMBERSHIP_KEY;
+use crate::ordering;
+use crate::repository::{cipher::SequenceCipher, db::DbHandle, snapshot::{self, Cursor, PAGE_RECORDS}, verify};
+
+/// Metadata key of the manifest a database was last restored from.
+const RESTORED_FROM_KEY: &str = "restored_from";
+
+/// Distributed, signature-checked DNA sequence store.
+#[derive(Parser)]
+#[command(name = "repyh", version)]
+pub struct Cli {
+    #[command(flatten)]
+    pub overrides: ConfigOverrides,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the node. This is the default when no command is given.
+    Serve(ServeArgs),
+    #[command(flatten)]
+    Maintenance(Maintenance),
+}
+
+/// Commands that manage a node's configuration and database instead of serving.
+#[derive(Subcommand)]
+pub enum Maintenance {
+    /// Write a configuration file for a new node to --config and create its database.
+    Init {
+        /// Overwrite an existing configuration file.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Validate the configuration and print it with the derived quorum values.
+    CheckConfig,
+    /// Export public keys, sequences and tombstones as JSON lines.
+    Export {
+        /// Output file, standard output by default.
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Import records written by `export`, verifying each against its owner's public key.
+    Import {
+        /// Input file, standard input by default.
+        input: Option<PathBuf>,
+    },
+    /// Re-verify stored signatures, schema and invariants, printing a JSON report.
+    VerifyDb,
+    /// Re-wrap every stored sequence under the current master key.
+    Reencrypt,
+    /// Replace the node's database with a verified snapshot. The node must be stopped.
+    Restore {
+        /// Snapshot directory written by `POST /admin/snapshot`.
+        snapshot: PathBuf,
+        /// Overwrite a database that already holds records.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Rebuild the node's state from scratch by replaying its operation log into a new database,
+    /// checking the hash chain, and print a JSON report comparing both states.
+    Replay {
+        /// Database file to create.
+        output: PathBuf,
+        /// Overwrite an existing file.
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+/// Options of the `serve` command.
+#[derive(Args, Default)]
+pub struct ServeArgs {
+    /// Fill the database from a snapshot streamed by this peer (id or address) before relying on
+    /// live replication. Every record is verified against its owner's key.
+    #[arg(long)]
+    pub bootstrap_from: Option<String>,
+    /// Trust the bootstrap peer's manifest even when it is signed by neither the peer's configured
+    /// public key nor an admin key.
+    #[arg(long, requires = "bootstrap_from")]
+    pub insecure_bootstrap: bool,
+}
+
+/// Runs a maintenance command.
+pub fn run(command: Maintenance, overrides: &ConfigOverrides) -> Result<(), Box<dyn Error>> {
+    match command {
+        Maintenance::Init { force } => init(overrides, force),
+        Maintenance::CheckConfig => check_config(overrides),
+        Maintenance::Export { output } => export(&open_db(overrides)?, output),
+        Maintenance::Import { input } => import(&open_db(overrides)?, input),
+        Maintenance::VerifyDb => verify_db(overrides),
+        Maintenance::Reencrypt => reencrypt(overrides),
+        Maintenance::Restore { snapshot, force } => restore(overrides, &snapshot, force),
+        Maintenance::Replay { output, force } => replay(overrides, &output, force),
+    }
+}
+
+/// Returns the database path from `--database`, falling back to the configuration file.
+fn database(overrides: &ConfigOverrides) -> Result<String, ConfigError> {
+    match &overrides.database {
+        Some(database) => Ok(database.clone()),
+        None => Config::load(overrides).map(|config| config.database),
+    }
+}
+
+fn open_db(overrides: &ConfigOverrides) -> Result<DbHandle, Box<dyn Error>> {
+    Ok(DbHandle::new(database(overrides)?, SequenceCipher::from_env()?)?)
+}
+
+fn init(overrides: &ConfigOverrides, force: bool) -> Result<(), Box<dyn Error>> {
+    let path = overrides.config.clone().unwrap_or_else(|| PathBuf::from("conf/node.toml"));
+    if path.exists() && !force {
+        return Err(format!("{} already exists, pass --force to overwrite it", path.display()).into());
+    }
+    let mut config = Config {
+        node_id: "node0".to_string(),
+        p2p_address: "127.0.0.1:9090".to_string(),
+        api_address: "127.0.0.1:8080".to_string(),
+        ..Config::empty()
+    };
+    config.apply(overrides);
+    if config.database.is_empty() {
+        config.database = format!("var/{}.db", config.node_id);
+    }
+    config.validate()?;
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&path, toml::to_string_pretty(&config)?)?;
+    if let Some(parent) = PathBuf::from(&config.database).parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    DbHandle::new(config.database.clone(), SequenceCipher::from_env()?)?;
+    println!("Wrote {} and created {}. Add the cluster's peers before serving.", path.display(), config.database);
+    Ok(())
+}
+
+fn check_config(overrides: &ConfigOverrides) -> Result<(), Box<dyn Error>> {
+    let config = Config::load(overrides)?;
+    print!("{}", toml::to_string_pretty(&config)?);
+    println!(
+        "# {} nodes, tolerating {} byzantine faults, broadcasts wait for {} peer acks",
+        config.peers.len() + 1,
+        config.faults(),
+        config.acks(),
+    );
+    Ok(())
+}
+
+fn export(db: &DbHandle, output: Option<PathBuf>) -> Result<(), Box<dyn Error>> {
+    let mut writer: Box<dyn Write> = match output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(io::stdout().lock())),
+    };
+    let mut cursor = Cursor::default();
+    let mut exported = 0;
+    loop {
+        let page = cursor.next_page(db, PAGE_RECORDS)?;
+        if page.is_empty() {
+            break;
+        }
+        for record in page {
+            serde_json::to_writer(&mut writer, &record)?;
+            writeln!(writer)?;
+            exported += 1;
+        }
+    }
+    writer.flush()?;
+    info!("Exported {} records", exported);
+    Ok(())
+}
+
+fn import(db: &DbHandle, input: Option<PathBuf>) -> Result<(), Box<dyn Error>> {
+    let reader: Box<dyn BufRead> = match input {
+        Some(path) => Box::new(BufReader::new(File::open(path)?)),
+        None => Box::new(BufReader::new(io::stdin().lock())),
+    };
+    let (mut applied, mut skipped, mut rejected) = (0, 0, 0);
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: Record = serde_json::from_str(&line)
+            .map_err(|e| format!("line {}: {}", number + 1, e))?;
+        match bootstrap::apply(db, record)? {
+            Outcome::Applied => applied += 1,
+            Outcome::Skipped => skipped += 1,
+            Outcome::Rejected(reason) => {
+                warn!("line {}: rejecting {}", number + 1, reason);
+                rejected += 1;
+            },
+        }
+    }
+    println!("Imported {} records; skipped {} already held and rejected {}", applied, skipped, rejected);
+    if rejected > 0 {
+        return Err(format!("{} records failed verification", rejected).into());
+    }
+    Ok(())
+}
+
+fn verify_db(overrides: &ConfigOverrides) -> Result<(), Box<dyn Error>> {
+    let database = database(overrides)?;
+    let db = DbHandle::new(database.clone(), SequenceCipher::from_env()?)?;
+    let report = verify::verify(&db, database)?;
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    if !report.is_healthy() {
+        return Err("database failed verification".into());
+    }
+    Ok(())
+}
+
+/// Re-wraps every sequence in the node's database under the current master key.
+fn reencrypt(overrides: &ConfigOverrides) -> Result<(), Box<dyn Error>> {
+    let cipher = SequenceCipher::from_env()?
+        .ok_or("reencrypt requires DNA_KEY_FILE or DNA_KEY to be set")?;
+    let key_id = cipher.key_id().to_string();
+    let db = DbHandle::new(database(overrides)?, Some(cipher))?;
+    let rewritten = db.reencrypt()?;
+    info!("Re-encrypted {} sequences and chunks under key {}", rewritten, key_id);
+    Ok(())
+}
+
+/// Verifies a snapshot's manifest and contents, then copies it over the node's database. Only
+/// snapshots signed by this node or a configured peer are accepted.
+fn restore(overrides: &ConfigOverrides, dir: &Path, force: bool) -> Result<(), Box<dyn Error>> {
+    let config = Config::load(overrides)?;
+    let identity = NodeIdentity::load_or_generate(&config.node_key)?;
+    let trusted_keys: Vec<String> = std::iter::once(identity.public_key())
+        .chain(config.peers.iter().filter_map(|peer| peer.public_key.clone()))
+        .collect();
+    let manifest = snapshot::verify(dir, SequenceCipher::from_env()?, &trusted_keys)?;
+
+    let mut db = DbHandle::new(config.database.clone(), SequenceCipher::from_env()?)?;
+    let is_empty = db.get_public_keys()?.is_empty() && db.get_dna_sequence_ids()?.is_empty() && db.get_tombstones()?.is_empty();
+    if !is_empty && !force {
+        return Err(format!("{} already holds records, pass --force to overwrite it", config.database).into());
+    }
+    db.restore(&dir.join(snapshot::SNAPSHOT_DATABASE), &[MEMBERSHIP_KEY, RESTORED_FROM_KEY])?;
+    manifest.check_contents(&db)?;
+    db.set_meta(RESTORED_FROM_KEY, &serde_json::to_string(&manifest)?)?;
+    info!("Restored {} from {} taken by {} at {}", config.database, dir.display(), manifest.node_id, manifest.created_at);
+    println!("{}", serde_json::to_string_pretty(&manifest)?);
+    Ok(())
+}
+
+/// Response of the `replay` command.
+#[derive(serde::Serialize)]
+struct ReplayResponse {
+    output: String,
+    report: ordering::ReplayReport,
+    merkle_root: String, // Of the rebuilt state.
+    matches_source: bool, // Whether the node's state holds exactly what the log rebuilt.
+}
+
+/// Rebuilds the node's state from its operation log into `output`. States differ when the node
+/// also took writes outside the total order, from legacy replication or a bootstrap.
+fn replay(overrides: &ConfigOverrides, output: &Path, force: bool) -> Result<(), Box<dyn Error>> {
+    let config = Config::load(overrides)?;
+    if output.exists() {
+        if !force {
+            return Err(format!("{} already exists, pass --force to overwrite it", output.display()).into());
+        }
+        fs::remove_file(output)?;
+    }
+    let identity = NodeIdentity::load_or_generate(&config.node_key)?;
+    let source = DbHandle::new(config.database.clone(), SequenceCipher::from_env()?)?;
+    let target = DbHandle::new(output.to_string_lossy().into_owned(), SequenceCipher::from_env()?)?;
+    let report = ordering::replay(&source, &target)?;
+    let rebuilt = snapshot::SnapshotManifest::describe(&target, &config.node_id, &identity)?;
+    let matches_source = rebuilt.check_contents(&source).is_ok();
+    info!("Replayed {} operation log entries into {}", report.entries, output.display());
+    let response = ReplayResponse {
+        output: output.display().to_string(),
+        report,
+        merkle_root: rebuilt.merkle_root,
+        matches_source,
+    };
+    println!("{}", serde_json::to_string_pretty(&response)?);
+    Ok(())
+}
@@ -0,0 +1,632 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use base64::{Engine as _, engine::general_purpose};
+use clap::{Args, ValueEnum};
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use thiserror::Error;
+
+/// Default time tombstones are kept before being purged: 30 days.
+const DEFAULT_TOMBSTONE_GRACE_SECS: i64 = 30 * 24 * 60 * 60;
+
+/// Errors for loading and validating the node configuration.
+#[derive(Error, Debug, derive_more::Display)]
+pub enum ConfigError {
+    #[display(fmt = "Could not read {}: {}", "_0.display()", _1)]
+    Read(PathBuf, std::io::Error),
+    #[display(fmt = "Could not parse {}: {}", "_0.display()", _1)]
+    Parse(PathBuf, String),
+    #[display(fmt = "Invalid value for `{}`: {}", field, reason)]
+    InvalidField { field: String, reason: String },
+}
+
+/// Output format of the node's logs.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Compact,
+    Json,
+}
+
+/// A peer node of the cluster.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct PeerConfig {
+    pub id: String,
+    pub address: String,
+    #[serde(default)]
+    pub public_key: Option<String>, // Base64 Ed25519 key the peer signs node messages with.
+}
+
+/// Timeouts applied to requests sent to peers, in milliseconds.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutConfig {
+    pub connect_ms: u64,
+    pub request_ms: u64,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        TimeoutConfig {
+            connect_ms: 2_000,
+            request_ms: 10_000,
+        }
+    }
+}
+
+/// Connection reuse settings of the node-wide HTTP client used to reach peers.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    pub pool_idle_timeout_ms: u64, // Time an idle pooled connection is kept open.
+    pub pool_max_idle_per_host: usize,
+    pub tcp_keepalive_ms: u64, // Interval of TCP keep-alive probes on open connections.
+    pub http2: bool, // Speak HTTP/2 to peers without negotiation; every node accepts both versions.
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            pool_idle_timeout_ms: 90_000,
+            pool_max_idle_per_host: 8,
+            tcp_keepalive_ms: 30_000,
+            http2: true,
+        }
+    }
+}
+
+/// TLS settings of the API listener and peer links, in PEM files. Plain HTTP is used while unset.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: Option<String>, // Certificate chain the node serves and presents to peers.
+    pub key: Option<String>, // PKCS#8 private key of `cert`.
+    pub ca: Option<String>, // CA issuing node certificates. Enables mutual TLS between nodes.
+}
+
+impl TlsConfig {
+    /// Checks whether the node serves and reaches peers over TLS.
+    pub fn is_enabled(&self) -> bool {
+        self.cert.is_some()
+    }
+}
+
+/// Heartbeat failure detector settings.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    pub interval_ms: u64, // Time between heartbeats to each peer.
+    pub timeout_ms: u64, // Time a heartbeat may take before it counts as missed.
+    pub suspect_after: u32, // Consecutive missed heartbeats before a peer is suspect.
+    pub dead_after: u32, // Consecutive missed heartbeats before a peer is dead and skipped by broadcasts.
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            interval_ms: 1_000,
+            timeout_ms: 1_000,
+            suspect_after: 1,
+            dead_after: 3,
+        }
+    }
+}
+
+/// Delivery settings of the per-peer replication outbox.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutboxConfig {
+    pub poll_ms: u64, // Time between scans for messages due for delivery.
+    pub retry_base_ms: u64, // Delay before the first retry, doubled after every failed attempt.
+    pub retry_max_ms: u64, // Longest delay between retries.
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        OutboxConfig {
+            poll_ms: 250,
+            retry_base_ms: 500,
+            retry_max_ms: 30_000,
+        }
+    }
+}
+
+/// Byzantine fault tolerant ordering of client writes.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct OrderingConfig {
+    pub enabled: bool, // Order writes through the primary; otherwise each node broadcasts its own writes.
+    pub view_timeout_ms: u64, // Time an accepted write may stay uncommitted before replicas replace the primary.
+    pub checkpoint_interval: u64, // Operations between checkpoints.
+    pub window: u64, // Operations that may be in flight beyond the last executed one.
+}
+
+impl Default for OrderingConfig {
+    fn default() -> Self {
+        OrderingConfig {
+            enabled: true,
+            view_timeout_ms: 5_000,
+            checkpoint_interval: 16,
+            window: 256,
+        }
+    }
+}
+
+/// Handling of peers caught misbehaving.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct EvidenceConfig {
+    pub quarantine_after: usize, // Pieces of evidence against a peer before its acks stop counting towards quorums.
+}
+
+impl Default for EvidenceConfig {
+    fn default() -> Self {
+        EvidenceConfig { quarantine_after: 3 }
+    }
+}
+
+/// Chunked uploads of large sequences.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct UploadConfig {
+    pub part_limit_bytes: usize, // Largest body accepted for one numbered part.
+    pub sequence_limit_bytes: usize, // Largest sequence accepted, uploaded whole or in parts.
+    pub expire_secs: i64, // Time an upload may stay unfinalized before its parts are dropped.
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        UploadConfig {
+            part_limit_bytes: 8 * 1024 * 1024,
+            sequence_limit_bytes: 64 * 1024 * 1024,
+            expire_secs: 24 * 60 * 60,
+        }
+    }
+}
+
+/// Replication quorum parameters. Unset values are derived from the cluster size.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuorumConfig {
+    pub faults: Option<usize>, // Byzantine nodes tolerated, `f`.
+    pub acks: Option<usize>, // Peer acks a broadcast waits for.
+}
+
+/// Typed node configuration.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub node_id: String,
+    pub p2p_address: String,
+    pub api_address: String,
+    #[serde(default)]
+    pub peers: Vec<PeerConfig>,
+    #[serde(default)]
+    pub database: String,
+    #[serde(default)]
+    pub timeouts: TimeoutConfig,
+    #[serde(default)]
+    pub quorum: QuorumConfig,
+    #[serde(default)]
+    pub client: ClientConfig,
+    #[serde(default)]
+    pub tls: TlsConfig,
+    #[serde(default)]
+    pub health: HealthConfig,
+    #[serde(default)]
+    pub outbox: OutboxConfig,
+    #[serde(default)]
+    pub ordering: OrderingConfig,
+    #[serde(default)]
+    pub evidence: EvidenceConfig,
+    #[serde(default)]
+    pub upload: UploadConfig,
+    #[serde(default)]
+    pub log_format: LogFormat,
+    #[serde(default = "default_tombstone_grace_secs")]
+    pub tombstone_grace_secs: i64,
+    #[serde(default)]
+    pub node_key: String, // PKCS#8 Ed25519 key the node signs with, generated when missing.
+    #[serde(default)]
+    pub snapshot_dir: String,
+    #[serde(default)]
+    pub admin_keys: Vec<String>, // Base64 Ed25519 keys allowed to sign membership changes.
+}
+
+fn default_tombstone_grace_secs() -> i64 {
+    DEFAULT_TOMBSTONE_GRACE_SECS
+}
+
+/// Command-line and environment overrides of configuration values.
+#[derive(Args, Clone, Debug, Default)]
+pub struct ConfigOverrides {
+    /// Configuration file (TOML, JSON, or the legacy address array).
+    #[arg(long, short, env = "FILENAME", global = true)]
+    pub config: Option<PathBuf>,
+    /// Identifier of this node, defaults to its API address.
+    #[arg(long, env = "REPYH_NODE_ID", global = true)]
+    pub node_id: Option<String>,
+    /// Peer-to-peer listen address (host:port).
+    #[arg(long, env = "REPYH_P2P_ADDRESS", global = true)]
+    pub p2p_address: Option<String>,
+    /// Client API listen address (host:port).
+    #[arg(long, env = "REPYH_API_ADDRESS", global = true)]
+    pub api_address: Option<String>,
+    /// SQLite database file.
+    #[arg(long, env = "DATABASE", global = true)]
+    pub database: Option<String>,
+    #[arg(long, env = "REPYH_LOG_FORMAT", global = true)]
+    pub log_format: Option<LogFormat>,
+    /// Seconds tombstones are kept before being purged.
+    #[arg(long, env = "TOMBSTONE_GRACE_SECS", global = true)]
+    pub tombstone_grace_secs: Option<i64>,
+    /// Node signing key file, defaults to the database path with a `.key` extension.
+    #[arg(long, env = "REPYH_NODE_KEY", global = true)]
+    pub node_key: Option<String>,
+    /// Directory snapshots are written to, defaults to `snapshots` next to the database.
+    #[arg(long, env = "REPYH_SNAPSHOT_DIR", global = true)]
+    pub snapshot_dir: Option<String>,
+}
+
+pub(crate) fn invalid(field: impl Into<String>, reason: impl Into<String>) -> ConfigError {
+    ConfigError::InvalidField { field: field.into(), reason: reason.into() }
+}
+
+/// Checks that an address has the form `host:port`.
+fn check_address(field: &str, address: &str) -> Result<(), ConfigError> {
+    match address.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(()),
+        _ => Err(invalid(field, format!("expected host:port, got {:?}", address))),
+    }
+}
+
+/// Checks that a key is a base64 Ed25519 public key.
+fn check_public_key(field: &str, key: &str) -> Result<(), ConfigError> {
+    match general_purpose::STANDARD.decode(key) {
+        Ok(raw) if raw.len() == 32 => Ok(()),
+        _ => Err(invalid(field, "expected a base64 Ed25519 public key")),
+    }
+}
+
+/// Checks a list of nodes, named `field` in errors, for unique ids, addresses and keys.
+/// `ids` holds ids already taken.
+pub(crate) fn check_peers<'a>(field: &str, peers: &'a [PeerConfig], mut ids: HashSet<&'a str>) -> Result<(), ConfigError> {
+    for (i, peer) in peers.iter().enumerate() {
+        if !ids.insert(&peer.id) {
+            return Err(invalid(format!("{}[{}].id", field, i), format!("duplicate id {:?}", peer.id)));
+        }
+        check_address(&format!("{}[{}].address", field, i), &peer.address)?;
+        if let Some(key) = &peer.public_key {
+            check_public_key(&format!("{}[{}].public_key", field, i), key)?;
+        }
+    }
+    Ok(())
+}
+
+impl QuorumConfig {
+    /// Byzantine faults tolerated by `n` nodes, defaulting to the largest with `n >= 3f + 1`.
+    pub fn faults(&self, n: usize) -> usize {
+        self.faults.unwrap_or(n.saturating_sub(1) / 3)
+    }
+
+    /// Peer acks a broadcast waits for among `n` nodes, defaulting to a two-thirds majority.
+    pub fn acks(&self, n: usize) -> usize {
+        self.acks.unwrap_or((n * 2 / 3 + 1).min(n.saturating_sub(1)))
+    }
+
+    /// Checks that `n` nodes can tolerate the faults and provide the acks, naming `field` in errors.
+    pub(crate) fn validate(&self, field: &str, n: usize) -> Result<(), ConfigError> {
+        if 3 * self.faults(n) + 1 > n {
+            return Err(invalid(format!("{}.faults", field), format!("{} nodes cannot tolerate {} byzantine faults", n, self.faults(n))));
+        }
+        if self.acks(n) + 1 > n {
+            return Err(invalid(format!("{}.acks", field), format!("cannot exceed the {} other nodes", n.saturating_sub(1))));
+        }
+        Ok(())
+    }
+}
+
+impl Config {
+    /// Loads the configuration file, if any, and applies overrides on top.
+    pub fn load(overrides: &ConfigOverrides) -> Result<Self, ConfigError> {
+        let mut config = match &overrides.config {
+            Some(path) => Self::from_file(path)?,
+            None => Config::empty(),
+        };
+        config.apply(overrides);
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub(crate) fn empty() -> Self {
+        Config {
+            node_id: String::new(),
+            p2p_address: String::new(),
+            api_address: String::new(),
+            peers: vec![],
+            database: String::new(),
+            timeouts: TimeoutConfig::default(),
+            quorum: QuorumConfig::default(),
+            client: ClientConfig::default(),
+            tls: TlsConfig::default(),
+            health: HealthConfig::default(),
+            outbox: OutboxConfig::default(),
+            ordering: OrderingConfig::default(),
+            evidence: EvidenceConfig::default(),
+            upload: UploadConfig::default(),
+            log_format: LogFormat::default(),
+            tombstone_grace_secs: DEFAULT_TOMBSTONE_GRACE_SECS,
+            node_key: String::new(),
+            snapshot_dir: String::new(),
+            admin_keys: vec![],
+        }
+    }
+
+    /// Parses a TOML file by extension, otherwise JSON in either the typed or the legacy format.
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let contents = fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_owned(), e))?;
+        let parse_error = |e: String| ConfigError::Parse(path.to_owned(), e);
+        if path.extension().is_some_and(|extension| extension == "toml") {
+            return toml::from_str(&contents).map_err(|e| parse_error(e.to_string()));
+        }
+        let json: Value = serde_json::from_str(&contents).map_err(|e| parse_error(e.to_string()))?;
+        if json.is_array() {
+            let legacy: Vec<Vec<String>> = serde_json::from_value(json).map_err(|e| parse_error(e.to_string()))?;
+            return Self::from_legacy(legacy);
+        }
+        serde_json::from_value(json).map_err(|e| parse_error(e.to_string()))
+    }
+
+    /// Converts `[[p2p_address, api_address], [peer, ...]]`. Node and peer ids default to their
+    /// API addresses; legacy files listing the node among its own peers are tolerated.
+    fn from_legacy(legacy: Vec<Vec<String>>) -> Result<Self, ConfigError> {
+        let [addresses, peers]: [Vec<String>; 2] = legacy.try_into()
+            .map_err(|_| invalid("[]", "legacy configuration must hold exactly two arrays"))?;
+        let [p2p_address, api_address]: [String; 2] = addresses.try_into()
+            .map_err(|_| invalid("[0]", "expected [p2p_address, api_address]"))?;
+        Ok(Config {
+            node_id: api_address.clone(),
+            peers: peers.into_iter()
+                .filter(|address| *address != api_address)
+                .map(|address| PeerConfig { id: address.clone(), address, public_key: None })
+                .collect(),
+            p2p_address,
+            api_address,
+            // Peers have no keys to check ordering messages against.
+            ordering: OrderingConfig { enabled: false, ..OrderingConfig::default() },
+            ..Config::empty()
+        })
+    }
+
+    pub(crate) fn apply(&mut self, overrides: &ConfigOverrides) {
+        let ConfigOverrides {
+            config: _, node_id, p2p_address, api_address, database, log_format, tombstone_grace_secs, node_key, snapshot_dir,
+        } = overrides.clone();
+        self.node_id = node_id.unwrap_or(std::mem::take(&mut self.node_id));
+        self.p2p_address = p2p_address.unwrap_or(std::mem::take(&mut self.p2p_address));
+        self.api_address = api_address.unwrap_or(std::mem::take(&mut self.api_address));
+        self.database = database.unwrap_or(std::mem::take(&mut self.database));
+        self.log_format = log_format.unwrap_or(self.log_format);
+        self.tombstone_grace_secs = tombstone_grace_secs.unwrap_or(self.tombstone_grace_secs);
+        self.node_key = node_key.unwrap_or(std::mem::take(&mut self.node_key));
+        self.snapshot_dir = snapshot_dir.unwrap_or(std::mem::take(&mut self.snapshot_dir));
+        if self.node_id.is_empty() {
+            self.node_id = self.api_address.clone();
+        }
+        if !self.database.is_empty() {
+            let database = Path::new(&self.database);
+            if self.node_key.is_empty() {
+                self.node_key = database.with_extension("key").to_string_lossy().into_owned();
+            }
+            if self.snapshot_dir.is_empty() {
+                self.snapshot_dir = database.with_file_name("snapshots").to_string_lossy().into_owned();
+            }
+        }
+    }
+
+    /// Checks every field, naming the first offending one.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        check_address("p2p_address", &self.p2p_address)?;
+        check_address("api_address", &self.api_address)?;
+        if self.node_id.is_empty() {
+            return Err(invalid("node_id", "must not be empty"));
+        }
+        if self.database.is_empty() {
+            return Err(invalid("database", "must be set in the file, with --database or DATABASE"));
+        }
+        check_peers("peers", &self.peers, HashSet::from([self.node_id.as_str()]))?;
+        if self.ordering.enabled {
+            // Ordering messages are only as trustworthy as the keys they are checked against.
+            if let Some(i) = self.peers.iter().position(|peer| peer.public_key.is_none()) {
+                return Err(invalid(format!("peers[{}].public_key", i), "is required while ordering is enabled"));
+            }
+        }
+        for (i, key) in self.admin_keys.iter().enumerate() {
+            check_public_key(&format!("admin_keys[{}]", i), key)?;
+        }
+        if self.timeouts.connect_ms == 0 {
+            return Err(invalid("timeouts.connect_ms", "must be positive"));
+        }
+        if self.timeouts.request_ms == 0 {
+            return Err(invalid("timeouts.request_ms", "must be positive"));
+        }
+        if self.tls.cert.is_some() != self.tls.key.is_some() {
+            return Err(invalid("tls", "cert and key must be set together"));
+        }
+        if self.tls.ca.is_some() && !self.tls.is_enabled() {
+            return Err(invalid("tls.ca", "mutual TLS requires tls.cert and tls.key"));
+        }
+        if self.client.tcp_keepalive_ms == 0 {
+            return Err(invalid("client.tcp_keepalive_ms", "must be positive"));
+        }
+        if self.health.interval_ms == 0 {
+            return Err(invalid("health.interval_ms", "must be positive"));
+        }
+        if self.health.timeout_ms == 0 {
+            return Err(invalid("health.timeout_ms", "must be positive"));
+        }
+        if self.health.suspect_after == 0 {
+            return Err(invalid("health.suspect_after", "must be positive"));
+        }
+        if self.health.dead_after < self.health.suspect_after {
+            return Err(invalid("health.dead_after", "must not be less than health.suspect_after"));
+        }
+        if self.outbox.poll_ms == 0 {
+            return Err(invalid("outbox.poll_ms", "must be positive"));
+        }
+        if self.outbox.retry_base_ms == 0 {
+            return Err(invalid("outbox.retry_base_ms", "must be positive"));
+        }
+        if self.outbox.retry_max_ms < self.outbox.retry_base_ms {
+            return Err(invalid("outbox.retry_max_ms", "must not be less than outbox.retry_base_ms"));
+        }
+        if self.ordering.view_timeout_ms == 0 {
+            return Err(invalid("ordering.view_timeout_ms", "must be positive"));
+        }
+        if self.ordering.checkpoint_interval == 0 {
+            return Err(invalid("ordering.checkpoint_interval", "must be positive"));
+        }
+        if self.ordering.window < self.ordering.checkpoint_interval {
+            return Err(invalid("ordering.window", "must not be less than ordering.checkpoint_interval"));
+        }
+        if self.evidence.quarantine_after == 0 {
+            return Err(invalid("evidence.quarantine_after", "must be positive"));
+        }
+        if self.upload.part_limit_bytes == 0 {
+            return Err(invalid("upload.part_limit_bytes", "must be positive"));
+        }
+        if self.upload.sequence_limit_bytes < self.upload.part_limit_bytes {
+            return Err(invalid("upload.sequence_limit_bytes", "must not be less than upload.part_limit_bytes"));
+        }
+        if self.upload.expire_secs <= 0 {
+            return Err(invalid("upload.expire_secs", "must be positive"));
+        }
+        if self.tombstone_grace_secs < 0 {
+            return Err(invalid("tombstone_grace_secs", "must not be negative"));
+        }
+        self.quorum.validate("quorum", self.peers.len() + 1)
+    }
+
+    /// Byzantine faults tolerated, `f`, defaulting to the largest with `n >= 3f + 1`.
+    pub fn faults(&self) -> usize {
+        self.quorum.faults(self.peers.len() + 1)
+    }
+
+    /// Peer acks a broadcast waits for, defaulting to a two-thirds majority of the cluster.
+    pub fn acks(&self) -> usize {
+        self.quorum.acks(self.peers.len() + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conf(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("conf").join(name)
+    }
+
+    /// Parses `contents` as a configuration file with the given extension.
+    fn parse(extension: &str, contents: &str) -> Result<Config, ConfigError> {
+        let path = std::env::temp_dir().join(format!("repyh-test-{}.{}", uuid::Uuid::new_v4(), extension));
+        fs::write(&path, contents).unwrap();
+        let config = Config::from_file(&path);
+        fs::remove_file(&path).unwrap();
+        config
+    }
+
+    /// Returns the field named by a validation error.
+    fn offending_field(config: &Config) -> String {
+        match config.validate() {
+            Err(ConfigError::InvalidField { field, .. }) => field,
+            other => panic!("expected an invalid field, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn legacy_json_loads() {
+        // ips4.json lists the node among its own peers.
+        let mut config = Config::from_file(&conf("ips4.json")).unwrap();
+        assert_eq!(config.node_id, "127.0.0.1:8084");
+        assert_eq!(config.p2p_address, "127.0.0.1:9094");
+        assert_eq!(config.api_address, "127.0.0.1:8084");
+        let peers: Vec<&str> = config.peers.iter().map(|peer| peer.id.as_str()).collect();
+        assert_eq!(peers, ["127.0.0.1:8081", "127.0.0.1:8082", "127.0.0.1:8083"]);
+        assert!(config.peers.iter().all(|peer| peer.address == peer.id && peer.public_key.is_none()));
+        assert!(!config.ordering.enabled);
+
+        config.apply(&ConfigOverrides { database: Some("var/dna4.db".to_string()), ..ConfigOverrides::default() });
+        config.validate().unwrap();
+        assert_eq!(config.node_key, "var/dna4.key");
+
+        assert!(matches!(parse("json", r#"[["127.0.0.1:9090", "127.0.0.1:8080"]]"#), Err(ConfigError::InvalidField { field, .. }) if field == "[]"));
+        assert!(matches!(parse("json", r#"[["127.0.0.1:9090"], []]"#), Err(ConfigError::InvalidField { field, .. }) if field == "[0]"));
+    }
+
+    #[test]
+    fn toml_parses() {
+        let config = Config::from_file(&conf("example.toml")).unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.node_id, "node0");
+        assert_eq!(config.log_format, LogFormat::Compact);
+        assert_eq!(config.peers.len(), 4);
+        assert_eq!(config.peers[3], PeerConfig {
+            id: "node4".to_string(),
+            address: "127.0.0.1:8084".to_string(),
+            public_key: Some("5MipozNmOL+HbJaVkoczMV9hlA2NVQCstoCJmuiECFo=".to_string()),
+        });
+        assert_eq!((config.faults(), config.acks()), (1, 4));
+        assert!(config.ordering.enabled);
+
+        // Omitted sections take their defaults.
+        let config = parse("toml", "node_id = \"a\"\np2p_address = \"127.0.0.1:9090\"\napi_address = \"127.0.0.1:8080\"\n").unwrap();
+        assert!(config.peers.is_empty());
+        assert_eq!(config.tombstone_grace_secs, DEFAULT_TOMBSTONE_GRACE_SECS);
+        assert_eq!(config.health.dead_after, HealthConfig::default().dead_after);
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        let base = "node_id = \"a\"\np2p_address = \"127.0.0.1:9090\"\napi_address = \"127.0.0.1:8080\"\n";
+        assert!(matches!(parse("toml", &format!("{}colour = \"blue\"\n", base)), Err(ConfigError::Parse(..))));
+        assert!(matches!(parse("toml", &format!("{}[health]\ndead_afer = 3\n", base)), Err(ConfigError::Parse(..))));
+        assert!(matches!(parse("toml", &format!("{}[[peers]]\nid = \"b\"\naddress = \"127.0.0.1:8081\"\nkey = \"\"\n", base)), Err(ConfigError::Parse(..))));
+        let json = r#"{"node_id": "a", "p2p_address": "127.0.0.1:9090", "api_address": "127.0.0.1:8080", "peer": []}"#;
+        assert!(matches!(parse("json", json), Err(ConfigError::Parse(..))));
+    }
+
+    #[test]
+    fn validate_names_the_offending_field() {
+        let example = Config::from_file(&conf("example.toml")).unwrap();
+        let check = |field: &str, change: &dyn Fn(&mut Config)| {
+            let mut config = example.clone();
+            change(&mut config);
+            assert_eq!(offending_field(&config), field);
+        };
+        check("p2p_address", &|config| config.p2p_address = "9090".to_string());
+        check("database", &|config| config.database.clear());
+        check("peers[1].address", &|config| config.peers[1].address = "127.0.0.1:http".to_string());
+        check("peers[2].id", &|config| config.peers[2].id = "node1".to_string());
+        check("peers[0].id", &|config| config.peers[0].id = "node0".to_string());
+        check("health.dead_after", &|config| config.health.dead_after = 0);
+        check("peers[3].public_key", &|config| config.peers[3].public_key = Some("c2hvcnQ=".to_string()));
+        check("peers[2].public_key", &|config| config.peers[2].public_key = None);
+        check("admin_keys[0]", &|config| config.admin_keys = vec!["not a key".to_string()]);
+        check("tls", &|config| config.tls.cert = Some("node0.crt".into()));
+        check("ordering.window", &|config| config.ordering.window = 1);
+        check("timeouts.request_ms", &|config| config.timeouts.request_ms = 0);
+        check("tombstone_grace_secs", &|config| config.tombstone_grace_secs = -1);
+        check("quorum.faults", &|config| config.quorum.faults = Some(2));
+        check("quorum.acks", &|config| config.quorum.acks = Some(5));
+
+        // Without ordering, peers need no keys.
+        let mut config = example.clone();
+        config.ordering.enabled = false;
+        config.peers[2].public_key = None;
+        config.validate().unwrap();
+    }
+}
@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tracing::{error, warn};
+
+use crate::config::EvidenceConfig;
+use crate::model::evidence::{Evidence, EvidenceKind};
+use crate::repository::{cipher::to_hex, db::{DbHandle, QuerryError}};
+
+/// Evidence held against one peer.
+#[derive(Serialize)]
+pub struct PeerEvidence {
+    pub peer: String,
+    pub evidence: usize,
+    pub quarantined: bool,
+}
+
+/// Response of `/cluster/evidence`.
+#[derive(Serialize)]
+pub struct EvidenceReport {
+    pub quarantine_after: usize,
+    pub peers: Vec<PeerEvidence>,
+    pub evidence: Vec<Evidence>,
+}
+
+/// Persisted proofs of peer misbehaviour. Peers with `quarantine_after` distinct pieces of
+/// evidence are quarantined: broadcasts skip them and their acks stop counting, while the quorum
+/// stays as configured. Evidence is only recorded from messages the peer signed, so a peer cannot
+/// be framed by forged messages.
+#[derive(Clone)]
+pub struct EvidenceStore {
+    db: Arc<Mutex<DbHandle>>,
+    counts: Arc<RwLock<HashMap<String, usize>>>,
+    quarantine_after: usize,
+}
+
+impl EvidenceStore {
+    /// Loads the evidence counts persisted in `db`.
+    pub fn new(config: &EvidenceConfig, db: Arc<Mutex<DbHandle>>) -> Self {
+        let counts = match db.lock().unwrap().count_evidence() {
+            Ok(counts) => counts.into_iter().collect(),
+            Err(e) => {
+                error!("Could not load the evidence against peers: {}", e);
+                HashMap::new()
+            },
+        };
+        EvidenceStore { db, counts: Arc::new(RwLock::new(counts)), quarantine_after: config.quarantine_after }
+    }
+
+    /// Records evidence against `peer`, quarantining it once it reaches the threshold. The first
+    /// message of `proof` is the peer's offending one; evidence is counted once per such message.
+    pub fn record(&self, peer: &str, kind: EvidenceKind, detail: String, proof: Vec<String>) {
+        let kind_name = serde_json::to_string(&kind).expect("evidence kinds serialize");
+        let offending = proof.first().map(String::as_str).unwrap_or_default();
+        let digest = to_hex(&Sha256::new()
+            .chain_update(peer.as_bytes()).chain_update(b"\n")
+            .chain_update(kind_name.as_bytes()).chain_update(b"\n")
+            .chain_update(offending.as_bytes())
+            .finalize());
+        let evidence = Evidence { peer: peer.to_string(), kind, detail, proof, recorded_at: chrono::Utc::now().timestamp() };
+        match self.db.lock().unwrap().push_evidence(&digest, &evidence) {
+            Ok(true) => (),
+            Ok(false) => return,
+            Err(e) => {
+                error!("Could not record evidence against {}: {}", peer, e);
+                return;
+            },
+        }
+        warn!("Evidence of {:?} against {}: {}", kind, peer, &evidence.detail);
+        let mut counts = self.counts.write().unwrap();
+        let count = counts.entry(peer.to_string()).or_default();
+        *count += 1;
+        if *count == self.quarantine_after {
+            warn!("Quarantining {} after {} pieces of evidence", peer, count);
+        }
+    }
+
+    /// Checks whether the peer with id `peer` is quarantined.
+    pub fn is_quarantined(&self, peer: &str) -> bool {
+        self.counts.read().unwrap().get(peer).is_some_and(|count| *count >= self.quarantine_after)
+    }
+
+    pub fn report(&self) -> Result<EvidenceReport, QuerryError> {
+        let evidence = self.db.lock().unwrap().get_evidence()?;
+        let mut peers: Vec<PeerEvidence> = self.counts.read().unwrap().iter()
+            .map(|(peer, count)| PeerEvidence { peer: peer.clone(), evidence: *count, quarantined: *count >= self.quarantine_after })
+            .collect();
+        peers.sort_by(|a, b| a.peer.cmp(&b.peer));
+        Ok(EvidenceReport { quarantine_after: self.quarantine_after, peers, evidence })
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use futures::future::join_all;
+use serde::Serialize;
+use tokio::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::config::HealthConfig;
+use crate::sender::Cluster;
+
+/// Weight of the newest sample in the mean heartbeat latency.
+const LATENCY_WEIGHT: f64 = 0.2;
+
+/// Liveness of a peer as seen by the failure detector.
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PeerState {
+    #[default]
+    Alive,
+    Suspect,
+    Dead,
+}
+
+/// Heartbeat history of one peer.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct PeerHealth {
+    pub state: PeerState,
+    pub failures: u32, // Consecutive missed heartbeats.
+    pub last_seen: Option<i64>, // Unix time of the last answered heartbeat.
+    pub latency_ms: Option<f64>, // Round trip of the last answered heartbeat.
+    pub mean_latency_ms: Option<f64>, // Exponentially weighted mean round trip.
+    pub max_latency_ms: Option<f64>,
+}
+
+/// Round trips of the replication requests sent to one peer.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct RequestStats {
+    pub requests: u64,
+    pub errors: u64, // Requests that got no answer, timeouts included.
+    pub latency_ms: Option<f64>, // Round trip of the last answered request.
+    pub mean_latency_ms: Option<f64>, // Exponentially weighted mean round trip.
+    pub max_latency_ms: Option<f64>,
+}
+
+/// Per-peer latency of the requests the node sends besides heartbeats.
+#[derive(Clone, Default)]
+pub struct PeerMetrics {
+    peers: Arc<RwLock<HashMap<String, RequestStats>>>,
+}
+
+impl PeerMetrics {
+    /// Returns the request statistics of the peer at `address`.
+    pub fn stats(&self, address: &str) -> RequestStats {
+        self.peers.read().unwrap().get(address).cloned().unwrap_or_default()
+    }
+
+    /// Records a request to `address` that was answered after `latency`, or got no answer.
+    pub fn record(&self, address: &str, latency: Option<Duration>) {
+        let mut peers = self.peers.write().unwrap();
+        let stats = peers.entry(address.to_string()).or_default();
+        stats.requests += 1;
+        let Some(latency) = latency else {
+            stats.errors += 1;
+            return;
+        };
+        let latency_ms = latency.as_secs_f64() * 1000.0;
+        stats.latency_ms = Some(latency_ms);
+        stats.mean_latency_ms = Some(mean(stats.mean_latency_ms, latency_ms));
+        stats.max_latency_ms = Some(stats.max_latency_ms.map_or(latency_ms, |max| max.max(latency_ms)));
+    }
+
+    /// Forgets peers that left the membership.
+    fn retain(&self, addresses: &[String]) {
+        self.peers.write().unwrap().retain(|address, _| addresses.contains(address));
+    }
+}
+
+/// Folds a new sample into an exponentially weighted mean.
+fn mean(mean: Option<f64>, sample: f64) -> f64 {
+    match mean {
+        Some(mean) => mean + LATENCY_WEIGHT * (sample - mean),
+        None => sample,
+    }
+}
+
+/// Tracks peer liveness from heartbeats. Peers start alive until they miss heartbeats.
+#[derive(Clone)]
+pub struct FailureDetector {
+    peers: Arc<RwLock<HashMap<String, PeerHealth>>>,
+    suspect_after: u32,
+    dead_after: u32,
+}
+
+impl FailureDetector {
+    pub fn new(config: &HealthConfig) -> Self {
+        FailureDetector {
+            peers: Arc::new(RwLock::new(HashMap::new())),
+            suspect_after: config.suspect_after,
+            dead_after: config.dead_after,
+        }
+    }
+
+    /// Returns the heartbeat history of the peer at `address`.
+    pub fn health(&self, address: &str) -> PeerHealth {
+        self.peers.read().unwrap().get(address).cloned().unwrap_or_default()
+    }
+
+    /// Checks whether the peer at `address` is considered dead.
+    pub fn is_dead(&self, address: &str) -> bool {
+        self.peers.read().unwrap().get(address).is_some_and(|health| health.state == PeerState::Dead)
+    }
+
+    /// Records an answered heartbeat.
+    pub fn record_success(&self, address: &str, latency: Duration) {
+        let latency_ms = latency.as_secs_f64() * 1000.0;
+        let mut peers = self.peers.write().unwrap();
+        let health = peers.entry(address.to_string()).or_default();
+        if health.state != PeerState::Alive {
+            info!("Peer {} is alive again", address);
+        }
+        health.state = PeerState::Alive;
+        health.failures = 0;
+        health.last_seen = Some(chrono::Utc::now().timestamp());
+        health.latency_ms = Some(latency_ms);
+        health.mean_latency_ms = Some(mean(health.mean_latency_ms, latency_ms));
+        health.max_latency_ms = Some(health.max_latency_ms.map_or(latency_ms, |max| max.max(latency_ms)));
+    }
+
+    /// Records a missed heartbeat, moving the peer to suspect or dead past the thresholds.
+    pub fn record_failure(&self, address: &str) {
+        let mut peers = self.peers.write().unwrap();
+        let health = peers.entry(address.to_string()).or_default();
+        health.failures += 1;
+        let state = if health.failures >= self.dead_after {
+            PeerState::Dead
+        } else if health.failures >= self.suspect_after {
+            PeerState::Suspect
+        } else {
+            health.state
+        };
+        if state != health.state {
+            warn!("Peer {} is {:?} after {} missed heartbeats", address, state, health.failures);
+        }
+        health.state = state;
+    }
+
+    /// Forgets peers that left the membership.
+    fn retain(&self, addresses: &[String]) {
+        self.peers.write().unwrap().retain(|address, _| addresses.contains(address));
+    }
+}
+
+/// Sends heartbeats to every peer of the membership, dead ones included so they can recover.
+pub fn spawn_heartbeats(cluster: Cluster, config: HealthConfig) {
+    let timeout = Duration::from_millis(config.timeout_ms);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_millis(config.interval_ms));
+        loop {
+            interval.tick().await;
+            let addresses = cluster.view().addresses;
+            cluster.detector.retain(&addresses);
+            cluster.metrics.retain(&addresses);
+            let heartbeats = addresses.into_iter().map(|address| {
+                let request = cluster.client.get(cluster.url(&address, "/health")).timeout(timeout).send();
+                let detector = cluster.detector.clone();
+                async move {
+                    let started = Instant::now();
+                    match request.await {
+                        Ok(response) if response.status().is_success() => detector.record_success(&address, started.elapsed()),
+                        _ => detector.record_failure(&address),
+                    }
+                }
+            });
+            join_all(heartbeats).await;
+        }
+    });
+}
@@ -0,0 +1,62 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use base64::{Engine as _, engine::general_purpose};
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use thiserror::Error;
+use tracing::info;
+
+/// Errors for loading or generating the node signing key.
+#[derive(Error, Debug, derive_more::Display)]
+pub enum IdentityError {
+    #[display(fmt = "Could not access node key {}: {}", "_0.display()", _1)]
+    Io(PathBuf, std::io::Error),
+    #[display(fmt = "Node key {} is not a PKCS#8 Ed25519 key", "_0.display()")]
+    InvalidKey(PathBuf),
+}
+
+/// Ed25519 key pair a node signs its own statements with, such as snapshot manifests.
+pub struct NodeIdentity {
+    key_pair: Ed25519KeyPair,
+}
+
+impl NodeIdentity {
+    /// Loads the PKCS#8 key at `path`, generating and saving a new one if the file does not exist.
+    pub fn load_or_generate(path: impl AsRef<Path>) -> Result<Self, IdentityError> {
+        let path = path.as_ref();
+        let io_error = |e| IdentityError::Io(path.to_owned(), e);
+        let pkcs8 = match fs::read(path) {
+            Ok(pkcs8) => pkcs8,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+                    .map_err(|_| IdentityError::InvalidKey(path.to_owned()))?;
+                if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+                    fs::create_dir_all(parent).map_err(io_error)?;
+                }
+                let mut options = fs::OpenOptions::new();
+                options.write(true).create_new(true);
+                #[cfg(unix)]
+                std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+                options.open(path).and_then(|mut file| file.write_all(pkcs8.as_ref())).map_err(io_error)?;
+                info!("Generated node key {}", path.display());
+                pkcs8.as_ref().to_vec()
+            },
+            Err(e) => return Err(io_error(e)),
+        };
+        let key_pair = Ed25519KeyPair::from_pkcs8(&pkcs8).map_err(|_| IdentityError::InvalidKey(path.to_owned()))?;
+        Ok(NodeIdentity { key_pair })
+    }
+
+    /// Returns the base64 public key peers verify this node's signatures with.
+    pub fn public_key(&self) -> String {
+        general_purpose::STANDARD.encode(self.key_pair.public_key().as_ref())
+    }
+
+    /// Signs a message, returning the base64 signature.
+    pub fn sign(&self, message: &[u8]) -> Arc<str> {
+        general_purpose::STANDARD.encode(self.key_pair.sign(message).as_ref()).into()
+    }
+}
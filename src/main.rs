@@ -1,27 +1,50 @@
 pub mod sender;
 
 mod api;
+mod bootstrap;
+mod cli;
+mod config;
+mod evidence;
+mod health;
+mod identity;
+mod membership;
+mod ordering;
 mod repository;
 mod model;
+mod tls;
 
 use std::error::Error;
-use std::fs::File;
-use std::io::Read;
-use std::env;
+use std::process::ExitCode;
 
 
 use std::sync::{Arc, Mutex};
-use actix_web::{web, App, HttpServer};
+use std::time::Duration;
+use actix_web::{middleware::from_fn, web, App, HttpServer};
+use clap::Parser;
 
-use crate::repository::db::DbHandle;
-use tracing::{debug, info};
+use crate::bootstrap::BootstrapProgress;
+use crate::cli::{Cli, Command, ServeArgs};
+use crate::config::{Config, LogFormat};
+use crate::identity::NodeIdentity;
+use crate::membership::Membership;
+use crate::ordering::Ordering;
+use crate::repository::{cipher::SequenceCipher, db::DbHandle};
+use crate::sender::Cluster;
+use tracing::{debug, error, info};
 
 
 use api::dna_sequence::{
     dna,
+    dna_range,
+    dna_stream,
+    dna_changes,
+    dna_vcf,
+    delete_dna_sequence,
     insert_dna_sequence,
+    patch_dna_sequence,
     share_patch,
-    share_dna_sequence
+    share_dna_sequence,
+    share_tombstone,
 };
 
 use api::public_key::{
@@ -29,50 +52,208 @@ use api::public_key::{
     share_public_key
 };
 
+use api::chunk::{get_chunk, get_chunks, share_manifest};
+
+use api::upload::{finalize_upload, start_upload, upload_part};
+
+use api::search::search;
+
+use api::admin::{create_snapshot, get_manifest, stream_snapshot};
+
+use api::status::status;
+
+use api::health::{cluster_evidence, heartbeat, peers};
+
+use api::ordering::{ordering_compare, ordering_log, ordering_message, ordering_request, ordering_status, ordering_view};
+
+use api::membership::{
+    commit_reconfiguration,
+    get_membership,
+    reconfigure,
+    share_reconfiguration,
+};
+
 
 type Db = Arc<Mutex<DbHandle>>;
 
+/// Time idempotency keys of applied replication messages are remembered: 7 days.
+const RECEIVED_RETENTION_SECS: i64 = 7 * 24 * 60 * 60;
+
+/// Time chunks no sequence uses are kept before collection: 1 hour. Peers replicating a
+/// replaced version can still fetch them, and resubmitted content reuses them.
+const CHUNK_RETENTION_SECS: i64 = 60 * 60;
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let result = match cli.command.unwrap_or(Command::Serve(ServeArgs::default())) {
+        Command::Serve(args) => {
+            let config = match Config::load(&cli.overrides) {
+                Ok(config) => config,
+                Err(e) => {
+                    eprintln!("Configuration error: {}", e);
+                    return ExitCode::FAILURE;
+                },
+            };
+            init_tracing(config.log_format, false);
+            serve(config, args)
+        },
+        Command::Maintenance(command) => {
+            // Commands may write their output to stdout, so their logs go to stderr.
+            init_tracing(cli.overrides.log_format.unwrap_or_default(), true);
+            cli::run(command, &cli.overrides)
+        },
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            error!("{}", e);
+            ExitCode::FAILURE
+        },
+    }
+}
+
 #[actix_web::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    init_tracing();
-    //Loading conf files with peer ips
-    let file_name = env::var("FILENAME").unwrap();
-    let mut file = File::open(file_name).unwrap();
-    let mut ips_str = String::new();
-    file.read_to_string(&mut ips_str).unwrap();
-    let json: Vec<Vec<String>> = serde_json::from_str(ips_str.as_ref()).unwrap();
-    let ip_list = json[0].clone();
-    let ip = ip_list[0].clone();
-    let api_ip = ip_list[1].clone();
-    let peers = json[1].clone();
+async fn serve(config: Config, args: ServeArgs) -> Result<(), Box<dyn Error>> {
+    let cipher = SequenceCipher::from_env()?;
+    debug!("Node {} P2P address: {}", &config.node_id, &config.p2p_address);
     //Creating client-side service
-    let db_name = env::var("DATABASE").unwrap();
-    let db: Db = Arc::new(Mutex::new(DbHandle::new(String::from(db_name)).unwrap()));
-    println!("Listening on: {}", &api_ip);
-    let _ = HttpServer::new(move || { 
+    let db: Db = Arc::new(Mutex::new(DbHandle::new(config.database.clone(), cipher)?));
+    spawn_purge(db.clone(), config.tombstone_grace_secs, config.upload.expire_secs);
+    let identity = Arc::new(NodeIdentity::load_or_generate(&config.node_key)?);
+    info!("Node {} signs with public key {}", &config.node_id, identity.public_key());
+    let progress = Arc::new(Mutex::new(BootstrapProgress::default()));
+    if let Some(target) = &args.bootstrap_from {
+        let client = tls::client_builder(&config)?
+            .connect_timeout(Duration::from_millis(config.timeouts.connect_ms))
+            .read_timeout(Duration::from_millis(config.timeouts.request_ms))
+            .build()?;
+        tokio::spawn(bootstrap::bootstrap(
+            db.clone(),
+            progress.clone(),
+            bootstrap::resolve_peer(&config, target),
+            bootstrap::Trust { admin_keys: config.admin_keys.clone(), insecure: args.insecure_bootstrap },
+            client,
+            tls::scheme(&config),
+        ));
+    }
+    let membership = Membership::load(&db.lock().unwrap(), &config)?;
+    if membership.epoch > 0 {
+        info!("Following membership epoch {} from the database instead of the configured peers", membership.epoch);
+    }
+    let cluster = Cluster::new(&config, membership, db.clone(), sender::http_client(&config)?);
+    health::spawn_heartbeats(cluster.clone(), config.health.clone());
+    sender::spawn_delivery(cluster.clone());
+    let ordering = Ordering::new(&config, cluster.clone(), db.clone(), identity.clone())?;
+    ordering.spawn_timer();
+    let api_address = config.api_address.clone();
+    let acceptor = config.tls.is_enabled().then(|| tls::acceptor(&config.tls)).transpose()?;
+    println!("Listening on: {}{}", tls::scheme(&config), &config.api_address);
+    let server = HttpServer::new(move || { 
         let db_handle = web::Data::new(db.clone()); //a struct that represents data
-        let addresses_data = web::Data::new(peers.clone()); 
+        let cluster_data = web::Data::new(cluster.clone()); 
+        let config_data = web::Data::new(config.clone());
+        let identity_data = web::Data::new(identity.clone());
+        let progress_data = web::Data::new(progress.clone());
+        let ordering_data = web::Data::new(ordering.clone());
+        let payload_config = web::PayloadConfig::new(config.upload.part_limit_bytes); // Limits raw upload parts.
+        // Ordered writes and replicated sequences carry the whole sequence, with room for the rest.
+        let json_config = web::JsonConfig::default().limit(config.upload.sequence_limit_bytes + (1 << 20));
         App::new()
+            .wrap(from_fn(api::idempotency::deduplicate))
+            .wrap(from_fn(tls::require_peer_certificate))
             .service(insert_public_key)
             .service(share_public_key)
             .service(insert_dna_sequence)
+            .service(patch_dna_sequence)
             .service(dna)
+            .service(dna_range)
+            .service(dna_stream)
+            .service(dna_changes)
+            .service(dna_vcf)
             .service(share_patch)
             .service(share_dna_sequence)
-            .app_data(addresses_data)
+            .service(delete_dna_sequence)
+            .service(share_tombstone)
+            .service(start_upload)
+            .service(upload_part)
+            .service(finalize_upload)
+            .service(get_chunks)
+            .service(get_chunk)
+            .service(share_manifest)
+            .service(search)
+            .service(create_snapshot)
+            .service(get_manifest)
+            .service(stream_snapshot)
+            .service(status)
+            .service(reconfigure)
+            .service(share_reconfiguration)
+            .service(commit_reconfiguration)
+            .service(get_membership)
+            .service(heartbeat)
+            .service(peers)
+            .service(cluster_evidence)
+            .service(ordering_message)
+            .service(ordering_request)
+            .service(ordering_log)
+            .service(ordering_status)
+            .service(ordering_compare)
+            .service(ordering_view)
+            .app_data(cluster_data)
+            .app_data(config_data)
+            .app_data(identity_data)
+            .app_data(progress_data)
+            .app_data(ordering_data)
             .app_data(db_handle) 
+            .app_data(payload_config)
+            .app_data(json_config)
     })
-        .bind(api_ip)?
-        .run()
-        .await;
+        .on_connect(tls::on_connect);
+    let server = match acceptor {
+        Some(acceptor) => server.bind_openssl(&api_address, acceptor)?,
+        None => server.bind_auto_h2c(&api_address)?,
+    };
+    let _ = server.run().await;
     Ok(())
 }
 
 
-pub fn init_tracing() {
+/// Periodically purges tombstones older than the grace period, forgotten idempotency keys,
+/// expired uploads and chunks no sequence uses anymore.
+fn spawn_purge(db: Db, grace_secs: i64, upload_expire_secs: i64) {
+    let period = Duration::from_secs(grace_secs.clamp(1, 3600) as u64);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            let now = chrono::Utc::now().timestamp();
+            let db = db.lock().unwrap();
+            match db.purge_tombstones(now - grace_secs) {
+                Ok(0) => (),
+                Ok(n) => info!("Purged {} tombstones", n),
+                Err(e) => error!("Tombstone purge failed: {}", e),
+            }
+            if let Err(e) = db.purge_received(now - RECEIVED_RETENTION_SECS) {
+                error!("Idempotency key purge failed: {}", e);
+            }
+            match db.purge_uploads(now - upload_expire_secs) {
+                Ok(0) => (),
+                Ok(n) => info!("Dropped {} expired uploads", n),
+                Err(e) => error!("Upload purge failed: {}", e),
+            }
+            match db.collect_chunks(now - CHUNK_RETENTION_SECS) {
+                Ok(0) => (),
+                Ok(n) => info!("Collected {} unused chunks", n),
+                Err(e) => error!("Chunk collection failed: {}", e),
+            }
+        }
+    });
+}
+
+pub fn init_tracing(log_format: LogFormat, to_stderr: bool) {
     use tracing::level_filters::LevelFilter;
     use tracing_subscriber::prelude::*;
     use tracing_subscriber::EnvFilter;
+    use tracing_subscriber::fmt::writer::BoxMakeWriter;
 
     let env = EnvFilter::builder()
         .with_default_directive(LevelFilter::DEBUG.into())
@@ -80,11 +261,15 @@ pub fn init_tracing() {
         .from_env_lossy();
 
     let fmt_layer = tracing_subscriber::fmt::layer()
-        .compact()
         .with_file(true)
         .with_line_number(true)
         .with_thread_ids(false)
-        .with_target(false);
+        .with_target(false)
+        .with_writer(if to_stderr { BoxMakeWriter::new(std::io::stderr) } else { BoxMakeWriter::new(std::io::stdout) });
+    let fmt_layer = match log_format {
+        LogFormat::Compact => fmt_layer.compact().boxed(),
+        LogFormat::Json => fmt_layer.json().boxed(),
+    };
     tracing_subscriber::registry()
         .with(fmt_layer)
         .with(env)
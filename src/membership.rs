@@ -0,0 +1,471 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+
+use crate::config::{check_peers, Config, ConfigError, PeerConfig, QuorumConfig};
+use crate::identity::NodeIdentity;
+use crate::model::public_key::PublicKey;
+use crate::repository::db::{DbHandle, QuerryError};
+
+/// Key of the persisted membership in the metadata table.
+pub const MEMBERSHIP_KEY: &str = "membership";
+
+/// Errors for membership changes.
+#[derive(Error, Debug, derive_more::Display)]
+pub enum MembershipError {
+    #[display(fmt = "Reconfiguration is not signed by an admin key")]
+    Unauthorized,
+    #[display(fmt = "Epoch {} is not newer than the current epoch {}", _0, _1)]
+    StaleEpoch(u64, u64),
+    #[display(fmt = "A different reconfiguration to epoch {} is already in progress", _0)]
+    Conflicting(u64),
+    Invalid(ConfigError),
+    #[display(fmt = "Commit to epoch {} is not backed by a joint quorum: {}", _0, _1)]
+    Unproven(u64, String),
+    #[display(fmt = "Persisted membership is corrupt: {}", _0)]
+    Corrupt(serde_json::Error),
+    Unreadable(QuerryError),
+}
+
+/// A cluster configuration: every member, this node included, and its quorum parameters.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MemberSet {
+    pub members: Vec<PeerConfig>,
+    #[serde(default)]
+    pub quorum: QuorumConfig,
+}
+
+impl MemberSet {
+    /// Checks member ids, addresses and keys, and that `n >= 3f + 1` holds.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.members.is_empty() {
+            return Err(crate::config::invalid("members", "must not be empty"));
+        }
+        check_peers("members", &self.members, HashSet::new())?;
+        self.quorum.validate("quorum", self.members.len())
+    }
+
+    /// Returns the API addresses of every member except `node_id`.
+    fn peer_addresses(&self, node_id: &str) -> Vec<String> {
+        self.members.iter()
+            .filter(|member| member.id != node_id)
+            .map(|member| member.address.clone())
+            .collect()
+    }
+
+    /// Returns the peer acks a broadcast from `node_id` needs within this set.
+    fn acks(&self, node_id: &str) -> usize {
+        self.quorum.acks(self.members.len()).min(self.peer_addresses(node_id).len())
+    }
+
+    fn member(&self, id: &str) -> Option<&PeerConfig> {
+        self.members.iter().find(|member| member.id == id)
+    }
+
+    /// Checks both sets have the same member ids at the same addresses. Keys may differ, as a
+    /// node's own entry in its configuration has none.
+    pub fn same_members(&self, other: &MemberSet) -> bool {
+        let pairs = |set: &MemberSet| set.members.iter()
+            .map(|member| (member.id.clone(), member.address.clone()))
+            .collect::<HashSet<_>>();
+        self.members.len() == other.members.len() && pairs(self) == pairs(other)
+    }
+}
+
+/// An admin-signed request to replace the cluster membership.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Reconfiguration {
+    pub epoch: u64, // Epoch the new membership takes effect in, greater than the current one.
+    #[serde(flatten)]
+    pub member_set: MemberSet,
+    pub signature: Arc<str>, // Admin signature over `message()`.
+}
+
+impl Reconfiguration {
+    /// Returns the message an admin signs:
+    /// `reconfigure:{epoch}:{faults}:{acks}:{id}={address}={public_key},...`, with unset values empty.
+    pub fn message(&self) -> Arc<str> {
+        let optional = |value: Option<usize>| value.map(|value| value.to_string()).unwrap_or_default();
+        let members: Vec<String> = self.member_set.members.iter()
+            .map(|member| format!("{}={}={}", member.id, member.address, member.public_key.as_deref().unwrap_or("")))
+            .collect();
+        format!(
+            "reconfigure:{}:{}:{}:{}",
+            self.epoch,
+            optional(self.member_set.quorum.faults),
+            optional(self.member_set.quorum.acks),
+            members.join(","),
+        ).into()
+    }
+
+    /// Checks the request is signed by one of `admin_keys` and describes a valid membership.
+    pub fn verify(&self, admin_keys: &[String]) -> Result<(), MembershipError> {
+        let signed = admin_keys.iter().any(|key| {
+            PublicKey::from_raw("admin".to_string(), key.clone())
+                .is_ok_and(|key| PublicKey::check_signature(self.signature.clone(), key, self.message()).is_ok())
+        });
+        if !signed {
+            return Err(MembershipError::Unauthorized);
+        }
+        self.member_set.validate().map_err(MembershipError::Invalid)
+    }
+
+    /// Returns the hex SHA-256 of `message()`, binding acks and commits to this exact request.
+    pub fn digest(&self) -> String {
+        envelope::sha256_hex(self.message().as_bytes())
+    }
+}
+
+/// A member's signed statement that it entered the joint configuration of a reconfiguration.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct JointAck {
+    pub node: String, // Id of the acknowledging member.
+    pub epoch: u64,
+    pub signature: Arc<str>, // Node signature over `message()`.
+}
+
+impl JointAck {
+    /// Signs the ack of `reconfiguration` by `node`.
+    pub fn new(node: String, reconfiguration: &Reconfiguration, identity: &NodeIdentity) -> Self {
+        let signature = identity.sign(Self::message(&node, reconfiguration).as_bytes());
+        JointAck { node, epoch: reconfiguration.epoch, signature }
+    }
+
+    /// Returns the message a member signs: `joint:{node}:{epoch}:{digest}`.
+    pub fn message(node: &str, reconfiguration: &Reconfiguration) -> String {
+        format!("joint:{}:{}:{}", node, reconfiguration.epoch, reconfiguration.digest())
+    }
+}
+
+/// Proof, signed by the coordinating node, that a quorum of both the old and the new members
+/// entered the joint configuration of `reconfiguration`. Nodes only switch memberships on it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ReconfigurationCommit {
+    pub reconfiguration: Reconfiguration,
+    pub previous: MemberSet, // Membership the joint configuration started from.
+    pub coordinator: String, // Id of the node that collected the acks.
+    pub acks: Vec<JointAck>,
+    pub signature: Arc<str>, // Coordinator's node signature over `message()`.
+}
+
+impl ReconfigurationCommit {
+    /// Builds and signs the commit of `reconfiguration` by `coordinator` from the acks it collected.
+    pub fn new(reconfiguration: Reconfiguration, previous: MemberSet, coordinator: String, acks: Vec<JointAck>, identity: &NodeIdentity) -> Self {
+        let mut commit = ReconfigurationCommit { reconfiguration, previous, coordinator, acks, signature: "".into() };
+        commit.signature = identity.sign(commit.message().as_bytes());
+        commit
+    }
+
+    /// Returns the message the coordinator signs:
+    /// `commit:{coordinator}:{epoch}:{digest}:{previous digest}:{ack},...` with every ack as
+    /// `{node}={signature}`.
+    pub fn message(&self) -> String {
+        let previous = serde_json::to_string(&self.previous).expect("member sets serialize");
+        let acks: Vec<String> = self.acks.iter().map(|ack| format!("{}={}", ack.node, ack.signature)).collect();
+        format!(
+            "commit:{}:{}:{}:{}:{}",
+            self.coordinator,
+            self.reconfiguration.epoch,
+            self.reconfiguration.digest(),
+            envelope::sha256_hex(previous.as_bytes()),
+            acks.join(","),
+        )
+    }
+
+    /// Checks the reconfiguration is admin-signed, the commit is signed by a member of either
+    /// set, and the acks hold a quorum of each set besides the coordinator. Keys are taken from
+    /// `known`, the verifying node's membership, before the commit's sets; the verifying node
+    /// `node_id` is checked against `identity`. Members without any public key are refused.
+    pub fn verify(&self, admin_keys: &[String], known: &MemberSet, node_id: &str, identity: &NodeIdentity) -> Result<(), MembershipError> {
+        self.reconfiguration.verify(admin_keys)?;
+        let epoch = self.reconfiguration.epoch;
+        let unproven = |reason: String| MembershipError::Unproven(epoch, reason);
+        let new = &self.reconfiguration.member_set;
+        let check = |id: &str, signature: Arc<str>, message: String| {
+            if self.previous.member(id).or_else(|| new.member(id)).is_none() {
+                return Err(unproven(format!("{} is not a member", id)));
+            }
+            let key = match id == node_id {
+                true => Some(identity.public_key()),
+                false => [known, new, &self.previous].into_iter().find_map(|set| set.member(id)?.public_key.clone()),
+            };
+            let Some(key) = key else {
+                return Err(unproven(format!("{} has no public key", id)));
+            };
+            let key = PublicKey::from_raw(id.to_string(), key).map_err(|_| unproven(format!("{} has a malformed public key", id)))?;
+            PublicKey::check_signature(signature, key, message.into()).map_err(|_| unproven(format!("bad signature from {}", id)))
+        };
+        check(&self.coordinator, self.signature.clone(), self.message())?;
+        let mut acked = HashSet::new();
+        for ack in &self.acks {
+            if ack.epoch != epoch {
+                return Err(unproven(format!("ack of {} is for epoch {}", ack.node, ack.epoch)));
+            }
+            check(&ack.node, ack.signature.clone(), JointAck::message(&ack.node, &self.reconfiguration))?;
+            if ack.node != self.coordinator {
+                acked.insert(ack.node.as_str());
+            }
+        }
+        for set in [&self.previous, new] {
+            let acks = set.members.iter().filter(|member| acked.contains(member.id.as_str())).count();
+            if acks < set.acks(&self.coordinator) {
+                return Err(unproven(format!("{} of {} required acks", acks, set.acks(&self.coordinator))));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Addresses a broadcast goes to and the acks it waits for.
+pub struct ClusterView {
+    pub addresses: Vec<String>,
+    pub quorum: u32,
+}
+
+/// The membership a node currently follows. While `pending` is set the cluster is in a joint
+/// configuration: broadcasts go to both member sets and wait for enough acks to hold a quorum in each.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Membership {
+    pub epoch: u64, // 0 for the configuration file, then the epoch of the last committed change.
+    pub current: MemberSet,
+    pub pending: Option<Reconfiguration>,
+}
+
+impl Membership {
+    /// Describes the membership in the configuration file.
+    pub fn from_config(config: &Config) -> Self {
+        let this = PeerConfig { id: config.node_id.clone(), address: config.api_address.clone(), public_key: None };
+        Membership {
+            epoch: 0,
+            current: MemberSet {
+                members: std::iter::once(this).chain(config.peers.iter().cloned()).collect(),
+                quorum: config.quorum.clone(),
+            },
+            pending: None,
+        }
+    }
+
+    /// Loads the membership persisted in the database, falling back to the configuration file
+    /// when none was persisted. A persisted membership that does not parse is an error.
+    pub fn load(db: &DbHandle, config: &Config) -> Result<Self, MembershipError> {
+        match db.get_meta(MEMBERSHIP_KEY).map_err(|e| MembershipError::Unreadable(e.into()))? {
+            Some(json) => serde_json::from_str(&json).map_err(MembershipError::Corrupt),
+            None => Ok(Self::from_config(config)),
+        }
+    }
+
+    /// Saves the membership so it survives restarts.
+    pub fn persist(&self, db: &DbHandle) -> Result<(), QuerryError> {
+        let json = serde_json::to_string(self).expect("membership serializes");
+        Ok(db.set_meta(MEMBERSHIP_KEY, &json)?)
+    }
+
+    /// Returns where a broadcast from `node_id` goes and how many acks it needs. In a joint
+    /// configuration `k` acks from the union of both sets must include a quorum of each, so
+    /// `k` covers every peer outside a set plus that set's quorum.
+    pub fn view(&self, node_id: &str) -> ClusterView {
+        let Some(pending) = &self.pending else {
+            return ClusterView {
+                addresses: self.current.peer_addresses(node_id),
+                quorum: self.current.acks(node_id) as u32,
+            };
+        };
+        let old: HashSet<String> = self.current.peer_addresses(node_id).into_iter().collect();
+        let new: HashSet<String> = pending.member_set.peer_addresses(node_id).into_iter().collect();
+        let union: Vec<String> = old.union(&new).cloned().collect();
+        let quorum = (union.len() - old.len() + self.current.acks(node_id))
+            .max(union.len() - new.len() + pending.member_set.acks(node_id))
+            .min(union.len());
+        ClusterView { addresses: union, quorum: quorum as u32 }
+    }
+
+    /// Enters the joint configuration of a verified reconfiguration. Returns false if the node
+    /// is already in it or has committed it.
+    pub fn begin(&mut self, reconfiguration: &Reconfiguration) -> Result<bool, MembershipError> {
+        if reconfiguration.epoch <= self.epoch {
+            if reconfiguration.epoch == self.epoch && reconfiguration.member_set == self.current {
+                return Ok(false);
+            }
+            return Err(MembershipError::StaleEpoch(reconfiguration.epoch, self.epoch));
+        }
+        if let Some(pending) = &self.pending {
+            if pending.epoch == reconfiguration.epoch {
+                if pending.member_set == reconfiguration.member_set {
+                    return Ok(false);
+                }
+                return Err(MembershipError::Conflicting(pending.epoch));
+            }
+            if pending.epoch > reconfiguration.epoch {
+                return Err(MembershipError::StaleEpoch(reconfiguration.epoch, pending.epoch));
+            }
+        }
+        self.pending = Some(reconfiguration.clone());
+        Ok(true)
+    }
+
+    /// Leaves the joint configuration for the new membership. Returns false if already committed.
+    pub fn commit(&mut self, reconfiguration: &Reconfiguration) -> Result<bool, MembershipError> {
+        if reconfiguration.epoch <= self.epoch {
+            if reconfiguration.epoch == self.epoch && reconfiguration.member_set == self.current {
+                return Ok(false);
+            }
+            return Err(MembershipError::StaleEpoch(reconfiguration.epoch, self.epoch));
+        }
+        if let Some(pending) = &self.pending {
+            if pending.epoch == reconfiguration.epoch && pending.member_set != reconfiguration.member_set {
+                return Err(MembershipError::Conflicting(pending.epoch));
+            }
+        }
+        self.epoch = reconfiguration.epoch;
+        self.current = reconfiguration.member_set.clone();
+        self.pending = None;
+        Ok(true)
+    }
+
+    /// Checks whether `node_id` belongs to the current membership.
+    pub fn is_member(&self, node_id: &str) -> bool {
+        self.current.members.iter().any(|member| member.id == node_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn identity() -> NodeIdentity {
+        let path = std::env::temp_dir().join(format!("repyh-test-{}.key", uuid::Uuid::new_v4()));
+        let identity = NodeIdentity::load_or_generate(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        identity
+    }
+
+    struct Nodes {
+        admin: NodeIdentity,
+        nodes: HashMap<String, NodeIdentity>,
+    }
+
+    impl Nodes {
+        fn new(ids: &str) -> Self {
+            Nodes { admin: identity(), nodes: ids.chars().map(|id| (id.to_string(), identity())).collect() }
+        }
+
+        /// Returns the set of members `ids`, one character per member.
+        fn set(&self, ids: &str) -> MemberSet {
+            let members = ids.chars().map(|id| PeerConfig {
+                id: id.to_string(),
+                address: format!("{}:8080", id),
+                public_key: Some(self.nodes[&id.to_string()].public_key()),
+            }).collect();
+            MemberSet { members, quorum: QuorumConfig::default() }
+        }
+
+        fn reconfiguration(&self, epoch: u64, ids: &str) -> Reconfiguration {
+            let mut reconfiguration = Reconfiguration { epoch, member_set: self.set(ids), signature: "".into() };
+            reconfiguration.signature = self.admin.sign(reconfiguration.message().as_bytes());
+            reconfiguration
+        }
+
+        fn commit(&self, reconfiguration: &Reconfiguration, previous: &str, coordinator: &str, acked: &str) -> ReconfigurationCommit {
+            let acks = acked.chars().map(|id| JointAck::new(id.to_string(), reconfiguration, &self.nodes[&id.to_string()])).collect();
+            ReconfigurationCommit::new(reconfiguration.clone(), self.set(previous), coordinator.to_string(), acks, &self.nodes[coordinator])
+        }
+
+        /// Verifies a commit as node `b`, a member of the previous set.
+        fn verify(&self, commit: &ReconfigurationCommit) -> Result<(), MembershipError> {
+            commit.verify(&[self.admin.public_key()], &commit.previous, "b", &self.nodes["b"])
+        }
+    }
+
+    #[test]
+    fn commit_needs_a_quorum_of_both_sets_besides_the_coordinator() {
+        let nodes = Nodes::new("abcde");
+        let reconfiguration = nodes.reconfiguration(1, "abcde");
+        // Three of the three old peers, and four of the four new ones.
+        assert!(nodes.verify(&nodes.commit(&reconfiguration, "abcd", "a", "bcde")).is_ok());
+        assert!(nodes.verify(&nodes.commit(&reconfiguration, "abcd", "a", "abcde")).is_ok());
+        for acked in ["abcd", "bcd", "bce", ""] {
+            let commit = nodes.commit(&reconfiguration, "abcd", "a", acked);
+            assert!(matches!(nodes.verify(&commit), Err(MembershipError::Unproven(1, _))), "acks {}", acked);
+        }
+    }
+
+    #[test]
+    fn commit_rejects_acks_of_another_reconfiguration() {
+        let nodes = Nodes::new("abcde");
+        let reconfiguration = nodes.reconfiguration(2, "abcde");
+        let mut commit = nodes.commit(&reconfiguration, "abcd", "a", "bcde");
+        // Acks given to an earlier request for the same members do not carry over.
+        let earlier = nodes.reconfiguration(1, "abcde");
+        commit.acks[3] = JointAck::new("e".to_string(), &earlier, &nodes.nodes["e"]);
+        let commit = ReconfigurationCommit::new(reconfiguration, commit.previous, "a".to_string(), commit.acks, &nodes.nodes["a"]);
+        assert!(matches!(nodes.verify(&commit), Err(MembershipError::Unproven(2, _))));
+    }
+
+    #[test]
+    fn commit_must_be_signed_by_a_member_coordinator() {
+        let nodes = Nodes::new("abcdex");
+        let reconfiguration = nodes.reconfiguration(1, "abcde");
+        let mut commit = nodes.commit(&reconfiguration, "abcd", "a", "bcde");
+        commit.signature = nodes.nodes["b"].sign(commit.message().as_bytes());
+        assert!(matches!(nodes.verify(&commit), Err(MembershipError::Unproven(..))));
+        let outsider = nodes.commit(&reconfiguration, "abcd", "x", "bcde");
+        assert!(matches!(nodes.verify(&outsider), Err(MembershipError::Unproven(..))));
+    }
+
+    #[test]
+    fn commit_refuses_members_without_a_key() {
+        let nodes = Nodes::new("abcde");
+        let mut reconfiguration = nodes.reconfiguration(1, "abcde");
+        reconfiguration.member_set.members[4].public_key = None;
+        reconfiguration.signature = nodes.admin.sign(reconfiguration.message().as_bytes());
+        // Without a key for e, its ack could have been made up by the coordinator.
+        let commit = nodes.commit(&reconfiguration, "abcd", "a", "bcde");
+        assert!(matches!(nodes.verify(&commit), Err(MembershipError::Unproven(1, reason)) if reason == "e has no public key"));
+    }
+
+    #[test]
+    fn commit_of_an_unsigned_reconfiguration_is_unauthorized() {
+        let nodes = Nodes::new("abcde");
+        let mut reconfiguration = nodes.reconfiguration(1, "abcde");
+        reconfiguration.signature = nodes.nodes["a"].sign(reconfiguration.message().as_bytes());
+        let commit = nodes.commit(&reconfiguration, "abcd", "a", "bcde");
+        assert!(matches!(nodes.verify(&commit), Err(MembershipError::Unauthorized)));
+    }
+
+    fn membership(nodes: &Nodes, current: &str, pending: Option<&str>) -> Membership {
+        Membership { epoch: 0, current: nodes.set(current), pending: pending.map(|ids| nodes.reconfiguration(1, ids)) }
+    }
+
+    #[test]
+    fn joint_view_waits_for_a_quorum_of_each_set() {
+        let nodes = Nodes::new("abcdefghij");
+        let view = membership(&nodes, "abcd", None).view("a");
+        assert_eq!((view.addresses.len(), view.quorum), (3, 3));
+
+        // Every 4 acks among b, c, d and e hold 3 old and 4 new peers.
+        let view = membership(&nodes, "abcd", Some("abcde")).view("a");
+        assert_eq!((view.addresses.len(), view.quorum), (4, 4));
+
+        // 8 of the 9 peers always hold 5 of the 6 old and 5 of the 6 new peers.
+        let view = membership(&nodes, "abcdefg", Some("abcdhij")).view("a");
+        assert_eq!((view.addresses.len(), view.quorum), (9, 8));
+    }
+
+    #[test]
+    fn begin_and_commit_move_through_epochs_once() {
+        let nodes = Nodes::new("abcde");
+        let mut membership = membership(&nodes, "abcd", None);
+        let reconfiguration = nodes.reconfiguration(1, "abcde");
+        assert!(membership.begin(&reconfiguration).unwrap());
+        assert!(!membership.begin(&reconfiguration).unwrap());
+        assert!(matches!(membership.begin(&nodes.reconfiguration(1, "abc")), Err(MembershipError::Conflicting(1))));
+        assert!(membership.commit(&reconfiguration).unwrap());
+        assert!(!membership.commit(&reconfiguration).unwrap());
+        assert_eq!((membership.epoch, membership.current.members.len()), (1, 5));
+        assert!(membership.pending.is_none());
+        assert!(matches!(membership.begin(&nodes.reconfiguration(1, "abc")), Err(MembershipError::StaleEpoch(1, 1))));
+    }
+}
@@ -1,12 +1,37 @@
 use std::sync::Arc;
 use serde::{Serialize, Deserialize};
 use std::fmt::{self, Display};
+use thiserror::Error;
+use envelope::SequenceEnvelope;
+
+use crate::model::public_key::{PublicKey, WrongSignatureError};
 
 /// Structure representing a DNA sequence.
 #[derive(Serialize, Deserialize, Clone)]
 pub struct DnaSequence {
     pub id: Arc<str>, // Unique identifier for the DNA sequence.
-    pub dna_sequence: Arc<str>, // The DNA sequence data.
+    pub dna_sequence: Arc<str>, // The DNA sequence data, or client-side ciphertext when `encrypted`.
+    #[serde(default)]
+    pub signature: Option<Arc<str>>, // Owner signature over `envelope()`, or over `dna_sequence` when `counter` is 0.
+    #[serde(default)]
+    pub encrypted: bool, // Whether `dna_sequence` is an opaque client-encrypted blob.
+    #[serde(default)]
+    pub counter: u64, // Owner's write counter from the signed envelope.
+}
+
+/// Error for sequences containing characters that are not IUPAC nucleotide codes.
+#[derive(Error, Debug, derive_more::Display)]
+#[display(fmt = "Invalid nucleotide '{}' at position {}", _0, _1)]
+pub struct InvalidSequenceError(pub char, pub usize);
+
+/// Strand a subsequence is read from.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Strand {
+    #[default]
+    #[serde(rename = "+", alias = "plus")]
+    Plus,
+    #[serde(rename = "-", alias = "minus")]
+    Minus,
 }
 
 impl Display for DnaSequence {
@@ -22,7 +47,72 @@ impl DnaSequence {
         DnaSequence {
             id,
             dna_sequence,
+            signature: None,
+            encrypted: false,
+            counter: 0,
+        }
+    }
+
+    /// Creates a DNA sequence carrying its owner's signature.
+    pub fn signed(id: Arc<str>, dna_sequence: Arc<str>, signature: Arc<str>, encrypted: bool, counter: u64) -> Self {
+        DnaSequence {
+            id,
+            dna_sequence,
+            signature: Some(signature),
+            encrypted,
+            counter,
+        }
+    }
+
+    /// Returns the version clients state as the base of an update: the hex SHA-256 of the
+    /// sequence as stored, ciphertext for client-encrypted sequences.
+    pub fn version(&self) -> String {
+        envelope::sha256_hex(self.dna_sequence.as_bytes())
+    }
+
+    /// Returns the envelope the owner signs: the version of the sequence, its id and counter.
+    pub fn envelope(&self) -> SequenceEnvelope {
+        SequenceEnvelope::from_digest(self.id.as_ref(), self.version(), self.counter)
+    }
+
+    /// Verifies `signature` against the owner's key, over the envelope or, for sequences
+    /// without a counter, over the sequence itself.
+    pub fn check_signature(&self, signature: Arc<str>, public_key: PublicKey) -> Result<(), WrongSignatureError> {
+        PublicKey::check_signature(signature.clone(), public_key.clone(), self.envelope().canonical().into())
+            .or_else(|e| match self.counter {
+                0 => PublicKey::check_signature(signature, public_key, self.dna_sequence.clone()),
+                _ => Err(e),
+            })
+    }
+
+    /// Checks that a plaintext sequence only contains IUPAC nucleotide codes or gaps.
+    /// Client-encrypted sequences are opaque and always pass.
+    pub fn validate(&self) -> Result<(), InvalidSequenceError> {
+        if self.encrypted {
+            return Ok(());
+        }
+        match self.dna_sequence.chars().enumerate()
+            .find(|(_, c)| !"ACGTURYSWKMBDHVN-.".contains(c.to_ascii_uppercase())) {
+            Some((position, c)) => Err(InvalidSequenceError(c, position)),
+            None => Ok(()),
         }
     }
 }
 
+/// Returns the complement of a single IUPAC nucleotide code, preserving case.
+fn complement(base: char) -> char {
+    match base {
+        'A' => 'T', 'T' => 'A', 'U' => 'A', 'G' => 'C', 'C' => 'G',
+        'R' => 'Y', 'Y' => 'R', 'K' => 'M', 'M' => 'K',
+        'B' => 'V', 'V' => 'B', 'D' => 'H', 'H' => 'D',
+        'a' => 't', 't' => 'a', 'u' => 'a', 'g' => 'c', 'c' => 'g',
+        'r' => 'y', 'y' => 'r', 'k' => 'm', 'm' => 'k',
+        'b' => 'v', 'v' => 'b', 'd' => 'h', 'h' => 'd',
+        other => other, // S, W, N and gaps are their own complement.
+    }
+}
+
+/// Returns the reverse complement of a nucleotide sequence.
+pub fn reverse_complement(sequence: &str) -> String {
+    sequence.chars().rev().map(complement).collect()
+}
@@ -0,0 +1,21 @@
+use serde::{Serialize, Deserialize};
+
+/// Misbehaviour a piece of evidence proves.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EvidenceKind {
+    Equivocation, // Two signed messages for different operations at the same position.
+    ForgedOperation, // A primary proposed an operation its origin did not sign.
+    InvalidSignature, // A signed message relays messages whose signatures do not check out.
+    ConflictingCheckpoint, // A signed checkpoint disagrees with the state a quorum agreed on.
+}
+
+/// Proof that a peer misbehaved: messages it signed that no correct node would send.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Evidence {
+    pub peer: String, // Id of the peer the evidence is against.
+    pub kind: EvidenceKind,
+    pub detail: String,
+    pub proof: Vec<String>, // JSON signed messages showing the misbehaviour; anyone holding the peer's key can check them.
+    pub recorded_at: i64,
+}
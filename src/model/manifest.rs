@@ -0,0 +1,44 @@
+use std::sync::Arc;
+use serde::{Serialize, Deserialize};
+
+use crate::repository::chunk::ChunkRef;
+
+/// Chunk list of a replicated sequence, sent instead of its text so a peer fetches from
+/// `source` only the chunks it does not hold.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SequenceManifest {
+    pub id: Arc<str>,
+    pub chunks: Vec<ChunkRef>,
+    pub length: usize,
+    pub signature: Arc<str>,
+    pub encrypted: bool,
+    pub counter: u64,
+    pub source: String, // API address of the node the chunks are fetched from.
+}
+
+impl SequenceManifest {
+    /// Checks the chunks cover `[0, length)` in order without gaps.
+    pub fn is_contiguous(&self) -> bool {
+        let mut offset = 0;
+        for chunk in &self.chunks {
+            if chunk.offset != offset || chunk.length == 0 {
+                return false;
+            }
+            offset += chunk.length;
+        }
+        offset == self.length
+    }
+}
+
+/// Request for the chunks a peer lacks.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ChunkRequest {
+    pub hashes: Vec<String>,
+}
+
+/// A chunk sent to a peer.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ChunkData {
+    pub hash: String,
+    pub data: String,
+}
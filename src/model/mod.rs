@@ -1,4 +1,12 @@
 pub mod dna_sequence;
 pub mod public_key;
 pub mod patch;
+pub mod variant;
+pub mod manifest;
 
+pub mod motif;
+pub mod tombstone;
+pub mod record;
+pub mod outbox;
+pub mod ordered;
+pub mod evidence;
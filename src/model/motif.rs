@@ -0,0 +1,234 @@
+use std::sync::Arc;
+use serde::Deserialize;
+use regex::{Regex, RegexBuilder};
+use thiserror::Error;
+
+use crate::model::dna_sequence::{reverse_complement, Strand};
+
+/// Maximum number of concrete k-mers an ambiguous window may expand to
+/// before the index is bypassed in favour of a full scan.
+const MAX_KMER_EXPANSIONS: usize = 256;
+
+/// Kind of pattern accepted by the motif search.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MotifKind {
+    Exact,
+    Iupac,
+    Regex,
+}
+
+/// Errors for patterns that cannot be compiled into a motif.
+#[derive(Error, Debug, derive_more::Display)]
+pub enum MotifError {
+    #[display(fmt = "Empty search pattern")]
+    EmptyPattern,
+    #[display(fmt = "Invalid IUPAC code '{}' in pattern", _0)]
+    InvalidIupacCode(char),
+    #[display(fmt = "Invalid regular expression: {}", _0)]
+    InvalidRegex(regex::Error),
+}
+
+/// A compiled search pattern matched against both strands of a sequence.
+pub struct Motif {
+    pub kind: MotifKind,
+    pub pattern: Arc<str>,
+    regex: Regex,
+}
+
+/// A motif occurrence. `position` is the 0-based start on the plus strand.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MotifMatch {
+    pub position: usize,
+    pub length: usize,
+    pub strand: Strand,
+}
+
+/// Returns the concrete bases an IUPAC code stands for.
+fn iupac_bases(code: char) -> Option<&'static str> {
+    match code.to_ascii_uppercase() {
+        'A' => Some("A"),
+        'C' => Some("C"),
+        'G' => Some("G"),
+        'T' | 'U' => Some("T"),
+        'R' => Some("AG"),
+        'Y' => Some("CT"),
+        'S' => Some("CG"),
+        'W' => Some("AT"),
+        'K' => Some("GT"),
+        'M' => Some("AC"),
+        'B' => Some("CGT"),
+        'D' => Some("AGT"),
+        'H' => Some("ACT"),
+        'V' => Some("ACG"),
+        'N' => Some("ACGT"),
+        _ => None,
+    }
+}
+
+impl Motif {
+    /// Compiles a pattern of the given kind.
+    pub fn new(kind: MotifKind, pattern: Arc<str>) -> Result<Self, MotifError> {
+        if pattern.is_empty() {
+            return Err(MotifError::EmptyPattern);
+        }
+        let (expression, case_insensitive) = match kind {
+            MotifKind::Exact => {
+                let mut expression = String::new();
+                for code in pattern.chars() {
+                    match code.to_ascii_uppercase() {
+                        'T' | 'U' => expression.push_str("[TU]"),
+                        _ => expression.push_str(&regex::escape(code.encode_utf8(&mut [0; 4]))),
+                    }
+                }
+                (expression, true)
+            },
+            MotifKind::Iupac => {
+                let mut expression = String::new();
+                for code in pattern.chars() {
+                    let bases = iupac_bases(code).ok_or(MotifError::InvalidIupacCode(code))?;
+                    expression.push('[');
+                    expression.push_str(bases);
+                    if bases.contains('T') {
+                        expression.push('U');
+                    }
+                    expression.push(']');
+                }
+                (expression, true)
+            },
+            MotifKind::Regex => (pattern.to_string(), false),
+        };
+        let regex = RegexBuilder::new(&expression)
+            .case_insensitive(case_insensitive)
+            .build()
+            .map_err(MotifError::InvalidRegex)?;
+        Ok(Motif { kind, pattern, regex })
+    }
+
+    /// Returns the k-mers a matching sequence must contain at least one of,
+    /// on either strand, or `None` when the index cannot narrow the search.
+    pub fn index_kmers(&self, k: usize) -> Option<Vec<String>> {
+        let alternatives: Vec<&str> = match self.kind {
+            MotifKind::Regex => return None,
+            MotifKind::Exact => self.pattern.chars()
+                .map(|c| match c.to_ascii_uppercase() {
+                    'A' => "A", 'C' => "C", 'G' => "G", 'T' | 'U' => "T",
+                    _ => "",
+                })
+                .collect(),
+            MotifKind::Iupac => self.pattern.chars().map(|c| iupac_bases(c).unwrap_or("")).collect(),
+        };
+        if alternatives.len() < k {
+            return None;
+        }
+        // Pick the window with the fewest expansions; empty alternatives make it unusable.
+        let (_, start) = alternatives.windows(k)
+            .enumerate()
+            .map(|(i, window)| (window.iter().map(|a| a.len()).product::<usize>(), i))
+            .filter(|&(n, _)| n > 0 && n <= MAX_KMER_EXPANSIONS)
+            .min()?;
+        let mut kmers = vec![String::new()];
+        for bases in &alternatives[start..start + k] {
+            kmers = kmers.iter()
+                .flat_map(|prefix| bases.chars().map(move |b| format!("{}{}", prefix, b)))
+                .collect();
+        }
+        let reverse: Vec<String> = kmers.iter().map(|kmer| reverse_complement(kmer)).collect();
+        kmers.extend(reverse);
+        kmers.sort();
+        kmers.dedup();
+        Some(kmers)
+    }
+
+    /// Finds all, possibly overlapping, occurrences on both strands.
+    pub fn find(&self, sequence: &str) -> Vec<MotifMatch> {
+        let mut matches: Vec<MotifMatch> = self.find_forward(sequence)
+            .map(|(position, length)| MotifMatch { position, length, strand: Strand::Plus })
+            .collect();
+        let reverse = reverse_complement(sequence);
+        matches.extend(self.find_forward(&reverse).map(|(position, length)| MotifMatch {
+            position: sequence.len() - position - length,
+            length,
+            strand: Strand::Minus,
+        }));
+        matches.sort();
+        matches
+    }
+
+    /// Yields `(start, length)` of non-empty matches, restarting after each match start.
+    fn find_forward<'a>(&'a self, sequence: &'a str) -> impl Iterator<Item = (usize, usize)> + 'a {
+        let mut from = 0;
+        std::iter::from_fn(move || {
+            while from <= sequence.len() {
+                let found = self.regex.find_at(sequence, from)?;
+                from = found.start() + 1;
+                while from < sequence.len() && !sequence.is_char_boundary(from) {
+                    from += 1;
+                }
+                if !found.is_empty() {
+                    return Some((found.start(), found.len()));
+                }
+            }
+            None
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find(kind: MotifKind, pattern: &str, sequence: &str) -> Vec<(usize, Strand)> {
+        Motif::new(kind, pattern.into()).unwrap()
+            .find(sequence)
+            .into_iter()
+            .map(|found| (found.position, found.strand))
+            .collect()
+    }
+
+    #[test]
+    fn exact_patterns_ignore_case() {
+        assert_eq!(find(MotifKind::Exact, "accg", "TTACCGTT"), vec![(2, Strand::Plus)]);
+        assert_eq!(find(MotifKind::Exact, "ACCG", "ttaccgtt"), vec![(2, Strand::Plus)]);
+        assert_eq!(find(MotifKind::Exact, "ACCG", "TTAGCCTT"), vec![]);
+    }
+
+    #[test]
+    fn iupac_codes_expand() {
+        assert_eq!(find(MotifKind::Iupac, "ACRG", "ACAGACGGACTG"), vec![(0, Strand::Plus), (4, Strand::Plus)]);
+        assert_eq!(find(MotifKind::Iupac, "ACNG", "GGACTGGG"), vec![(2, Strand::Plus)]);
+        assert!(matches!(Motif::new(MotifKind::Iupac, "ACXG".into()), Err(MotifError::InvalidIupacCode('X'))));
+    }
+
+    #[test]
+    fn regexes_are_taken_as_written() {
+        assert_eq!(find(MotifKind::Regex, "GA+C", "TTGAAACTT"), vec![(2, Strand::Plus)]);
+        assert_eq!(find(MotifKind::Regex, "GA+C", "ttgaaactt"), vec![]);
+        assert_eq!(find(MotifKind::Regex, "GTTC", "GUUC"), vec![]);
+    }
+
+    #[test]
+    fn matches_on_the_minus_strand_report_plus_strand_positions() {
+        // ACCG reverse-complements to CGGT, found at 2 on the plus strand.
+        assert_eq!(find(MotifKind::Exact, "ACCG", "TTCGGTTT"), vec![(2, Strand::Minus)]);
+        // A palindrome matches at the same place on both strands.
+        assert_eq!(find(MotifKind::Exact, "GAATTC", "TTGAATTCTT"), vec![(2, Strand::Plus), (2, Strand::Minus)]);
+    }
+
+    #[test]
+    fn t_and_u_match_each_other() {
+        assert_eq!(find(MotifKind::Exact, "ACTG", "GGACUGGG"), vec![(2, Strand::Plus)]);
+        assert_eq!(find(MotifKind::Exact, "acug", "GGACTGGG"), vec![(2, Strand::Plus)]);
+        assert_eq!(find(MotifKind::Iupac, "ACNG", "ggacugg"), vec![(2, Strand::Plus)]);
+        assert_eq!(find(MotifKind::Iupac, "ACYG", "GGACUGGG"), vec![(2, Strand::Plus)]);
+        assert_eq!(find(MotifKind::Exact, "ACCG", "UUCGGUUU"), vec![(2, Strand::Minus)]);
+    }
+
+    #[test]
+    fn index_kmers_cover_both_strands() {
+        let motif = Motif::new(MotifKind::Exact, "acug".into()).unwrap();
+        assert_eq!(motif.index_kmers(4), Some(vec!["ACTG".to_string(), "CAGT".to_string()]));
+        assert_eq!(motif.index_kmers(5), None);
+        assert_eq!(Motif::new(MotifKind::Regex, "ACTG".into()).unwrap().index_kmers(4), None);
+    }
+}
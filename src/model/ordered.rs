@@ -0,0 +1,40 @@
+use std::sync::Arc;
+use serde::{Serialize, Deserialize};
+
+use crate::model::record::Record;
+
+/// A client write as submitted to the ordering layer, signed by the node that accepted it.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Operation {
+    pub origin: String, // Id of the node the client sent the write to.
+    pub record: Record,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base: Option<String>, // Version the sequence must be at for the write to apply.
+    pub signature: Arc<str>, // Origin node signature over `message()`.
+}
+
+impl Operation {
+    /// Returns the message the origin node signs: `operation:{origin}:{base}:{record JSON}`, with
+    /// an unset base empty.
+    pub fn message(origin: &str, base: Option<&str>, record: &Record) -> String {
+        format!(
+            "operation:{}:{}:{}",
+            origin,
+            base.unwrap_or_default(),
+            serde_json::to_string(record).expect("records serialize"),
+        )
+    }
+}
+
+/// An entry of the operation log: an operation executed at its agreed position in the total
+/// order of writes, chained to the entry before it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OrderedOperation {
+    pub seq: u64,
+    pub view: u64, // View the operation was committed in.
+    pub origin: Option<String>, // Node that accepted the write; none for null operations.
+    pub digest: String, // Hex SHA-256 of `operation`, or `null` for a null operation.
+    pub operation: Option<String>, // JSON `Operation`; none for null operations filling gaps after a view change.
+    pub prev: String, // Hash of the entry before this one.
+    pub state: String, // Hex SHA-256 of `prev` and `digest`, chaining every operation up to this one.
+}
@@ -0,0 +1,13 @@
+use std::sync::Arc;
+
+/// A replication message queued for one peer until it acknowledges it.
+#[derive(Clone, Debug)]
+pub struct OutboxMessage {
+    pub seq: i64, // Queue position; each peer receives its messages in this order.
+    pub peer: String, // API address of the receiving peer.
+    pub idempotency_key: Arc<str>, // Shared by the copies of one broadcast, so retries are applied once.
+    pub path: String,
+    pub body: String, // JSON request body.
+    pub attempts: u32, // Failed delivery attempts so far.
+    pub next_attempt_at: i64, // Unix time in milliseconds before which the message is not retried.
+}
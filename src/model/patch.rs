@@ -1,18 +1,207 @@
 use std::sync::Arc;
 use serde::{Serialize, Deserialize};
+use diff_match_patch_rs::{DiffMatchPatch, Efficient};
+use thiserror::Error;
+
+use crate::model::variant::{self, Variant};
 
 /// Structure for DNA sequence patch data.
 #[derive(Serialize, Deserialize, Clone)]
-pub struct Patch { 
+pub struct Patch {
     pub id: Arc<str>,
-    pub patch_txt: Arc<str>,
+    #[serde(flatten)]
+    pub kind: PatchKind,
+    pub base: Arc<str>, // Version of the sequence the patch was made against.
+    #[serde(default)]
+    pub counter: u64, // Counter of the patched sequence's signed envelope.
+}
+
+/// Content of a replicated patch: diff-match-patch text, or VCF-style variants.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum PatchKind {
+    Text { patch_txt: Arc<str> },
+    Variants { variants: Vec<Variant> },
 }
 
 impl Patch {
-    pub fn new(id: Arc<str>, patch_txt: Arc<str>) -> Self {
+    pub fn new(id: Arc<str>, kind: PatchKind, base: Arc<str>, counter: u64) -> Self {
         Patch {
             id,
-            patch_txt,
+            kind,
+            base,
+            counter,
+        }
+    }
+}
+
+impl PatchKind {
+    /// Applies the patch to `sequence`, returning the patched sequence.
+    pub fn apply(&self, sequence: &str) -> Result<String, PatchError> {
+        match self {
+            PatchKind::Text { patch_txt } => apply_text(sequence, patch_txt),
+            PatchKind::Variants { variants } => variant::apply_variants(sequence, variants),
+        }
+    }
+}
+
+/// A single edit of an edit-list patch. Positions are 0-based offsets into the base sequence.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Edit {
+    Substitute { position: usize, bases: String }, // Replaces `bases.len()` bases starting at `position`.
+    Insert { position: usize, bases: String }, // Inserts before `position`; the sequence length appends.
+    Delete { position: usize, length: usize },
+}
+
+impl Edit {
+    /// Returns the `[start, end)` region of the base sequence the edit replaces.
+    fn span(&self) -> (usize, usize) {
+        match self {
+            Edit::Substitute { position, bases } => (*position, position + bases.len()),
+            Edit::Insert { position, .. } => (*position, *position),
+            Edit::Delete { position, length } => (*position, position + length),
         }
     }
+
+    fn bases(&self) -> &str {
+        match self {
+            Edit::Substitute { bases, .. } | Edit::Insert { bases, .. } => bases,
+            Edit::Delete { .. } => "",
+        }
+    }
+}
+
+/// A patch submitted by a client, in diff-match-patch text, as a list of edits or as variants.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "format", rename_all = "snake_case")]
+pub enum ClientPatch {
+    Dmp { patch_txt: String },
+    Edits { edits: Vec<Edit> },
+    Variants { variants: Vec<Variant> },
+}
+
+/// Errors for patches that cannot be applied to a sequence.
+#[derive(Error, Debug, derive_more::Display)]
+pub enum PatchError {
+    #[display(fmt = "Malformed patch text")]
+    Malformed,
+    #[display(fmt = "Patch does not apply to the sequence")]
+    Failed,
+    #[display(fmt = "Edit {} ends at {}, past the end of a sequence of length {}", _0, _1, _2)]
+    OutOfRange(usize, usize, usize),
+    #[display(fmt = "Edit {} overlaps or precedes the edit before it", _0)]
+    Unordered(usize),
+    #[display(fmt = "Variant {} does not match the reference bases at position {}", _0, _1)]
+    ReferenceMismatch(usize, usize),
+    #[display(fmt = "Variant {} has an empty allele or position", _0)]
+    EmptyAllele(usize),
+}
+
+impl ClientPatch {
+    /// Applies the patch to `sequence`, returning the patched sequence.
+    pub fn apply(&self, sequence: &str) -> Result<String, PatchError> {
+        match self {
+            ClientPatch::Dmp { patch_txt } => apply_text(sequence, patch_txt),
+            ClientPatch::Edits { edits } => apply_edits(sequence, edits),
+            ClientPatch::Variants { variants } => variant::apply_variants(sequence, variants),
+        }
+    }
+}
+
+/// Applies diff-match-patch text to `sequence`, failing unless every hunk applies.
+pub fn apply_text(sequence: &str, patch_txt: &str) -> Result<String, PatchError> {
+    let dmp = DiffMatchPatch::new();
+    let patches = dmp.patch_from_text::<Efficient>(patch_txt).map_err(|_| PatchError::Malformed)?;
+    let (patched, applied) = dmp.patch_apply(&patches, sequence).map_err(|_| PatchError::Failed)?;
+    if !applied.iter().all(|&applied| applied) {
+        return Err(PatchError::Failed);
+    }
+    Ok(patched)
+}
+
+/// Applies an edit list to `sequence`. Every edit refers to base coordinates, so edits must be
+/// sorted and must not overlap; an insertion may share its position with the edit after it.
+pub fn apply_edits(sequence: &str, edits: &[Edit]) -> Result<String, PatchError> {
+    let mut patched = String::with_capacity(sequence.len());
+    let mut copied = 0;
+    for (index, edit) in edits.iter().enumerate() {
+        let (start, end) = edit.span();
+        if end > sequence.len() || !sequence.is_char_boundary(start) || !sequence.is_char_boundary(end) {
+            return Err(PatchError::OutOfRange(index, end, sequence.len()));
+        }
+        if start < copied {
+            return Err(PatchError::Unordered(index));
+        }
+        patched.push_str(&sequence[copied..start]);
+        patched.push_str(edit.bases());
+        copied = end;
+    }
+    patched.push_str(&sequence[copied..]);
+    Ok(patched)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diff_match_patch_rs::PatchInput;
+
+    fn substitute(position: usize, bases: &str) -> Edit {
+        Edit::Substitute { position, bases: bases.to_string() }
+    }
+
+    fn insert(position: usize, bases: &str) -> Edit {
+        Edit::Insert { position, bases: bases.to_string() }
+    }
+
+    #[test]
+    fn edits_apply_in_base_coordinates() {
+        let edits = [insert(0, "GG"), substitute(2, "T"), Edit::Delete { position: 4, length: 2 }, insert(8, "CC")];
+        assert_eq!(apply_edits("ACGTACGT", &edits).unwrap(), "GGACTTGTCC");
+    }
+
+    #[test]
+    fn insertion_may_share_its_position_with_the_next_edit() {
+        let edits = [insert(2, "TT"), substitute(2, "A")];
+        assert_eq!(apply_edits("ACGT", &edits).unwrap(), "ACTTAT");
+    }
+
+    #[test]
+    fn edits_past_the_end_are_rejected() {
+        let edits = [Edit::Delete { position: 2, length: 3 }];
+        assert!(matches!(apply_edits("ACGT", &edits), Err(PatchError::OutOfRange(0, 5, 4))));
+    }
+
+    #[test]
+    fn overlapping_edits_are_rejected() {
+        let edits = [substitute(1, "TT"), substitute(2, "A")];
+        assert!(matches!(apply_edits("ACGTACGT", &edits), Err(PatchError::Unordered(1))));
+    }
+
+    #[test]
+    fn text_patch_applies_and_inverts() {
+        let (old, new) = ("ACGTACGTACGTACGT", "ACGTTTGTACGAACGT");
+        let dmp = DiffMatchPatch::new();
+        let forward = dmp.patch_to_text(&dmp.patch_make::<Efficient>(PatchInput::new_text_text(old, new)).unwrap());
+        let backward = dmp.patch_to_text(&dmp.patch_make::<Efficient>(PatchInput::new_text_text(new, old)).unwrap());
+        assert_eq!(apply_text(old, &forward).unwrap(), new);
+        assert_eq!(apply_text(new, &backward).unwrap(), old);
+    }
+
+    #[test]
+    fn text_patch_that_does_not_match_fails() {
+        let dmp = DiffMatchPatch::new();
+        let patch = dmp.patch_to_text(&dmp.patch_make::<Efficient>(PatchInput::new_text_text("AAAAAAAA", "AAAATAAA")).unwrap());
+        assert!(matches!(apply_text("CCCCCCCC", &patch), Err(PatchError::Failed)));
+        assert!(matches!(apply_text("AAAA", "not a patch"), Err(PatchError::Malformed)));
+    }
+
+    #[test]
+    fn replicated_variants_apply_like_client_variants() {
+        let variants = vec![Variant { position: 3, reference: "G".to_string(), alternate: "T".to_string() }];
+        let client = ClientPatch::Variants { variants: variants.clone() };
+        let replicated = PatchKind::Variants { variants };
+        assert_eq!(client.apply("ACGT").unwrap(), "ACTT");
+        assert_eq!(replicated.apply("ACGT").unwrap(), "ACTT");
+    }
 }
@@ -1,7 +1,7 @@
 use std::fmt::{self, Display};
 use std::sync::Arc;
 use uuid::Uuid;
-use tracing::{debug, info};
+use tracing::debug;
 use serde::{Serialize, Deserialize};
 use base64::{Engine as _, engine::general_purpose};
 use thiserror::Error;
@@ -57,9 +57,10 @@ impl PublicKey {
     pub fn check_signature(signature: Arc<str>, public_key: PublicKey, message: Arc<str>) -> Result<(), WrongSignatureError> {
         match public_key.public_key {
             Some(pk) => {
-                let raw_signature = general_purpose::STANDARD.decode(signature.to_string()).unwrap();
+                let raw_signature = general_purpose::STANDARD.decode(signature.to_string())
+                    .map_err(|_| WrongSignatureError::VerificationFailed)?;
                 let peer_public_key = UnparsedPublicKey::new(&signature::ED25519, pk);
-                match peer_public_key.verify(&message.as_bytes(), &raw_signature.as_ref()) {
+                match peer_public_key.verify(message.as_bytes(), raw_signature.as_ref()) {
                     Ok(()) => Ok(()),
                     //Err(_) => Ok(()), // TODO: revisit this for proper error handling.
                     Err(e) => {
@@ -0,0 +1,24 @@
+use std::sync::Arc;
+use serde::{Serialize, Deserialize};
+
+use crate::model::{dna_sequence::DnaSequence, tombstone::Tombstone};
+
+/// A stored record as written by `export` and streamed in peer snapshots, one JSON object per line.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Record {
+    PublicKey { id: Arc<str>, public_key: String }, // Base64 Ed25519 key of the sequence owner.
+    DnaSequence(DnaSequence),
+    Tombstone(Tombstone),
+}
+
+impl Record {
+    /// Returns the id of the owner the record belongs to.
+    pub fn id(&self) -> Arc<str> {
+        match self {
+            Record::PublicKey { id, .. } => id.clone(),
+            Record::DnaSequence(dna_sequence) => dna_sequence.id.clone(),
+            Record::Tombstone(tombstone) => tombstone.id.clone(),
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use std::sync::Arc;
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+
+/// Seconds a tombstone's `deleted_at` may differ from the receiving node's clock in the
+/// direction that would otherwise reject it.
+pub const MAX_CLOCK_SKEW_SECS: i64 = 5 * 60;
+
+/// Errors for tombstones whose time does not fit the node's clock or the stored sequence.
+#[derive(Error, Debug, derive_more::Display)]
+pub enum TombstoneError {
+    #[display(fmt = "Tombstone of {} is dated {}, after the node's clock", _0, _1)]
+    FromFuture(Arc<str>, i64),
+    #[display(fmt = "Tombstone of {} is dated {}, before the tombstone grace period", _0, _1)]
+    Expired(Arc<str>, i64),
+    #[display(fmt = "Tombstone of {} is dated {}, before the stored version was written at {}", _0, _1, _2)]
+    BeforeRecord(Arc<str>, i64, i64),
+}
+
+/// Structure recording the owner-signed deletion of a DNA sequence.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Tombstone {
+    pub id: Arc<str>, // Identifier of the deleted DNA sequence.
+    #[serde(default)]
+    pub counter: u64, // Envelope counter of the deleted version; newer versions are not deleted.
+    pub deleted_at: i64, // Owner-supplied deletion time, in seconds since the epoch.
+    pub signature: Arc<str>, // Owner signature over `message()`.
+}
+
+impl Tombstone {
+    /// Creates a new tombstone for the given sequence ID.
+    pub fn new(id: Arc<str>, counter: u64, deleted_at: i64, signature: Arc<str>) -> Self {
+        Tombstone {
+            id,
+            counter,
+            deleted_at,
+            signature,
+        }
+    }
+
+    /// Returns the message the owner signs to authorize the deletion.
+    pub fn message(&self) -> Arc<str> {
+        format!("delete:{}:{}:{}", self.id, self.counter, self.deleted_at).into()
+    }
+
+    /// Checks `deleted_at` against the node's clock `now` and against `stored_at`, the time the
+    /// stored version was written, if the sequence is held. Tombstones older than `grace_secs`
+    /// would already have been purged, so accepting them again could only replay a deletion.
+    pub fn check_time(&self, now: i64, grace_secs: i64, stored_at: Option<i64>) -> Result<(), TombstoneError> {
+        if self.deleted_at > now + MAX_CLOCK_SKEW_SECS {
+            return Err(TombstoneError::FromFuture(self.id.clone(), self.deleted_at));
+        }
+        if self.deleted_at < now - grace_secs {
+            return Err(TombstoneError::Expired(self.id.clone(), self.deleted_at));
+        }
+        match stored_at {
+            Some(stored_at) if self.deleted_at < stored_at - MAX_CLOCK_SKEW_SECS => {
+                Err(TombstoneError::BeforeRecord(self.id.clone(), self.deleted_at, stored_at))
+            },
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,230 @@
+use std::fmt::Write;
+use serde::{Serialize, Deserialize};
+use diff_match_patch_rs::{DiffMatchPatch, Efficient, Ops};
+
+use crate::model::patch::PatchError;
+
+/// A VCF-style variant: the `reference` bases at 1-based `position` become `alternate`.
+/// As in VCF, insertions and deletions keep an anchoring base shared by both alleles.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Variant {
+    #[serde(rename = "pos")]
+    pub position: usize,
+    #[serde(rename = "ref")]
+    pub reference: String,
+    #[serde(rename = "alt")]
+    pub alternate: String,
+}
+
+/// Kind of a variant, written to the `TYPE` field of exported VCF records.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, derive_more::Display)]
+pub enum VariantKind {
+    #[display(fmt = "snv")]
+    Snv,
+    #[display(fmt = "mnv")]
+    Mnv,
+    #[display(fmt = "ins")]
+    Insertion,
+    #[display(fmt = "del")]
+    Deletion,
+    #[display(fmt = "complex")]
+    Complex,
+}
+
+/// A recorded change from one version of a sequence to the next.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SequenceChange {
+    pub base: String, // Version the change applies to.
+    pub version: String, // Version it produces.
+    pub base_length: usize,
+    pub variants: Vec<Variant>,
+    pub recorded_at: i64,
+}
+
+impl Variant {
+    pub fn kind(&self) -> VariantKind {
+        let (reference, alternate) = (self.reference.len(), self.alternate.len());
+        let anchored = self.reference.as_bytes().first() == self.alternate.as_bytes().first();
+        match (reference, alternate) {
+            (1, 1) => VariantKind::Snv,
+            (r, a) if r == a => VariantKind::Mnv,
+            (1, _) if anchored => VariantKind::Insertion,
+            (_, 1) if anchored => VariantKind::Deletion,
+            _ => VariantKind::Complex,
+        }
+    }
+
+    /// Returns the 0-based `[start, end)` region of the sequence the variant replaces.
+    fn span(&self) -> (usize, usize) {
+        let start = self.position.saturating_sub(1);
+        (start, start + self.reference.len())
+    }
+}
+
+/// Applies variants to `sequence`. Variants must be sorted, must not overlap and must match the
+/// reference bases of the sequence, so the outcome does not depend on which node applies them.
+pub fn apply_variants(sequence: &str, variants: &[Variant]) -> Result<String, PatchError> {
+    let mut patched = String::with_capacity(sequence.len());
+    let mut copied = 0;
+    for (index, variant) in variants.iter().enumerate() {
+        // Only a variant replacing the whole sequence may leave an allele empty, as there is no
+        // base left to anchor on.
+        let whole = variant.position == 1 && variant.reference == sequence;
+        if variant.position == 0 || (!whole && (variant.reference.is_empty() || variant.alternate.is_empty())) {
+            return Err(PatchError::EmptyAllele(index));
+        }
+        let (start, end) = variant.span();
+        if end > sequence.len() {
+            return Err(PatchError::OutOfRange(index, end, sequence.len()));
+        }
+        if start < copied {
+            return Err(PatchError::Unordered(index));
+        }
+        if sequence.get(start..end) != Some(variant.reference.as_str()) {
+            return Err(PatchError::ReferenceMismatch(index, variant.position));
+        }
+        patched.push_str(&sequence[copied..start]);
+        patched.push_str(&variant.alternate);
+        copied = end;
+    }
+    patched.push_str(&sequence[copied..]);
+    Ok(patched)
+}
+
+/// Returns the variants turning `old` into `new`. The diff runs without a timeout, so every node
+/// derives the same variants from the same versions.
+pub fn variants(old: &str, new: &str) -> Vec<Variant> {
+    let mut dmp = DiffMatchPatch::new();
+    dmp.set_timeout(None);
+    let diffs = dmp.diff_main::<Efficient>(old, new).unwrap_or_default();
+
+    // Runs of deletions and insertions between equal stretches, as `[start, end)` of `old` and
+    // the bases replacing them.
+    let mut changes: Vec<(usize, usize, String)> = Vec::new();
+    let mut offset = 0;
+    let mut pending: Option<(usize, usize, String)> = None;
+    for diff in &diffs {
+        let data = String::from_utf8_lossy(diff.data());
+        match diff.op() {
+            Ops::Equal => {
+                changes.extend(pending.take());
+                offset += data.len();
+            },
+            Ops::Delete => {
+                let change = pending.get_or_insert_with(|| (offset, offset, String::new()));
+                change.1 += data.len();
+                offset += data.len();
+            },
+            Ops::Insert => {
+                pending.get_or_insert_with(|| (offset, offset, String::new())).2.push_str(&data);
+            },
+        }
+    }
+    changes.extend(pending);
+
+    changes.into_iter().map(|(start, end, bases)| {
+        if start != end && !bases.is_empty() {
+            return Variant { position: start + 1, reference: old[start..end].to_string(), alternate: bases };
+        }
+        // Pure insertions and deletions are anchored on the base before them, or after them at
+        // the start of the sequence.
+        if start > 0 {
+            let anchor = &old[start - 1..start];
+            Variant { position: start, reference: format!("{}{}", anchor, &old[start..end]), alternate: format!("{}{}", anchor, bases) }
+        } else if end < old.len() {
+            let anchor = &old[end..end + 1];
+            Variant { position: 1, reference: format!("{}{}", &old[..end], anchor), alternate: format!("{}{}", bases, anchor) }
+        } else {
+            // The whole sequence was emptied or was empty, leaving nothing to anchor on.
+            Variant { position: 1, reference: old.to_string(), alternate: bases }
+        }
+    }).collect()
+}
+
+/// Renders a change of sequence `id` as VCF, with the sequence as the only contig.
+pub fn to_vcf(id: &str, change: &SequenceChange) -> String {
+    let mut vcf = String::new();
+    vcf.push_str("##fileformat=VCFv4.3\n");
+    vcf.push_str("##source=repyh\n");
+    let _ = writeln!(vcf, "##reference=sha256:{}", change.base);
+    let _ = writeln!(vcf, "##contig=<ID={},length={}>", id, change.base_length);
+    let _ = writeln!(vcf, "##version=sha256:{}", change.version);
+    vcf.push_str("##INFO=<ID=TYPE,Number=1,Type=String,Description=\"Variant type: snv, mnv, ins, del or complex\">\n");
+    vcf.push_str("#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\n");
+    for variant in &change.variants {
+        let _ = writeln!(vcf, "{}\t{}\t.\t{}\t{}\t.\tPASS\tTYPE={}", id, variant.position, variant.reference, variant.alternate, variant.kind());
+    }
+    vcf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variant(position: usize, reference: &str, alternate: &str) -> Variant {
+        Variant { position, reference: reference.to_string(), alternate: alternate.to_string() }
+    }
+
+    /// Checks the derived variants turn `old` into `new`, and the variants derived the other way
+    /// turn it back.
+    fn assert_round_trip(old: &str, new: &str) {
+        assert_eq!(apply_variants(old, &variants(old, new)).unwrap(), new);
+        assert_eq!(apply_variants(new, &variants(new, old)).unwrap(), old);
+    }
+
+    #[test]
+    fn derived_variants_apply_and_invert() {
+        assert_round_trip("ACGTACGTAC", "ACGAACGTAC"); // SNV.
+        assert_round_trip("ACGTACGTAC", "ACGTTTACGTAC"); // Insertion.
+        assert_round_trip("ACGTACGTAC", "ACGTAC"); // Deletion.
+        assert_round_trip("ACGTACGTAC", "GGACGTACGTAC"); // Insertion at the start.
+        assert_round_trip("ACGTACGTAC", "TACGTAC"); // Deletion at the start.
+        assert_round_trip("ACGTACGTAC", "TTTTACGAACGTCC"); // Several changes.
+        assert_round_trip("ACGT", ""); // Emptied, and filled again.
+        assert_round_trip("ACGT", "ACGT"); // Unchanged.
+    }
+
+    #[test]
+    fn indels_are_anchored_on_the_base_before_them() {
+        assert_eq!(variants("ACGT", "ACTTGT"), vec![variant(2, "C", "CTT")]);
+        assert_eq!(variants("ACGTACGT", "ACGACGT"), vec![variant(3, "GT", "G")]);
+        assert_eq!(variants("ACGT", "CGT"), vec![variant(1, "AC", "C")]);
+    }
+
+    #[test]
+    fn kinds() {
+        assert_eq!(variant(1, "A", "T").kind(), VariantKind::Snv);
+        assert_eq!(variant(1, "AC", "TT").kind(), VariantKind::Mnv);
+        assert_eq!(variant(1, "A", "ACG").kind(), VariantKind::Insertion);
+        assert_eq!(variant(1, "ACG", "A").kind(), VariantKind::Deletion);
+        assert_eq!(variant(1, "AC", "GTT").kind(), VariantKind::Complex);
+    }
+
+    #[test]
+    fn variants_must_match_the_reference() {
+        assert!(matches!(apply_variants("ACGT", &[variant(2, "G", "T")]), Err(PatchError::ReferenceMismatch(0, 2))));
+    }
+
+    #[test]
+    fn malformed_variants_are_rejected() {
+        assert!(matches!(apply_variants("ACGT", &[variant(0, "A", "T")]), Err(PatchError::EmptyAllele(0))));
+        assert!(matches!(apply_variants("ACGT", &[variant(1, "A", "")]), Err(PatchError::EmptyAllele(0))));
+        assert!(matches!(apply_variants("ACGT", &[variant(4, "TA", "T")]), Err(PatchError::OutOfRange(0, 5, 4))));
+        let unordered = [variant(3, "G", "T"), variant(1, "A", "T")];
+        assert!(matches!(apply_variants("ACGT", &unordered), Err(PatchError::Unordered(1))));
+    }
+
+    #[test]
+    fn vcf_lists_each_variant_with_its_kind() {
+        let change = SequenceChange {
+            base: "b".to_string(),
+            version: "v".to_string(),
+            base_length: 4,
+            variants: vec![variant(2, "C", "CTT")],
+            recorded_at: 0,
+        };
+        let vcf = to_vcf("seq", &change);
+        assert!(vcf.contains("##contig=<ID=seq,length=4>\n"));
+        assert!(vcf.ends_with("seq\t2\t.\tC\tCTT\t.\tPASS\tTYPE=ins\n"));
+    }
+}
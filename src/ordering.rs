@@ -0,0 +1,1566 @@
+use crate::{
+    config::{Config, OrderingConfig},
+    identity::NodeIdentity,
+    membership::MemberSet,
+    model::{evidence::EvidenceKind, ordered::{Operation, OrderedOperation}, public_key::PublicKey, record::Record},
+    repository::{cipher::to_hex, db::{DbHandle, QuerryError}},
+    sender::{self, Cluster},
+};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    sync::{atomic::{AtomicBool, Ordering as AtomicOrdering}, Arc, Mutex},
+};
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tokio::{sync::Notify, time::{Duration, Instant}};
+use tracing::{debug, error, info, warn};
+
+/// Key of the view a replica last entered or voted for in the metadata table.
+const VIEW_KEY: &str = "ordering_view";
+/// Key of the new view message that started the current view in the metadata table.
+const NEW_VIEW_KEY: &str = "ordering_new_view";
+/// Key of the last stable checkpoint and its proof in the metadata table.
+const CHECKPOINT_KEY: &str = "ordering_checkpoint";
+/// Digest of the null operations filling sequence gaps after a view change.
+const NULL_DIGEST: &str = "null";
+/// Operations served per log page, and fetched per request while catching up.
+pub const LOG_PAGE: usize = 500;
+/// Execution results kept for the writes waiting on them.
+const KEPT_RESULTS: usize = 1024;
+/// Messages of later views buffered until the replica enters them.
+const KEPT_FUTURE: usize = 4096;
+/// Pause before forwarding a write again while the cluster changes view.
+const FORWARD_RETRY: Duration = Duration::from_millis(200);
+
+/// Errors for ordered writes and ordering messages.
+#[derive(Error, Debug, derive_more::Display)]
+pub enum OrderingError {
+    #[display(fmt = "Writes are ordered by the primary {} at {} in view {}", id, address, view)]
+    NotPrimary { id: String, address: String, view: u64 },
+    #[display(fmt = "View change to view {} in progress", _0)]
+    ViewChange(u64),
+    #[display(fmt = "Too many writes in flight")]
+    Busy,
+    #[display(fmt = "Write was not committed in time")]
+    Timeout,
+    #[display(fmt = "Write was rejected: {}", _0)]
+    Rejected(String),
+    #[display(fmt = "Invalid ordering message: {}", _0)]
+    InvalidMessage(String),
+    #[display(fmt = "Ordering is disabled")]
+    Disabled,
+    #[display(fmt = "Primary at {} is unreachable: {}", _0, _1)]
+    Unreachable(String, String),
+    #[display(fmt = "{}", _1)]
+    Forwarded(u16, String), // Status and message the primary answered a forwarded write with.
+    QueryFailed(QuerryError),
+}
+
+/// A protocol message signed by the replica that sent it. The payload is kept as sent so the
+/// signature can be checked again when the envelope is relayed inside a proof.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Envelope {
+    pub replica: String,
+    pub payload: String, // JSON `Message`.
+    pub signature: Arc<str>, // Replica signature over `ordering:{replica}:{payload}`.
+}
+
+impl Envelope {
+    fn message(replica: &str, payload: &str) -> String {
+        format!("ordering:{}:{}", replica, payload)
+    }
+}
+
+impl OrderingError {
+    /// Whether the write may succeed when forwarded again, once the cluster settled on a primary.
+    fn is_transient(&self) -> bool {
+        match self {
+            OrderingError::ViewChange(_) | OrderingError::Unreachable(..) => true,
+            OrderingError::Forwarded(status, _) => *status == 421 || *status == 503,
+            _ => false,
+        }
+    }
+}
+
+/// Messages of the three-phase agreement, checkpoints and view changes.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Message {
+    PrePrepare { view: u64, seq: u64, digest: String, operation: Option<Operation> },
+    Prepare { view: u64, seq: u64, digest: String },
+    Commit { view: u64, seq: u64, digest: String },
+    Checkpoint { seq: u64, state: String },
+    ViewChange(ViewChange),
+    NewView { view: u64, view_changes: Vec<Envelope>, pre_prepares: Vec<Envelope> },
+}
+
+impl Message {
+    /// Returns the view a message belongs to, if any.
+    fn view(&self) -> Option<u64> {
+        match self {
+            Message::PrePrepare { view, .. } | Message::Prepare { view, .. } | Message::Commit { view, .. } => Some(*view),
+            Message::NewView { view, .. } => Some(*view),
+            Message::ViewChange(view_change) => Some(view_change.view),
+            Message::Checkpoint { .. } => None,
+        }
+    }
+}
+
+/// A replica's vote to move to `view`, carrying its stable checkpoint and every operation it
+/// prepared after it.
+#[derive(Serialize, Deserialize, Clone)]
+struct ViewChange {
+    view: u64,
+    checkpoint: u64,
+    state: String,
+    checkpoint_proof: Vec<Envelope>, // Matching checkpoint messages from a quorum.
+    prepared: Vec<PreparedProof>,
+}
+
+/// A pre-prepare with the prepares from a quorum of backups that match it.
+#[derive(Serialize, Deserialize, Clone)]
+struct PreparedProof {
+    pre_prepare: Envelope,
+    prepares: Vec<Envelope>,
+}
+
+/// A state the replicas agreed on, with the checkpoint messages proving it.
+#[derive(Serialize, Deserialize, Clone)]
+struct Checkpoint {
+    seq: u64,
+    state: String,
+    proof: Vec<Envelope>,
+}
+
+/// What a replica has seen for one sequence number in the current view.
+#[derive(Default)]
+struct Slot {
+    pre_prepare: Option<(String, Option<Operation>, Envelope)>, // Digest, operation and the primary's message.
+    prepares: HashMap<String, (String, Envelope)>, // Digest each backup prepared.
+    commits: HashMap<String, (String, Envelope)>, // Digest each replica committed.
+    commit_sent: bool,
+}
+
+/// Protocol state of the local replica. Only the view and the message that started it, the
+/// executed operations and the stable checkpoint are persisted; messages in flight when the
+/// node stops are lost.
+struct Replica {
+    view: u64,
+    active: bool, // False while changing to `view`.
+    last_active: u64, // Last view the replica operated in.
+    view_started: Instant,
+    next_seq: u64, // Next sequence number the replica assigns as primary.
+    executed: u64,
+    state: String, // Hash chain over the digests of every executed operation.
+    stable: Checkpoint,
+    log: BTreeMap<u64, Slot>,
+    prepared: BTreeMap<u64, PreparedProof>, // Highest-view proof per prepared sequence number.
+    checkpoints: BTreeMap<u64, HashMap<String, (String, Envelope)>>,
+    view_changes: BTreeMap<u64, HashMap<String, ViewChange>>,
+    view_change_envelopes: BTreeMap<u64, HashMap<String, Envelope>>,
+    waiting: HashMap<String, Instant>, // Digests of accepted operations not yet executed.
+    results: BTreeMap<u64, (String, Result<(), String>)>,
+    future: Vec<Envelope>,
+    behind: Option<Checkpoint>, // A stable checkpoint beyond the executed operations.
+    new_view_sent: Option<u64>,
+    new_view: Option<Envelope>, // New view message that started the current view, relayed to lagging replicas.
+}
+
+/// A sequence number with the digest and operation proposed for it.
+type Proposal = (u64, String, Option<Operation>);
+
+/// Members of the current configuration in a fixed order, with the quorum sizes.
+struct Members {
+    node_id: String,
+    ids: Vec<String>,
+    addresses: HashMap<String, String>,
+    keys: HashMap<String, Option<String>>,
+    faults: usize,
+    quorum: usize, // Matching messages from distinct replicas a phase needs: ceil((n + f + 1) / 2).
+}
+
+impl Members {
+    fn of(cluster: &Cluster) -> Self {
+        Self::new(cluster.node_id.clone(), &cluster.membership.read().unwrap().current)
+    }
+
+    fn new(node_id: String, set: &MemberSet) -> Self {
+        let members = &set.members;
+        let mut ids: Vec<String> = members.iter().map(|member| member.id.clone()).collect();
+        ids.sort();
+        let n = ids.len();
+        let faults = set.quorum.faults(n);
+        Members {
+            node_id,
+            ids,
+            addresses: members.iter().map(|member| (member.id.clone(), member.address.clone())).collect(),
+            keys: members.iter().map(|member| (member.id.clone(), member.public_key.clone())).collect(),
+            faults,
+            quorum: (n + faults + 2) / 2,
+        }
+    }
+
+    fn primary(&self, view: u64) -> &str {
+        &self.ids[(view % self.ids.len() as u64) as usize]
+    }
+
+    fn contains(&self, id: &str) -> bool {
+        self.addresses.contains_key(id)
+    }
+
+    fn peer_addresses(&self) -> Vec<String> {
+        self.addresses.iter()
+            .filter(|(id, _)| **id != self.node_id)
+            .map(|(_, address)| address.clone())
+            .collect()
+    }
+
+    /// Checks `id` is a member and signed `message`. Members without a known public key are
+    /// refused, as anyone could speak for them.
+    fn verify(&self, identity: &NodeIdentity, id: &str, signature: Arc<str>, message: String) -> Result<(), String> {
+        if !self.contains(id) {
+            return Err(format!("{} is not a member", id));
+        }
+        let key = match id == self.node_id {
+            true => Some(identity.public_key()),
+            false => self.keys[id].clone(),
+        };
+        let key = key.ok_or_else(|| format!("{} has no public key", id))?;
+        let key = PublicKey::from_raw(id.to_string(), key).map_err(|_| format!("{} has a malformed public key", id))?;
+        PublicKey::check_signature(signature, key, message.into()).map_err(|_| format!("bad signature from {}", id))?;
+        Ok(())
+    }
+
+    /// Checks an operation is signed by the member it claims to originate from.
+    fn verify_origin(&self, identity: &NodeIdentity, operation: &Operation) -> Result<(), String> {
+        let message = Operation::message(&operation.origin, operation.base.as_deref(), &operation.record);
+        self.verify(identity, &operation.origin, operation.signature.clone(), message)
+            .map_err(|e| format!("operation origin: {}", e))
+    }
+}
+
+/// Returns the digest an operation is agreed on by.
+fn digest(operation: Option<&str>) -> String {
+    match operation {
+        Some(operation) => to_hex(&Sha256::digest(operation.as_bytes())),
+        None => NULL_DIGEST.to_string(),
+    }
+}
+
+fn digest_of(operation: &Option<Operation>) -> String {
+    digest(operation.as_ref().map(|operation| serde_json::to_string(operation).expect("operations serialize")).as_deref())
+}
+
+/// Extends the state hash chain with the digest of the next executed operation.
+fn chain(state: &str, digest: &str) -> String {
+    to_hex(&Sha256::new().chain_update(state.as_bytes()).chain_update(b"\n").chain_update(digest.as_bytes()).finalize())
+}
+
+/// State before any operation executed.
+fn genesis() -> String {
+    to_hex(&Sha256::digest([]))
+}
+
+/// Applies an ordered operation. Every correct replica reaches the same outcome from the same
+/// state, so rejected operations stay in the order and are rejected everywhere.
+fn apply(db: &DbHandle, operation: Operation) -> Result<(), String> {
+    if let (Some(base), Record::DnaSequence(dna_sequence)) = (&operation.base, &operation.record) {
+        check_base(db, &dna_sequence.id, base)?;
+    }
+    match operation.record {
+        Record::PublicKey { id, public_key } => {
+            let public_key = PublicKey::from_raw(id.to_string(), public_key)
+                .map_err(|_| format!("malformed public key {}", id))?;
+            // A key is never replaced, or any member could take over an owner's sequences.
+            match db.get_public_key(id.clone()) {
+                Ok(stored) if stored.public_key != public_key.public_key => {
+                    return Err(format!("public key {} conflicts with the stored one", id));
+                },
+                Ok(_) => (),
+                Err(_) => {
+                    db.push_public_key(&public_key).map_err(|e| e.to_string())?;
+                },
+            }
+        },
+        Record::DnaSequence(dna_sequence) => {
+            let id = dna_sequence.id.clone();
+            let public_key = db.get_public_key(id.clone()).map_err(|_| format!("sequence {} has no public key", id))?;
+            let signature = dna_sequence.signature.clone().ok_or_else(|| format!("sequence {} is unsigned", id))?;
+            dna_sequence.check_signature(signature, public_key)
+                .map_err(|e| format!("sequence {}: {}", id, e))?;
+            dna_sequence.validate().map_err(|e| format!("sequence {}: {}", id, e))?;
+            db.push_dna_sequence(&dna_sequence).map_err(|e| e.to_string())?;
+        },
+        Record::Tombstone(tombstone) => {
+            let id = tombstone.id.clone();
+            let public_key = db.get_public_key(id.clone()).map_err(|_| format!("tombstone {} has no public key", id))?;
+            PublicKey::check_signature(tombstone.signature.clone(), public_key, tombstone.message())
+                .map_err(|e| format!("tombstone {}: {}", id, e))?;
+            db.push_tombstone(&tombstone, chrono::Utc::now().timestamp()).map_err(|e| e.to_string())?;
+        },
+    }
+    Ok(())
+}
+
+/// Picks the operations a new view re-proposes: from the highest stable checkpoint, the
+/// operation prepared in the latest view for every sequence number, or a null operation.
+fn plan(votes: &[ViewChange]) -> (&ViewChange, Vec<Proposal>) {
+    let base = votes.iter().max_by_key(|vote| vote.checkpoint).expect("a quorum of view changes");
+    let mut chosen: BTreeMap<u64, (u64, String, Option<Operation>)> = BTreeMap::new();
+    for proof in votes.iter().flat_map(|vote| vote.prepared.iter()) {
+        let Ok(Message::PrePrepare { view, seq, digest, operation }) = serde_json::from_str(&proof.pre_prepare.payload) else {
+            continue;
+        };
+        if seq > base.checkpoint && chosen.get(&seq).is_none_or(|(latest, ..)| *latest < view) {
+            chosen.insert(seq, (view, digest, operation));
+        }
+    }
+    let last = chosen.keys().next_back().copied().unwrap_or(base.checkpoint);
+    let plan = (base.checkpoint + 1..=last)
+        .map(|seq| match chosen.remove(&seq) {
+            Some((_, digest, operation)) => (seq, digest, operation),
+            None => (seq, NULL_DIGEST.to_string(), None),
+        })
+        .collect();
+    (base, plan)
+}
+
+/// Checks the sequence `id` is at version `base`, for writes made against that version.
+fn check_base(db: &DbHandle, id: &Arc<str>, base: &str) -> Result<(), String> {
+    match db.get_dna_sequence(id.clone()) {
+        Ok(current) if current.version() == base => Ok(()),
+        Ok(current) => Err(format!("sequence {} is at version {}, not {}", id, current.version(), base)),
+        Err(_) => Err(format!("sequence {} does not exist, so it is not at version {}", id, base)),
+    }
+}
+
+/// Shared dependencies of one protocol step.
+struct Context<'a> {
+    members: Members,
+    identity: &'a NodeIdentity,
+    db: &'a Mutex<DbHandle>,
+    config: &'a OrderingConfig,
+    cluster: &'a Cluster,
+}
+
+/// One pass over the replica: messages the replica sends itself are handled in the same pass,
+/// messages for peers are sent once it ends.
+struct Step<'a> {
+    replica: &'a mut Replica,
+    context: Context<'a>,
+    inbox: VecDeque<(Envelope, Message)>,
+    outgoing: Vec<Envelope>,
+    executed: bool,
+}
+
+impl Step<'_> {
+    fn me(&self) -> &str {
+        &self.context.members.node_id
+    }
+
+    fn is_primary(&self, view: u64) -> bool {
+        self.context.members.primary(view) == self.me()
+    }
+
+    /// Signs a message, handles it locally and queues it for every peer.
+    fn emit(&mut self, message: Message) {
+        let payload = serde_json::to_string(&message).expect("ordering messages serialize");
+        let signature = self.context.identity.sign(Envelope::message(self.me(), &payload).as_bytes());
+        let envelope = Envelope { replica: self.me().to_string(), payload, signature };
+        self.outgoing.push(envelope.clone());
+        self.inbox.push_back((envelope, message));
+    }
+
+    /// Checks the sender belongs to the membership and signed the envelope, then decodes it.
+    fn open(&self, envelope: &Envelope) -> Result<Message, OrderingError> {
+        let message = Envelope::message(&envelope.replica, &envelope.payload);
+        self.context.members.verify(self.context.identity, &envelope.replica, envelope.signature.clone(), message)
+            .map_err(OrderingError::InvalidMessage)?;
+        serde_json::from_str(&envelope.payload).map_err(|e| OrderingError::InvalidMessage(e.to_string()))
+    }
+
+    /// Records evidence against a replica from messages it signed. This replica never accuses itself.
+    fn accuse(&self, replica: &str, kind: EvidenceKind, detail: String, proof: &[&Envelope]) {
+        if replica == self.me() {
+            return;
+        }
+        let proof = proof.iter().map(|envelope| serde_json::to_string(envelope).expect("envelopes serialize")).collect();
+        self.context.cluster.evidence.record(replica, kind, detail, proof);
+    }
+
+    /// Opens a message relayed inside `relay`. The replica that signed `relay` vouched for it, so a
+    /// relayed message that does not open is evidence against that replica.
+    fn open_relayed(&self, relay: &Envelope, envelope: &Envelope) -> Result<Message, OrderingError> {
+        self.open(envelope).inspect_err(|e| {
+            self.accuse(&relay.replica, EvidenceKind::InvalidSignature, format!("relayed a message that does not check out: {}", e), &[relay]);
+        })
+    }
+
+    /// Handles the queued local messages until none is left.
+    fn drain(&mut self) {
+        while let Some((envelope, message)) = self.inbox.pop_front() {
+            self.handle(envelope, message);
+        }
+    }
+
+    fn handle(&mut self, envelope: Envelope, message: Message) {
+        let sender = envelope.replica.clone();
+        match message {
+            Message::PrePrepare { view, seq, digest, operation } => self.on_pre_prepare(envelope, view, seq, digest, operation),
+            Message::Prepare { view, seq, digest } => self.on_prepare(envelope, view, seq, digest),
+            Message::Commit { view, seq, digest } => self.on_commit(envelope, view, seq, digest),
+            Message::Checkpoint { seq, state } => self.on_checkpoint(envelope, seq, state),
+            Message::ViewChange(view_change) => self.on_view_change(envelope, view_change),
+            Message::NewView { view, view_changes, pre_prepares } => {
+                if let Err(e) = self.on_new_view(envelope, view, view_changes, pre_prepares) {
+                    warn!("Ignoring new view {} from {}: {}", view, sender, e);
+                }
+            },
+        }
+        self.advance();
+    }
+
+    /// Accepts normal-case messages of the current view, buffering those of later views.
+    fn accepts(&mut self, envelope: &Envelope, view: u64, seq: u64) -> bool {
+        let replica = &mut *self.replica;
+        if view > replica.view || (view == replica.view && !replica.active) {
+            if replica.future.len() < KEPT_FUTURE {
+                replica.future.push(envelope.clone());
+            }
+            return false;
+        }
+        view == replica.view && seq > replica.stable.seq && seq <= replica.stable.seq + self.context.config.window
+    }
+
+    fn on_pre_prepare(&mut self, envelope: Envelope, view: u64, seq: u64, digest: String, operation: Option<Operation>) {
+        if envelope.replica != self.context.members.primary(view) || !self.accepts(&envelope, view, seq) {
+            return;
+        }
+        if digest != digest_of(&operation) {
+            warn!("Pre-prepare {} from {} does not match its operation", seq, envelope.replica);
+            return;
+        }
+        if let Some(Err(e)) = operation.as_ref().map(|operation| self.context.members.verify_origin(self.context.identity, operation)) {
+            warn!("Pre-prepare {} from {} holds a forged operation: {}", seq, envelope.replica, e);
+            self.accuse(&envelope.replica, EvidenceKind::ForgedOperation, format!("pre-prepare {} in view {}: {}", seq, view, e), &[&envelope]);
+            return;
+        }
+        let slot = self.replica.log.entry(seq).or_default();
+        if let Some((accepted, _, first)) = &slot.pre_prepare {
+            if *accepted != digest {
+                warn!("Primary {} proposed conflicting operations for {} in view {}", envelope.replica, seq, view);
+                let first = first.clone();
+                self.accuse(&envelope.replica, EvidenceKind::Equivocation, format!("two pre-prepares for {} in view {}", seq, view), &[&first, &envelope]);
+                self.start_view_change(view + 1);
+            }
+            return;
+        }
+        slot.pre_prepare = Some((digest.clone(), operation, envelope));
+        if !self.is_primary(view) {
+            if seq > self.replica.executed {
+                self.replica.waiting.entry(digest.clone()).or_insert_with(Instant::now);
+            }
+            self.emit(Message::Prepare { view, seq, digest });
+        }
+    }
+
+    fn on_prepare(&mut self, envelope: Envelope, view: u64, seq: u64, digest: String) {
+        if envelope.replica == self.context.members.primary(view) || !self.accepts(&envelope, view, seq) {
+            return;
+        }
+        let slot = self.replica.log.entry(seq).or_default();
+        if let Some((prepared, first)) = slot.prepares.get(&envelope.replica) {
+            if *prepared != digest {
+                let first = first.clone();
+                self.accuse(&envelope.replica, EvidenceKind::Equivocation, format!("two prepares for {} in view {}", seq, view), &[&first, &envelope]);
+            }
+            return;
+        }
+        slot.prepares.insert(envelope.replica.clone(), (digest, envelope));
+    }
+
+    fn on_commit(&mut self, envelope: Envelope, view: u64, seq: u64, digest: String) {
+        if !self.accepts(&envelope, view, seq) {
+            return;
+        }
+        let slot = self.replica.log.entry(seq).or_default();
+        if let Some((committed, first)) = slot.commits.get(&envelope.replica) {
+            if *committed != digest {
+                let first = first.clone();
+                self.accuse(&envelope.replica, EvidenceKind::Equivocation, format!("two commits for {} in view {}", seq, view), &[&first, &envelope]);
+            }
+            return;
+        }
+        slot.commits.insert(envelope.replica.clone(), (digest, envelope));
+    }
+
+    /// Commits prepared slots and executes committed ones in sequence order.
+    fn advance(&mut self) {
+        let quorum = self.context.members.quorum;
+        let view = self.replica.view;
+        let mut prepared = vec![];
+        for (seq, slot) in self.replica.log.iter_mut() {
+            let Some((digest, _, pre_prepare)) = &slot.pre_prepare else {
+                continue;
+            };
+            if slot.commit_sent {
+                continue;
+            }
+            let prepares: Vec<Envelope> = slot.prepares.values()
+                .filter(|(prepared, _)| prepared == digest)
+                .map(|(_, envelope)| envelope.clone())
+                .collect();
+            if prepares.len() + 1 >= quorum {
+                slot.commit_sent = true;
+                prepared.push((*seq, digest.clone(), PreparedProof { pre_prepare: pre_prepare.clone(), prepares }));
+            }
+        }
+        for (seq, digest, proof) in prepared {
+            self.replica.prepared.insert(seq, proof);
+            self.emit(Message::Commit { view, seq, digest });
+        }
+        loop {
+            let seq = self.replica.executed + 1;
+            let Some(slot) = self.replica.log.get(&seq) else {
+                break;
+            };
+            let Some((digest, operation, _)) = &slot.pre_prepare else {
+                break;
+            };
+            if !slot.commit_sent || slot.commits.values().filter(|(committed, _)| committed == digest).count() < quorum {
+                break;
+            }
+            let (digest, operation) = (digest.clone(), operation.clone());
+            self.execute(seq, digest, operation);
+        }
+    }
+
+    fn execute(&mut self, seq: u64, digest: String, operation: Option<Operation>) {
+        let json = operation.as_ref().map(|operation| serde_json::to_string(operation).expect("operations serialize"));
+        let origin = operation.as_ref().map(|operation| operation.origin.clone());
+        let state = chain(&self.replica.state, &digest);
+        let result = {
+            let db = self.context.db.lock().unwrap();
+            let result = operation.map_or(Ok(()), |operation| apply(&db, operation));
+            let ordered = OrderedOperation {
+                seq,
+                view: self.replica.view,
+                origin,
+                digest: digest.clone(),
+                operation: json,
+                prev: self.replica.state.clone(),
+                state: state.clone(),
+            };
+            if let Err(e) = db.push_ordered(&ordered) {
+                error!("Could not record ordered operation {}: {}", seq, e);
+            }
+            result
+        };
+        match &result {
+            Ok(()) => debug!("Executed operation {} ({})", seq, &digest),
+            Err(reason) => info!("Operation {} was rejected: {}", seq, reason),
+        }
+        let replica = &mut *self.replica;
+        replica.executed = seq;
+        replica.state = state.clone();
+        replica.next_seq = replica.next_seq.max(seq + 1);
+        replica.waiting.remove(&digest);
+        replica.results.insert(seq, (digest, result));
+        while replica.results.len() > KEPT_RESULTS {
+            replica.results.pop_first();
+        }
+        self.executed = true;
+        if seq.is_multiple_of(self.context.config.checkpoint_interval) {
+            self.emit(Message::Checkpoint { seq, state });
+        }
+    }
+
+    fn on_checkpoint(&mut self, envelope: Envelope, seq: u64, state: String) {
+        if seq <= self.replica.stable.seq {
+            return;
+        }
+        let votes = self.replica.checkpoints.entry(seq).or_default();
+        votes.entry(envelope.replica.clone()).or_insert((state.clone(), envelope));
+        let proof: Vec<Envelope> = votes.values()
+            .filter(|(voted, _)| *voted == state)
+            .map(|(_, envelope)| envelope.clone())
+            .collect();
+        if proof.len() < self.context.members.quorum {
+            return;
+        }
+        let conflicting: Vec<Envelope> = votes.values()
+            .filter(|(voted, _)| *voted != state)
+            .map(|(_, envelope)| envelope.clone())
+            .collect();
+        let mut agreed: Vec<&Envelope> = proof.iter().collect();
+        agreed.sort_by(|a, b| a.replica.cmp(&b.replica));
+        for envelope in &conflicting {
+            let mut evidence: Vec<&Envelope> = vec![envelope];
+            evidence.extend(agreed.iter().copied());
+            self.accuse(&envelope.replica, EvidenceKind::ConflictingCheckpoint, format!("checkpoint {} differs from a quorum", seq), &evidence);
+        }
+        let checkpoint = Checkpoint { seq, state, proof };
+        if seq > self.replica.executed {
+            if self.replica.behind.as_ref().is_none_or(|behind| behind.seq < seq) {
+                info!("Replicas agree on operation {} while this replica executed {}", seq, self.replica.executed);
+                self.replica.behind = Some(checkpoint);
+            }
+            return;
+        }
+        self.stabilize(checkpoint);
+    }
+
+    /// Makes a checkpoint the replica reached stable, discarding the protocol state before it.
+    fn stabilize(&mut self, checkpoint: Checkpoint) {
+        let local = match checkpoint.seq == self.replica.executed {
+            true => Ok(Some(self.replica.state.clone())),
+            false => self.context.db.lock().unwrap().get_ordered_state(checkpoint.seq),
+        };
+        match local {
+            Ok(Some(local)) if local == checkpoint.state => (),
+            Ok(local) => {
+                error!(
+                    "Local state {:?} after operation {} differs from the state {} a quorum agreed on",
+                    local, checkpoint.seq, &checkpoint.state,
+                );
+                return;
+            },
+            Err(e) => {
+                error!("Could not read the state after operation {}: {}", checkpoint.seq, e);
+                return;
+            },
+        }
+        debug!("Checkpoint {} is stable", checkpoint.seq);
+        let json = serde_json::to_string(&checkpoint).expect("checkpoints serialize");
+        if let Err(e) = self.context.db.lock().unwrap().set_meta(CHECKPOINT_KEY, &json) {
+            error!("Could not persist checkpoint {}: {}", checkpoint.seq, e);
+        }
+        let replica = &mut *self.replica;
+        let seq = checkpoint.seq;
+        replica.log.retain(|slot, _| *slot > seq);
+        replica.prepared.retain(|slot, _| *slot > seq);
+        replica.checkpoints.retain(|slot, _| *slot > seq);
+        if replica.behind.as_ref().is_some_and(|behind| behind.seq <= seq) {
+            replica.behind = None;
+        }
+        replica.stable = checkpoint;
+    }
+
+    /// Stops taking part in the current view and votes for `view`.
+    fn start_view_change(&mut self, view: u64) {
+        if view <= self.replica.view {
+            return;
+        }
+        info!("Moving to view {}, led by {}", view, self.context.members.primary(view));
+        let replica = &mut *self.replica;
+        if replica.active {
+            replica.last_active = replica.view;
+        }
+        replica.view = view;
+        replica.active = false;
+        replica.view_started = Instant::now();
+        self.persist_view();
+        let stable = &self.replica.stable;
+        let view_change = ViewChange {
+            view,
+            checkpoint: stable.seq,
+            state: stable.state.clone(),
+            checkpoint_proof: stable.proof.clone(),
+            prepared: self.replica.prepared.range(stable.seq + 1..).map(|(_, proof)| proof.clone()).collect(),
+        };
+        self.emit(Message::ViewChange(view_change));
+    }
+
+    fn persist_view(&self) {
+        if let Err(e) = self.context.db.lock().unwrap().set_meta(VIEW_KEY, &self.replica.view.to_string()) {
+            error!("Could not persist view {}: {}", self.replica.view, e);
+        }
+    }
+
+    /// Checks a view change carries a proven checkpoint and well-formed prepared proofs.
+    fn validate_view_change(&self, relay: &Envelope, view_change: &ViewChange) -> Result<(), OrderingError> {
+        let sender = &relay.replica;
+        let invalid = |reason: &str| OrderingError::InvalidMessage(format!("view change from {}: {}", sender, reason));
+        let members = &self.context.members;
+        if view_change.checkpoint == 0 {
+            if view_change.state != genesis() {
+                return Err(invalid("unexpected initial state"));
+            }
+        } else {
+            let mut signers = HashSet::new();
+            for envelope in &view_change.checkpoint_proof {
+                if let Message::Checkpoint { seq, state } = self.open_relayed(relay, envelope)? {
+                    if seq == view_change.checkpoint && state == view_change.state {
+                        signers.insert(envelope.replica.clone());
+                    }
+                }
+            }
+            if signers.len() < members.quorum {
+                return Err(invalid("checkpoint is not proven by a quorum"));
+            }
+        }
+        let mut seqs = HashSet::new();
+        for proof in &view_change.prepared {
+            let Message::PrePrepare { view, seq, digest, operation } = self.open_relayed(relay, &proof.pre_prepare)? else {
+                return Err(invalid("prepared proof without a pre-prepare"));
+            };
+            if proof.pre_prepare.replica != members.primary(view) || view >= view_change.view {
+                return Err(invalid("pre-prepare not sent by the primary of an earlier view"));
+            }
+            if seq <= view_change.checkpoint || seq > view_change.checkpoint + self.context.config.window || !seqs.insert(seq) {
+                return Err(invalid("prepared operation outside the window"));
+            }
+            if digest != digest_of(&operation) {
+                return Err(invalid("pre-prepare does not match its operation"));
+            }
+            let mut signers = HashSet::new();
+            for envelope in &proof.prepares {
+                if let Message::Prepare { view: prepared_view, seq: prepared_seq, digest: prepared } = self.open_relayed(relay, envelope)? {
+                    if prepared_view == view && prepared_seq == seq && prepared == digest && envelope.replica != members.primary(view) {
+                        signers.insert(envelope.replica.clone());
+                    }
+                }
+            }
+            if signers.len() + 1 < members.quorum {
+                return Err(invalid("operation is not prepared by a quorum"));
+            }
+        }
+        Ok(())
+    }
+
+    fn on_view_change(&mut self, envelope: Envelope, view_change: ViewChange) {
+        let sender = envelope.replica.clone();
+        if view_change.view < self.replica.view || (view_change.view == self.replica.view && self.replica.active) {
+            return;
+        }
+        if let Err(e) = self.validate_view_change(&envelope, &view_change) {
+            warn!("{}", e);
+            return;
+        }
+        let view = view_change.view;
+        self.replica.view_changes.entry(view).or_default().insert(sender.clone(), view_change);
+        self.replica.view_change_envelopes.entry(view).or_default().insert(sender, envelope);
+        // Join as soon as f + 1 replicas want a later view: at least one of them is correct.
+        let current = self.replica.view;
+        let mut voters = HashSet::new();
+        let mut lowest = None;
+        for (view, votes) in self.replica.view_changes.range(current + 1..) {
+            voters.extend(votes.keys().filter(|voter| **voter != self.context.members.node_id).cloned());
+            lowest = lowest.or(Some(*view));
+        }
+        if let Some(lowest) = lowest.filter(|_| voters.len() > self.context.members.faults) {
+            self.start_view_change(lowest);
+        }
+        self.announce_view();
+    }
+
+    /// Sends the new view once this replica leads the view it is changing to and holds a quorum
+    /// of view changes for it.
+    fn announce_view(&mut self) {
+        let view = self.replica.view;
+        if self.replica.active || !self.is_primary(view) || self.replica.new_view_sent == Some(view) {
+            return;
+        }
+        let Some(envelopes) = self.replica.view_change_envelopes.get(&view) else {
+            return;
+        };
+        if envelopes.len() < self.context.members.quorum {
+            return;
+        }
+        let view_changes: Vec<Envelope> = envelopes.values().cloned().collect();
+        let votes: Vec<ViewChange> = self.replica.view_changes[&view].values().cloned().collect();
+        let (_, plan) = plan(&votes);
+        let pre_prepares = plan.into_iter()
+            .map(|(seq, digest, operation)| {
+                let payload = serde_json::to_string(&Message::PrePrepare { view, seq, digest, operation }).expect("ordering messages serialize");
+                let signature = self.context.identity.sign(Envelope::message(self.me(), &payload).as_bytes());
+                Envelope { replica: self.me().to_string(), payload, signature }
+            })
+            .collect();
+        self.replica.new_view_sent = Some(view);
+        info!("Starting view {} from {} view changes", view, view_changes.len());
+        self.emit(Message::NewView { view, view_changes, pre_prepares });
+    }
+
+    fn on_new_view(&mut self, new_view: Envelope, view: u64, view_changes: Vec<Envelope>, pre_prepares: Vec<Envelope>) -> Result<(), OrderingError> {
+        let invalid = |reason: &str| OrderingError::InvalidMessage(reason.to_string());
+        let sender = new_view.replica.as_str();
+        if sender != self.context.members.primary(view) {
+            return Err(invalid("not sent by the primary of the view"));
+        }
+        if view < self.replica.view || (view == self.replica.view && self.replica.active) {
+            return Ok(());
+        }
+        let mut votes = HashMap::new();
+        for envelope in &view_changes {
+            let Message::ViewChange(view_change) = self.open_relayed(&new_view, envelope)? else {
+                return Err(invalid("view changes hold another message"));
+            };
+            if view_change.view != view {
+                return Err(invalid("view change for another view"));
+            }
+            self.validate_view_change(envelope, &view_change)?;
+            votes.insert(envelope.replica.clone(), view_change);
+        }
+        if votes.len() < self.context.members.quorum {
+            return Err(invalid("fewer view changes than a quorum"));
+        }
+        let votes: Vec<ViewChange> = votes.into_values().collect();
+        let (base, plan) = plan(&votes);
+        let base = Checkpoint { seq: base.checkpoint, state: base.state.clone(), proof: base.checkpoint_proof.clone() };
+        let mut proposed = vec![];
+        for envelope in &pre_prepares {
+            let Message::PrePrepare { view: proposed_view, seq, digest, operation } = self.open_relayed(&new_view, envelope)? else {
+                return Err(invalid("pre-prepares hold another message"));
+            };
+            if envelope.replica != sender || proposed_view != view || digest != digest_of(&operation) {
+                return Err(invalid("malformed pre-prepare"));
+            }
+            proposed.push((seq, digest));
+        }
+        let expected: Vec<(u64, String)> = plan.iter().map(|(seq, digest, _)| (*seq, digest.clone())).collect();
+        if proposed != expected {
+            return Err(invalid("pre-prepares differ from the view changes"));
+        }
+        let last = expected.last().map_or(0, |(seq, _)| *seq);
+        self.enter_view(view, base, pre_prepares, last, new_view);
+        Ok(())
+    }
+
+    fn enter_view(&mut self, view: u64, base: Checkpoint, pre_prepares: Vec<Envelope>, last: u64, new_view: Envelope) {
+        info!("Entered view {}, led by {}", view, self.context.members.primary(view));
+        if base.seq > self.replica.stable.seq {
+            match base.seq <= self.replica.executed {
+                true => self.stabilize(base),
+                false => self.replica.behind = Some(base),
+            }
+        }
+        let now = Instant::now();
+        let replica = &mut *self.replica;
+        replica.view = view;
+        replica.active = true;
+        replica.last_active = view;
+        replica.view_started = now;
+        replica.next_seq = last.max(replica.executed) + 1;
+        replica.log.clear();
+        let json = serde_json::to_string(&new_view).expect("envelopes serialize");
+        replica.new_view = Some(new_view);
+        if let Err(e) = self.context.db.lock().unwrap().set_meta(NEW_VIEW_KEY, &json) {
+            error!("Could not persist the new view message of view {}: {}", view, e);
+        }
+        // Operations the new view drops are not waited for; their clients were answered with an error.
+        replica.waiting.clear();
+        replica.view_changes.retain(|voted, _| *voted > view);
+        replica.view_change_envelopes.retain(|voted, _| *voted > view);
+        self.persist_view();
+        for envelope in pre_prepares {
+            if let Ok(message) = serde_json::from_str(&envelope.payload) {
+                self.inbox.push_back((envelope, message));
+            }
+        }
+        let future = std::mem::take(&mut self.replica.future);
+        for envelope in future {
+            if let Ok(message) = self.open(&envelope) {
+                self.inbox.push_back((envelope, message));
+            }
+        }
+    }
+
+    /// Assigns the next sequence number to a client write. Only the primary of an active view does.
+    fn propose(&mut self, operation: Operation) -> Result<u64, OrderingError> {
+        let view = self.replica.view;
+        if !self.replica.active {
+            return Err(OrderingError::ViewChange(view));
+        }
+        if !self.is_primary(view) {
+            let id = self.context.members.primary(view).to_string();
+            let address = self.context.members.addresses[&id].clone();
+            return Err(OrderingError::NotPrimary { id, address, view });
+        }
+        let seq = self.replica.next_seq.max(self.replica.executed + 1);
+        if seq > self.replica.stable.seq + self.context.config.window {
+            return Err(OrderingError::Busy);
+        }
+        self.replica.next_seq = seq + 1;
+        let operation = Some(operation);
+        let digest = digest_of(&operation);
+        self.emit(Message::PrePrepare { view, seq, digest, operation });
+        Ok(seq)
+    }
+
+    /// Starts the view change timer for a write forwarded to the primary.
+    fn watch(&mut self, digest: &str) {
+        if !self.is_primary(self.replica.view) {
+            self.replica.waiting.entry(digest.to_string()).or_insert_with(Instant::now);
+        }
+    }
+
+    /// Starts a view change when an accepted operation waits too long, the primary is dead,
+    /// or the view being changed to does not start in time.
+    fn tick(&mut self) {
+        let timeout = Duration::from_millis(self.context.config.view_timeout_ms);
+        let view = self.replica.view;
+        if !self.replica.active {
+            let attempts = (view - self.replica.last_active).saturating_sub(1).min(5) as u32;
+            if self.replica.view_started.elapsed() > timeout * 2u32.pow(attempts) {
+                self.start_view_change(view + 1);
+            }
+            return;
+        }
+        if self.is_primary(view) {
+            return;
+        }
+        if self.replica.waiting.values().any(|since| since.elapsed() > timeout) {
+            warn!("Operations accepted in view {} were not executed in time", view);
+            self.start_view_change(view + 1);
+            return;
+        }
+        let primary = self.context.members.primary(view);
+        if self.context.members.addresses.get(primary).is_some_and(|address| self.context.cluster.detector.is_dead(address)) {
+            warn!("Primary {} of view {} is dead", primary, view);
+            self.start_view_change(view + 1);
+        }
+    }
+
+    /// Returns the replicas ahead of this one when more than `f` of them sent messages of later
+    /// views, so at least one correct replica moved on and the view can be fetched from them.
+    fn ahead(&self) -> Vec<String> {
+        let view = self.replica.view;
+        let ahead: HashSet<String> = self.replica.future.iter()
+            .filter(|envelope| serde_json::from_str::<Message>(&envelope.payload).ok().and_then(|message| message.view()) > Some(view))
+            .map(|envelope| envelope.replica.clone())
+            .collect();
+        match ahead.len() > self.context.members.faults {
+            true => ahead.into_iter().collect(),
+            false => vec![],
+        }
+    }
+}
+
+/// Where the operation logs of two replicas part, reported by `/ordering/compare`.
+#[derive(Serialize)]
+pub struct Divergence {
+    pub peer: String,
+    pub executed: u64, // Last entry of the local log.
+    pub peer_executed: u64,
+    pub agreed: u64, // Last entry both logs hold with the same hash, and so every entry before it.
+    pub diverged_at: Option<u64>, // First entry the logs differ at; none if one log extends the other.
+    pub local: Option<OrderedOperation>, // Local and peer entries at `diverged_at`.
+    pub remote: Option<OrderedOperation>,
+}
+
+/// Outcome of rebuilding a database from an operation log.
+#[derive(Serialize)]
+pub struct ReplayReport {
+    pub entries: u64,
+    pub applied: u64,
+    pub rejected: u64, // Operations every replica rejected when they were executed.
+    pub state: String, // Hash of the last entry.
+}
+
+/// Errors for replaying an operation log.
+#[derive(Error, Debug, derive_more::Display)]
+pub enum ReplayError {
+    #[display(fmt = "Operation log entry {} is corrupt: {}", _0, _1)]
+    Corrupt(u64, String),
+    QueryFailed(QuerryError),
+}
+
+/// Replays the operation log of `source` into the empty database `target`, checking every entry
+/// chains to the one before it. Operations are applied as when they were first executed, so the
+/// target ends with the state the log describes and a copy of the log itself.
+pub fn replay(source: &DbHandle, target: &DbHandle) -> Result<ReplayReport, ReplayError> {
+    let query = |e: rusqlite::Error| ReplayError::QueryFailed(e.into());
+    let mut report = ReplayReport { entries: 0, applied: 0, rejected: 0, state: genesis() };
+    loop {
+        let page = source.get_ordered(report.entries, i64::MAX as u64, LOG_PAGE).map_err(query)?;
+        if page.is_empty() {
+            return Ok(report);
+        }
+        for entry in page {
+            let corrupt = |reason: &str| ReplayError::Corrupt(entry.seq, reason.to_string());
+            if entry.seq != report.entries + 1 {
+                return Err(corrupt(&format!("expected entry {}", report.entries + 1)));
+            }
+            if entry.prev != report.state {
+                return Err(corrupt("does not chain to the entry before it"));
+            }
+            if entry.digest != digest(entry.operation.as_deref()) || entry.state != chain(&entry.prev, &entry.digest) {
+                return Err(corrupt("hash does not match its operation"));
+            }
+            if let Some(json) = &entry.operation {
+                let operation: Operation = serde_json::from_str(json).map_err(|e| corrupt(&e.to_string()))?;
+                match apply(target, operation) {
+                    Ok(()) => report.applied += 1,
+                    Err(reason) => {
+                        debug!("Operation {} is rejected again: {}", entry.seq, reason);
+                        report.rejected += 1;
+                    },
+                }
+            }
+            target.push_ordered(&entry).map_err(query)?;
+            report.entries = entry.seq;
+            report.state = entry.state;
+        }
+    }
+}
+
+/// The entry every log starts from, before any operation.
+fn genesis_entry() -> OrderedOperation {
+    OrderedOperation { seq: 0, view: 0, origin: None, digest: NULL_DIGEST.to_string(), operation: None, prev: String::new(), state: genesis() }
+}
+
+/// Replica state reported by `/ordering/status`.
+#[derive(Serialize, Deserialize)]
+pub struct OrderingStatus {
+    pub enabled: bool,
+    pub view: u64,
+    pub primary: String,
+    pub changing_view: bool,
+    pub executed: u64,
+    pub state: String,
+    pub stable_checkpoint: u64,
+    pub in_flight: usize, // Operations with a pre-prepare that are not executed yet.
+    pub behind: Option<u64>, // Stable checkpoint the replica is catching up to.
+}
+
+/// Total order of client writes across the cluster, after PBFT: the primary of the view assigns
+/// sequence numbers, replicas agree on them in pre-prepare, prepare and commit phases, and
+/// execute committed writes in order. Quorums of `ceil((n + f + 1) / 2)` replicas keep correct
+/// replicas in agreement with up to `f` byzantine ones, and replicas replace a primary that
+/// stops making progress through view changes.
+#[derive(Clone)]
+pub struct Ordering {
+    replica: Arc<Mutex<Replica>>,
+    cluster: Cluster,
+    db: Arc<Mutex<DbHandle>>,
+    identity: Arc<NodeIdentity>,
+    config: OrderingConfig,
+    request_timeout: Duration,
+    executed: Arc<Notify>, // Wakes writes waiting for their operation to execute.
+    catching_up: Arc<AtomicBool>,
+    syncing: Arc<AtomicBool>, // Set while fetching the current view from replicas ahead.
+}
+
+impl Ordering {
+    /// Restores the view, executed operations and stable checkpoint persisted in `db`.
+    pub fn new(config: &Config, cluster: Cluster, db: Arc<Mutex<DbHandle>>, identity: Arc<NodeIdentity>) -> Result<Self, QuerryError> {
+        let replica = {
+            let db = db.lock().unwrap();
+            let view = db.get_meta(VIEW_KEY)?.and_then(|view| view.parse().ok()).unwrap_or(0);
+            let (executed, state) = db.get_last_ordered()?.unwrap_or_else(|| (0, genesis()));
+            let stable = db.get_meta(CHECKPOINT_KEY)?
+                .and_then(|json| serde_json::from_str::<Checkpoint>(&json).ok())
+                .filter(|checkpoint| checkpoint.seq <= executed)
+                .unwrap_or(Checkpoint { seq: 0, state: genesis(), proof: vec![] });
+            let new_view = db.get_meta(NEW_VIEW_KEY)?.and_then(|json| serde_json::from_str::<Envelope>(&json).ok());
+            if config.ordering.enabled {
+                info!("Ordering resumes in view {} after operation {}", view, executed);
+            }
+            Replica {
+                view,
+                active: true,
+                last_active: view,
+                view_started: Instant::now(),
+                next_seq: executed + 1,
+                executed,
+                state,
+                stable,
+                log: BTreeMap::new(),
+                prepared: BTreeMap::new(),
+                checkpoints: BTreeMap::new(),
+                view_changes: BTreeMap::new(),
+                view_change_envelopes: BTreeMap::new(),
+                waiting: HashMap::new(),
+                results: BTreeMap::new(),
+                future: vec![],
+                behind: None,
+                new_view_sent: None,
+                new_view,
+            }
+        };
+        Ok(Ordering {
+            replica: Arc::new(Mutex::new(replica)),
+            cluster,
+            db,
+            identity,
+            config: config.ordering.clone(),
+            request_timeout: Duration::from_millis(config.timeouts.request_ms),
+            executed: Arc::new(Notify::new()),
+            catching_up: Arc::new(AtomicBool::new(false)),
+            syncing: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    /// Whether client writes go through the ordering layer.
+    pub fn is_enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    /// Runs `action` on the replica, handles the messages it sends itself, then queues the
+    /// messages for peers. The replica stays locked until they are queued so peers receive
+    /// them in the order they were produced.
+    fn step<T>(&self, action: impl FnOnce(&mut Step) -> T) -> T {
+        let mut replica = self.replica.lock().unwrap();
+        let members = Members::of(&self.cluster);
+        let mut step = Step {
+            replica: &mut replica,
+            context: Context { members, identity: &self.identity, db: &self.db, config: &self.config, cluster: &self.cluster },
+            inbox: VecDeque::new(),
+            outgoing: vec![],
+            executed: false,
+        };
+        let result = action(&mut step);
+        step.drain();
+        let Step { outgoing, executed, context, .. } = step;
+        let addresses = context.members.peer_addresses();
+        for envelope in outgoing {
+            if let Err(e) = sender::send(&self.cluster, &addresses, "/ordering/message", &envelope) {
+                error!("Could not queue an ordering message: {}", e);
+            }
+        }
+        if executed {
+            self.executed.notify_waiters();
+        }
+        if let Some(target) = replica.behind.clone() {
+            if !self.catching_up.swap(true, AtomicOrdering::SeqCst) {
+                tokio::spawn(self.clone().catch_up(target));
+            }
+        }
+        result
+    }
+
+    /// Orders a client write and waits until this replica executed it, so reads from this node
+    /// see it. The write is signed as originating from this node. Other replicas than the primary
+    /// forward the write to it and vote for a view change if it is not executed in time. Returns
+    /// its sequence number.
+    pub async fn order(&self, record: Record) -> Result<u64, OrderingError> {
+        self.order_against(record, None).await
+    }
+
+    /// Orders a sequence update that only applies if the sequence is still at version `base`
+    /// when the update executes, so concurrent updates made against the same version do not
+    /// overwrite each other. Otherwise the update is rejected with the current version.
+    pub async fn order_against(&self, record: Record, base: Option<String>) -> Result<u64, OrderingError> {
+        if !self.config.enabled {
+            return Err(OrderingError::Disabled);
+        }
+        let origin = self.cluster.node_id.clone();
+        let signature = self.identity.sign(Operation::message(&origin, base.as_deref(), &record).as_bytes());
+        let operation = Operation { origin, record, base, signature };
+        let digest = digest_of(&Some(operation.clone()));
+        let deadline = Instant::now() + self.request_timeout;
+        loop {
+            let result = match self.step(|step| step.propose(operation.clone())) {
+                Ok(seq) => return self.wait(seq, &digest).await,
+                Err(OrderingError::NotPrimary { address, .. }) => {
+                    self.step(|step| step.watch(&digest));
+                    self.forward(&address, &operation).await
+                },
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(seq) => return self.wait(seq, &digest).await,
+                Err(e) if e.is_transient() && Instant::now() + FORWARD_RETRY < deadline => {
+                    debug!("Retrying write: {}", e);
+                    tokio::time::sleep(FORWARD_RETRY).await;
+                },
+                Err(e) => {
+                    if let OrderingError::Forwarded(status, _) = e {
+                        if status < 500 {
+                            self.step(|step| step.replica.waiting.remove(&digest));
+                        }
+                    }
+                    return Err(e);
+                },
+            }
+        }
+    }
+
+    /// Orders a write forwarded by another replica once its origin signature checks out. Only the
+    /// primary accepts it.
+    pub async fn order_forwarded(&self, operation: Operation) -> Result<u64, OrderingError> {
+        if !self.config.enabled {
+            return Err(OrderingError::Disabled);
+        }
+        Members::of(&self.cluster).verify_origin(&self.identity, &operation).map_err(OrderingError::InvalidMessage)?;
+        let digest = digest_of(&Some(operation.clone()));
+        let seq = self.step(|step| step.propose(operation))?;
+        self.wait(seq, &digest).await
+    }
+
+    /// Sends a write to the primary at `address`, returning the sequence number it was executed at.
+    async fn forward(&self, address: &str, operation: &Operation) -> Result<u64, OrderingError> {
+        let unreachable = |e: reqwest::Error| OrderingError::Unreachable(address.to_string(), e.to_string());
+        let response = self.cluster.client.post(self.cluster.url(address, "/ordering/request"))
+            .json(operation)
+            .send()
+            .await
+            .map_err(unreachable)?;
+        let status = response.status();
+        if status.is_success() {
+            return response.json().await.map_err(unreachable);
+        }
+        Err(OrderingError::Forwarded(status.as_u16(), response.text().await.unwrap_or_default()))
+    }
+
+    /// Waits until this replica executed the operation with `digest` at `seq`.
+    async fn wait(&self, seq: u64, digest: &str) -> Result<u64, OrderingError> {
+        let deadline = Instant::now() + self.request_timeout;
+        loop {
+            let notified = self.executed.notified();
+            {
+                let replica = self.replica.lock().unwrap();
+                if let Some((executed, result)) = replica.results.get(&seq) {
+                    if executed != digest {
+                        return Err(OrderingError::ViewChange(replica.view));
+                    }
+                    return result.clone().map(|_| seq).map_err(OrderingError::Rejected);
+                }
+                if replica.executed >= seq {
+                    return Err(OrderingError::Timeout);
+                }
+            }
+            if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                return Err(OrderingError::Timeout);
+            }
+        }
+    }
+
+    /// Returns the new view message that started the current view.
+    pub fn new_view(&self) -> Option<Envelope> {
+        self.replica.lock().unwrap().new_view.clone()
+    }
+
+    /// Handles a message from a peer.
+    pub fn receive(&self, envelope: Envelope) -> Result<(), OrderingError> {
+        if !self.config.enabled {
+            return Err(OrderingError::Disabled);
+        }
+        self.step(|step| {
+            let message = step.open(&envelope)?;
+            step.handle(envelope, message);
+            Ok(())
+        })
+    }
+
+    /// Returns up to `limit` entries of the operation log after `after`, up to `until`.
+    pub fn log(&self, after: u64, until: u64, limit: usize) -> Result<Vec<OrderedOperation>, OrderingError> {
+        let until = until.min(i64::MAX as u64);
+        self.db.lock().unwrap().get_ordered(after, until, limit)
+            .map_err(|e| OrderingError::QueryFailed(e.into()))
+    }
+
+    /// Compares the operation log with the one of the peer at `address`, finding the first entry
+    /// they differ at. Entries chain every entry before them, so logs agreeing on an entry agree
+    /// on the whole log up to it and the search only compares a logarithmic number of entries.
+    pub async fn compare(&self, address: &str) -> Result<Divergence, OrderingError> {
+        let unreachable = |e: reqwest::Error| OrderingError::Unreachable(address.to_string(), e.to_string());
+        let response = self.cluster.client.get(self.cluster.url(address, "/ordering/status")).send().await.map_err(unreachable)?;
+        if !response.status().is_success() {
+            return Err(OrderingError::Forwarded(response.status().as_u16(), response.text().await.unwrap_or_default()));
+        }
+        let peer_executed = response.json::<OrderingStatus>().await.map_err(unreachable)?.executed;
+        let executed = self.replica.lock().unwrap().executed;
+        let (mut agreed, mut differs) = (0, executed.min(peer_executed));
+        if self.local_entry(differs)?.map(|entry| entry.state) != self.remote_entry(address, differs).await?.map(|entry| entry.state) {
+            while differs - agreed > 1 {
+                let seq = agreed + (differs - agreed) / 2;
+                match self.local_entry(seq)?.map(|entry| entry.state) == self.remote_entry(address, seq).await?.map(|entry| entry.state) {
+                    true => agreed = seq,
+                    false => differs = seq,
+                }
+            }
+        } else {
+            agreed = differs;
+        }
+        let diverged_at = (agreed < differs).then_some(differs);
+        let (local, remote) = match diverged_at {
+            Some(seq) => (self.local_entry(seq)?, self.remote_entry(address, seq).await?),
+            None => (None, None),
+        };
+        if let Some(seq) = diverged_at {
+            warn!("Operation log diverges from the one of {} at entry {}", address, seq);
+        }
+        Ok(Divergence { peer: address.to_string(), executed, peer_executed, agreed, diverged_at, local, remote })
+    }
+
+    /// Returns the local log entry at `seq`, the genesis entry for 0.
+    fn local_entry(&self, seq: u64) -> Result<Option<OrderedOperation>, OrderingError> {
+        if seq == 0 {
+            return Ok(Some(genesis_entry()));
+        }
+        Ok(self.log(seq - 1, seq, 1)?.pop())
+    }
+
+    /// Fetches the log entry at `seq` from the peer at `address`, the genesis entry for 0.
+    async fn remote_entry(&self, address: &str, seq: u64) -> Result<Option<OrderedOperation>, OrderingError> {
+        if seq == 0 {
+            return Ok(Some(genesis_entry()));
+        }
+        let unreachable = |e: reqwest::Error| OrderingError::Unreachable(address.to_string(), e.to_string());
+        let url = self.cluster.url(address, &format!("/ordering/log?after={}&until={}", seq - 1, seq));
+        let response = self.cluster.client.get(url).send().await.map_err(unreachable)?;
+        if !response.status().is_success() {
+            return Err(OrderingError::Forwarded(response.status().as_u16(), response.text().await.unwrap_or_default()));
+        }
+        Ok(response.json::<Vec<OrderedOperation>>().await.map_err(unreachable)?.pop())
+    }
+
+    pub fn status(&self) -> OrderingStatus {
+        let replica = self.replica.lock().unwrap();
+        let members = Members::of(&self.cluster);
+        OrderingStatus {
+            enabled: self.config.enabled,
+            view: replica.view,
+            primary: members.primary(replica.view).to_string(),
+            changing_view: !replica.active,
+            executed: replica.executed,
+            state: replica.state.clone(),
+            stable_checkpoint: replica.stable.seq,
+            in_flight: replica.log.range(replica.executed + 1..).filter(|(_, slot)| slot.pre_prepare.is_some()).count(),
+            behind: replica.behind.as_ref().map(|behind| behind.seq),
+        }
+    }
+
+    /// Checks for stalled views and catch-ups a quarter of the view timeout at a time.
+    pub fn spawn_timer(&self) {
+        if !self.config.enabled {
+            return;
+        }
+        let ordering = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_millis(ordering.config.view_timeout_ms / 4 + 1));
+            loop {
+                interval.tick().await;
+                let ahead = ordering.step(|step| {
+                    step.tick();
+                    step.ahead()
+                });
+                if !ahead.is_empty() && !ordering.syncing.swap(true, AtomicOrdering::SeqCst) {
+                    tokio::spawn(ordering.clone().sync_view(ahead));
+                }
+            }
+        });
+    }
+
+    /// Fetches the operations up to a stable checkpoint from the replicas that signed it,
+    /// checking they chain from the local state to the checkpoint state before executing them.
+    async fn catch_up(self, target: Checkpoint) {
+        let (executed, state) = {
+            let replica = self.replica.lock().unwrap();
+            (replica.executed, replica.state.clone())
+        };
+        let members = Members::of(&self.cluster);
+        let signers: Vec<String> = target.proof.iter()
+            .filter(|envelope| envelope.replica != self.cluster.node_id)
+            .filter_map(|envelope| members.addresses.get(&envelope.replica).cloned())
+            .collect();
+        let mut operations = None;
+        for address in signers {
+            match self.fetch(&address, executed, &state, &target).await {
+                Ok(fetched) => {
+                    operations = Some(fetched);
+                    break;
+                },
+                Err(e) => warn!("Could not catch up from {}: {}", address, e),
+            }
+        }
+        if let Some(operations) = operations {
+            let mut replica = self.replica.lock().unwrap();
+            if replica.executed == executed {
+                let db = self.db.lock().unwrap();
+                for ordered in &operations {
+                    let operation = ordered.operation.as_deref().and_then(|json| serde_json::from_str::<Operation>(json).ok());
+                    let result = operation.map_or(Ok(()), |operation| apply(&db, operation));
+                    if let Err(e) = db.push_ordered(ordered) {
+                        error!("Could not record ordered operation {}: {}", ordered.seq, e);
+                    }
+                    replica.results.insert(ordered.seq, (ordered.digest.clone(), result));
+                    replica.waiting.remove(&ordered.digest);
+                }
+                drop(db);
+                while replica.results.len() > KEPT_RESULTS {
+                    replica.results.pop_first();
+                }
+                info!("Caught up from operation {} to {}", executed, target.seq);
+                replica.executed = target.seq;
+                replica.state = target.state.clone();
+                replica.next_seq = replica.next_seq.max(target.seq + 1);
+            }
+            drop(replica);
+            self.step(|step| if step.replica.executed >= target.seq {
+                step.stabilize(target);
+            });
+            self.executed.notify_waiters();
+        }
+        self.catching_up.store(false, AtomicOrdering::SeqCst);
+    }
+
+    /// Fetches the new view message of a later view from the replicas ahead, for a replica that
+    /// missed it while it was down. The message is verified like any other.
+    async fn sync_view(self, ahead: Vec<String>) {
+        let members = Members::of(&self.cluster);
+        for address in ahead.iter().filter_map(|id| members.addresses.get(id)) {
+            let response = self.cluster.client.get(self.cluster.url(address, "/ordering/view")).send().await;
+            let new_view = match response {
+                Ok(response) if response.status().is_success() => response.json::<Option<Envelope>>().await.ok().flatten(),
+                _ => None,
+            };
+            let Some(new_view) = new_view else {
+                continue;
+            };
+            match self.receive(new_view) {
+                Ok(()) => break,
+                Err(e) => warn!("Could not take the view of {}: {}", address, e),
+            }
+        }
+        self.syncing.store(false, AtomicOrdering::SeqCst);
+    }
+
+    /// Downloads and verifies the operations after `after` up to the checkpoint from one peer.
+    async fn fetch(&self, address: &str, after: u64, state: &str, target: &Checkpoint) -> Result<Vec<OrderedOperation>, String> {
+        let (mut seq, mut state) = (after, state.to_string());
+        let mut operations = vec![];
+        while seq < target.seq {
+            let url = self.cluster.url(address, &format!("/ordering/log?after={}&until={}", seq, target.seq));
+            let response = self.cluster.client.get(url).send().await.map_err(|e| e.to_string())?;
+            if !response.status().is_success() {
+                return Err(format!("peer answered {}", response.status()));
+            }
+            let page: Vec<OrderedOperation> = response.json().await.map_err(|e| e.to_string())?;
+            if page.is_empty() {
+                return Err(format!("log ends at {}", seq));
+            }
+            for ordered in page {
+                if ordered.seq != seq + 1 || ordered.prev != state || ordered.digest != digest(ordered.operation.as_deref()) {
+                    return Err(format!("malformed operation {}", ordered.seq));
+                }
+                state = chain(&state, &ordered.digest);
+                seq = ordered.seq;
+                operations.push(OrderedOperation { state: state.clone(), ..ordered });
+            }
+        }
+        if state != target.state {
+            return Err(format!("operations do not lead to the state of checkpoint {}", target.seq));
+        }
+        Ok(operations)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{PeerConfig, QuorumConfig};
+
+    fn set(ids: &[&str], quorum: QuorumConfig) -> MemberSet {
+        let members = ids.iter().enumerate()
+            .map(|(i, id)| PeerConfig { id: id.to_string(), address: format!("127.0.0.1:{}", 8080 + i), public_key: None })
+            .collect();
+        MemberSet { members, quorum }
+    }
+
+    fn members(n: usize) -> Members {
+        let ids: Vec<String> = (0..n).map(|i| format!("node{}", i)).collect();
+        let ids: Vec<&str> = ids.iter().map(String::as_str).collect();
+        Members::new("node0".to_string(), &set(&ids, QuorumConfig::default()))
+    }
+
+    #[test]
+    fn quorum_sizes() {
+        let sizes: Vec<(usize, usize)> = (1..=7).map(members).map(|m| (m.faults, m.quorum)).collect();
+        assert_eq!(sizes, [(0, 1), (0, 2), (0, 2), (1, 3), (1, 4), (1, 4), (2, 5)]);
+    }
+
+    #[test]
+    fn quorums_intersect_in_a_correct_replica_and_stay_reachable() {
+        for n in 1..=16 {
+            let members = members(n);
+            let (f, q) = (members.faults, members.quorum);
+            assert!(2 * q > n + f, "n = {}: two quorums share no correct replica", n);
+            assert!(q <= n - f, "n = {}: a quorum needs a faulty replica", n);
+        }
+    }
+
+    #[test]
+    fn configured_faults_override_the_derived_ones() {
+        let members = Members::new("a".to_string(), &set(&["a", "b", "c", "d", "e", "f", "g"], QuorumConfig { faults: Some(0), acks: None }));
+        assert_eq!((members.faults, members.quorum), (0, 4));
+    }
+
+    #[test]
+    fn primary_rotates_through_sorted_ids() {
+        let members = Members::new("b".to_string(), &set(&["c", "a", "b"], QuorumConfig::default()));
+        let primaries: Vec<&str> = (0..5).map(|view| members.primary(view)).collect();
+        assert_eq!(primaries, ["a", "b", "c", "a", "b"]);
+        let mut peers = members.peer_addresses();
+        peers.sort();
+        assert_eq!(peers, ["127.0.0.1:8080", "127.0.0.1:8081"]);
+    }
+
+    #[test]
+    fn members_without_a_key_are_refused() {
+        let path = std::env::temp_dir().join(format!("repyh-test-{}.key", uuid::Uuid::new_v4()));
+        let identity = NodeIdentity::load_or_generate(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        let members = Members::new("a".to_string(), &set(&["a", "b"], QuorumConfig::default()));
+        let message = "ordering:a:{}".to_string();
+        assert!(members.verify(&identity, "a", identity.sign(message.as_bytes()), message.clone()).is_ok());
+        let forged = members.verify(&identity, "b", identity.sign(message.as_bytes()), message);
+        assert_eq!(forged.unwrap_err(), "b has no public key");
+    }
+
+    fn key_operation(id: &str, seed: u8) -> Operation {
+        let public_key = base64::Engine::encode(&base64::engine::general_purpose::STANDARD, [seed; 32]);
+        Operation { origin: "a".to_string(), record: Record::PublicKey { id: id.into(), public_key }, base: None, signature: "".into() }
+    }
+
+    #[test]
+    fn ordered_public_keys_are_never_replaced() {
+        let db = DbHandle::new(":memory:".to_string(), None).unwrap();
+        apply(&db, key_operation("owner", 1)).unwrap();
+        apply(&db, key_operation("owner", 1)).unwrap();
+        assert!(apply(&db, key_operation("owner", 2)).unwrap_err().contains("conflicts"));
+        assert_eq!(db.get_public_key("owner".into()).unwrap().public_key, Some(vec![1; 32]));
+    }
+
+    fn envelope(replica: &str, message: &Message) -> Envelope {
+        Envelope { replica: replica.to_string(), payload: serde_json::to_string(message).unwrap(), signature: "".into() }
+    }
+
+    fn prepared(view: u64, seq: u64, digest: &str) -> PreparedProof {
+        let pre_prepare = Message::PrePrepare { view, seq, digest: digest.to_string(), operation: None };
+        PreparedProof { pre_prepare: envelope("a", &pre_prepare), prepares: vec![] }
+    }
+
+    fn vote(checkpoint: u64, prepared: Vec<PreparedProof>) -> ViewChange {
+        ViewChange { view: 3, checkpoint, state: format!("state{}", checkpoint), checkpoint_proof: vec![], prepared }
+    }
+
+    #[test]
+    fn new_view_reproposes_the_latest_prepared_operations_from_the_highest_checkpoint() {
+        let votes = [
+            vote(10, vec![prepared(1, 11, "old"), prepared(1, 12, "x")]),
+            vote(20, vec![prepared(1, 21, "a"), prepared(1, 22, "stale")]),
+            vote(10, vec![prepared(2, 22, "fresh"), prepared(1, 24, "b")]),
+        ];
+        let (base, plan) = plan(&votes);
+        assert_eq!((base.checkpoint, base.state.as_str()), (20, "state20"));
+        let plan: Vec<(u64, String)> = plan.into_iter().map(|(seq, digest, _)| (seq, digest)).collect();
+        assert_eq!(plan, [
+            (21, "a".to_string()),
+            (22, "fresh".to_string()),
+            (23, NULL_DIGEST.to_string()), // A gap nobody prepared is filled with a null operation.
+            (24, "b".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn new_view_without_prepared_operations_proposes_nothing() {
+        let votes = [vote(5, vec![]), vote(0, vec![])];
+        let (base, plan) = plan(&votes);
+        assert_eq!(base.checkpoint, 5);
+        assert!(plan.is_empty());
+    }
+}
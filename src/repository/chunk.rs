@@ -0,0 +1,154 @@
+use serde::{Serialize, Deserialize};
+
+/// Chunks are never cut shorter than this, except at the end of a sequence.
+pub const MIN_CHUNK: usize = 8 * 1024;
+/// Chunks are always cut at this length. Sequences that fit in one chunk are replicated whole.
+pub const MAX_CHUNK: usize = 128 * 1024;
+/// Bits of the rolling hash that must be zero for a cut; chunks average `MIN_CHUNK + 2^CUT_BITS`.
+const CUT_BITS: u32 = 15;
+const CUT_MASK: u64 = !(u64::MAX >> CUT_BITS);
+
+/// Random values the rolling hash adds per byte. Generated with SplitMix64 from a fixed seed, so
+/// every node and version cuts a sequence at the same places.
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// A stored piece of a sequence, addressed by the hex SHA-256 of its bases.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ChunkRef {
+    pub hash: String,
+    pub offset: usize, // Position of the chunk's first base in the sequence.
+    pub length: usize,
+}
+
+/// Storage used by sequences, before and after chunks shared between them are deduplicated.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct StorageStats {
+    pub sequences: usize,
+    pub sequence_bytes: usize, // Total length of the stored sequences.
+    pub chunks: usize, // Distinct chunks referenced by the sequences.
+    pub chunk_bytes: usize, // Total length of those chunks, the bases actually stored.
+    pub unreferenced_chunks: usize, // Released chunks waiting for garbage collection.
+}
+
+/// Splits `sequence` into content-defined chunks. Cuts depend only on the bases around them, so
+/// an edit changes the chunks it touches and leaves the others, and those of similar sequences,
+/// identical.
+pub fn split(sequence: &str) -> Vec<(ChunkRef, &str)> {
+    let mut chunks = Vec::new();
+    let mut offset = 0;
+    while offset < sequence.len() {
+        let mut end = offset + cut_point(&sequence.as_bytes()[offset..]);
+        while !sequence.is_char_boundary(end) {
+            end += 1;
+        }
+        let data = &sequence[offset..end];
+        chunks.push((ChunkRef { hash: envelope::sha256_hex(data.as_bytes()), offset, length: data.len() }, data));
+        offset = end;
+    }
+    chunks
+}
+
+/// Returns the length of the next chunk of `bytes`: the first position past `MIN_CHUNK` where
+/// the top `CUT_BITS` of a gear hash over the preceding bytes are zero, or `MAX_CHUNK`.
+fn cut_point(bytes: &[u8]) -> usize {
+    if bytes.len() <= MIN_CHUNK {
+        return bytes.len();
+    }
+    let limit = bytes.len().min(MAX_CHUNK);
+    let mut hash: u64 = 0;
+    for (index, &byte) in bytes[..limit].iter().enumerate() {
+        hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+        if index >= MIN_CHUNK && hash & CUT_MASK == 0 {
+            return index + 1;
+        }
+    }
+    limit
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a sequence of `length` pseudo-random bases.
+    fn bases(length: usize, seed: u64) -> String {
+        let mut state = seed;
+        (0..length).map(|_| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            b"ACGT"[(state >> 62) as usize] as char
+        }).collect()
+    }
+
+    fn hashes(sequence: &str) -> Vec<String> {
+        split(sequence).into_iter().map(|(chunk, _)| chunk.hash).collect()
+    }
+
+    /// Counts the chunks of `new` that `old` does not have.
+    fn new_chunks(old: &str, new: &str) -> usize {
+        let old = hashes(old);
+        hashes(new).iter().filter(|hash| !old.contains(hash)).count()
+    }
+
+    #[test]
+    fn chunks_respect_the_bounds_and_cover_the_sequence() {
+        let repeated = "A".repeat(400_000);
+        for sequence in [bases(1_000_000, 1), bases(300_000, 2), repeated] {
+            let chunks = split(&sequence);
+            let (last, rest) = chunks.split_last().unwrap();
+            for (chunk, data) in rest {
+                assert!((MIN_CHUNK..=MAX_CHUNK).contains(&chunk.length), "chunk of {} bases", chunk.length);
+                assert_eq!(chunk.hash, envelope::sha256_hex(data.as_bytes()));
+            }
+            assert!(last.0.length <= MAX_CHUNK);
+            let mut offset = 0;
+            for (chunk, data) in &chunks {
+                assert_eq!(chunk.offset, offset);
+                assert_eq!(*data, &sequence[offset..offset + chunk.length]);
+                offset += chunk.length;
+            }
+            assert_eq!(offset, sequence.len());
+        }
+        assert_eq!(split(&"A".repeat(400_000)).len(), 4); // Nothing to cut on but the maximum.
+        assert_eq!(split(&bases(MIN_CHUNK, 3)).len(), 1);
+        assert!(split("").is_empty());
+    }
+
+    #[test]
+    fn chunking_is_deterministic() {
+        let sequence = bases(500_000, 4);
+        assert_eq!(split(&sequence), split(&sequence.clone()));
+        assert!(split(&sequence).len() > 1);
+    }
+
+    #[test]
+    fn edits_only_change_nearby_chunks() {
+        let old = bases(1_000_000, 5);
+        assert!(split(&old).len() > 10);
+
+        let mut substituted = old.clone();
+        let base = if &old[500_000..500_001] == "A" { "C" } else { "A" };
+        substituted.replace_range(500_000..500_001, base);
+        assert!(new_chunks(&old, &substituted) <= 2);
+
+        // An insertion shifts the bases after it, but the cuts after it line up again.
+        let mut inserted = old.clone();
+        inserted.insert_str(500_000, "GATTACA");
+        assert!(new_chunks(&old, &inserted) <= 2);
+        let deleted = format!("{}{}", &old[..500_000], &old[500_100..]);
+        assert!(new_chunks(&old, &deleted) <= 2);
+    }
+}
@@ -0,0 +1,280 @@
+use std::env;
+use std::fs;
+use std::sync::Arc;
+
+use base64::{Engine as _, engine::general_purpose};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+/// Prefix identifying an encrypted `dna_sequence` value.
+const ENVELOPE_PREFIX: &str = "enc1";
+const KEY_LEN: usize = 32;
+
+/// Errors for loading keys and sealing or opening sequence envelopes.
+#[derive(Error, Debug, derive_more::Display)]
+pub enum CipherError {
+    #[display(fmt = "Could not read key file: {}", _0)]
+    KeyFile(std::io::Error),
+    #[display(fmt = "Keys must be base64-encoded 32-byte values")]
+    InvalidKey,
+    #[display(fmt = "Malformed encrypted value")]
+    MalformedEnvelope,
+    #[display(fmt = "No master key with id {} is loaded", _0)]
+    UnknownKey(String),
+    #[display(fmt = "Decryption failed")]
+    DecryptionFailed,
+    #[display(fmt = "Value is encrypted at rest; set DNA_KEY_FILE or DNA_KEY to open it")]
+    Sealed,
+}
+
+/// A master key used to wrap per-record data keys.
+struct MasterKey {
+    id: String,
+    key: LessSafeKey,
+    index_key: hmac::Key,
+}
+
+/// Envelope encryption of DNA sequences with AES-256-GCM.
+///
+/// Each value is encrypted with a fresh data key, which is itself encrypted
+/// ("wrapped") by the current master key. Stored values are tagged with the
+/// master key id, so retired keys can still open older records until they are
+/// re-wrapped.
+pub struct SequenceCipher {
+    current: MasterKey,
+    retired: Vec<MasterKey>,
+    rng: SystemRandom,
+}
+
+/// Returns the lowercase hex encoding of a byte slice.
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl MasterKey {
+    fn new(raw: &[u8]) -> Result<Self, CipherError> {
+        if raw.len() != KEY_LEN {
+            return Err(CipherError::InvalidKey);
+        }
+        let id = to_hex(&Sha256::digest(raw)[..4]);
+        let key = LessSafeKey::new(UnboundKey::new(&AES_256_GCM, raw).map_err(|_| CipherError::InvalidKey)?);
+        let index_secret = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, raw), b"kmer-index");
+        let index_key = hmac::Key::new(hmac::HMAC_SHA256, index_secret.as_ref());
+        Ok(MasterKey { id, key, index_key })
+    }
+}
+
+impl SequenceCipher {
+    /// Creates a cipher from base64-encoded master keys. The first key encrypts,
+    /// the others are retired keys only used for decryption.
+    pub fn new(keys: &[&str]) -> Result<Self, CipherError> {
+        let mut keys = keys.iter()
+            .map(|key| general_purpose::STANDARD.decode(key.trim()).map_err(|_| CipherError::InvalidKey))
+            .map(|raw| raw.and_then(|raw| MasterKey::new(&raw)));
+        let current = keys.next().ok_or(CipherError::InvalidKey)??;
+        let retired = keys.collect::<Result<_, _>>()?;
+        Ok(SequenceCipher { current, retired, rng: SystemRandom::new() })
+    }
+
+    /// Loads master keys from the file named by `DNA_KEY_FILE` (one key per line)
+    /// or from `DNA_KEY` (comma-separated). Returns `None` when neither is set.
+    pub fn from_env() -> Result<Option<Self>, CipherError> {
+        let keys = match (env::var("DNA_KEY_FILE"), env::var("DNA_KEY")) {
+            (Ok(path), _) => fs::read_to_string(path).map_err(CipherError::KeyFile)?,
+            (_, Ok(keys)) => keys.replace(',', "\n"),
+            _ => return Ok(None),
+        };
+        let keys: Vec<&str> = keys.lines().filter(|line| !line.trim().is_empty()).collect();
+        Ok(Some(Self::new(&keys)?))
+    }
+
+    /// Id of the master key new values are sealed with.
+    pub fn key_id(&self) -> &str {
+        &self.current.id
+    }
+
+    fn master_key(&self, id: &str) -> Result<&MasterKey, CipherError> {
+        std::iter::once(&self.current)
+            .chain(self.retired.iter())
+            .find(|key| key.id == id)
+            .ok_or_else(|| CipherError::UnknownKey(id.to_string()))
+    }
+
+    /// Encrypts `data` under `key`, returning `nonce || ciphertext || tag`.
+    fn encrypt(&self, key: &LessSafeKey, aad: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut nonce = [0u8; NONCE_LEN];
+        self.rng.fill(&mut nonce).expect("System random number generator failed");
+        let mut sealed = data.to_vec();
+        key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(aad), &mut sealed)
+            .expect("AES-GCM sealing failed");
+        let mut out = nonce.to_vec();
+        out.extend(sealed);
+        out
+    }
+
+    fn decrypt(key: &LessSafeKey, aad: &[u8], data: &[u8]) -> Result<Vec<u8>, CipherError> {
+        if data.len() < NONCE_LEN {
+            return Err(CipherError::MalformedEnvelope);
+        }
+        let (nonce, sealed) = data.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| CipherError::MalformedEnvelope)?;
+        let mut sealed = sealed.to_vec();
+        let plain = key.open_in_place(nonce, Aad::from(aad), &mut sealed)
+            .map_err(|_| CipherError::DecryptionFailed)?;
+        Ok(plain.to_vec())
+    }
+
+    /// Seals a sequence value. The record id is authenticated so values cannot be swapped between rows.
+    pub fn seal(&self, id: &str, plaintext: &str) -> Arc<str> {
+        let mut data_key = [0u8; KEY_LEN];
+        self.rng.fill(&mut data_key).expect("System random number generator failed");
+        let wrapped = self.encrypt(&self.current.key, id.as_bytes(), &data_key);
+        let data = LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &data_key).expect("Data key has a valid length"));
+        let body = self.encrypt(&data, id.as_bytes(), plaintext.as_bytes());
+        format!(
+            "{}:{}:{}:{}",
+            ENVELOPE_PREFIX,
+            self.current.id,
+            general_purpose::STANDARD.encode(wrapped),
+            general_purpose::STANDARD.encode(body),
+        ).into()
+    }
+
+    /// Checks whether a stored value is sealed in an envelope.
+    pub fn is_sealed(stored: &str) -> bool {
+        Self::split(stored).is_some()
+    }
+
+    /// Opens a stored value. Values without the envelope prefix are returned unchanged.
+    pub fn open(&self, id: &str, stored: Arc<str>) -> Result<Arc<str>, CipherError> {
+        let Some((key_id, wrapped, body)) = Self::split(&stored) else {
+            return Ok(stored);
+        };
+        let master = self.master_key(key_id)?;
+        let data_key = Self::decrypt(&master.key, id.as_bytes(), &Self::decode(wrapped)?)?;
+        let data = LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &data_key).map_err(|_| CipherError::MalformedEnvelope)?);
+        let plain = Self::decrypt(&data, id.as_bytes(), &Self::decode(body)?)?;
+        String::from_utf8(plain).map(Arc::from).map_err(|_| CipherError::MalformedEnvelope)
+    }
+
+    /// Re-wraps a stored value under the current master key, encrypting plaintext values.
+    /// Returns `None` when the value is already sealed with the current key.
+    pub fn rewrap(&self, id: &str, stored: Arc<str>) -> Result<Option<Arc<str>>, CipherError> {
+        let Some((key_id, wrapped, body)) = Self::split(&stored) else {
+            return Ok(Some(self.seal(id, &stored)));
+        };
+        if key_id == self.current.id {
+            return Ok(None);
+        }
+        let data_key = Self::decrypt(&self.master_key(key_id)?.key, id.as_bytes(), &Self::decode(wrapped)?)?;
+        let wrapped = self.encrypt(&self.current.key, id.as_bytes(), &data_key);
+        Ok(Some(format!(
+            "{}:{}:{}:{}",
+            ENVELOPE_PREFIX,
+            self.current.id,
+            general_purpose::STANDARD.encode(wrapped),
+            body,
+        ).into()))
+    }
+
+    /// Returns the keyed tag stored in the k-mer index in place of a plaintext k-mer.
+    pub fn kmer_tag(&self, kmer: &str) -> String {
+        to_hex(&hmac::sign(&self.current.index_key, kmer.as_bytes()).as_ref()[..8])
+    }
+
+    fn split(stored: &str) -> Option<(&str, &str, &str)> {
+        let mut parts = stored.splitn(4, ':');
+        if parts.next()? != ENVELOPE_PREFIX {
+            return None;
+        }
+        Some((parts.next()?, parts.next()?, parts.next()?))
+    }
+
+    fn decode(part: &str) -> Result<Vec<u8>, CipherError> {
+        general_purpose::STANDARD.decode(part).map_err(|_| CipherError::MalformedEnvelope)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(seed: u8) -> String {
+        general_purpose::STANDARD.encode([seed; KEY_LEN])
+    }
+
+    fn cipher(seeds: &[u8]) -> SequenceCipher {
+        let keys: Vec<String> = seeds.iter().map(|&seed| key(seed)).collect();
+        SequenceCipher::new(&keys.iter().map(String::as_str).collect::<Vec<_>>()).unwrap()
+    }
+
+    #[test]
+    fn sealed_values_open_to_the_plaintext() {
+        let cipher = cipher(&[1]);
+        let sealed = cipher.seal("a", "ACGTACGT");
+        assert!(SequenceCipher::is_sealed(&sealed));
+        assert!(sealed.starts_with(&format!("{}:{}:", ENVELOPE_PREFIX, cipher.key_id())));
+        assert!(!sealed.contains("ACGTACGT"));
+        assert_ne!(sealed, cipher.seal("a", "ACGTACGT")); // Fresh data key and nonces.
+        assert_eq!(&*cipher.open("a", sealed).unwrap(), "ACGTACGT");
+
+        // Plaintext rows from before encryption was enabled pass through.
+        assert!(!SequenceCipher::is_sealed("ACGTACGT"));
+        assert_eq!(&*cipher.open("a", "ACGTACGT".into()).unwrap(), "ACGTACGT");
+    }
+
+    #[test]
+    fn values_are_bound_to_their_id() {
+        let cipher = cipher(&[1]);
+        let sealed = cipher.seal("a", "ACGTACGT");
+        assert!(matches!(cipher.open("b", sealed.clone()), Err(CipherError::DecryptionFailed)));
+
+        // Nor can a body be moved under the wrapped key of another row.
+        let other = cipher.seal("b", "TTTTTTTT");
+        let (_, _, body) = SequenceCipher::split(&sealed).unwrap();
+        let (key_id, wrapped, _) = SequenceCipher::split(&other).unwrap();
+        let moved = format!("{}:{}:{}:{}", ENVELOPE_PREFIX, key_id, wrapped, body);
+        assert!(matches!(cipher.open("b", moved.into()), Err(CipherError::DecryptionFailed)));
+    }
+
+    #[test]
+    fn values_sealed_with_an_unknown_key_are_refused() {
+        let sealed = cipher(&[1]).seal("a", "ACGTACGT");
+        let other = cipher(&[2]);
+        assert!(matches!(other.open("a", sealed), Err(CipherError::UnknownKey(id)) if id == cipher(&[1]).key_id()));
+        assert!(matches!(SequenceCipher::new(&["c2hvcnQ="]), Err(CipherError::InvalidKey)));
+        assert!(matches!(SequenceCipher::new(&[]), Err(CipherError::InvalidKey)));
+    }
+
+    #[test]
+    fn rewrap_moves_values_to_the_current_key() {
+        let old = cipher(&[1]);
+        let new = cipher(&[2, 1]);
+        let sealed = old.seal("a", "ACGTACGT");
+        let rewrapped = new.rewrap("a", sealed.clone()).unwrap().unwrap();
+        assert!(rewrapped.starts_with(&format!("{}:{}:", ENVELOPE_PREFIX, new.key_id())));
+        // Only the data key is re-encrypted.
+        assert_eq!(SequenceCipher::split(&rewrapped).unwrap().2, SequenceCipher::split(&sealed).unwrap().2);
+        assert_eq!(&*new.open("a", rewrapped.clone()).unwrap(), "ACGTACGT");
+        assert_eq!(&*cipher(&[2]).open("a", rewrapped.clone()).unwrap(), "ACGTACGT");
+        assert!(matches!(old.open("a", rewrapped.clone()), Err(CipherError::UnknownKey(_))));
+
+        assert!(new.rewrap("a", rewrapped).unwrap().is_none());
+        let encrypted = new.rewrap("a", "ACGTACGT".into()).unwrap().unwrap();
+        assert_eq!(&*new.open("a", encrypted).unwrap(), "ACGTACGT");
+    }
+
+    #[test]
+    fn kmer_tags_are_keyed() {
+        let tag = cipher(&[1]).kmer_tag("ACGTACGT");
+        assert_eq!(tag.len(), 16);
+        assert_eq!(tag, cipher(&[1]).kmer_tag("ACGTACGT"));
+        assert_ne!(tag, cipher(&[1]).kmer_tag("ACGTACGA"));
+        assert_ne!(tag, cipher(&[2]).kmer_tag("ACGTACGT"));
+        // Retired keys do not change the tags of the current one.
+        assert_eq!(tag, cipher(&[1, 2]).kmer_tag("ACGTACGT"));
+    }
+}
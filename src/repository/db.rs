@@ -1,15 +1,29 @@
-use rusqlite::Connection;
+use rusqlite::{backup::Progress, Connection, DatabaseName, OpenFlags};
+use std::collections::BTreeSet;
 use std::fmt;
+use std::path::Path;
 use std::sync::Arc;
 use thiserror::Error;
 
 use crate::model::dna_sequence::DnaSequence;
+use crate::model::ordered::OrderedOperation;
+use crate::model::evidence::{Evidence, EvidenceKind};
+use crate::model::outbox::OutboxMessage;
 use crate::model::public_key::PublicKey;
+use crate::model::tombstone::Tombstone;
+use crate::model::variant::{self, SequenceChange};
+use crate::repository::chunk::{self, ChunkRef, StorageStats};
+use crate::repository::cipher::{CipherError, SequenceCipher};
+
+/// Length of the k-mers stored in the sequence search index.
+pub const KMER_LENGTH: usize = 8;
 
 /// Database handle for managing DNA sequences and public keys.
 pub struct DbHandle {
     connection: Connection,
+    #[allow(dead_code)]
     name: String,
+    cipher: Option<SequenceCipher>,
 }
 
 /// Errors that can occur during database queries.
@@ -17,6 +31,10 @@ pub struct DbHandle {
 pub enum QuerryError {
     RusqliteError(rusqlite::Error),
     EmptyTableErrorW(EmptyTableError),
+    DeletedErrorW(DeletedError),
+    StaleErrorW(StaleError),
+    RangeErrorW(RangeError),
+    CipherErrorW(CipherError),
 }
 
 /// Errors indicating missing entries in tables.
@@ -24,19 +42,71 @@ pub enum QuerryError {
 pub enum EmptyTableError {
     NoDnaSequences,
     NoPublicKeys,
+    MissingChunk(String),
 }
 
+/// Error returned when writing a DNA sequence that has been deleted.
+#[derive(Error, Debug)]
+pub struct DeletedError(pub Arc<str>);
+
+/// Error returned when writing a DNA sequence with a counter behind the stored one: id, stored
+/// counter and the counter of the write.
+#[derive(Error, Debug)]
+pub struct StaleError(pub Arc<str>, pub u64, pub u64);
+
+/// Error returned when reading a range outside a DNA sequence: start, end and the length of
+/// the sequence.
+#[derive(Error, Debug)]
+pub struct RangeError(pub usize, pub usize, pub usize);
+
 impl fmt::Display for EmptyTableError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             EmptyTableError::NoDnaSequences => write!(f, "No Dna Sequences in the database."),
             EmptyTableError::NoPublicKeys => write!(f, "No PublicKeys in the database."),
+            EmptyTableError::MissingChunk(hash) => write!(f, "Chunk {} is missing from the database.", hash),
         }
     }
 }
 
+impl fmt::Display for DeletedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Dna Sequence {} has been deleted.", self.0)
+    }
+}
+
+impl fmt::Display for StaleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Dna Sequence {} is at counter {}, write has counter {}.", self.0, self.1, self.2)
+    }
+}
+
+impl fmt::Display for RangeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Range [{}, {}) is outside a Dna Sequence of length {}.", self.0, self.1, self.2)
+    }
+}
+
+/// Tables and columns of the current schema.
+const SCHEMA: &[(&str, &[&str])] = &[
+    ("DnaSequence", &["id", "dna_sequence", "signature", "encrypted", "counter", "length", "stored_at"]),
+    ("Chunk", &["hash", "data", "refs", "released_at"]),
+    ("SequenceChunk", &["id", "idx", "hash", "offset", "length"]),
+    ("Upload", &["upload", "id", "created_at"]),
+    ("UploadPart", &["upload", "idx", "data"]),
+    ("PublicKey", &["id", "public_key"]),
+    ("Kmer", &["kmer", "id"]),
+    ("Tombstone", &["id", "deleted_at", "signature", "recorded_at", "counter"]),
+    ("Meta", &["key", "value"]),
+    ("Outbox", &["seq", "peer", "idempotency_key", "path", "body", "attempts", "next_attempt_at"]),
+    ("Received", &["idempotency_key", "received_at"]),
+    ("Ordered", &["seq", "view", "origin", "digest", "operation", "prev", "state"]),
+    ("Evidence", &["digest", "peer", "kind", "detail", "proof", "recorded_at"]),
+    ("SequenceChange", &["id", "base", "version", "base_length", "variants", "recorded_at"]),
+];
+
 /// Initializes database tables if they do not already exist.
-fn create_tables(connection: Connection) -> Result<Connection, rusqlite::Error> {
+fn create_tables(connection: &Connection) -> Result<(), rusqlite::Error> {
     connection.execute(
         "CREATE TABLE IF NOT EXISTS DnaSequence( 
             id TEXT PRIMARY KEY,
@@ -44,6 +114,49 @@ fn create_tables(connection: Connection) -> Result<Connection, rusqlite::Error>
         );",
         []
     )?;
+    add_column_if_missing(connection, "DnaSequence", "signature", "TEXT")?;
+    add_column_if_missing(connection, "DnaSequence", "encrypted", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(connection, "DnaSequence", "counter", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(connection, "DnaSequence", "length", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(connection, "DnaSequence", "stored_at", "INTEGER NOT NULL DEFAULT 0")?;
+    connection.execute(
+        "CREATE TABLE IF NOT EXISTS Chunk(
+            hash TEXT PRIMARY KEY,
+            data TEXT NOT NULL
+        );",
+        []
+    )?;
+    connection.execute(
+        "CREATE TABLE IF NOT EXISTS SequenceChunk(
+            id TEXT NOT NULL,
+            idx INTEGER NOT NULL,
+            hash TEXT NOT NULL,
+            offset INTEGER NOT NULL,
+            length INTEGER NOT NULL,
+            PRIMARY KEY(id, idx)
+        );",
+        []
+    )?;
+    add_column_if_missing(connection, "Chunk", "refs", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(connection, "Chunk", "released_at", "INTEGER")?;
+    connection.execute("CREATE INDEX IF NOT EXISTS SequenceChunkHash ON SequenceChunk(hash);", [])?;
+    connection.execute(
+        "CREATE TABLE IF NOT EXISTS Upload(
+            upload TEXT PRIMARY KEY,
+            id TEXT NOT NULL,
+            created_at INTEGER NOT NULL
+        );",
+        []
+    )?;
+    connection.execute(
+        "CREATE TABLE IF NOT EXISTS UploadPart(
+            upload TEXT NOT NULL,
+            idx INTEGER NOT NULL,
+            data TEXT NOT NULL,
+            PRIMARY KEY(upload, idx)
+        );",
+        []
+    )?;
     connection.execute(
         "CREATE TABLE IF NOT EXISTS PublicKey(
             id TEXT PRIMARY KEY,
@@ -51,30 +164,501 @@ fn create_tables(connection: Connection) -> Result<Connection, rusqlite::Error>
         );",
         []
     )?;
-    Ok(connection)
+    connection.execute(
+        "CREATE TABLE IF NOT EXISTS Kmer(
+            kmer TEXT,
+            id TEXT,
+            PRIMARY KEY(kmer, id)
+        ) WITHOUT ROWID;",
+        []
+    )?;
+    connection.execute(
+        "CREATE TABLE IF NOT EXISTS Tombstone(
+            id TEXT PRIMARY KEY,
+            deleted_at INTEGER,
+            signature TEXT,
+            recorded_at INTEGER
+        );",
+        []
+    )?;
+    add_column_if_missing(connection, "Tombstone", "counter", "INTEGER NOT NULL DEFAULT 0")?;
+    connection.execute(
+        "CREATE TABLE IF NOT EXISTS Meta(
+            key TEXT PRIMARY KEY,
+            value TEXT
+        );",
+        []
+    )?;
+    connection.execute(
+        "CREATE TABLE IF NOT EXISTS Outbox(
+            seq INTEGER PRIMARY KEY AUTOINCREMENT,
+            peer TEXT NOT NULL,
+            idempotency_key TEXT NOT NULL,
+            path TEXT NOT NULL,
+            body TEXT NOT NULL,
+            attempts INTEGER NOT NULL DEFAULT 0,
+            next_attempt_at INTEGER NOT NULL,
+            UNIQUE(peer, idempotency_key)
+        );",
+        []
+    )?;
+    connection.execute(
+        "CREATE TABLE IF NOT EXISTS Received(
+            idempotency_key TEXT PRIMARY KEY,
+            received_at INTEGER
+        );",
+        []
+    )?;
+    connection.execute(
+        "CREATE TABLE IF NOT EXISTS Ordered(
+            seq INTEGER PRIMARY KEY,
+            view INTEGER NOT NULL,
+            origin TEXT,
+            digest TEXT NOT NULL,
+            operation TEXT,
+            prev TEXT NOT NULL DEFAULT '',
+            state TEXT NOT NULL
+        );",
+        []
+    )?;
+    add_column_if_missing(connection, "Ordered", "origin", "TEXT")?;
+    add_column_if_missing(connection, "Ordered", "prev", "TEXT NOT NULL DEFAULT ''")?;
+    connection.execute(
+        "CREATE TABLE IF NOT EXISTS Evidence(
+            digest TEXT PRIMARY KEY,
+            peer TEXT NOT NULL,
+            kind TEXT NOT NULL,
+            detail TEXT NOT NULL,
+            proof TEXT NOT NULL,
+            recorded_at INTEGER NOT NULL
+        );",
+        []
+    )?;
+    connection.execute(
+        "CREATE TABLE IF NOT EXISTS SequenceChange(
+            id TEXT NOT NULL,
+            base TEXT NOT NULL,
+            version TEXT NOT NULL,
+            base_length INTEGER NOT NULL,
+            variants TEXT NOT NULL,
+            recorded_at INTEGER NOT NULL,
+            PRIMARY KEY(id, base)
+        );",
+        []
+    )?;
+    Ok(())
+}
+
+/// Adds a column to a table created by an older version of the schema.
+fn add_column_if_missing(connection: &Connection, table: &str, column: &str, definition: &str) -> Result<(), rusqlite::Error> {
+    let exists: bool = connection.query_row(
+        &format!("SELECT EXISTS(SELECT 1 FROM pragma_table_info('{}') WHERE name = ?1)", table),
+        [column],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        connection.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition), [])?;
+    }
+    Ok(())
+}
+
+/// Returns the distinct unambiguous k-mers of a sequence, upper-cased.
+fn distinct_kmers(sequence: &str) -> BTreeSet<String> {
+    sequence.as_bytes()
+        .windows(KMER_LENGTH)
+        .filter(|window| window.iter().all(|b| matches!(b.to_ascii_uppercase(), b'A' | b'C' | b'G' | b'T' | b'U')))
+        .map(|window| String::from_utf8_lossy(window).to_ascii_uppercase().replace('U', "T"))
+        .collect()
+}
+
+/// Returns the associated data an upload part is sealed with, binding it to its upload and index.
+fn part_key(upload: &str, idx: usize) -> String {
+    format!("{}:{}", upload, idx)
 }
 
 impl DbHandle {
     /// Creates a new `DbHandle` instance and initializes database tables.
-    pub fn new(name: String) -> Result<Self, rusqlite::Error> {
-        let mut connection = Connection::open(&name)?;
-        connection = create_tables(connection)?;
-        Ok(DbHandle { connection, name })
+    /// With a cipher, sequences are encrypted at rest and the k-mer index stores keyed tags.
+    pub fn new(name: String, cipher: Option<SequenceCipher>) -> Result<Self, QuerryError> {
+        let connection = Connection::open(&name)?;
+        create_tables(&connection)?;
+        let db = DbHandle { connection, name, cipher };
+        db.ensure_chunked()?;
+        db.ensure_chunk_refs()?;
+        db.ensure_kmer_index()?;
+        Ok(db)
     }
 
-    /// Inserts or updates a DNA sequence in the database.
-    pub fn push_dna_sequence(&self, dna_sequence: &DnaSequence) -> Result<Arc<str>, rusqlite::Error> {
-        self.connection.execute(
-            &format!(
-                "INSERT OR REPLACE INTO DnaSequence(id, dna_sequence) VALUES(\"{}\", \"{}\")",
-                dna_sequence.id.clone(),
-                dna_sequence.dna_sequence
-            ),
-            [],
+    /// Opens an existing database read-only, without creating tables or migrating it, so
+    /// inspecting a file such as a snapshot copy leaves it unchanged.
+    pub fn open_read_only(name: String, cipher: Option<SequenceCipher>) -> Result<Self, QuerryError> {
+        let connection = Connection::open_with_flags(&name, OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX)?;
+        Ok(DbHandle { connection, name, cipher })
+    }
+
+    /// Inserts or updates a DNA sequence in the database and refreshes its k-mer index entries.
+    /// Sequences with a tombstone are rejected so deletions cannot be undone by late writes.
+    /// Writes behind the stored counter are rejected so older signed versions cannot be replayed.
+    /// Updates of plaintext sequences record their variants against the previous version.
+    pub fn push_dna_sequence(&self, dna_sequence: &DnaSequence) -> Result<Arc<str>, QuerryError> {
+        let transaction = self.connection.unchecked_transaction()?;
+        if self.is_deleted(dna_sequence.id.clone())? {
+            return Err(DeletedError(dna_sequence.id.clone()).into());
+        }
+        match self.get_dna_sequence(dna_sequence.id.clone()) {
+            Ok(old) if dna_sequence.counter < old.counter
+                || (dna_sequence.counter == old.counter && old.counter > 0 && old.dna_sequence != dna_sequence.dna_sequence) => {
+                return Err(StaleError(dna_sequence.id.clone(), old.counter, dna_sequence.counter).into());
+            },
+            Ok(old) if !old.encrypted && !dna_sequence.encrypted && old.dna_sequence != dna_sequence.dna_sequence => {
+                let change = SequenceChange {
+                    base: old.version(),
+                    version: dna_sequence.version(),
+                    base_length: old.dna_sequence.len(),
+                    variants: variant::variants(&old.dna_sequence, &dna_sequence.dna_sequence),
+                    recorded_at: chrono::Utc::now().timestamp(),
+                };
+                self.push_change(&transaction, &dna_sequence.id, &change)?;
+            },
+            Ok(_) | Err(QuerryError::EmptyTableErrorW(_)) => (),
+            Err(e) => return Err(e),
+        }
+        transaction.execute(
+            "INSERT OR REPLACE INTO DnaSequence(id, dna_sequence, signature, encrypted, counter, length, stored_at) VALUES(?1, '', ?2, ?3, ?4, ?5, ?6)",
+            (dna_sequence.id.clone(), dna_sequence.signature.clone(), dna_sequence.encrypted, dna_sequence.counter, dna_sequence.dna_sequence.len(), chrono::Utc::now().timestamp()),
         )?;
+        self.store_chunks(&transaction, &dna_sequence.id, &dna_sequence.dna_sequence)?;
+        self.index_kmers(&transaction, dna_sequence)?;
+        transaction.commit()?;
         Ok(dna_sequence.id.clone())
     }
 
+    /// Replaces the chunks of sequence `id` with the content-defined chunks of `sequence`.
+    /// Chunks already held, by any sequence or version, are referenced rather than stored again.
+    fn store_chunks(&self, connection: &Connection, id: &Arc<str>, sequence: &str) -> Result<(), rusqlite::Error> {
+        let mut insert = connection.prepare("INSERT INTO SequenceChunk(id, idx, hash, offset, length) VALUES(?1, ?2, ?3, ?4, ?5)")?;
+        let chunks = chunk::split(sequence);
+        // References are added before the old ones are released, so chunks kept by the new
+        // version are never marked for collection.
+        for (chunk, data) in &chunks {
+            self.push_chunk(connection, &chunk.hash, data)?;
+        }
+        self.take_chunks(connection, id)?;
+        for (idx, (chunk, _)) in chunks.iter().enumerate() {
+            insert.execute((id.clone(), idx, &chunk.hash, chunk.offset, chunk.length))?;
+        }
+        Ok(())
+    }
+
+    /// Removes the chunk list of sequence `id` and releases its references. Chunks left without
+    /// references are kept until `collect_chunks` runs past their grace period.
+    fn take_chunks(&self, connection: &Connection, id: &Arc<str>) -> Result<(), rusqlite::Error> {
+        let hashes: Vec<String> = connection.prepare("SELECT hash FROM SequenceChunk WHERE id = ?1")?
+            .query_map([id.clone()], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        let now = chrono::Utc::now().timestamp();
+        let mut release = connection.prepare(
+            "UPDATE Chunk SET refs = refs - 1, released_at = CASE WHEN refs <= 1 THEN ?2 ELSE released_at END WHERE hash = ?1"
+        )?;
+        for hash in hashes {
+            release.execute((hash, now))?;
+        }
+        connection.execute("DELETE FROM SequenceChunk WHERE id = ?1", [id.clone()])?;
+        Ok(())
+    }
+
+    /// Adds a reference to a chunk, storing it if it is not held yet, encrypted under the cipher
+    /// with its hash authenticated.
+    fn push_chunk(&self, connection: &Connection, hash: &str, data: &str) -> Result<(), rusqlite::Error> {
+        let referenced = connection.execute("UPDATE Chunk SET refs = refs + 1, released_at = NULL WHERE hash = ?1", [hash])?;
+        if referenced > 0 {
+            return Ok(());
+        }
+        let stored = match &self.cipher {
+            Some(cipher) => cipher.seal(hash, data),
+            None => data.into(),
+        };
+        connection.execute("INSERT INTO Chunk(hash, data, refs) VALUES(?1, ?2, 1)", (hash, stored))?;
+        Ok(())
+    }
+
+    /// Deletes chunks without references released before `released_before`.
+    pub fn collect_chunks(&self, released_before: i64) -> Result<usize, rusqlite::Error> {
+        self.connection.execute("DELETE FROM Chunk WHERE refs <= 0 AND released_at < ?1", [released_before])
+    }
+
+    /// Counts the references of chunks stored before reference counting, recognisable by having
+    /// neither references nor a release time. Those still unused are released now.
+    fn ensure_chunk_refs(&self) -> Result<(), rusqlite::Error> {
+        let transaction = self.connection.unchecked_transaction()?;
+        transaction.execute(
+            "UPDATE Chunk SET refs = (SELECT COUNT(*) FROM SequenceChunk WHERE SequenceChunk.hash = Chunk.hash)
+             WHERE refs <= 0 AND released_at IS NULL",
+            [],
+        )?;
+        transaction.execute("UPDATE Chunk SET released_at = ?1 WHERE refs <= 0 AND released_at IS NULL", [chrono::Utc::now().timestamp()])?;
+        transaction.commit()
+    }
+
+    /// Lists chunks whose reference count differs from the number of chunk list entries
+    /// referring to them.
+    pub fn get_miscounted_chunks(&self) -> Result<Vec<Arc<str>>, rusqlite::Error> {
+        let mut query = self.connection.prepare(
+            "SELECT hash FROM Chunk WHERE refs != (SELECT COUNT(*) FROM SequenceChunk WHERE SequenceChunk.hash = Chunk.hash) ORDER BY hash;"
+        )?;
+        let hashes = query.query_map([], |row| row.get(0))?.collect();
+        hashes
+    }
+
+    /// Measures the stored sequences against the distinct chunks they are made of.
+    pub fn get_storage_stats(&self) -> Result<StorageStats, rusqlite::Error> {
+        let (sequences, sequence_bytes) = self.connection.query_row(
+            "SELECT COUNT(*), COALESCE(SUM(length), 0) FROM DnaSequence",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        let (chunks, chunk_bytes) = self.connection.query_row(
+            "SELECT COUNT(*), COALESCE(SUM(length), 0) FROM (SELECT hash, MAX(length) AS length FROM SequenceChunk GROUP BY hash)",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        let unreferenced_chunks = self.connection.query_row("SELECT COUNT(*) FROM Chunk WHERE refs <= 0", [], |row| row.get(0))?;
+        Ok(StorageStats { sequences, sequence_bytes, chunks, chunk_bytes, unreferenced_chunks })
+    }
+
+    /// Checks whether the chunk with `hash` is held.
+    pub fn has_chunk(&self, hash: &str) -> Result<bool, rusqlite::Error> {
+        self.connection.query_row("SELECT EXISTS(SELECT 1 FROM Chunk WHERE hash = ?1)", [hash], |row| row.get(0))
+    }
+
+    /// Retrieves the bases of a chunk.
+    pub fn get_chunk(&self, hash: &str) -> Result<Arc<str>, QuerryError> {
+        let stored: Option<Arc<str>> = self.connection
+            .query_row("SELECT data FROM Chunk WHERE hash = ?1", [hash], |row| row.get(0))
+            .map(Some)
+            .or_else(|e| match e {
+                rusqlite::Error::QueryReturnedNoRows => Ok(None),
+                e => Err(e),
+            })?;
+        let stored = stored.ok_or_else(|| EmptyTableError::MissingChunk(hash.to_string()))?;
+        Ok(self.open(hash, stored)?)
+    }
+
+    /// Retrieves the chunk list of a sequence, in order.
+    pub fn get_chunk_refs(&self, id: Arc<str>) -> Result<Vec<ChunkRef>, rusqlite::Error> {
+        let mut query = self.connection.prepare("SELECT hash, offset, length FROM SequenceChunk WHERE id = ?1 ORDER BY idx;")?;
+        let chunks = query.query_map([id], |row| Ok(ChunkRef { hash: row.get(0)?, offset: row.get(1)?, length: row.get(2)? }))?.collect();
+        chunks
+    }
+
+    /// Checks the chunks of a sequence are held and match their hashes, returning the problems.
+    pub fn check_chunks(&self, id: Arc<str>) -> Result<Vec<String>, QuerryError> {
+        let mut issues = vec![];
+        for chunk in self.get_chunk_refs(id)? {
+            match self.get_chunk(&chunk.hash) {
+                Ok(data) if envelope::sha256_hex(data.as_bytes()) != chunk.hash => {
+                    issues.push(format!("chunk {} does not match its hash", chunk.hash));
+                },
+                Ok(_) => (),
+                Err(QuerryError::CipherErrorW(CipherError::Sealed)) => {
+                    issues.push(format!("chunk {} is encrypted at rest; set DNA_KEY_FILE or DNA_KEY to verify", chunk.hash));
+                },
+                Err(QuerryError::EmptyTableErrorW(e)) => issues.push(e.to_string()),
+                Err(e) => issues.push(format!("chunk {}: {}", chunk.hash, e)),
+            }
+        }
+        Ok(issues)
+    }
+
+    /// Moves sequences written before chunked storage into chunks. Values sealed under a key
+    /// the node was not given are left in place.
+    fn ensure_chunked(&self) -> Result<(), QuerryError> {
+        let inline: Vec<(Arc<str>, Arc<str>)> = self.connection
+            .prepare("SELECT id, dna_sequence FROM DnaSequence WHERE dna_sequence != ''")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        if inline.is_empty() {
+            return Ok(());
+        }
+        let transaction = self.connection.unchecked_transaction()?;
+        for (id, stored) in inline {
+            let sequence = match &self.cipher {
+                Some(cipher) => cipher.open(&id, stored)?,
+                None if SequenceCipher::is_sealed(&stored) => continue,
+                None => stored,
+            };
+            transaction.execute("UPDATE DnaSequence SET dna_sequence = '', length = ?2 WHERE id = ?1", (id.clone(), sequence.len()))?;
+            self.store_chunks(&transaction, &id, &sequence)?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Stores a change of sequence `id`, encrypting its variants like the sequence itself.
+    fn push_change(&self, connection: &Connection, id: &Arc<str>, change: &SequenceChange) -> Result<(), rusqlite::Error> {
+        let variants = serde_json::to_string(&change.variants).expect("variants serialize");
+        let stored = match &self.cipher {
+            Some(cipher) => cipher.seal(id, &variants),
+            None => variants.into(),
+        };
+        connection.execute(
+            "INSERT OR REPLACE INTO SequenceChange(id, base, version, base_length, variants, recorded_at) VALUES(?1, ?2, ?3, ?4, ?5, ?6)",
+            (id.clone(), &change.base, &change.version, change.base_length, stored, change.recorded_at),
+        )?;
+        Ok(())
+    }
+
+    /// Retrieves the recorded changes of a sequence, oldest first.
+    pub fn get_changes(&self, id: Arc<str>) -> Result<Vec<SequenceChange>, QuerryError> {
+        let mut query = self.connection.prepare(
+            "SELECT base, version, base_length, variants, recorded_at FROM SequenceChange WHERE id = ?1 ORDER BY recorded_at, rowid;"
+        )?;
+        let rows: Vec<(String, String, usize, Arc<str>, i64)> = query
+            .query_map([id.clone()], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?)))?
+            .collect::<Result<_, _>>()?;
+        rows.into_iter().map(|(base, version, base_length, stored, recorded_at)| {
+            let variants = self.open(&id, stored)?;
+            let variants = serde_json::from_str(&variants)
+                .map_err(|e| rusqlite::Error::FromSqlConversionFailure(3, rusqlite::types::Type::Text, Box::new(e)))?;
+            Ok(SequenceChange { base, version, base_length, variants, recorded_at })
+        }).collect()
+    }
+
+    /// Returns the key stored in the k-mer index for a plaintext k-mer.
+    fn kmer_key(&self, kmer: &str) -> String {
+        match &self.cipher {
+            Some(cipher) => cipher.kmer_tag(kmer),
+            None => kmer.to_string(),
+        }
+    }
+
+    /// Replaces the k-mer index entries of a sequence. Client-encrypted sequences are not indexed.
+    fn index_kmers(&self, connection: &Connection, dna_sequence: &DnaSequence) -> Result<(), rusqlite::Error> {
+        connection.execute("DELETE FROM Kmer WHERE id = ?1", [dna_sequence.id.clone()])?;
+        if dna_sequence.encrypted {
+            return Ok(());
+        }
+        let mut insert = connection.prepare("INSERT INTO Kmer(kmer, id) VALUES(?1, ?2)")?;
+        for kmer in distinct_kmers(&dna_sequence.dna_sequence) {
+            insert.execute((self.kmer_key(&kmer), dna_sequence.id.clone()))?;
+        }
+        Ok(())
+    }
+
+    /// Rebuilds the k-mer index when it was built with a different key, or never built.
+    fn ensure_kmer_index(&self) -> Result<(), QuerryError> {
+        let expected = match &self.cipher {
+            Some(cipher) => cipher.key_id().to_string(),
+            None => "plain".to_string(),
+        };
+        let current: Option<String> = self.connection
+            .query_row("SELECT value FROM Meta WHERE key = 'kmer_index'", [], |row| row.get(0))
+            .ok();
+        if current.as_ref() == Some(&expected) {
+            return Ok(());
+        }
+        let transaction = self.connection.unchecked_transaction()?;
+        transaction.execute("DELETE FROM Kmer", [])?;
+        for id in self.get_dna_sequence_ids()? {
+            // Sequences sealed under a key the node was not given stay unindexed until it is.
+            match self.get_dna_sequence(id) {
+                Ok(dna_sequence) => self.index_kmers(&transaction, &dna_sequence)?,
+                Err(QuerryError::CipherErrorW(CipherError::Sealed)) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        transaction.execute(
+            "INSERT OR REPLACE INTO Meta(key, value) VALUES('kmer_index', ?1)",
+            [expected],
+        )?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Re-wraps every stored sequence, chunk, upload part and recorded change under the current
+    /// master key, encrypting plaintext rows. Returns the number of sequences and chunks rewritten.
+    pub fn reencrypt(&self) -> Result<usize, QuerryError> {
+        let Some(cipher) = &self.cipher else {
+            return Ok(0);
+        };
+        let transaction = self.connection.unchecked_transaction()?;
+        let mut rewritten = 0;
+        for id in self.get_dna_sequence_ids()? {
+            let stored: Arc<str> = transaction.query_row(
+                "SELECT dna_sequence FROM DnaSequence WHERE id = ?1",
+                [id.clone()],
+                |row| row.get(0),
+            )?;
+            if stored.is_empty() {
+                continue;
+            }
+            if let Some(stored) = cipher.rewrap(&id, stored)? {
+                transaction.execute("UPDATE DnaSequence SET dna_sequence = ?2 WHERE id = ?1", (id, stored))?;
+                rewritten += 1;
+            }
+        }
+        let chunks: Vec<(String, Arc<str>)> = transaction
+            .prepare("SELECT hash, data FROM Chunk")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        for (hash, stored) in chunks {
+            if let Some(stored) = cipher.rewrap(&hash, stored)? {
+                transaction.execute("UPDATE Chunk SET data = ?2 WHERE hash = ?1", (hash, stored))?;
+                rewritten += 1;
+            }
+        }
+        let parts: Vec<(String, usize, Arc<str>)> = transaction
+            .prepare("SELECT upload, idx, data FROM UploadPart")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<Result<_, _>>()?;
+        for (upload, idx, stored) in parts {
+            if let Some(stored) = cipher.rewrap(&part_key(&upload, idx), stored)? {
+                transaction.execute("UPDATE UploadPart SET data = ?3 WHERE upload = ?1 AND idx = ?2", (upload, idx, stored))?;
+            }
+        }
+        let changes: Vec<(i64, Arc<str>, Arc<str>)> = transaction
+            .prepare("SELECT rowid, id, variants FROM SequenceChange")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<Result<_, _>>()?;
+        for (rowid, id, stored) in changes {
+            if let Some(stored) = cipher.rewrap(&id, stored)? {
+                transaction.execute("UPDATE SequenceChange SET variants = ?2 WHERE rowid = ?1", (rowid, stored))?;
+            }
+        }
+        transaction.commit()?;
+        Ok(rewritten)
+    }
+
+    /// Retrieves the IDs of all stored DNA sequences.
+    pub fn get_dna_sequence_ids(&self) -> Result<Vec<Arc<str>>, rusqlite::Error> {
+        let mut query = self.connection.prepare("SELECT id FROM DnaSequence ORDER BY id;")?;
+        let ids = query.query_map([], |row| row.get::<_, String>(0))?
+            .map(|id| id.map(Arc::from))
+            .collect();
+        ids
+    }
+
+    /// Retrieves up to `limit` sequence IDs following `after`, in order.
+    pub fn get_dna_sequence_ids_after(&self, after: Option<&str>, limit: usize) -> Result<Vec<Arc<str>>, rusqlite::Error> {
+        let mut query = self.connection.prepare("SELECT id FROM DnaSequence WHERE ?1 IS NULL OR id > ?1 ORDER BY id LIMIT ?2;")?;
+        let ids = query.query_map(rusqlite::params![after, limit as i64], |row| row.get::<_, String>(0))?
+            .map(|id| id.map(Arc::from))
+            .collect();
+        ids
+    }
+
+    /// Retrieves the IDs of sequences containing any of the given k-mers.
+    pub fn get_dna_sequence_ids_by_kmers(&self, kmers: &[String]) -> Result<Vec<Arc<str>>, rusqlite::Error> {
+        let mut query = self.connection.prepare("SELECT id FROM Kmer WHERE kmer = ?1;")?;
+        let mut ids = BTreeSet::new();
+        for kmer in kmers {
+            for id in query.query_map([self.kmer_key(kmer)], |row| row.get::<_, String>(0))? {
+                ids.insert(id?);
+            }
+        }
+        Ok(ids.into_iter().map(Arc::from).collect())
+    }
+
     /// Inserts or updates a public key in the database.
     pub fn push_public_key(&self, public_key: &PublicKey) -> Result<Arc<str>, rusqlite::Error> {
         self.connection.execute(
@@ -96,16 +680,691 @@ impl DbHandle {
         })
     }
 
+    /// Retrieves all public keys.
+    pub fn get_public_keys(&self) -> Result<Vec<PublicKey>, rusqlite::Error> {
+        let mut query = self.connection.prepare("SELECT id, public_key FROM PublicKey ORDER BY id;")?;
+        let public_keys = query.query_map([], |row| Ok(PublicKey {
+            id: row.get(0)?,
+            public_key: row.get(1)?,
+        }))?.collect();
+        public_keys
+    }
+
+    /// Retrieves up to `limit` public keys whose IDs follow `after`, in order.
+    pub fn get_public_keys_after(&self, after: Option<&str>, limit: usize) -> Result<Vec<PublicKey>, rusqlite::Error> {
+        let mut query = self.connection.prepare("SELECT id, public_key FROM PublicKey WHERE ?1 IS NULL OR id > ?1 ORDER BY id LIMIT ?2;")?;
+        let public_keys = query.query_map(rusqlite::params![after, limit as i64], |row| Ok(PublicKey {
+            id: row.get(0)?,
+            public_key: row.get(1)?,
+        }))?.collect();
+        public_keys
+    }
+
     /// Retrieves a DNA sequence by ID.
     pub fn get_dna_sequence(&self, id: Arc<str>) -> Result<DnaSequence, QuerryError> {
-        let mut query = self.connection.prepare("SELECT id, dna_sequence FROM DnaSequence WHERE id = ?1;")?;
+        let mut query = self.connection.prepare("SELECT id, dna_sequence, signature, encrypted, counter FROM DnaSequence WHERE id = ?1;")?;
         let mut rows = query.query(rusqlite::params![id])?;
         let maybe_row = rows.next()?;
         let row = maybe_row.ok_or(EmptyTableError::NoDnaSequences)?;
+        let stored: Arc<str> = row.get(1)?;
         Ok(DnaSequence {
             id: row.get(0)?,
-            dna_sequence: row.get(1)?
+            dna_sequence: match stored.is_empty() {
+                true => self.assemble(id)?,
+                false => self.open(&id, stored)?,
+            },
+            signature: row.get(2)?,
+            encrypted: row.get(3)?,
+            counter: row.get(4)?,
         })
     }
+
+    /// Checks whether a DNA sequence is stored.
+    pub fn has_dna_sequence(&self, id: Arc<str>) -> Result<bool, rusqlite::Error> {
+        self.connection.query_row("SELECT EXISTS(SELECT 1 FROM DnaSequence WHERE id = ?1);", [id], |row| row.get(0))
+    }
+
+    /// Checks whether a DNA sequence was encrypted by its owner before upload.
+    pub fn is_encrypted(&self, id: Arc<str>) -> Result<bool, QuerryError> {
+        let mut query = self.connection.prepare("SELECT encrypted FROM DnaSequence WHERE id = ?1;")?;
+        let mut rows = query.query(rusqlite::params![id])?;
+        let maybe_row = rows.next()?;
+        let row = maybe_row.ok_or(EmptyTableError::NoDnaSequences)?;
+        Ok(row.get(0)?)
+    }
+
+    /// Retrieves the length of a DNA sequence by ID without reading its contents.
+    /// Sequences not yet moved into chunks have to be read to be measured.
+    pub fn get_dna_sequence_length(&self, id: Arc<str>) -> Result<usize, QuerryError> {
+        let mut query = self.connection.prepare("SELECT dna_sequence = '', length FROM DnaSequence WHERE id = ?1;")?;
+        let mut rows = query.query(rusqlite::params![id])?;
+        let maybe_row = rows.next()?;
+        let row = maybe_row.ok_or(EmptyTableError::NoDnaSequences)?;
+        if !row.get::<_, bool>(0)? {
+            return Ok(self.get_dna_sequence(id)?.dna_sequence.len());
+        }
+        Ok(row.get(1)?)
+    }
+
+    /// Retrieves the half-open range `[start, end)` of a DNA sequence by ID.
+    /// Only the chunks overlapping the range are read.
+    pub fn get_dna_sequence_range(&self, id: Arc<str>, start: usize, end: usize) -> Result<Arc<str>, QuerryError> {
+        if !self.has_dna_sequence(id.clone())? {
+            return Err(EmptyTableError::NoDnaSequences.into());
+        }
+        if self.get_stored_dna_sequence(id.clone())?.is_some_and(|stored| !stored.is_empty()) {
+            let dna_sequence = self.get_dna_sequence(id)?.dna_sequence;
+            return dna_sequence.get(start..end)
+                .map(Arc::from)
+                .ok_or_else(|| RangeError(start, end, dna_sequence.len()).into());
+        }
+        let length = self.get_dna_sequence_length(id.clone())?;
+        if start > end || end > length {
+            return Err(RangeError(start, end, length).into());
+        }
+        let mut query = self.connection.prepare(
+            "SELECT hash, offset, length FROM SequenceChunk WHERE id = ?1 AND offset < ?3 AND offset + length > ?2 ORDER BY idx;"
+        )?;
+        let chunks: Vec<ChunkRef> = query
+            .query_map(rusqlite::params![id, start, end], |row| Ok(ChunkRef { hash: row.get(0)?, offset: row.get(1)?, length: row.get(2)? }))?
+            .collect::<Result<_, _>>()?;
+        let mut slice = String::with_capacity(end.saturating_sub(start));
+        for chunk in chunks {
+            let data = self.get_chunk(&chunk.hash)?;
+            let from = start.saturating_sub(chunk.offset);
+            let to = (end - chunk.offset).min(chunk.length);
+            slice.push_str(data.get(from..to).ok_or(EmptyTableError::NoDnaSequences)?);
+        }
+        Ok(slice.into())
+    }
+
+    /// Reassembles a sequence from its chunks.
+    fn assemble(&self, id: Arc<str>) -> Result<Arc<str>, QuerryError> {
+        let mut sequence = String::new();
+        for chunk in self.get_chunk_refs(id)? {
+            sequence.push_str(&self.get_chunk(&chunk.hash)?);
+        }
+        Ok(sequence.into())
+    }
+
+    /// Checks whether a tombstone exists for the given sequence ID.
+    pub fn is_deleted(&self, id: Arc<str>) -> Result<bool, rusqlite::Error> {
+        self.connection.query_row(
+            "SELECT EXISTS(SELECT 1 FROM Tombstone WHERE id = ?1)",
+            [id],
+            |row| row.get(0),
+        )
+    }
+
+    /// Retrieves the counter of the stored version of a sequence and the local time it was
+    /// written, or `None` if the sequence is not held.
+    pub fn get_sequence_stamp(&self, id: Arc<str>) -> Result<Option<(u64, i64)>, rusqlite::Error> {
+        self.connection
+            .query_row("SELECT counter, stored_at FROM DnaSequence WHERE id = ?1", [id], |row| Ok((row.get(0)?, row.get(1)?)))
+            .map(Some)
+            .or_else(|e| match e {
+                rusqlite::Error::QueryReturnedNoRows => Ok(None),
+                e => Err(e),
+            })
+    }
+
+    /// Records a tombstone and erases the sequence and its index entries.
+    /// `recorded_at` is the local time the grace period is counted from. Tombstones of a
+    /// version behind the stored counter are rejected, so they cannot delete a newer version.
+    pub fn push_tombstone(&self, tombstone: &Tombstone, recorded_at: i64) -> Result<Arc<str>, QuerryError> {
+        let transaction = self.connection.unchecked_transaction()?;
+        if let Some((counter, _)) = self.get_sequence_stamp(tombstone.id.clone())? {
+            if tombstone.counter < counter {
+                return Err(StaleError(tombstone.id.clone(), counter, tombstone.counter).into());
+            }
+        }
+        transaction.execute(
+            "INSERT OR IGNORE INTO Tombstone(id, counter, deleted_at, signature, recorded_at) VALUES(?1, ?2, ?3, ?4, ?5)",
+            (tombstone.id.clone(), tombstone.counter, tombstone.deleted_at, tombstone.signature.clone(), recorded_at),
+        )?;
+        transaction.execute("DELETE FROM DnaSequence WHERE id = ?1", [tombstone.id.clone()])?;
+        transaction.execute("DELETE FROM Kmer WHERE id = ?1", [tombstone.id.clone()])?;
+        transaction.execute("DELETE FROM SequenceChange WHERE id = ?1", [tombstone.id.clone()])?;
+        self.take_chunks(&transaction, &tombstone.id)?;
+        transaction.commit()?;
+        Ok(tombstone.id.clone())
+    }
+
+    /// Retrieves all tombstones.
+    pub fn get_tombstones(&self) -> Result<Vec<Tombstone>, rusqlite::Error> {
+        let mut query = self.connection.prepare("SELECT id, deleted_at, signature, counter FROM Tombstone ORDER BY id;")?;
+        let tombstones = query.query_map([], |row| Ok(Tombstone {
+            id: row.get(0)?,
+            counter: row.get(3)?,
+            deleted_at: row.get(1)?,
+            signature: row.get(2)?,
+        }))?.collect();
+        tombstones
+    }
+
+    /// Retrieves up to `limit` tombstones whose IDs follow `after`, in order.
+    pub fn get_tombstones_after(&self, after: Option<&str>, limit: usize) -> Result<Vec<Tombstone>, rusqlite::Error> {
+        let mut query = self.connection.prepare("SELECT id, deleted_at, signature, counter FROM Tombstone WHERE ?1 IS NULL OR id > ?1 ORDER BY id LIMIT ?2;")?;
+        let tombstones = query.query_map(rusqlite::params![after, limit as i64], |row| Ok(Tombstone {
+            id: row.get(0)?,
+            counter: row.get(3)?,
+            deleted_at: row.get(1)?,
+            signature: row.get(2)?,
+        }))?.collect();
+        tombstones
+    }
+
+    /// Runs SQLite's integrity check, returning its findings; `["ok"]` when healthy.
+    pub fn integrity_check(&self) -> Result<Vec<String>, rusqlite::Error> {
+        let mut query = self.connection.prepare("PRAGMA integrity_check;")?;
+        let findings = query.query_map([], |row| row.get(0))?.collect();
+        findings
+    }
+
+    /// Lists tables and columns of the current schema missing from the database.
+    pub fn schema_issues(&self) -> Result<Vec<String>, rusqlite::Error> {
+        let mut issues = vec![];
+        let mut query = self.connection.prepare("SELECT name FROM pragma_table_info(?1);")?;
+        for (table, columns) in SCHEMA {
+            let present: Vec<String> = query.query_map([table], |row| row.get(0))?.collect::<Result<_, _>>()?;
+            if present.is_empty() {
+                issues.push(format!("missing table {}", table));
+                continue;
+            }
+            for column in columns.iter().filter(|column| !present.iter().any(|name| name == *column)) {
+                issues.push(format!("missing column {}.{}", table, column));
+            }
+        }
+        Ok(issues)
+    }
+
+    /// Lists sequence ids referenced by the k-mer index but absent from the sequence table.
+    pub fn get_orphaned_kmer_ids(&self) -> Result<Vec<Arc<str>>, rusqlite::Error> {
+        let mut query = self.connection.prepare(
+            "SELECT DISTINCT id FROM Kmer WHERE id NOT IN (SELECT id FROM DnaSequence) ORDER BY id;"
+        )?;
+        let ids = query.query_map([], |row| row.get(0))?.collect();
+        ids
+    }
+
+    /// Lists sequences stored despite having a tombstone.
+    pub fn get_deleted_dna_sequence_ids(&self) -> Result<Vec<Arc<str>>, rusqlite::Error> {
+        let mut query = self.connection.prepare(
+            "SELECT id FROM DnaSequence WHERE id IN (SELECT id FROM Tombstone) ORDER BY id;"
+        )?;
+        let ids = query.query_map([], |row| row.get(0))?.collect();
+        ids
+    }
+
+    /// Reads a stored sequence value without decrypting it.
+    pub fn get_stored_dna_sequence(&self, id: Arc<str>) -> Result<Option<Arc<str>>, rusqlite::Error> {
+        self.connection.query_row("SELECT dna_sequence FROM DnaSequence WHERE id = ?1;", [id], |row| row.get(0))
+    }
+
+    /// Opens a stored value under the cipher. Without one, sealed values are refused instead of
+    /// being returned as ciphertext.
+    fn open(&self, key: &str, stored: Arc<str>) -> Result<Arc<str>, CipherError> {
+        match &self.cipher {
+            Some(cipher) => cipher.open(key, stored),
+            None if SequenceCipher::is_sealed(&stored) => Err(CipherError::Sealed),
+            None => Ok(stored),
+        }
+    }
+
+    /// Checks whether the database was opened with a cipher.
+    pub fn has_cipher(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    /// Writes a consistent online copy of the database to `path` with the SQLite backup API.
+    pub fn snapshot(&self, path: &Path) -> Result<(), rusqlite::Error> {
+        self.connection.backup(DatabaseName::Main, path, None)
+    }
+
+    /// Replaces the contents of the database with the database at `path`. Metadata under
+    /// `node_local` describes this node rather than its data, so it keeps its local values and
+    /// the copy's are dropped. The copy may predate the current schema, so it is migrated like
+    /// a database opened with [`DbHandle::new`].
+    pub fn restore(&mut self, path: &Path, node_local: &[&str]) -> Result<(), QuerryError> {
+        let kept = node_local.iter()
+            .map(|key| Ok((*key, self.get_meta(key)?)))
+            .collect::<Result<Vec<_>, rusqlite::Error>>()?;
+        self.connection.restore(DatabaseName::Main, path, None::<fn(Progress)>)?;
+        create_tables(&self.connection)?;
+        for (key, value) in kept {
+            self.connection.execute("DELETE FROM Meta WHERE key = ?1;", [key])?;
+            if let Some(value) = value {
+                self.set_meta(key, &value)?;
+            }
+        }
+        self.ensure_chunked()?;
+        self.ensure_chunk_refs()?;
+        self.ensure_kmer_index()
+    }
+
+    /// Reads a value from the metadata table.
+    pub fn get_meta(&self, key: &str) -> Result<Option<String>, rusqlite::Error> {
+        let mut query = self.connection.prepare("SELECT value FROM Meta WHERE key = ?1;")?;
+        let mut rows = query.query([key])?;
+        rows.next()?.map(|row| row.get(0)).transpose()
+    }
+
+    /// Stores a value in the metadata table.
+    pub fn set_meta(&self, key: &str, value: &str) -> Result<(), rusqlite::Error> {
+        self.connection.execute("INSERT OR REPLACE INTO Meta(key, value) VALUES(?1, ?2);", [key, value])?;
+        Ok(())
+    }
+
+    /// Removes tombstones recorded before `recorded_before`, returning how many were purged.
+    pub fn purge_tombstones(&self, recorded_before: i64) -> Result<usize, rusqlite::Error> {
+        self.connection.execute("DELETE FROM Tombstone WHERE recorded_at < ?1", [recorded_before])
+    }
+
+    /// Opens a chunked upload of sequence `id`.
+    pub fn push_upload(&self, upload: &str, id: Arc<str>, created_at: i64) -> Result<(), rusqlite::Error> {
+        self.connection.execute("INSERT INTO Upload(upload, id, created_at) VALUES(?1, ?2, ?3)", (upload, id, created_at))?;
+        Ok(())
+    }
+
+    /// Retrieves the sequence ID an upload is for, or `None` for unknown or expired uploads.
+    pub fn get_upload(&self, upload: &str) -> Result<Option<Arc<str>>, rusqlite::Error> {
+        self.connection
+            .query_row("SELECT id FROM Upload WHERE upload = ?1", [upload], |row| row.get(0))
+            .map(Some)
+            .or_else(|e| match e {
+                rusqlite::Error::QueryReturnedNoRows => Ok(None),
+                e => Err(e),
+            })
+    }
+
+    /// Stores part `idx` of an upload, replacing a previous attempt at the same part.
+    pub fn push_upload_part(&self, upload: &str, idx: usize, data: &str) -> Result<(), rusqlite::Error> {
+        let stored = match &self.cipher {
+            Some(cipher) => cipher.seal(&part_key(upload, idx), data),
+            None => data.into(),
+        };
+        self.connection.execute(
+            "INSERT OR REPLACE INTO UploadPart(upload, idx, data) VALUES(?1, ?2, ?3)",
+            (upload, idx, stored),
+        )?;
+        Ok(())
+    }
+
+    /// Retrieves the parts of an upload ordered by index.
+    pub fn get_upload_parts(&self, upload: &str) -> Result<Vec<(usize, Arc<str>)>, QuerryError> {
+        let parts: Vec<(usize, Arc<str>)> = self.connection
+            .prepare("SELECT idx, data FROM UploadPart WHERE upload = ?1 ORDER BY idx")?
+            .query_map([upload], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        parts.into_iter().map(|(idx, stored)| Ok((idx, self.open(&part_key(upload, idx), stored)?))).collect()
+    }
+
+    /// Drops an upload and its parts.
+    pub fn remove_upload(&self, upload: &str) -> Result<(), rusqlite::Error> {
+        self.connection.execute("DELETE FROM UploadPart WHERE upload = ?1", [upload])?;
+        self.connection.execute("DELETE FROM Upload WHERE upload = ?1", [upload])?;
+        Ok(())
+    }
+
+    /// Drops uploads opened before `created_before` that were never finalized.
+    pub fn purge_uploads(&self, created_before: i64) -> Result<usize, rusqlite::Error> {
+        self.connection.execute(
+            "DELETE FROM UploadPart WHERE upload IN (SELECT upload FROM Upload WHERE created_at < ?1)",
+            [created_before],
+        )?;
+        self.connection.execute("DELETE FROM Upload WHERE created_at < ?1", [created_before])
+    }
+
+    /// Queues one copy of a message per peer, all due at once.
+    pub fn enqueue_messages(&self, peers: &[String], idempotency_key: &str, path: &str, body: &str, now_ms: i64) -> Result<(), rusqlite::Error> {
+        let transaction = self.connection.unchecked_transaction()?;
+        {
+            let mut insert = transaction.prepare(
+                "INSERT OR IGNORE INTO Outbox(peer, idempotency_key, path, body, next_attempt_at) VALUES(?1, ?2, ?3, ?4, ?5);"
+            )?;
+            for peer in peers {
+                insert.execute((peer, idempotency_key, path, body, now_ms))?;
+            }
+        }
+        transaction.commit()
+    }
+
+    /// Returns the oldest message queued for `peer`.
+    pub fn next_message(&self, peer: &str) -> Result<Option<OutboxMessage>, rusqlite::Error> {
+        let mut query = self.connection.prepare(
+            "SELECT seq, peer, idempotency_key, path, body, attempts, next_attempt_at FROM Outbox
+            WHERE peer = ?1 ORDER BY seq LIMIT 1;"
+        )?;
+        let mut rows = query.query([peer])?;
+        rows.next()?.map(|row| Ok(OutboxMessage {
+            seq: row.get(0)?,
+            peer: row.get(1)?,
+            idempotency_key: row.get(2)?,
+            path: row.get(3)?,
+            body: row.get(4)?,
+            attempts: row.get(5)?,
+            next_attempt_at: row.get(6)?,
+        })).transpose()
+    }
+
+    /// Lists the peers whose oldest queued message is due at `now_ms`.
+    pub fn get_due_peers(&self, now_ms: i64) -> Result<Vec<String>, rusqlite::Error> {
+        let mut query = self.connection.prepare(
+            "SELECT peer FROM Outbox AS head
+            WHERE seq = (SELECT MIN(seq) FROM Outbox WHERE peer = head.peer) AND next_attempt_at <= ?1;"
+        )?;
+        let peers = query.query_map([now_ms], |row| row.get(0))?.collect();
+        peers
+    }
+
+    /// Removes a delivered or refused message from the outbox.
+    pub fn remove_message(&self, seq: i64) -> Result<(), rusqlite::Error> {
+        self.connection.execute("DELETE FROM Outbox WHERE seq = ?1;", [seq])?;
+        Ok(())
+    }
+
+    /// Records a failed delivery attempt and when to retry.
+    pub fn postpone_message(&self, seq: i64, attempts: u32, next_attempt_at: i64) -> Result<(), rusqlite::Error> {
+        self.connection.execute(
+            "UPDATE Outbox SET attempts = ?2, next_attempt_at = ?3 WHERE seq = ?1;",
+            (seq, attempts, next_attempt_at),
+        )?;
+        Ok(())
+    }
+
+    /// Drops every message queued for `peer`, returning how many were dropped.
+    pub fn drop_messages(&self, peer: &str) -> Result<usize, rusqlite::Error> {
+        self.connection.execute("DELETE FROM Outbox WHERE peer = ?1;", [peer])
+    }
+
+    /// Lists the peers still holding a copy of a message, with its failed attempts.
+    pub fn get_undelivered(&self, idempotency_key: &str) -> Result<Vec<(String, u32)>, rusqlite::Error> {
+        let mut query = self.connection.prepare("SELECT peer, attempts FROM Outbox WHERE idempotency_key = ?1;")?;
+        let peers = query.query_map([idempotency_key], |row| Ok((row.get(0)?, row.get(1)?)))?.collect();
+        peers
+    }
+
+    /// Counts the messages queued for each peer.
+    pub fn get_queue_depths(&self) -> Result<Vec<(String, usize)>, rusqlite::Error> {
+        let mut query = self.connection.prepare("SELECT peer, COUNT(*) FROM Outbox GROUP BY peer;")?;
+        let depths = query.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?.collect();
+        depths
+    }
+
+    /// Checks whether a message with this idempotency key was already applied.
+    pub fn is_received(&self, idempotency_key: &str) -> Result<bool, rusqlite::Error> {
+        self.connection.query_row(
+            "SELECT EXISTS(SELECT 1 FROM Received WHERE idempotency_key = ?1);",
+            [idempotency_key],
+            |row| row.get(0),
+        )
+    }
+
+    /// Remembers that a message was applied, so redeliveries are acknowledged without effect.
+    pub fn mark_received(&self, idempotency_key: &str, received_at: i64) -> Result<(), rusqlite::Error> {
+        self.connection.execute(
+            "INSERT OR IGNORE INTO Received(idempotency_key, received_at) VALUES(?1, ?2);",
+            (idempotency_key, received_at),
+        )?;
+        Ok(())
+    }
+
+    /// Forgets idempotency keys received before `received_before`, returning how many were purged.
+    pub fn purge_received(&self, received_before: i64) -> Result<usize, rusqlite::Error> {
+        self.connection.execute("DELETE FROM Received WHERE received_at < ?1", [received_before])
+    }
+
+    /// Appends an operation executed at its position in the total order to the operation log.
+    pub fn push_ordered(&self, operation: &OrderedOperation) -> Result<(), rusqlite::Error> {
+        self.connection.execute(
+            "INSERT OR REPLACE INTO Ordered(seq, view, origin, digest, operation, prev, state) VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7);",
+            (operation.seq, operation.view, &operation.origin, &operation.digest, &operation.operation, &operation.prev, &operation.state),
+        )?;
+        Ok(())
+    }
+
+    /// Retrieves the logged operations after `after` up to `until`, at most `limit` of them.
+    pub fn get_ordered(&self, after: u64, until: u64, limit: usize) -> Result<Vec<OrderedOperation>, rusqlite::Error> {
+        let mut query = self.connection.prepare(
+            "SELECT seq, view, origin, digest, operation, prev, state FROM Ordered WHERE seq > ?1 AND seq <= ?2 ORDER BY seq LIMIT ?3;"
+        )?;
+        let operations = query.query_map((after, until, limit), |row| Ok(OrderedOperation {
+            seq: row.get(0)?,
+            view: row.get(1)?,
+            origin: row.get(2)?,
+            digest: row.get(3)?,
+            operation: row.get(4)?,
+            prev: row.get(5)?,
+            state: row.get(6)?,
+        }))?.collect();
+        operations
+    }
+
+    /// Returns the position and state of the last executed ordered operation.
+    pub fn get_last_ordered(&self) -> Result<Option<(u64, String)>, rusqlite::Error> {
+        let mut query = self.connection.prepare("SELECT seq, state FROM Ordered ORDER BY seq DESC LIMIT 1;")?;
+        let mut rows = query.query([])?;
+        rows.next()?.map(|row| Ok((row.get(0)?, row.get(1)?))).transpose()
+    }
+
+    /// Returns the state after the ordered operation at `seq`.
+    pub fn get_ordered_state(&self, seq: u64) -> Result<Option<String>, rusqlite::Error> {
+        let mut query = self.connection.prepare("SELECT state FROM Ordered WHERE seq = ?1;")?;
+        let mut rows = query.query([seq])?;
+        rows.next()?.map(|row| row.get(0)).transpose()
+    }
+
+    /// Stores evidence of misbehaviour under the digest of its proof. Returns false if the same
+    /// proof was already recorded.
+    pub fn push_evidence(&self, digest: &str, evidence: &Evidence) -> Result<bool, rusqlite::Error> {
+        let kind = serde_json::to_value(evidence.kind).expect("evidence kinds serialize");
+        let proof = serde_json::to_string(&evidence.proof).expect("proofs serialize");
+        let inserted = self.connection.execute(
+            "INSERT OR IGNORE INTO Evidence(digest, peer, kind, detail, proof, recorded_at) VALUES(?1, ?2, ?3, ?4, ?5, ?6);",
+            (digest, &evidence.peer, kind.as_str(), &evidence.detail, &proof, evidence.recorded_at),
+        )?;
+        Ok(inserted > 0)
+    }
+
+    /// Retrieves every piece of evidence, oldest first.
+    pub fn get_evidence(&self) -> Result<Vec<Evidence>, rusqlite::Error> {
+        let mut query = self.connection.prepare(
+            "SELECT peer, kind, detail, proof, recorded_at FROM Evidence ORDER BY recorded_at, rowid;"
+        )?;
+        let evidence = query.query_map([], |row| {
+            let kind: String = row.get(1)?;
+            let proof: String = row.get(3)?;
+            Ok(Evidence {
+                peer: row.get(0)?,
+                kind: serde_json::from_value::<EvidenceKind>(kind.into())
+                    .map_err(|e| rusqlite::Error::FromSqlConversionFailure(1, rusqlite::types::Type::Text, Box::new(e)))?,
+                detail: row.get(2)?,
+                proof: serde_json::from_str(&proof)
+                    .map_err(|e| rusqlite::Error::FromSqlConversionFailure(3, rusqlite::types::Type::Text, Box::new(e)))?,
+                recorded_at: row.get(4)?,
+            })
+        })?.collect();
+        evidence
+    }
+
+    /// Counts the pieces of evidence against each peer.
+    pub fn count_evidence(&self) -> Result<Vec<(String, usize)>, rusqlite::Error> {
+        let mut query = self.connection.prepare("SELECT peer, COUNT(*) FROM Evidence GROUP BY peer;")?;
+        let counts = query.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?.collect();
+        counts
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CHUNK_RETENTION_SECS;
+
+    fn db() -> DbHandle {
+        DbHandle::new(":memory:".to_string(), None).unwrap()
+    }
+
+    /// Returns a sequence of `length` pseudo-random bases, so it is cut into several chunks.
+    fn bases(length: usize, seed: u64) -> String {
+        let mut state = seed;
+        (0..length).map(|_| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            b"ACGT"[(state >> 62) as usize] as char
+        }).collect()
+    }
+
+    fn put(db: &DbHandle, id: &str, sequence: &str, counter: u64) {
+        db.push_dna_sequence(&DnaSequence::signed(id.into(), sequence.into(), "signature".into(), false, counter)).unwrap();
+    }
+
+    /// Returns the reference count and release time of a chunk.
+    fn chunk(db: &DbHandle, hash: &str) -> (i64, Option<i64>) {
+        db.connection.query_row("SELECT refs, released_at FROM Chunk WHERE hash = ?1", [hash], |row| Ok((row.get(0)?, row.get(1)?))).unwrap()
+    }
+
+    fn hashes(db: &DbHandle, id: &str) -> Vec<String> {
+        db.get_chunk_refs(id.into()).unwrap().into_iter().map(|chunk| chunk.hash).collect()
+    }
+
+    #[test]
+    fn shared_chunks_are_stored_once() {
+        let db = db();
+        let sequence = bases(400 * 1024, 1);
+        let mut edited = sequence.clone();
+        edited.replace_range(10..11, if &sequence[10..11] == "A" { "C" } else { "A" });
+        put(&db, "a", &sequence, 1);
+        put(&db, "b", &edited, 1);
+
+        let (a, b) = (hashes(&db, "a"), hashes(&db, "b"));
+        assert!(a.len() > 1);
+        let shared: Vec<&String> = a.iter().filter(|hash| b.contains(hash)).collect();
+        assert_eq!(shared.len(), a.len() - 1);
+        assert!(shared.iter().all(|hash| chunk(&db, hash) == (2, None)));
+        let stats = db.get_storage_stats().unwrap();
+        assert_eq!(stats.chunks, a.len() + 1);
+        assert!(db.get_miscounted_chunks().unwrap().is_empty());
+        assert_eq!(db.get_dna_sequence("b".into()).unwrap().dna_sequence.as_ref(), edited);
+    }
+
+    #[test]
+    fn replaced_chunks_are_released_and_collected_after_the_retention() {
+        let db = db();
+        let (old, new) = (bases(1000, 1), bases(1000, 2));
+        put(&db, "a", &old, 1);
+        put(&db, "b", &old, 1);
+        let hash = hashes(&db, "a").remove(0);
+        assert_eq!(chunk(&db, &hash), (2, None));
+
+        put(&db, "a", &new, 2);
+        assert_eq!(chunk(&db, &hash), (1, None));
+        db.push_tombstone(&Tombstone::new("b".into(), 1, 0, "signature".into()), 0).unwrap();
+        let (refs, released_at) = chunk(&db, &hash);
+        assert_eq!(refs, 0);
+        let released_at = released_at.unwrap();
+        assert!(db.get_miscounted_chunks().unwrap().is_empty());
+
+        // Kept while within the retention, as the purge task sees it.
+        assert_eq!(db.collect_chunks(released_at - CHUNK_RETENTION_SECS).unwrap(), 0);
+        assert!(db.has_chunk(&hash).unwrap());
+        assert_eq!(db.collect_chunks(released_at + 1).unwrap(), 1);
+        assert!(!db.has_chunk(&hash).unwrap());
+        assert!(db.has_chunk(&hashes(&db, "a")[0]).unwrap());
+    }
+
+    #[test]
+    fn resubmitted_content_reuses_a_released_chunk() {
+        let db = db();
+        let (old, new) = (bases(1000, 1), bases(1000, 2));
+        put(&db, "a", &old, 1);
+        let hash = hashes(&db, "a").remove(0);
+        put(&db, "a", &new, 2);
+        assert_eq!(chunk(&db, &hash).0, 0);
+
+        put(&db, "a", &old, 3);
+        assert_eq!(chunk(&db, &hash), (1, None));
+        assert_eq!(db.collect_chunks(i64::MAX).unwrap(), 1); // Only the chunk of `new`.
+        assert!(db.has_chunk(&hash).unwrap());
+    }
+
+    fn temp_path() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("repyh-test-{}.db", uuid::Uuid::new_v4()))
+    }
+
+    #[test]
+    fn restore_round_trips_a_snapshot() {
+        let source = db();
+        let sequence = bases(200_000, 3);
+        put(&source, "a", &sequence, 1);
+        source.set_meta("membership", "source").unwrap();
+        let path = temp_path();
+        source.snapshot(&path).unwrap();
+
+        let mut target = db();
+        put(&target, "b", "ACGTACGTACGT", 1);
+        target.set_meta("membership", "target").unwrap();
+        target.restore(&path, &["membership"]).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(&*target.get_dna_sequence("a".into()).unwrap().dna_sequence, sequence);
+        assert!(target.get_dna_sequence("b".into()).is_err());
+        assert_eq!(target.get_meta("membership").unwrap().as_deref(), Some("target"));
+        assert!(target.get_miscounted_chunks().unwrap().is_empty());
+        let kmers = vec![sequence[1000..1000 + KMER_LENGTH].to_string()];
+        assert_eq!(target.get_dna_sequence_ids_by_kmers(&kmers).unwrap(), vec![Arc::from("a")]);
+    }
+
+    #[test]
+    fn restore_migrates_an_old_snapshot() {
+        let path = temp_path();
+        let old = Connection::open(&path).unwrap();
+        old.execute("CREATE TABLE DnaSequence(id TEXT PRIMARY KEY, dna_sequence TEXT);", []).unwrap();
+        old.execute("INSERT INTO DnaSequence(id, dna_sequence) VALUES('a', 'ACGTACGTACGTAAAA');", []).unwrap();
+        drop(old);
+
+        let mut db = db();
+        db.restore(&path, &[]).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // The inline sequence is moved into chunks and indexed, as on a fresh start.
+        assert_eq!(&*db.get_dna_sequence("a".into()).unwrap().dna_sequence, "ACGTACGTACGTAAAA");
+        assert_eq!(hashes(&db, "a").len(), 1);
+        assert!(db.get_miscounted_chunks().unwrap().is_empty());
+        assert!(db.schema_issues().unwrap().is_empty());
+        let kmers = vec!["GTACGTAA".to_string()];
+        assert_eq!(db.get_dna_sequence_ids_by_kmers(&kmers).unwrap(), vec![Arc::from("a")]);
+    }
+
+    /// Checks ranges of `id`, whose sequence is `sequence`, against slicing it directly.
+    fn assert_ranges(db: &DbHandle, id: &str, sequence: &str) {
+        let length = sequence.len();
+        for (start, end) in [(0, length), (10, 20), (length - 1, length), (length / 2, length / 2)] {
+            assert_eq!(&*db.get_dna_sequence_range(id.into(), start, end).unwrap(), &sequence[start..end]);
+        }
+        for (start, end) in [(20, 10), (0, length + 1), (length + 1, length + 2)] {
+            let result = db.get_dna_sequence_range(id.into(), start, end);
+            assert!(matches!(result, Err(QuerryError::RangeErrorW(RangeError(s, e, l))) if (s, e, l) == (start, end, length)));
+        }
+    }
+
+    #[test]
+    fn ranges_of_chunked_sequences() {
+        let db = db();
+        let sequence = bases(300_000, 4);
+        put(&db, "a", &sequence, 1);
+        assert!(hashes(&db, "a").len() > 1);
+        assert_ranges(&db, "a", &sequence);
+        // Across a chunk boundary.
+        let boundary = db.get_chunk_refs("a".into()).unwrap()[1].offset;
+        assert_eq!(&*db.get_dna_sequence_range("a".into(), boundary - 5, boundary + 5).unwrap(), &sequence[boundary - 5..boundary + 5]);
+        assert!(matches!(db.get_dna_sequence_range("b".into(), 0, 1), Err(QuerryError::EmptyTableErrorW(EmptyTableError::NoDnaSequences))));
+    }
+
+    #[test]
+    fn ranges_of_inline_sequences() {
+        let db = db();
+        let sequence = bases(100, 5);
+        // A row written before sequences were chunked.
+        db.connection.execute("INSERT INTO DnaSequence(id, dna_sequence) VALUES('a', ?1);", [&sequence]).unwrap();
+        assert_ranges(&db, "a", &sequence);
+    }
+}
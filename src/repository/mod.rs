@@ -1 +1,5 @@
 pub mod db;
+pub mod chunk;
+pub mod cipher;
+pub mod snapshot;
+pub mod verify;
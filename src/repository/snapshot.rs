@@ -0,0 +1,271 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::identity::NodeIdentity;
+use crate::model::{public_key::PublicKey, record::Record};
+use crate::repository::cipher::{to_hex, SequenceCipher};
+use crate::repository::db::{DbHandle, QuerryError};
+
+/// File name of the database copy inside a snapshot directory.
+pub const SNAPSHOT_DATABASE: &str = "dna.db";
+/// File name of the signed manifest inside a snapshot directory.
+pub const SNAPSHOT_MANIFEST: &str = "manifest.json";
+
+/// Errors for creating, verifying or restoring snapshots.
+#[derive(Error, Debug, derive_more::From, derive_more::Display)]
+pub enum SnapshotError {
+    QueryFailed(QuerryError),
+    #[display(fmt = "Snapshot I/O failed: {}", _0)]
+    Io(std::io::Error),
+    #[display(fmt = "Malformed manifest: {}", _0)]
+    Manifest(serde_json::Error),
+    #[display(fmt = "Manifest signature does not verify")]
+    #[from(ignore)]
+    BadSignature,
+    #[display(fmt = "Manifest is signed by {}, which is neither this node nor a configured peer", _0)]
+    #[from(ignore)]
+    UntrustedSigner(String),
+    #[display(fmt = "Snapshot does not match its manifest: {}", _0)]
+    #[from(ignore)]
+    Mismatch(String),
+}
+
+impl From<rusqlite::Error> for SnapshotError {
+    fn from(e: rusqlite::Error) -> Self {
+        SnapshotError::QueryFailed(e.into())
+    }
+}
+
+/// Summary of a database's contents, signed by the node that produced it.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SnapshotManifest {
+    pub node_id: String,
+    pub created_at: i64,
+    pub public_keys: usize,
+    pub dna_sequences: usize,
+    pub tombstones: usize,
+    pub merkle_root: String, // Hex SHA-256 Merkle root over every record, see `merkle_root`.
+    pub public_key: String, // Base64 Ed25519 key of the signing node.
+    pub signature: Arc<str>,
+}
+
+impl SnapshotManifest {
+    /// Describes the current contents of the database and signs the result.
+    pub fn describe(db: &DbHandle, node_id: &str, identity: &NodeIdentity) -> Result<Self, QuerryError> {
+        Ok(Self::sign(State::of(db)?, node_id, identity))
+    }
+
+    /// Describes the database like `describe`, locking it for one page of records at a time so
+    /// other requests proceed meanwhile. Writes made during the walk may or may not be described.
+    pub fn describe_paged(db: &Mutex<DbHandle>, node_id: &str, identity: &NodeIdentity) -> Result<Self, QuerryError> {
+        let mut state = State::default();
+        let mut cursor = Cursor::default();
+        loop {
+            let page = cursor.next_page(&db.lock().unwrap(), PAGE_RECORDS)?;
+            if page.is_empty() {
+                return Ok(Self::sign(state, node_id, identity));
+            }
+            page.iter().for_each(|record| state.add(record));
+        }
+    }
+
+    fn sign(state: State, node_id: &str, identity: &NodeIdentity) -> Self {
+        let merkle_root = state.merkle_root();
+        let mut manifest = SnapshotManifest {
+            node_id: node_id.to_string(),
+            created_at: chrono::Utc::now().timestamp(),
+            public_keys: state.public_keys,
+            dna_sequences: state.dna_sequences,
+            tombstones: state.tombstones,
+            merkle_root,
+            public_key: identity.public_key(),
+            signature: "".into(),
+        };
+        manifest.signature = identity.sign(manifest.message().as_bytes());
+        manifest
+    }
+
+    /// Returns the message the node signs.
+    pub fn message(&self) -> Arc<str> {
+        format!(
+            "snapshot:{}:{}:{}:{}:{}:{}",
+            self.node_id, self.created_at, self.public_keys, self.dna_sequences, self.tombstones, self.merkle_root,
+        ).into()
+    }
+
+    /// Verifies the manifest signature against its embedded public key.
+    pub fn check_signature(&self) -> Result<(), SnapshotError> {
+        let public_key = PublicKey::from_raw(self.node_id.clone(), self.public_key.clone())
+            .map_err(|_| SnapshotError::BadSignature)?;
+        PublicKey::check_signature(self.signature.clone(), public_key, self.message())
+            .map_err(|_| SnapshotError::BadSignature)
+    }
+
+    /// Checks that the manifest describes the contents of `db`.
+    pub fn check_contents(&self, db: &DbHandle) -> Result<(), SnapshotError> {
+        let state = State::of(db)?;
+        let expected = (self.public_keys, self.dna_sequences, self.tombstones);
+        let found = (state.public_keys, state.dna_sequences, state.tombstones);
+        if expected != found {
+            return Err(SnapshotError::Mismatch(format!(
+                "expected {:?} public keys, sequences and tombstones, found {:?}", expected, found,
+            )));
+        }
+        let merkle_root = state.merkle_root();
+        if self.merkle_root != merkle_root {
+            return Err(SnapshotError::Mismatch(format!(
+                "expected Merkle root {}, found {}", self.merkle_root, merkle_root,
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Record counts and Merkle leaves of a database.
+#[derive(Default)]
+struct State {
+    public_keys: usize,
+    dna_sequences: usize,
+    tombstones: usize,
+    leaves: Vec<[u8; 32]>,
+}
+
+impl State {
+    /// Reads the whole database a page at a time.
+    fn of(db: &DbHandle) -> Result<Self, QuerryError> {
+        let mut state = State::default();
+        let mut cursor = Cursor::default();
+        loop {
+            let page = cursor.next_page(db, PAGE_RECORDS)?;
+            if page.is_empty() {
+                return Ok(state);
+            }
+            page.iter().for_each(|record| state.add(record));
+        }
+    }
+
+    /// Adds the next record in the order of `Cursor`. Sequences are hashed in plaintext so
+    /// nodes with different keys at rest agree on the root.
+    fn add(&mut self, record: &Record) {
+        self.leaves.push(leaf(&match record {
+            Record::PublicKey { id, public_key } => {
+                self.public_keys += 1;
+                format!("public_key\n{}\n{}", id, public_key)
+            },
+            Record::DnaSequence(dna_sequence) => {
+                self.dna_sequences += 1;
+                format!(
+                    "dna_sequence\n{}\n{}\n{}\n{}",
+                    dna_sequence.id,
+                    to_hex(&Sha256::digest(dna_sequence.dna_sequence.as_bytes())),
+                    dna_sequence.signature.as_deref().unwrap_or(""),
+                    dna_sequence.encrypted,
+                )
+            },
+            Record::Tombstone(tombstone) => {
+                self.tombstones += 1;
+                format!("tombstone\n{}\n{}\n{}", tombstone.id, tombstone.deleted_at, tombstone.signature)
+            },
+        }));
+    }
+
+    fn merkle_root(&self) -> String {
+        to_hex(&merkle_root(self.leaves.clone()))
+    }
+}
+
+/// Records read per page by a `Cursor`. Paged readers release the database between pages.
+pub const PAGE_RECORDS: usize = 64;
+
+/// Position of a paged read over every record in a fixed order: public keys, sequences, then
+/// tombstones, each by id. Sequences are decrypted.
+#[derive(Default)]
+pub struct Cursor {
+    section: usize, // 0 for public keys, 1 for sequences, 2 for tombstones, 3 once done.
+    after: Option<Arc<str>>, // Id of the last record read in the section.
+}
+
+impl Cursor {
+    /// Reads up to `limit` records past the cursor and moves it after them. Returns an empty
+    /// page once every record was read.
+    pub fn next_page(&mut self, db: &DbHandle, limit: usize) -> Result<Vec<Record>, QuerryError> {
+        while self.section < 3 {
+            let after = self.after.as_deref();
+            let page: Vec<Record> = match self.section {
+                0 => db.get_public_keys_after(after, limit)?.into_iter()
+                    .map(|public_key| Record::PublicKey { id: public_key.id.clone(), public_key: public_key.encode() })
+                    .collect(),
+                1 => db.get_dna_sequence_ids_after(after, limit)?.into_iter()
+                    .map(|id| db.get_dna_sequence(id).map(Record::DnaSequence))
+                    .collect::<Result<_, _>>()?,
+                _ => db.get_tombstones_after(after, limit)?.into_iter().map(Record::Tombstone).collect(),
+            };
+            if page.len() < limit {
+                self.section += 1;
+                self.after = None;
+            } else {
+                self.after = page.last().map(Record::id);
+            }
+            if !page.is_empty() {
+                return Ok(page);
+            }
+        }
+        Ok(vec![])
+    }
+}
+
+/// Hashes a record into a Merkle leaf, domain-separated from inner nodes.
+fn leaf(record: &str) -> [u8; 32] {
+    Sha256::new().chain_update([0u8]).chain_update(record.as_bytes()).finalize().into()
+}
+
+/// Computes a binary Merkle root, promoting the last node of odd-sized levels unchanged.
+pub fn merkle_root(mut level: Vec<[u8; 32]>) -> [u8; 32] {
+    if level.is_empty() {
+        return Sha256::digest([]).into();
+    }
+    while level.len() > 1 {
+        level = level.chunks(2)
+            .map(|pair| match pair {
+                [left, right] => Sha256::new().chain_update([1u8]).chain_update(left).chain_update(right).finalize().into(),
+                [single] => *single,
+                _ => unreachable!(),
+            })
+            .collect();
+    }
+    level[0]
+}
+
+/// Writes a snapshot of `db` to a new directory under `snapshot_dir` holding the database copy and
+/// its signed manifest. The caller must keep the database locked so both describe the same state.
+pub fn create(db: &DbHandle, node_id: &str, identity: &NodeIdentity, snapshot_dir: &Path) -> Result<(PathBuf, SnapshotManifest), SnapshotError> {
+    let manifest = SnapshotManifest::describe(db, node_id, identity)?;
+    let name: String = node_id.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' }).collect();
+    let dir = snapshot_dir.join(format!("{}-{}", name, manifest.created_at));
+    fs::create_dir_all(&dir)?;
+    db.snapshot(&dir.join(SNAPSHOT_DATABASE))?;
+    fs::write(dir.join(SNAPSHOT_MANIFEST), serde_json::to_string_pretty(&manifest)?)?;
+    Ok((dir, manifest))
+}
+
+/// Verifies a snapshot directory: the manifest must be signed by one of `trusted_keys` and
+/// describe the database copy exactly.
+pub fn verify(dir: &Path, cipher: Option<SequenceCipher>, trusted_keys: &[String]) -> Result<SnapshotManifest, SnapshotError> {
+    let manifest: SnapshotManifest = serde_json::from_str(&fs::read_to_string(dir.join(SNAPSHOT_MANIFEST))?)?;
+    manifest.check_signature()?;
+    if !trusted_keys.contains(&manifest.public_key) {
+        return Err(SnapshotError::UntrustedSigner(manifest.public_key));
+    }
+    let path = dir.join(SNAPSHOT_DATABASE);
+    if !path.is_file() {
+        return Err(SnapshotError::Mismatch(format!("{} is missing", path.display())));
+    }
+    let db = DbHandle::open_read_only(path.to_string_lossy().into_owned(), cipher)?;
+    manifest.check_contents(&db)?;
+    Ok(manifest)
+}
@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use serde::Serialize;
+
+use crate::model::public_key::PublicKey;
+use crate::repository::cipher::SequenceCipher;
+use crate::repository::db::{DbHandle, QuerryError};
+
+/// A record that failed verification and why.
+#[derive(Serialize)]
+pub struct RecordIssue {
+    pub id: Arc<str>,
+    pub reason: String,
+}
+
+impl RecordIssue {
+    fn new(id: Arc<str>, reason: impl Into<String>) -> Self {
+        RecordIssue { id, reason: reason.into() }
+    }
+}
+
+/// Machine-readable result of verifying a node's database.
+#[derive(Serialize)]
+pub struct VerifyReport {
+    pub database: String,
+    pub integrity: Vec<String>, // Findings of SQLite's integrity check, `["ok"]` when healthy.
+    pub schema: Vec<String>, // Missing tables and columns.
+    pub checked: usize, // Sequences and tombstones examined.
+    pub corrupt: Vec<RecordIssue>, // Records whose signature or contents do not verify.
+    pub unsigned: Vec<Arc<str>>, // Sequences stored without an owner signature.
+    pub orphaned: Vec<Arc<str>>, // Sequences and tombstones with no registered public key.
+    pub invariants: Vec<RecordIssue>, // Inconsistencies between tables.
+}
+
+impl VerifyReport {
+    /// Returns true when no check reported a problem.
+    pub fn is_healthy(&self) -> bool {
+        self.integrity == ["ok"]
+            && self.schema.is_empty()
+            && self.corrupt.is_empty()
+            && self.unsigned.is_empty()
+            && self.orphaned.is_empty()
+            && self.invariants.is_empty()
+    }
+}
+
+/// Walks every stored sequence and tombstone, re-verifying owner signatures against the
+/// registered public keys, and checks the schema and cross-table invariants.
+pub fn verify(db: &DbHandle, database: String) -> Result<VerifyReport, QuerryError> {
+    let mut report = VerifyReport {
+        database,
+        integrity: db.integrity_check()?,
+        schema: db.schema_issues()?,
+        checked: 0,
+        corrupt: vec![],
+        unsigned: vec![],
+        orphaned: vec![],
+        invariants: vec![],
+    };
+    if !report.schema.is_empty() {
+        return Ok(report);
+    }
+    let public_keys: HashMap<Arc<str>, PublicKey> = db.get_public_keys()?
+        .into_iter()
+        .map(|public_key| (public_key.id.clone(), public_key))
+        .collect();
+
+    for id in db.get_dna_sequence_ids()? {
+        report.checked += 1;
+        let stored = match db.get_stored_dna_sequence(id.clone()) {
+            Ok(Some(stored)) => stored,
+            Ok(None) => {
+                report.corrupt.push(RecordIssue::new(id, "sequence is NULL"));
+                continue;
+            },
+            Err(e) => {
+                report.corrupt.push(RecordIssue::new(id, e.to_string()));
+                continue;
+            },
+        };
+        if !db.has_cipher() && SequenceCipher::is_sealed(&stored) {
+            report.corrupt.push(RecordIssue::new(id, "encrypted at rest; set DNA_KEY_FILE or DNA_KEY to verify"));
+            continue;
+        }
+        match db.check_chunks(id.clone()) {
+            Ok(issues) if issues.is_empty() => (),
+            Ok(issues) => {
+                report.corrupt.extend(issues.into_iter().map(|issue| RecordIssue::new(id.clone(), issue)));
+                continue;
+            },
+            Err(e) => {
+                report.corrupt.push(RecordIssue::new(id, e.to_string()));
+                continue;
+            },
+        }
+        let dna_sequence = match db.get_dna_sequence(id.clone()) {
+            Ok(dna_sequence) => dna_sequence,
+            Err(e) => {
+                report.corrupt.push(RecordIssue::new(id, e.to_string()));
+                continue;
+            },
+        };
+        if let Err(e) = dna_sequence.validate() {
+            report.corrupt.push(RecordIssue::new(id.clone(), e.to_string()));
+        }
+        let Some(public_key) = public_keys.get(&id) else {
+            report.orphaned.push(id);
+            continue;
+        };
+        let Some(signature) = dna_sequence.signature.clone() else {
+            report.unsigned.push(id);
+            continue;
+        };
+        if let Err(e) = dna_sequence.check_signature(signature, public_key.clone()) {
+            report.corrupt.push(RecordIssue::new(id, e.to_string()));
+        }
+    }
+
+    for tombstone in db.get_tombstones()? {
+        report.checked += 1;
+        let Some(public_key) = public_keys.get(&tombstone.id) else {
+            report.orphaned.push(tombstone.id);
+            continue;
+        };
+        if let Err(e) = PublicKey::check_signature(tombstone.signature.clone(), public_key.clone(), tombstone.message()) {
+            report.corrupt.push(RecordIssue::new(tombstone.id, format!("tombstone: {}", e)));
+        }
+    }
+
+    for id in db.get_deleted_dna_sequence_ids()? {
+        report.invariants.push(RecordIssue::new(id, "sequence stored despite a tombstone"));
+    }
+    for id in db.get_orphaned_kmer_ids()? {
+        report.invariants.push(RecordIssue::new(id, "k-mer index references a missing sequence"));
+    }
+    for hash in db.get_miscounted_chunks()? {
+        report.invariants.push(RecordIssue::new(hash, "chunk reference count does not match the sequences using it"));
+    }
+    Ok(report)
+}
@@ -1,190 +1,388 @@
-use crate::model::{
-    dna_sequence::DnaSequence,
-    public_key::PublicKey,
-    patch::Patch,
+use crate::{
+    config::Config,
+    evidence::EvidenceStore,
+    repository::{chunk::{self, MAX_CHUNK}, db::DbHandle},
+    tls::{self, TlsError},
+    health::{FailureDetector, PeerMetrics},
+    membership::{ClusterView, Membership},
+    model::{
+        dna_sequence::DnaSequence,
+        manifest::SequenceManifest,
+        public_key::PublicKey,
+        patch::Patch,
+        tombstone::Tombstone,
+    },
 };
 use std::{
-    sync::{Arc, Mutex},
-    collections::HashMap,
+    sync::{Arc, Mutex, RwLock},
+    collections::{HashMap, HashSet},
 };
-use reqwest::{Client, Response};
-use tokio::time::{sleep, Duration};
+use reqwest::{header::CONTENT_TYPE, Client};
+use serde::Serialize;
+use tokio::{sync::Notify, time::{sleep, Duration, Instant}};
 use futures::future::join_all;
-use tracing::info;
+use tracing::{error, info, warn};
+use uuid::Uuid;
 
 
-const N_PEERS: u32 = 5;
-const BYZANTINE_THRESHOLD: u32 = (N_PEERS * 2 / 3) + 1; //4. Needs 7 reps to tolerate 2 traitors
+/// Header carrying the key receivers deduplicate replication messages by.
+pub(crate) const IDEMPOTENCY_KEY: &str = "Idempotency-Key";
+
+/// Membership a node replicates to, shared by every worker so reconfigurations apply at once.
+/// Replication messages go through the outbox of the node's database.
+#[derive(Clone)]
+pub struct Cluster {
+    pub node_id: String,
+    pub address: String, // API address peers reach this node at.
+    pub membership: Arc<RwLock<Membership>>,
+    pub detector: FailureDetector,
+    pub metrics: PeerMetrics,
+    pub evidence: EvidenceStore,
+    pub client: Client, // Node-wide client, so connections to peers are pooled and reused.
+    scheme: &'static str,
+    db: Arc<Mutex<DbHandle>>,
+    wake: Arc<Notify>, // Wakes the delivery task when messages are queued.
+    deliveries: Arc<Mutex<HashMap<String, HashMap<String, Delivery>>>>, // Outcome per peer of the broadcasts awaiting a quorum, by idempotency key.
+    request_timeout: Duration,
+    poll_interval: Duration,
+    retry_base: Duration,
+    retry_max: Duration,
+}
+
+impl Cluster {
+    /// Creates the cluster following `membership`, queuing messages in `db` and sending them
+    /// with `client`, with the retry settings of the node configuration.
+    pub fn new(config: &Config, membership: Membership, db: Arc<Mutex<DbHandle>>, client: Client) -> Self {
+        Cluster {
+            node_id: config.node_id.clone(),
+            address: config.api_address.clone(),
+            membership: Arc::new(RwLock::new(membership)),
+            detector: FailureDetector::new(&config.health),
+            metrics: PeerMetrics::default(),
+            evidence: EvidenceStore::new(&config.evidence, db.clone()),
+            client,
+            scheme: tls::scheme(config),
+            db,
+            wake: Arc::new(Notify::new()),
+            deliveries: Arc::new(Mutex::new(HashMap::new())),
+            request_timeout: Duration::from_millis(config.timeouts.request_ms),
+            poll_interval: Duration::from_millis(config.outbox.poll_ms),
+            retry_base: Duration::from_millis(config.outbox.retry_base_ms),
+            retry_max: Duration::from_millis(config.outbox.retry_max_ms),
+        }
+    }
+
+    /// Returns the URL of `path` on the peer at `address`.
+    pub fn url(&self, address: &str, path: &str) -> String {
+        format!("{}{}{}", self.scheme, address, path)
+    }
+
+    /// Returns the peers a broadcast goes to and the acks it waits for under the current membership.
+    pub fn view(&self) -> ClusterView {
+        self.membership.read().unwrap().view(&self.node_id)
+    }
+
+    /// Returns the view without the peers the failure detector considers dead or that are
+    /// quarantined. The quorum is the configured one, so it may exceed the peers left.
+    pub fn live_view(&self) -> ClusterView {
+        let ClusterView { mut addresses, quorum } = self.view();
+        addresses.retain(|address| !self.detector.is_dead(address) && !self.is_quarantined(address));
+        ClusterView { addresses, quorum }
+    }
+
+    /// Checks whether `address` belongs to a member of the current or pending membership.
+    pub fn is_member(&self, address: &str) -> bool {
+        let membership = self.membership.read().unwrap();
+        membership.current.members.iter()
+            .chain(membership.pending.iter().flat_map(|pending| pending.member_set.members.iter()))
+            .any(|member| member.address == address)
+    }
+
+    /// Checks whether the member at `address` is quarantined for misbehaving.
+    pub fn is_quarantined(&self, address: &str) -> bool {
+        let membership = self.membership.read().unwrap();
+        membership.current.members.iter()
+            .chain(membership.pending.iter().flat_map(|pending| pending.member_set.members.iter()))
+            .any(|member| member.address == address && self.evidence.is_quarantined(&member.id))
+    }
+
+    /// Records how `peer` answered the message under `idempotency_key`, if a broadcast awaits it.
+    fn record_delivery(&self, idempotency_key: &str, peer: &str, delivery: Delivery) {
+        if let Some(outcomes) = self.deliveries.lock().unwrap().get_mut(idempotency_key) {
+            outcomes.insert(peer.to_string(), delivery);
+        }
+    }
+}
+
+/// How a peer answered a broadcast. Peers without one are still pending.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Delivery {
+    Acked,
+    Refused(String), // Status of the client error the peer answered with.
+}
+
+/// Builds the node-wide HTTP client with the configured TLS, timeouts and connection reuse.
+/// Over TLS the HTTP version is negotiated instead of assumed.
+pub fn http_client(config: &Config) -> Result<Client, TlsError> {
+    let builder = tls::client_builder(config)?
+        .connect_timeout(Duration::from_millis(config.timeouts.connect_ms))
+        .timeout(Duration::from_millis(config.timeouts.request_ms))
+        .pool_idle_timeout(Duration::from_millis(config.client.pool_idle_timeout_ms))
+        .pool_max_idle_per_host(config.client.pool_max_idle_per_host)
+        .tcp_keepalive(Duration::from_millis(config.client.tcp_keepalive_ms));
+    let builder = match config.client.http2 && !config.tls.is_enabled() {
+        true => builder.http2_prior_knowledge().http2_keep_alive_interval(Duration::from_millis(config.client.tcp_keepalive_ms)),
+        false => builder,
+    };
+    builder.build().map_err(TlsError::Client)
+}
 
-const URL_BASE: &str = "http://";
 
+/// Milliseconds since the Unix epoch, the time unit of the outbox.
+fn now_ms() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+/// Queues a message for `addresses` under `idempotency_key` and wakes the delivery task.
+fn enqueue<T: Serialize>(cluster: &Cluster, addresses: &[String], idempotency_key: &str, path: &str, message: &T) -> Result<(), rusqlite::Error> {
+    let body = serde_json::to_string(message).expect("replication messages serialize");
+    cluster.db.lock().unwrap().enqueue_messages(addresses, idempotency_key, path, &body, now_ms())?;
+    cluster.wake.notify_one();
+    Ok(())
+}
+
+/// Queues a message for `addresses` without waiting for acknowledgements.
+pub fn send<T: Serialize>(cluster: &Cluster, addresses: &[String], path: &str, message: &T) -> Result<(), rusqlite::Error> {
+    enqueue(cluster, addresses, &Uuid::new_v4().to_string(), path, message)
+}
 
-pub async fn post_public_key(
-    ip: String, 
-    public_key: PublicKey, 
-    n_responses: Arc<Mutex<u32>>
-) -> Result<Response, String> {
-    let base = URL_BASE.to_string() + ip.as_ref();
-    let address = base + "/share_public_key";
-    let client = Client::new();
+/// Queues a message for every peer of the membership but the quarantined ones, dead ones
+/// included, and returns once a quorum of peers acknowledged it, every live peer was tried once,
+/// or the request timeout elapsed. Quarantined peers neither receive the message nor lower the
+/// quorum, and with fewer live peers than the quorum it returns at once rather than waiting for
+/// fewer acks. Only peers that accepted the message count as acks; refusals are reported rather
+/// than counted. Undelivered copies stay in the outbox and are retried until acknowledged.
+async fn broadcast<T: Serialize>(cluster: Cluster, what: &'static str, path: &str, message: &T) {
+    let ClusterView { mut addresses, .. } = cluster.view();
+    addresses.retain(|address| !cluster.is_quarantined(address));
+    let ClusterView { addresses: live, quorum: threshold } = cluster.live_view();
+    let idempotency_key = Uuid::new_v4().to_string();
+    cluster.deliveries.lock().unwrap().insert(idempotency_key.clone(), HashMap::new());
+    if let Err(e) = enqueue(&cluster, &addresses, &idempotency_key, path, message) {
+        error!("Could not queue {} for {} peers: {}", what, addresses.len(), e);
+        cluster.deliveries.lock().unwrap().remove(&idempotency_key);
+        return;
+    }
+    if live.len() < threshold as usize {
+        warn!("{} left queued: only {} live peers for a quorum of {}", what, live.len(), threshold);
+        cluster.deliveries.lock().unwrap().remove(&idempotency_key);
+        return;
+    }
+    let deadline = Instant::now() + cluster.request_timeout;
+    loop {
+        let undelivered = match cluster.db.lock().unwrap().get_undelivered(&idempotency_key) {
+            Ok(undelivered) => undelivered,
+            Err(e) => {
+                error!("Could not read the outbox: {}", e);
+                break;
+            },
+        };
+        let acks = {
+            let deliveries = cluster.deliveries.lock().unwrap();
+            let outcomes = &deliveries[&idempotency_key];
+            outcomes.values().filter(|delivery| **delivery == Delivery::Acked).count()
+        };
+        if acks >= threshold as usize {
+            info!("{} reached quorum", what);
+            break;
+        }
+        let untried = undelivered.iter().any(|(peer, attempts)| *attempts == 0 && live.contains(peer));
+        if !untried || Instant::now() >= deadline {
+            info!("{} broadcast finished without quorum, {} peers will be retried", what, undelivered.len());
+            break;
+        }
+        sleep(Duration::from_millis(50)).await; //TODO: optimize. Wait/Notify?
+    }
+    let outcomes = cluster.deliveries.lock().unwrap().remove(&idempotency_key).unwrap_or_default();
+    for (peer, delivery) in outcomes {
+        if let Delivery::Refused(status) = delivery {
+            warn!("{} refused {}: {}", peer, what, status);
+        }
+    }
+}
+
+pub async fn broadcast_public_key(cluster: Cluster, public_key: PublicKey) {
     let id = public_key.id.clone();
     let encoded_key: Arc<str> = public_key.encode().into();
     let data = HashMap::from([
         ("id", id),
         ("public_key", encoded_key),
     ]);
-    let response = match client.post(address)
-        .json(&data)
-        .send()
-        .await {
-            Ok(r) => r,
-            Err(e) => panic!("Item post request failed with {:?}", e),
-        };
-    *n_responses.lock().unwrap() += 1;
-    Ok(response)
+    broadcast(cluster, "Public key", "/share_public_key", &data).await
 }
 
-pub async fn post_patch(
-    ip: String, 
-    patch: Patch, 
+/// Patch as sent to `/share_patch`, with the owner's signature.
+#[derive(Serialize)]
+struct SignedPatch {
+    #[serde(flatten)]
+    patch: Patch,
     signature: Arc<str>,
-    n_responses: Arc<Mutex<u32>>
-) -> Result<Response, String> {
-    let base = URL_BASE.to_string() + ip.as_ref();
-    let address = base + "/share_patch";
-    let client = Client::new();
-    let data = HashMap::from([
-        ("id", patch.id),
-        ("patch_txt", patch.patch_txt),
-        ("signature", signature),
-    ]);
-    let response = match client.post(address)
-        .json(&data)
-        .send()
-        .await {
-            Ok(r) => r,
-            Err(e) => panic!("Item post request failed with {:?}", e),
-        };
-    *n_responses.lock().unwrap() += 1;
-    Ok(response)
-}
-
-pub async fn post_dna_sequence(
-    ip: String, 
-    dna_sequence: DnaSequence, 
-    signature: Arc<str>, 
-    n_responses: Arc<Mutex<u32>>
-) -> Result<Response, String> {
-    let base = URL_BASE.to_string() + ip.as_ref();
-    let address = base + "/share_dna_sequence";
-    println!("Posting dna sequence to {} with id {}", ip, dna_sequence.id.clone());
-    let data = HashMap::from([
-        ("id", dna_sequence.id),
-        ("dna_sequence", dna_sequence.dna_sequence),
-        ("signature", signature),
-    ]);
-    let client = Client::new();
-
-    let response = match client.post(address)
-        .json(&data)
-        .send()
-        .await {
-            Ok(r) => r,
-            Err(e) => panic!("Item post request failed with {:?}", e),
-        };
-    println!("Response: {:?}", response);
-    *n_responses.lock().unwrap() += 1;
-    Ok(response)
-}
-
-pub async fn broadcast_public_key(addresses: Vec<String>, public_key: PublicKey) {
-    let n_responses_arc = Arc::new(Mutex::new(0));
-    let n_responses = n_responses_arc.clone();
-    for address in addresses {
-        let public_key_clone = public_key.clone();//TODO: Expensive clone god knows I tried to avoid
-        let address_clone = address.clone();
-        let n_responses_clone = n_responses_arc.clone();
-        let _ =  tokio::spawn(async move {
-            let _ = post_public_key(address_clone, public_key_clone, n_responses_clone).await;
-        }).await;
-    };
+}
 
-    let mut quorum = false; //this is to minimize the lock usage
-    while !quorum {
-        tokio::time::sleep(Duration::from_millis(50)).await; //TODO: optimize. Wait/Notify?
-        if *n_responses.lock().unwrap() <= BYZANTINE_THRESHOLD {
-            quorum = true;
-        }
+pub async fn broadcast_patch(cluster: Cluster, signature: Arc<str>, patch: Patch) {
+    let data = SignedPatch { patch, signature };
+    broadcast(cluster, "Patch", "/share_patch", &data).await
+}
+
+/// Broadcasts a sequence. Sequences longer than the largest chunk are sent as a manifest, so
+/// peers fetch only the chunks they lack from this node instead of receiving the whole text.
+pub async fn broadcast_dna_sequence(cluster: Cluster, dna_sequence: DnaSequence, signature: Arc<str>) {
+    if dna_sequence.dna_sequence.len() > MAX_CHUNK {
+        let manifest = SequenceManifest {
+            id: dna_sequence.id.clone(),
+            chunks: chunk::split(&dna_sequence.dna_sequence).into_iter().map(|(chunk, _)| chunk).collect(),
+            length: dna_sequence.dna_sequence.len(),
+            signature,
+            encrypted: dna_sequence.encrypted,
+            counter: dna_sequence.counter,
+            source: cluster.address.clone(),
+        };
+        return broadcast(cluster, "Dna sequence manifest", "/share_manifest", &manifest).await;
     }
+    let data = DnaSequence::signed(
+        dna_sequence.id,
+        dna_sequence.dna_sequence,
+        signature,
+        dna_sequence.encrypted,
+        dna_sequence.counter,
+    );
+    broadcast(cluster, "Dna sequence", "/share_dna_sequence", &data).await
 }
 
-//TODO: Generic version of theses methods. Data could be a box.
-//Would probably require reflection.
-pub async fn broadcast_patch(addresses: Vec<String>, signature: Arc<str>, patch: Patch) {
-    let n_responses_arc = Arc::new(Mutex::new(0));
-    let n_responses = n_responses_arc.clone();
-    for address in addresses {
-        let patch_clone = patch.clone();//TODO: Expensive clone god knows I tried to avoid
-        let address_clone = address.clone();
-        let signature_clone = signature.clone();
-        let n_responses_clone = n_responses_arc.clone();
-        let _ =  tokio::spawn(async move {
-            let _ = post_patch(address_clone, patch_clone, signature_clone, n_responses_clone).await;
-        }).await;
-    };
+pub async fn broadcast_tombstone(cluster: Cluster, tombstone: Tombstone) {
+    broadcast(cluster, "Tombstone", "/share_tombstone", &tombstone).await
+}
 
-    let mut quorum = false; //this is to minimize the lock usage
-    while !quorum {
-        sleep(Duration::from_millis(50)).await; //TODO: optimize. Wait/Notify?
-        if *n_responses.lock().unwrap() <= BYZANTINE_THRESHOLD {
-            quorum = true;
+/// Delivers queued messages to every peer that has some due, one peer at a time per task so
+/// each peer receives its messages in order. Messages for peers that left the membership are
+/// dropped; dead peers are skipped until the failure detector sees them again.
+pub fn spawn_delivery(cluster: Cluster) {
+    tokio::spawn(async move {
+        let busy: Arc<Mutex<HashSet<String>>> = Arc::new(Mutex::new(HashSet::new()));
+        loop {
+            tokio::select! {
+                _ = cluster.wake.notified() => (),
+                _ = sleep(cluster.poll_interval) => (),
+            }
+            let due = match cluster.db.lock().unwrap().get_due_peers(now_ms()) {
+                Ok(due) => due,
+                Err(e) => {
+                    error!("Could not read the outbox: {}", e);
+                    continue;
+                },
+            };
+            let members = cluster.view().addresses;
+            for peer in due {
+                if !members.contains(&peer) {
+                    match cluster.db.lock().unwrap().drop_messages(&peer) {
+                        Ok(n) => warn!("Dropped {} queued messages for {}, which left the membership", n, peer),
+                        Err(e) => error!("Could not drop messages for {}: {}", peer, e),
+                    }
+                    continue;
+                }
+                if cluster.detector.is_dead(&peer) || !busy.lock().unwrap().insert(peer.clone()) {
+                    continue;
+                }
+                let cluster = cluster.clone();
+                let busy = busy.clone();
+                tokio::spawn(async move {
+                    deliver(&cluster, &peer).await;
+                    busy.lock().unwrap().remove(&peer);
+                });
+            }
         }
-    }
+    });
 }
-//TODO: Generic version of theses methods. Data could be a box.
-pub async fn broadcast_dna_sequence(addresses: Vec<String>, dna_sequence: DnaSequence, signature: Arc<str>) {
-    let n_responses_arc = Arc::new(Mutex::new(0));
-    let n_responses = n_responses_arc.clone();
-    let mut handles = vec![];
-    let threads = tokio::spawn(async move {
-        for address in addresses {
-            let dna_sequence_clone = dna_sequence.clone();
-            let address_clone = address.clone();
-            let n_responses_clone = n_responses_arc.clone();
-            let signature = signature.clone();
-            let res =  tokio::spawn(async move {
-                let _ = post_dna_sequence(address_clone, 
-                    dna_sequence_clone, 
-                    signature, 
-                    n_responses_clone).await;
-            });
-            handles.push(res);
 
+/// Sends the queued messages of `peer` in order until its queue is empty or a delivery fails.
+/// Client errors are final, so refused messages are dropped rather than blocking the queue, and
+/// reported to the broadcast awaiting them as refusals rather than acks.
+async fn deliver(cluster: &Cluster, peer: &str) {
+    loop {
+        let message = match cluster.db.lock().unwrap().next_message(peer) {
+            Ok(Some(message)) if message.next_attempt_at <= now_ms() => message,
+            Ok(_) => return,
+            Err(e) => {
+                error!("Could not read the outbox: {}", e);
+                return;
+            },
         };
-        let _ = join_all(handles).await;
-    });
+        let started = Instant::now();
+        let response = cluster.client.post(cluster.url(peer, &message.path))
+            .header(IDEMPOTENCY_KEY, message.idempotency_key.as_ref())
+            .header(CONTENT_TYPE, "application/json")
+            .body(message.body.clone())
+            .send()
+            .await;
+        cluster.metrics.record(peer, response.as_ref().ok().map(|_| started.elapsed()));
+        let failure = match response {
+            Ok(response) if response.status().is_success() => {
+                cluster.record_delivery(&message.idempotency_key, peer, Delivery::Acked);
+                None
+            },
+            Ok(response) if response.status().is_client_error() => {
+                warn!("{} refused {} message {}: {}", peer, &message.path, &message.idempotency_key, response.status());
+                cluster.record_delivery(&message.idempotency_key, peer, Delivery::Refused(response.status().to_string()));
+                None
+            },
+            Ok(response) => Some(response.status().to_string()),
+            Err(e) => Some(e.to_string()),
+        };
+        let db = cluster.db.lock().unwrap();
+        let Some(failure) = failure else {
+            if let Err(e) = db.remove_message(message.seq) {
+                error!("Could not remove delivered message {}: {}", message.seq, e);
+                return;
+            }
+            continue;
+        };
+        let attempts = message.attempts + 1;
+        let delay = cluster.retry_base.saturating_mul(1 << (attempts - 1).min(16)).min(cluster.retry_max);
+        info!("{} post to {} failed ({} attempts), retrying in {:?}: {}", &message.path, peer, attempts, delay, failure);
+        if let Err(e) = db.postpone_message(message.seq, attempts, now_ms() + delay.as_millis() as i64) {
+            error!("Could not postpone message {}: {}", message.seq, e);
+        }
+        return;
+    }
+}
 
-    let counter = tokio::spawn(async move {
-        let mut quorum = false; //this is to minimize the lock usage
-        while !quorum {
-            sleep(Duration::from_millis(500)).await; //TODO: optimize. Wait/Notify?
-            if *n_responses.lock().unwrap() >= BYZANTINE_THRESHOLD {
-                quorum = true;
+/// Sends one step of a reconfiguration to `addresses` concurrently, returning the response
+/// bodies of the peers that accepted it.
+pub async fn broadcast_reconfiguration<T: Serialize>(cluster: &Cluster, addresses: Vec<String>, path: &str, message: &T) -> Vec<String> {
+    let requests = addresses.into_iter().map(|address| {
+        let request = cluster.client.post(cluster.url(&address, path)).json(message).send();
+        async move {
+            let started = Instant::now();
+            let response = request.await;
+            cluster.metrics.record(&address, response.as_ref().ok().map(|_| started.elapsed()));
+            match response {
+                Ok(response) if response.status().is_success() => match response.text().await {
+                    Ok(body) => Some(body),
+                    Err(e) => {
+                        info!("Could not read the reconfiguration answer of {}: {}", address, e);
+                        None
+                    },
+                },
+                Ok(response) => {
+                    info!("{} refused reconfiguration: {}", address, response.status());
+                    None
+                },
+                Err(e) => {
+                    info!("Reconfiguration post to {} failed: {}", address, e);
+                    None
+                },
             }
         }
     });
-    tokio::select!{
-        _val = counter => {
-            info!("Counter completed");
-        }
-        _val = threads => {
-            info!("Shouldn't really happen");
-        }
-    };
+    join_all(requests).await.into_iter().flatten().collect()
 }
-
-
-
-    
-    
-
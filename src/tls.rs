@@ -0,0 +1,135 @@
+use std::any::Any;
+use std::fs;
+
+use actix_tls::accept::openssl::TlsStream;
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{Extensions, ServiceRequest, ServiceResponse},
+    middleware::Next,
+    rt::net::TcpStream,
+    HttpResponse,
+    web,
+    Error,
+};
+use openssl::error::ErrorStack;
+use openssl::ssl::{SslAcceptor, SslAcceptorBuilder, SslFiletype, SslMethod, SslVerifyMode};
+use reqwest::{Certificate, Client, ClientBuilder, Identity};
+use thiserror::Error;
+use tracing::{debug, warn};
+
+use crate::config::{Config, TlsConfig};
+
+/// Paths only other nodes call. With a cluster CA configured they require a client certificate.
+const PEER_PATHS: &[&str] = &[
+    "/share_public_key",
+    "/share_patch",
+    "/share_dna_sequence",
+    "/share_tombstone",
+    "/cluster/reconfigure",
+    "/cluster/reconfigure/commit",
+    "/ordering/message",
+    "/ordering/request",
+    "/ordering/log",
+    "/ordering/view",
+    "/snapshot",
+    "/share_manifest",
+];
+
+/// Prefixes of peer-only paths that carry a parameter.
+const PEER_PREFIXES: &[&str] = &["/chunks/"];
+
+/// Errors for loading certificates and keys.
+#[derive(Error, Debug, derive_more::Display)]
+pub enum TlsError {
+    #[display(fmt = "Could not read {}: {}", _0, _1)]
+    Read(String, std::io::Error),
+    #[display(fmt = "Invalid TLS material in {}: {}", _0, _1)]
+    Invalid(String, String),
+    #[display(fmt = "Could not build the HTTP client: {}", _0)]
+    Client(reqwest::Error),
+}
+
+/// Subject of the verified certificate a peer presented when connecting.
+#[derive(Clone, Debug)]
+pub struct PeerCertificate(pub String);
+
+fn read(path: &str) -> Result<Vec<u8>, TlsError> {
+    fs::read(path).map_err(|e| TlsError::Read(path.to_string(), e))
+}
+
+/// Returns the URL scheme peers are reached with.
+pub fn scheme(config: &Config) -> &'static str {
+    if config.tls.is_enabled() { "https://" } else { "http://" }
+}
+
+/// Builds the API listener's TLS acceptor. With a CA it asks connecting clients for a certificate
+/// issued by that CA; clients without one may still connect but are refused on peer paths.
+pub fn acceptor(tls: &TlsConfig) -> Result<SslAcceptorBuilder, TlsError> {
+    let (Some(cert), Some(key)) = (&tls.cert, &tls.key) else {
+        return Err(TlsError::Invalid("tls".to_string(), "cert and key must both be set".to_string()));
+    };
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).map_err(invalid("tls"))?;
+    builder.set_certificate_chain_file(cert).map_err(invalid(cert))?;
+    builder.set_private_key_file(key, SslFiletype::PEM).map_err(invalid(key))?;
+    builder.check_private_key().map_err(invalid(key))?;
+    if let Some(ca) = &tls.ca {
+        builder.set_ca_file(ca).map_err(invalid(ca))?;
+        builder.set_verify(SslVerifyMode::PEER);
+    }
+    Ok(builder)
+}
+
+/// Maps an OpenSSL error about the file at `path`.
+fn invalid(path: &str) -> impl Fn(ErrorStack) -> TlsError + '_ {
+    move |e| TlsError::Invalid(path.to_string(), e.to_string())
+}
+
+/// Starts an HTTP client builder that trusts the cluster CA and presents the node's certificate.
+pub fn client_builder(config: &Config) -> Result<ClientBuilder, TlsError> {
+    let mut builder = Client::builder();
+    if let Some(ca) = &config.tls.ca {
+        let certificates = Certificate::from_pem_bundle(&read(ca)?)
+            .map_err(|e| TlsError::Invalid(ca.clone(), e.to_string()))?;
+        for certificate in certificates {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+    if let (Some(cert), Some(key)) = (&config.tls.cert, &config.tls.key) {
+        let identity = Identity::from_pkcs8_pem(&read(cert)?, &read(key)?)
+            .map_err(|e| TlsError::Invalid(key.clone(), e.to_string()))?;
+        builder = builder.identity(identity);
+    }
+    Ok(builder)
+}
+
+/// Stores the subject of a verified client certificate in the connection's data.
+pub fn on_connect(connection: &dyn Any, data: &mut Extensions) {
+    let Some(stream) = connection.downcast_ref::<TlsStream<TcpStream>>() else {
+        return;
+    };
+    if let Some(certificate) = stream.ssl().peer_certificate() {
+        let subject: Vec<String> = certificate.subject_name().entries()
+            .filter_map(|entry| entry.data().to_string().ok())
+            .collect();
+        data.insert(PeerCertificate(subject.join(",")));
+    }
+}
+
+/// Middleware refusing peer requests over connections without a client certificate when
+/// mutual TLS is configured.
+pub async fn require_peer_certificate(
+    request: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let mutual = request.app_data::<web::Data<Config>>().is_some_and(|config| config.tls.ca.is_some());
+    let peer_path = PEER_PATHS.contains(&request.path()) || PEER_PREFIXES.iter().any(|prefix| request.path().starts_with(prefix));
+    if mutual && peer_path {
+        let Some(PeerCertificate(subject)) = request.conn_data::<PeerCertificate>() else {
+            warn!("Refused {} from {:?} without a client certificate", request.path(), request.peer_addr());
+            let response = HttpResponse::Forbidden().body("Peer requests require a certificate issued by the cluster CA");
+            return Ok(request.into_response(response).map_into_right_body());
+        };
+        debug!("{} from peer {}", request.path(), subject);
+    }
+    Ok(next.call(request).await?.map_into_left_body())
+}